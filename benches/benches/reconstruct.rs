@@ -0,0 +1,76 @@
+use ckb_core::header::HeaderBuilder;
+use ckb_core::transaction::{CellInput, CellOutput, OutPoint, Transaction, TransactionBuilder};
+use ckb_pool::txs_pool::Pool;
+use ckb_protocol::{short_transaction_id, short_transaction_id_keys, ShortTransactionID};
+use criterion::{criterion_group, criterion_main, Criterion};
+use fnv::{FnvHashMap, FnvHashSet};
+use numext_fixed_hash::H256;
+
+const POOL_SIZE: u32 = 10_000;
+const BLOCK_SIZE: usize = 10;
+
+// A pool of POOL_SIZE transactions and the short ids of a block committing BLOCK_SIZE
+// of them
+fn setup() -> (Pool, (u64, u64), Vec<ShortTransactionID>) {
+    let mut pool = Pool::new();
+    for index in 0..POOL_SIZE {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(H256::zero(), index),
+                Default::default(),
+            ))
+            .output(CellOutput::new(1, Vec::new(), H256::zero(), None))
+            .build();
+        pool.add_transaction(tx);
+    }
+    let header = HeaderBuilder::default().build();
+    let (key0, key1) = short_transaction_id_keys(0, &header, 0);
+    let short_ids = pool
+        .get_mineable_transactions(BLOCK_SIZE)
+        .iter()
+        .map(|tx| short_transaction_id(key0, key1, &tx.hash()))
+        .collect();
+    (pool, (key0, key1), short_ids)
+}
+
+fn place(
+    short_ids: &[ShortTransactionID],
+    (key0, key1): (u64, u64),
+    transactions: Vec<Transaction>,
+) -> usize {
+    let txs_map = transactions
+        .into_iter()
+        .map(|tx| (short_transaction_id(key0, key1, &tx.hash()), tx))
+        .collect::<FnvHashMap<_, _>>();
+    short_ids
+        .iter()
+        .filter(|short_id| txs_map.contains_key(*short_id))
+        .count()
+}
+
+// The pool side of reconstructing a small block against a large pool, copying every
+// pool transaction versus only the ones the block lists
+fn bench(c: &mut Criterion) {
+    c.bench_function("bench_reconstruct_full_pool", |b| {
+        let (pool, keys, short_ids) = setup();
+        b.iter(|| {
+            let transactions = pool.get_mineable_transactions(pool.size());
+            assert_eq!(place(&short_ids, keys, transactions), BLOCK_SIZE);
+        })
+    });
+
+    c.bench_function("bench_reconstruct_targeted", |b| {
+        let (pool, keys, short_ids) = setup();
+        b.iter(|| {
+            let wanted = short_ids.iter().cloned().collect::<FnvHashSet<_>>();
+            let (key0, key1) = keys;
+            let transactions = pool.get_matching_transactions(|hash| {
+                wanted.contains(&short_transaction_id(key0, key1, hash))
+            });
+            assert_eq!(place(&short_ids, keys, transactions), BLOCK_SIZE);
+        })
+    });
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -19,10 +19,16 @@ use std::cmp;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
+// Blocks of the current chain a reorg may roll back at most, a deeper one is more likely a
+// bug or an attack than a real fork and is rejected unless the limit is lifted
+pub const MAX_REORG_DEPTH: BlockNumber = 1000;
+
 pub struct ChainService<CI> {
     shared: Shared<CI>,
     notify: NotifyController,
     block_verifier: BlockVerifier<Shared<CI>>,
+    // 0 for no limit
+    max_reorg_depth: BlockNumber,
 }
 
 #[derive(Clone)]
@@ -59,12 +65,17 @@ pub struct BlockInsertionResult {
 }
 
 impl<CI: ChainIndex + 'static> ChainService<CI> {
-    pub fn new(shared: Shared<CI>, notify: NotifyController) -> ChainService<CI> {
+    pub fn new(
+        shared: Shared<CI>,
+        notify: NotifyController,
+        max_reorg_depth: BlockNumber,
+    ) -> ChainService<CI> {
         let block_verifier = BlockVerifier::new(shared.clone());
         ChainService {
             shared,
             notify,
             block_verifier,
+            max_reorg_depth,
         }
     }
 
@@ -102,9 +113,7 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
                 .verify(&block)
                 .map_err(ProcessBlockError::Verification)?
         }
-        let insert_result = self
-            .insert_block(&block)
-            .map_err(ProcessBlockError::Shared)?;
+        let insert_result = self.insert_block(&block)?;
         self.post_insert_result(block, insert_result);
         debug!(target: "chain", "finish processing block");
         Ok(())
@@ -134,8 +143,25 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
             .update_transaction_meta(batch, root, cells)
             .ok_or(SharedError::InvalidOutput)
     }
+    // Blocks of the current chain the block rolls back when it becomes the tip, found by
+    // walking its ancestors down to the first one on the current chain
+    fn reorg_depth(&self, tip_number: BlockNumber, block: &Block) -> BlockNumber {
+        let mut number = block.header().number() - 1;
+        let mut hash = block.header().parent_hash().clone();
+        while self.shared.block_hash(number).as_ref() != Some(&hash) {
+            hash = self
+                .shared
+                .block_header(&hash)
+                .expect("ancestor already store")
+                .parent_hash()
+                .clone();
+            number -= 1;
+        }
+        tip_number - number
+    }
+
     #[allow(clippy::op_ref)]
-    fn insert_block(&self, block: &Block) -> Result<BlockInsertionResult, SharedError> {
+    fn insert_block(&self, block: &Block) -> Result<BlockInsertionResult, ProcessBlockError> {
         let mut new_best_block = false;
         let mut output_root = H256::zero();
         let mut total_difficulty = U256::zero();
@@ -145,6 +171,32 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
 
         let mut tip_header = self.shared.tip_header().write();
         let tip_number = tip_header.number();
+
+        if self.max_reorg_depth > 0 && block.header().parent_hash() != &tip_header.hash() {
+            let parent_ext = self
+                .shared
+                .store()
+                .get_block_ext(&block.header().parent_hash())
+                .expect("parent already store");
+            let cannon_total_difficulty = parent_ext.total_difficulty + block.header().difficulty();
+            let current_total_difficulty = tip_header.total_difficulty();
+            if &cannon_total_difficulty > current_total_difficulty
+                || (current_total_difficulty == &cannon_total_difficulty
+                    && block.header().hash() < tip_header.hash())
+            {
+                let depth = self.reorg_depth(tip_number, block);
+                if depth > self.max_reorg_depth {
+                    error!(
+                        target: "chain",
+                        "reject block {} => {}, reorg rolling back {} blocks exceeds the limit {}",
+                        block.header().number(), block.header().hash(),
+                        depth, self.max_reorg_depth
+                    );
+                    return Err(ProcessBlockError::ReorgTooDeep(depth));
+                }
+            }
+        }
+
         self.shared.store().save_with_batch(|batch| {
             let root = self.check_transactions(batch, block)?;
             let parent_ext = self
@@ -367,6 +419,7 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
 pub struct ChainBuilder<CI> {
     shared: Shared<CI>,
     notify: Option<NotifyController>,
+    max_reorg_depth: BlockNumber,
 }
 
 impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
@@ -376,6 +429,7 @@ impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
         ChainBuilder {
             shared,
             notify: None,
+            max_reorg_depth: MAX_REORG_DEPTH,
         }
     }
 
@@ -384,13 +438,19 @@ impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
         self
     }
 
+    // 0 lifts the limit
+    pub fn max_reorg_depth(mut self, value: BlockNumber) -> Self {
+        self.max_reorg_depth = value;
+        self
+    }
+
     pub fn build(mut self) -> ChainService<CI> {
         let notify = self.notify.take().unwrap_or_else(|| {
             // FIXME: notify should not be optional
             let (_handle, notify) = NotifyService::default().start::<&str>(None);
             notify
         });
-        ChainService::new(self.shared, notify)
+        ChainService::new(self.shared, notify, self.max_reorg_depth)
     }
 }
 
@@ -654,6 +714,54 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn test_reorg_deeper_than_limit_rejected() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(Consensus::default().set_verification(false))
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::build();
+        let chain_service = ChainBuilder::new(shared.clone()).max_reorg_depth(5).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+
+        let gen_chain = |parent: Header, nonce: u64, step: u64, len: u64| {
+            let mut parent = parent;
+            (0..len)
+                .map(|i| {
+                    let difficulty = parent.difficulty().clone() + U256::from(step);
+                    let block = gen_block(parent.clone(), nonce + i, difficulty, vec![], vec![]);
+                    parent = block.header().clone();
+                    block
+                })
+                .collect::<Vec<_>>()
+        };
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let chain1 = gen_chain(genesis.clone(), 0, 100, 10);
+        for block in &chain1 {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        let tip = chain1[9].header().hash();
+
+        // a heavier chain forking from the genesis would roll back all the 10 blocks
+        let chain2 = gen_chain(genesis, 1000, 1000, 10);
+        let rejected = chain2
+            .iter()
+            .map(|block| chain_controller.process_block(Arc::new(block.clone())))
+            .find(Result::is_err);
+        assert_eq!(rejected, Some(Err(ProcessBlockError::ReorgTooDeep(10))));
+        assert_eq!(shared.tip_header().read().hash(), tip);
+
+        // rolling back 2 blocks is within the limit
+        let chain3 = gen_chain(chain1[7].header().clone(), 2000, 1000, 3);
+        for block in &chain3 {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(shared.tip_header().read().hash(), chain3[2].header().hash());
+    }
+
     #[test]
     fn test_chain_get_ancestor() {
         let (chain_controller, shared) = start_chain(None);
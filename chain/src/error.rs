@@ -1,3 +1,4 @@
+use ckb_core::header::BlockNumber;
 use ckb_shared::error::SharedError;
 use ckb_verification::Error as VerifyError;
 
@@ -5,4 +6,12 @@ use ckb_verification::Error as VerifyError;
 pub enum ProcessBlockError {
     Shared(SharedError),
     Verification(VerifyError),
+    // The block would become the tip by rolling back this many blocks, more than allowed
+    ReorgTooDeep(BlockNumber),
+}
+
+impl From<SharedError> for ProcessBlockError {
+    fn from(err: SharedError) -> Self {
+        ProcessBlockError::Shared(err)
+    }
 }
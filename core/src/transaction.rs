@@ -47,6 +47,10 @@ impl OutPoint {
         let OutPoint { hash, index } = self;
         (hash, index)
     }
+
+    pub fn bytes_len(&self) -> usize {
+        H256::size_of() + mem::size_of::<u32>()
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
@@ -85,6 +89,10 @@ impl CellInput {
         } = self;
         (previous_output, unlock)
     }
+
+    pub fn bytes_len(&self) -> usize {
+        self.previous_output.bytes_len() + self.unlock.bytes_len()
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
@@ -263,6 +271,17 @@ impl Transaction {
     pub fn get_output(&self, i: usize) -> Option<CellOutput> {
         self.outputs.get(i).cloned()
     }
+
+    pub fn bytes_len(&self) -> usize {
+        mem::size_of::<u32>()
+            + self.deps.iter().map(OutPoint::bytes_len).sum::<usize>()
+            + self.inputs.iter().map(CellInput::bytes_len).sum::<usize>()
+            + self
+                .outputs
+                .iter()
+                .map(CellOutput::bytes_len)
+                .sum::<usize>()
+    }
 }
 
 #[derive(Default)]
@@ -1,9 +1,11 @@
 use crate::errors::{Error, ErrorKind};
-use crate::{Network, SessionInfo, Timer};
+use crate::pending_tasks::TaskPriority;
+use crate::{Network, PeerId, SessionInfo, Timer};
 use crate::{PeerIndex, ProtocolId, TimerToken};
 use ckb_util::Mutex;
 use log::debug;
-use log::info;
+use log::{error, info};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -40,6 +42,33 @@ pub trait CKBProtocolContext: Send {
             .collect()
     }
     fn connected_peers(&self) -> Vec<PeerIndex>;
+    fn local_peer_id(&self) -> Option<PeerId>;
+}
+
+// A panic inside a handler only ends the task it is spawned in, which leaves no trace
+// of what failed. Run the handler catching the panic, log it with the task and peer it
+// happened in and return the logged context
+pub(crate) fn catch_handler_panic<F: FnOnce()>(
+    task: &str,
+    protocol_id: ProtocolId,
+    peer_index: Option<PeerIndex>,
+    handler: F,
+) -> Option<String> {
+    panic::catch_unwind(AssertUnwindSafe(handler))
+        .err()
+        .map(|err| {
+            let reason = err
+                .downcast_ref::<&str>()
+                .map(|reason| (*reason).to_owned())
+                .or_else(|| err.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown reason".to_owned());
+            let context = format!(
+                "{} handler of protocol {:?} panicked, peer {:?}: {}",
+                task, protocol_id, peer_index, reason
+            );
+            error!(target: "network", "{}", context);
+            context
+        })
 }
 
 pub(crate) struct DefaultCKBProtocolContext {
@@ -147,6 +176,10 @@ impl CKBProtocolContext for DefaultCKBProtocolContext {
     fn connected_peers(&self) -> Vec<PeerIndex> {
         self.network.peers_indexes()
     }
+
+    fn local_peer_id(&self) -> Option<PeerId> {
+        Some(self.network.local_peer_id().clone())
+    }
 }
 
 pub trait CKBProtocolHandler: Sync + Send {
@@ -155,4 +188,8 @@ pub trait CKBProtocolHandler: Sync + Send {
     fn connected(&self, _nc: Box<dyn CKBProtocolContext>, _peer: PeerIndex);
     fn disconnected(&self, _nc: Box<dyn CKBProtocolContext>, _peer: PeerIndex);
     fn timer_triggered(&self, _nc: Box<dyn CKBProtocolContext>, _timer: TimerToken) {}
+    // Classifies a received message, the low priority ones are dropped first under load
+    fn task_priority(&self, _data: &[u8]) -> TaskPriority {
+        TaskPriority::High
+    }
 }
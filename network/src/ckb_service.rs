@@ -1,18 +1,19 @@
 #![allow(clippy::needless_pass_by_value)]
 
 use crate::ckb_protocol::CKBProtocolOutput;
-use crate::ckb_protocol_handler::DefaultCKBProtocolContext;
+use crate::ckb_protocol_handler::{catch_handler_panic, DefaultCKBProtocolContext};
 use crate::peer_store::{Behaviour, Status};
+use crate::pending_tasks::{PendingTasks, TaskPriority};
 use crate::protocol::Protocol;
 use crate::protocol_service::ProtocolService;
-use crate::CKBProtocolHandler;
 use crate::Network;
 use crate::PeerId;
+use crate::{CKBProtocolContext, CKBProtocolHandler, Severity};
 use faketime::unix_time_as_millis;
 use futures::future::{self, Future};
 use futures::Stream;
 use libp2p::core::{Endpoint, Multiaddr, UniqueConnecState};
-use log::{error, info};
+use log::{debug, error, info, warn};
 use std::boxed::Box;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::sync::Arc;
@@ -77,18 +78,101 @@ impl CKBService {
                     network.modify_peer(&peer_id, |peer| {
                         peer.last_message_time = Some(unix_time_as_millis())
                     });
+                    let mut priority = TaskPriority::High;
+                    catch_handler_panic("task_priority", protocol_id, Some(peer_index), || {
+                        priority = protocol_handler.task_priority(&data)
+                    });
+                    let pending_task = match PendingTasks::try_acquire(
+                        &network.pending_tasks,
+                        peer_index,
+                        priority,
+                    ) {
+                        Some(pending_task) => future::Either::A(future::ok(pending_task)),
+                        // blocks and headers keep us in sync, wait for a slot rather than
+                        // dropping them, reading nothing more from the peer meanwhile
+                        None if priority == TaskPriority::Critical => {
+                            debug!(
+                                target: "network",
+                                "peer {:?} is busy, wait for a slot for message of protocol {:?}",
+                                peer_id,
+                                protocol_id
+                            );
+                            future::Either::B(
+                                PendingTasks::acquire(&network.pending_tasks, peer_index, priority)
+                                    .map_err(|()| {
+                                        IoError::new(IoErrorKind::Other, "acquire pending task")
+                                    }),
+                            )
+                        }
+                        // not the fault of the peer, all of them together keep us busy
+                        None if priority == TaskPriority::Low
+                            && network.pending_tasks.is_overloaded() =>
+                        {
+                            debug!(
+                                target: "network",
+                                "overloaded, drop low priority message of protocol {:?} from peer {:?}",
+                                protocol_id,
+                                peer_id
+                            );
+                            return Box::new(future::ok(()))
+                                as Box<Future<Item = (), Error = IoError> + Send>;
+                        }
+                        None => {
+                            // the peer sends faster than we handle, drop what it sent. A
+                            // burst is forgiven, the peer is only reported once it keeps
+                            // flooding us
+                            debug!(
+                                target: "network",
+                                "peer {:?} has {} messages pending, drop message of protocol {:?}",
+                                peer_id,
+                                network.pending_tasks.pending(peer_index),
+                                protocol_id
+                            );
+                            if network.pending_tasks.message_dropped(peer_index) {
+                                warn!(
+                                    target: "network",
+                                    "peer {:?} keeps flooding messages of protocol {:?}",
+                                    peer_id,
+                                    protocol_id
+                                );
+                                DefaultCKBProtocolContext::new(Arc::clone(&network), protocol_id)
+                                    .report_peer(peer_index, Severity::Bad("message flooding"));
+                            }
+                            return Box::new(future::ok(()));
+                        }
+                    };
                     let protocol_handler = Arc::clone(&protocol_handler);
                     let network = Arc::clone(&network);
-                    let handle_received = future::lazy(move || {
-                        protocol_handler.received(
-                            Box::new(DefaultCKBProtocolContext::new(network, protocol_id)),
-                            peer_index,
-                            &data,
-                        );
-                        Ok(())
-                    });
-                    tokio::spawn(handle_received);
-                    Ok(())
+                    Box::new(pending_task.map(move |pending_task| {
+                        let handle_received = future::lazy(move || {
+                            // frees the slot of the peer once handled
+                            let _pending_task = pending_task;
+                            let panicked = catch_handler_panic(
+                                "received",
+                                protocol_id,
+                                Some(peer_index),
+                                || {
+                                    protocol_handler.received(
+                                        Box::new(DefaultCKBProtocolContext::new(
+                                            Arc::clone(&network),
+                                            protocol_id,
+                                        )),
+                                        peer_index,
+                                        &data,
+                                    )
+                                },
+                            );
+                            // the handler panicked on what the peer sent
+                            if panicked.is_some() {
+                                DefaultCKBProtocolContext::new(network, protocol_id).report_peer(
+                                    peer_index,
+                                    Severity::Bad("handler panicked"),
+                                );
+                            }
+                            Ok(())
+                        });
+                        tokio::spawn(handle_received);
+                    }))
                 }
             });
             protocol_connec
@@ -137,13 +221,15 @@ impl CKBService {
         }
         {
             let handle_connected = future::lazy(move || {
-                protocol_handler.connected(
-                    Box::new(DefaultCKBProtocolContext::new(
-                        Arc::clone(&network),
-                        protocol_id,
-                    )),
-                    peer_index,
-                );
+                catch_handler_panic("connected", protocol_id, Some(peer_index), || {
+                    protocol_handler.connected(
+                        Box::new(DefaultCKBProtocolContext::new(
+                            Arc::clone(&network),
+                            protocol_id,
+                        )),
+                        peer_index,
+                    )
+                });
                 Ok(())
             });
             tokio::spawn(handle_connected);
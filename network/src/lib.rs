@@ -12,6 +12,7 @@ mod network_service;
 mod outbound_peer_service;
 pub mod peer_store;
 mod peers_registry;
+mod pending_tasks;
 mod ping_service;
 mod protocol;
 mod protocol_service;
@@ -26,6 +27,7 @@ pub use crate::errors::{Error, ErrorKind};
 pub use crate::network::{Network, PeerInfo, SessionInfo};
 pub use crate::network_config::NetworkConfig;
 pub use crate::network_service::NetworkService;
+pub use crate::pending_tasks::TaskPriority;
 pub use libp2p::{
     core::Endpoint, multiaddr::AddrComponent, multiaddr::ToMultiaddr, Multiaddr, PeerId,
 };
@@ -59,6 +61,11 @@ pub struct Config {
     /// Minimum number of connected peers to maintain
     pub max_peers: u32,
     pub outbound_peers_ratio: Option<u32>,
+    /// Maximum number of received messages of a single peer waiting to be handled
+    pub max_pending_tasks_per_peer: Option<usize>,
+    /// Number of received messages of all peers waiting to be handled from which low
+    /// priority ones are dropped
+    pub max_pending_tasks: Option<usize>,
     pub config_dir_path: Option<String>,
 }
 
@@ -82,6 +89,12 @@ impl From<Config> for NetworkConfig {
         cfg.listen_addresses = config.listen_addresses;
         cfg.bootnodes = config.bootnodes;
         cfg.reserved_peers = config.reserved_nodes;
+        if let Some(value) = config.max_pending_tasks_per_peer {
+            cfg.max_pending_tasks_per_peer = value;
+        }
+        if let Some(value) = config.max_pending_tasks {
+            cfg.max_pending_tasks = value;
+        }
         if let Some(value) = config.non_reserved_mode {
             cfg.reserved_only = match value.as_str() {
                 "Accept" => false,
@@ -8,6 +8,7 @@ use crate::identify_service::IdentifyService;
 use crate::outbound_peer_service::OutboundPeerService;
 use crate::peer_store::{Behaviour, PeerStore, SqlitePeerStore};
 use crate::peers_registry::{ConnectionStatus, PeerConnection, PeerIdentifyInfo, PeersRegistry};
+use crate::pending_tasks::PendingTasks;
 use crate::ping_service::PingService;
 use crate::protocol::Protocol;
 use crate::protocol_service::ProtocolService;
@@ -69,6 +70,7 @@ pub struct Network {
     pub(crate) listened_addresses: RwLock<Vec<Multiaddr>>,
     pub(crate) original_listened_addresses: RwLock<Vec<Multiaddr>>,
     pub(crate) ckb_protocols: CKBProtocols<Arc<CKBProtocolHandler>>,
+    pub(crate) pending_tasks: Arc<PendingTasks>,
     local_private_key: secio::SecioKeyPair,
     local_peer_id: PeerId,
 }
@@ -497,6 +499,10 @@ impl Network {
             listened_addresses: RwLock::new(listened_addresses),
             original_listened_addresses: RwLock::new(Vec::new()),
             ckb_protocols: CKBProtocols(ckb_protocols),
+            pending_tasks: Arc::new(PendingTasks::new(
+                config.max_pending_tasks_per_peer,
+                config.max_pending_tasks,
+            )),
             local_private_key: local_private_key.clone(),
             local_peer_id: local_private_key.to_peer_id(),
         });
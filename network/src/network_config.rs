@@ -40,6 +40,8 @@ pub struct NetworkConfig {
     pub identify_interval: Duration,
     pub try_outbound_connect_timeout: Duration,
     pub try_outbound_connect_interval: Duration,
+    pub max_pending_tasks_per_peer: usize,
+    pub max_pending_tasks: usize,
 }
 
 impl NetworkConfig {
@@ -168,6 +170,8 @@ impl Default for NetworkConfig {
             identify_interval: Duration::from_secs(15),
             try_outbound_connect_timeout: Duration::from_secs(30),
             try_outbound_connect_interval: Duration::from_secs(15),
+            max_pending_tasks_per_peer: 256,
+            max_pending_tasks: 4096,
         }
     }
 }
@@ -0,0 +1,137 @@
+use crate::PeerIndex;
+use ckb_util::Mutex;
+use fnv::FnvHashMap;
+use futures::future::{self, Future};
+use futures::task::{self, Task};
+use futures::Async;
+use std::sync::Arc;
+
+// Messages of a peer dropped before its backlog drains once, a peer getting that far
+// behind keeps flooding us rather than sending a burst
+pub(crate) const MAX_DROPPED_MESSAGES: u32 = 100;
+
+// Low priority tasks are the first dropped when the node is overloaded, e.g. relayed
+// transactions, which peers announce again, unlike the headers and blocks keeping us in sync.
+// Critical tasks are never dropped, once their peer is at its limit they wait for a slot
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TaskPriority {
+    Critical,
+    High,
+    Low,
+}
+
+// Every received message is handled in a task of its own, all of them sharing the same
+// executor. Count the tasks of each peer still waiting to be handled so a single peer
+// flooding messages can't queue unbounded work ahead of the other peers
+pub(crate) struct PendingTasks {
+    limit: usize,
+    // pending tasks of all peers from which low priority ones are shed
+    shed_threshold: usize,
+    pending: Mutex<FnvHashMap<PeerIndex, usize>>,
+    // messages dropped since the backlog of the peer last drained
+    dropped: Mutex<FnvHashMap<PeerIndex, u32>>,
+    // tasks waiting for a slot, woken up whenever one is released
+    waiting: Mutex<Vec<Task>>,
+}
+
+impl PendingTasks {
+    pub fn new(limit: usize, shed_threshold: usize) -> Self {
+        PendingTasks {
+            limit,
+            shed_threshold,
+            pending: Mutex::new(FnvHashMap::default()),
+            dropped: Mutex::new(FnvHashMap::default()),
+            waiting: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Take a slot for a task of the peer, released when the returned guard is dropped.
+    // Returns None when the peer already has `limit` tasks pending, or for a low priority
+    // task when all peers together have `shed_threshold` tasks pending
+    pub fn try_acquire(
+        pending_tasks: &Arc<Self>,
+        peer_index: PeerIndex,
+        priority: TaskPriority,
+    ) -> Option<PendingTask> {
+        let mut pending = pending_tasks.pending.lock();
+        if priority == TaskPriority::Low
+            && pending.values().sum::<usize>() >= pending_tasks.shed_threshold
+        {
+            return None;
+        }
+        let count = pending.entry(peer_index).or_insert(0);
+        if *count >= pending_tasks.limit {
+            return None;
+        }
+        *count += 1;
+        Some(PendingTask {
+            pending_tasks: Arc::clone(pending_tasks),
+            peer_index,
+        })
+    }
+
+    // Resolves to a slot for a task of the peer once try_acquire gets one, for the tasks
+    // which must not be dropped. The messages of the peer are not read meanwhile
+    pub fn acquire(
+        pending_tasks: &Arc<Self>,
+        peer_index: PeerIndex,
+        priority: TaskPriority,
+    ) -> impl Future<Item = PendingTask, Error = ()> {
+        let pending_tasks = Arc::clone(pending_tasks);
+        future::poll_fn(move || {
+            // registered before trying, a slot released in between still wakes us up
+            pending_tasks.waiting.lock().push(task::current());
+            match Self::try_acquire(&pending_tasks, peer_index, priority) {
+                Some(pending_task) => Ok(Async::Ready(pending_task)),
+                None => Ok(Async::NotReady),
+            }
+        })
+    }
+
+    pub fn pending(&self, peer_index: PeerIndex) -> usize {
+        self.pending.lock().get(&peer_index).cloned().unwrap_or(0)
+    }
+
+    // Count a message of the peer dropped for being over its limit, returns true once
+    // MAX_DROPPED_MESSAGES of them were dropped without its backlog draining
+    pub fn message_dropped(&self, peer_index: PeerIndex) -> bool {
+        let mut dropped = self.dropped.lock();
+        let count = dropped.entry(peer_index).or_insert(0);
+        *count += 1;
+        *count == MAX_DROPPED_MESSAGES
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.pending.lock().values().sum::<usize>() >= self.shed_threshold
+    }
+
+    fn release(&self, peer_index: PeerIndex) {
+        let mut pending = self.pending.lock();
+        let remove = match pending.get_mut(&peer_index) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            pending.remove(&peer_index);
+            self.dropped.lock().remove(&peer_index);
+        }
+        drop(pending);
+        for task in self.waiting.lock().drain(..) {
+            task.notify();
+        }
+    }
+}
+
+pub(crate) struct PendingTask {
+    pending_tasks: Arc<PendingTasks>,
+    peer_index: PeerIndex,
+}
+
+impl Drop for PendingTask {
+    fn drop(&mut self) {
+        self.pending_tasks.release(self.peer_index);
+    }
+}
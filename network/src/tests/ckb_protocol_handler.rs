@@ -0,0 +1,18 @@
+use crate::ckb_protocol_handler::catch_handler_panic;
+
+#[test]
+fn test_catch_handler_panic() {
+    assert!(catch_handler_panic("received", *b"syn", Some(1), || {}).is_none());
+
+    let data = vec![0xffu8; 4];
+    let context = catch_handler_panic("received", *b"syn", Some(1), || {
+        // a handler trusting the crafted message to be longer
+        if data.len() < 8 {
+            panic!("malformed message of {} bytes", data.len());
+        }
+    })
+    .expect("panic caught");
+    assert!(context.contains("received handler"));
+    assert!(context.contains("peer Some(1)"));
+    assert!(context.contains("malformed message of 4 bytes"));
+}
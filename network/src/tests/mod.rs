@@ -1,3 +1,5 @@
+mod ckb_protocol_handler;
 mod peers_registry;
+mod pending_tasks;
 #[cfg(test)]
 mod sqlite_peer_store;
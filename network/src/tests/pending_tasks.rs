@@ -0,0 +1,110 @@
+use crate::pending_tasks::{PendingTasks, TaskPriority, MAX_DROPPED_MESSAGES};
+use futures::Future;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_flooding_peer_does_not_starve_others() {
+    let pending_tasks = Arc::new(PendingTasks::new(4, 100));
+
+    // peer 1 floods messages its tasks can't keep up with
+    let flooded = (0..100)
+        .filter_map(|_| PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::High))
+        .collect::<Vec<_>>();
+    assert_eq!(flooded.len(), 4);
+    assert_eq!(pending_tasks.pending(1), 4);
+
+    // peer 2 still gets its messages handled
+    let pending_task = PendingTasks::try_acquire(&pending_tasks, 2, TaskPriority::High);
+    assert!(pending_task.is_some());
+    assert_eq!(pending_tasks.pending(2), 1);
+
+    // handled tasks free the slots of the flooding peer again
+    drop(flooded);
+    assert_eq!(pending_tasks.pending(1), 0);
+    assert!(PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::High).is_some());
+    assert_eq!(pending_tasks.pending(2), 1);
+}
+
+#[test]
+fn test_sustained_overflow_reported() {
+    let pending_tasks = Arc::new(PendingTasks::new(1, 100));
+    let busy = PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::High);
+    assert!(busy.is_some());
+
+    // a burst is dropped without being reported
+    for _ in 1..MAX_DROPPED_MESSAGES {
+        assert!(!pending_tasks.message_dropped(1));
+    }
+    // the count starts over once the backlog of the peer drained
+    drop(busy);
+    let busy = PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::High);
+    assert!(busy.is_some());
+    assert!(!pending_tasks.message_dropped(1));
+
+    for _ in 2..MAX_DROPPED_MESSAGES {
+        assert!(!pending_tasks.message_dropped(1));
+    }
+    assert!(pending_tasks.message_dropped(1));
+    assert!(!pending_tasks.message_dropped(2));
+}
+
+#[test]
+fn test_low_priority_tasks_shed_under_load() {
+    let pending_tasks = Arc::new(PendingTasks::new(4, 6));
+
+    let mut busy = (0..3)
+        .filter_map(|_| PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::Low))
+        .collect::<Vec<_>>();
+    busy.extend(
+        (0..3).filter_map(|_| PendingTasks::try_acquire(&pending_tasks, 2, TaskPriority::High)),
+    );
+    assert_eq!(busy.len(), 6);
+    assert!(pending_tasks.is_overloaded());
+
+    // relayed transactions are dropped while blocks and headers are still handled
+    assert!(PendingTasks::try_acquire(&pending_tasks, 3, TaskPriority::Low).is_none());
+    let block_task = PendingTasks::try_acquire(&pending_tasks, 3, TaskPriority::High);
+    assert!(block_task.is_some());
+    assert_eq!(pending_tasks.pending(3), 1);
+
+    // the per peer limit still applies to high priority tasks
+    let last_slot = PendingTasks::try_acquire(&pending_tasks, 2, TaskPriority::High);
+    assert!(last_slot.is_some());
+    assert!(PendingTasks::try_acquire(&pending_tasks, 2, TaskPriority::High).is_none());
+
+    drop(busy);
+    assert!(!pending_tasks.is_overloaded());
+    assert!(PendingTasks::try_acquire(&pending_tasks, 3, TaskPriority::Low).is_some());
+}
+
+#[test]
+fn test_critical_tasks_wait_for_a_slot() {
+    let pending_tasks = Arc::new(PendingTasks::new(2, 100));
+    let busy = (0..2)
+        .filter_map(|_| PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::High))
+        .collect::<Vec<_>>();
+    assert!(PendingTasks::try_acquire(&pending_tasks, 1, TaskPriority::Critical).is_none());
+
+    // blocks and headers received while the peer is at its limit wait for a slot
+    let waiting = (0..4)
+        .map(|_| {
+            let pending_tasks = Arc::clone(&pending_tasks);
+            thread::spawn(move || {
+                PendingTasks::acquire(&pending_tasks, 1, TaskPriority::Critical)
+                    .wait()
+                    .is_ok()
+            })
+        })
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pending_tasks.pending(1), 2);
+
+    // each handled task lets the next one in, none of them is dropped
+    drop(busy);
+    for handle in waiting {
+        assert!(handle.join().expect("join waiting task"));
+    }
+    assert_eq!(pending_tasks.pending(1), 0);
+}
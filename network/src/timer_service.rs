@@ -1,4 +1,4 @@
-use crate::ckb_protocol_handler::DefaultCKBProtocolContext;
+use crate::ckb_protocol_handler::{catch_handler_panic, DefaultCKBProtocolContext};
 use crate::protocol::Protocol;
 use crate::protocol_service::ProtocolService;
 use crate::transport::TransportOutput;
@@ -94,13 +94,15 @@ impl<T: Send> ProtocolService<T> for TimerService {
                                     let network = Arc::clone(&network);
                                     let handler = Arc::clone(&handler);
                                     let handle_timer = future::lazy(move || {
-                                        handler.timer_triggered(
-                                            Box::new(DefaultCKBProtocolContext::new(
-                                                Arc::clone(&network),
-                                                protocol_id,
-                                            )),
-                                            timer_symbol,
-                                        );
+                                        catch_handler_panic("timer", protocol_id, None, || {
+                                            handler.timer_triggered(
+                                                Box::new(DefaultCKBProtocolContext::new(
+                                                    Arc::clone(&network),
+                                                    protocol_id,
+                                                )),
+                                                timer_symbol,
+                                            )
+                                        });
                                         Ok(())
                                     });
                                     tokio::spawn(handle_timer);
@@ -6,6 +6,7 @@ use std::thread::JoinHandle;
 
 use channel::{select, Receiver, Sender};
 use ckb_core::block::Block;
+use ckb_core::header::BlockNumber;
 use ckb_core::service::Request;
 use fnv::FnvHashMap;
 use log::{debug, trace, warn};
@@ -37,11 +38,28 @@ impl ForkBlocks {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct PeerEviction {
+    pub peer: usize,
+    pub peer_id: Option<String>,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ReprocessProgress {
+    pub from: BlockNumber,
+    pub current: BlockNumber,
+    pub target: BlockNumber,
+}
+
 type StopSignal = ();
 pub type MsgNewTransaction = ();
 pub type MsgNewTip = Arc<Block>;
 pub type MsgNewUncle = Arc<Block>;
 pub type MsgSwitchFork = Arc<ForkBlocks>;
+pub type MsgPeerEvicted = Arc<PeerEviction>;
+pub type MsgReprocessProgress = Arc<ReprocessProgress>;
 pub type NotifyRegister<M> = Sender<Request<(String, usize), Receiver<M>>>;
 
 #[derive(Default)]
@@ -54,10 +72,14 @@ pub struct NotifyController {
     new_tip_register: NotifyRegister<MsgNewTip>,
     new_uncle_register: NotifyRegister<MsgNewUncle>,
     switch_fork_register: NotifyRegister<MsgSwitchFork>,
+    peer_evicted_register: NotifyRegister<MsgPeerEvicted>,
+    reprocess_progress_register: NotifyRegister<MsgReprocessProgress>,
     new_transaction_notifier: Sender<MsgNewTransaction>,
     new_tip_notifier: Sender<MsgNewTip>,
     new_uncle_notifier: Sender<MsgNewUncle>,
     switch_fork_notifier: Sender<MsgSwitchFork>,
+    peer_evicted_notifier: Sender<MsgPeerEvicted>,
+    reprocess_progress_notifier: Sender<MsgReprocessProgress>,
 }
 
 impl NotifyService {
@@ -70,6 +92,10 @@ impl NotifyService {
             channel::bounded(REGISTER_CHANNEL_SIZE);
         let (switch_fork_register, switch_fork_register_receiver) =
             channel::bounded(REGISTER_CHANNEL_SIZE);
+        let (peer_evicted_register, peer_evicted_register_receiver) =
+            channel::bounded(REGISTER_CHANNEL_SIZE);
+        let (reprocess_progress_register, reprocess_progress_register_receiver) =
+            channel::bounded(REGISTER_CHANNEL_SIZE);
 
         let (new_transaction_sender, new_transaction_receiver) =
             channel::bounded::<MsgNewTransaction>(NOTIFY_CHANNEL_SIZE);
@@ -78,11 +104,17 @@ impl NotifyService {
             channel::bounded::<MsgNewUncle>(NOTIFY_CHANNEL_SIZE);
         let (switch_fork_sender, switch_fork_receiver) =
             channel::bounded::<MsgSwitchFork>(NOTIFY_CHANNEL_SIZE);
+        let (peer_evicted_sender, peer_evicted_receiver) =
+            channel::bounded::<MsgPeerEvicted>(NOTIFY_CHANNEL_SIZE);
+        let (reprocess_progress_sender, reprocess_progress_receiver) =
+            channel::bounded::<MsgReprocessProgress>(NOTIFY_CHANNEL_SIZE);
 
         let mut new_transaction_subscribers = FnvHashMap::default();
         let mut new_tip_subscribers = FnvHashMap::default();
         let mut new_uncle_subscribers = FnvHashMap::default();
         let mut switch_fork_subscribers = FnvHashMap::default();
+        let mut peer_evicted_subscribers = FnvHashMap::default();
+        let mut reprocess_progress_subscribers = FnvHashMap::default();
 
         let mut thread_builder = thread::Builder::new();
         // Mainly for test: give a empty thread_name
@@ -108,6 +140,12 @@ impl NotifyService {
                     recv(switch_fork_register_receiver) -> msg => Self::handle_register_switch_fork(
                         &mut switch_fork_subscribers, msg
                     ),
+                    recv(peer_evicted_register_receiver) -> msg => Self::handle_register_peer_evicted(
+                        &mut peer_evicted_subscribers, msg
+                    ),
+                    recv(reprocess_progress_register_receiver) -> msg => Self::handle_register_reprocess_progress(
+                        &mut reprocess_progress_subscribers, msg
+                    ),
 
                     recv(new_transaction_receiver) -> msg => Self::handle_notify_new_transaction(
                         &new_transaction_subscribers, msg
@@ -120,6 +158,12 @@ impl NotifyService {
                     ),
                     recv(switch_fork_receiver) -> msg => Self::handle_notify_switch_fork(
                         &switch_fork_subscribers, msg
+                    ),
+                    recv(peer_evicted_receiver) -> msg => Self::handle_notify_peer_evicted(
+                        &peer_evicted_subscribers, msg
+                    ),
+                    recv(reprocess_progress_receiver) -> msg => Self::handle_notify_reprocess_progress(
+                        &reprocess_progress_subscribers, msg
                     )
                 }
             }).expect("Start notify service failed");
@@ -131,10 +175,14 @@ impl NotifyService {
                 new_tip_register,
                 new_uncle_register,
                 switch_fork_register,
+                peer_evicted_register,
+                reprocess_progress_register,
                 new_transaction_notifier: new_transaction_sender,
                 new_tip_notifier: new_tip_sender,
                 new_uncle_notifier: new_uncle_sender,
                 switch_fork_notifier: switch_fork_sender,
+                peer_evicted_notifier: peer_evicted_sender,
+                reprocess_progress_notifier: reprocess_progress_sender,
                 signal: signal_sender,
             },
         )
@@ -212,6 +260,42 @@ impl NotifyService {
         }
     }
 
+    fn handle_register_peer_evicted(
+        subscribers: &mut FnvHashMap<String, Sender<MsgPeerEvicted>>,
+        msg: Result<Request<(String, usize), Receiver<MsgPeerEvicted>>, channel::RecvError>,
+    ) {
+        match msg {
+            Ok(Request {
+                responder,
+                arguments: (name, capacity),
+            }) => {
+                debug!(target: "notify", "Register peer_evicted {:?}", name);
+                let (sender, receiver) = channel::bounded::<MsgPeerEvicted>(capacity);
+                subscribers.insert(name, sender);
+                let _ = responder.send(receiver);
+            }
+            _ => warn!(target: "notify", "Register peer_evicted channel is closed"),
+        }
+    }
+
+    fn handle_register_reprocess_progress(
+        subscribers: &mut FnvHashMap<String, Sender<MsgReprocessProgress>>,
+        msg: Result<Request<(String, usize), Receiver<MsgReprocessProgress>>, channel::RecvError>,
+    ) {
+        match msg {
+            Ok(Request {
+                responder,
+                arguments: (name, capacity),
+            }) => {
+                debug!(target: "notify", "Register reprocess_progress {:?}", name);
+                let (sender, receiver) = channel::bounded::<MsgReprocessProgress>(capacity);
+                subscribers.insert(name, sender);
+                let _ = responder.send(receiver);
+            }
+            _ => warn!(target: "notify", "Register reprocess_progress channel is closed"),
+        }
+    }
+
     fn handle_notify_new_transaction(
         subscribers: &FnvHashMap<String, Sender<MsgNewTransaction>>,
         msg: Result<MsgNewTransaction, channel::RecvError>,
@@ -271,6 +355,36 @@ impl NotifyService {
             _ => warn!(target: "notify", "event 3 channel is closed"),
         }
     }
+
+    fn handle_notify_peer_evicted(
+        subscribers: &FnvHashMap<String, Sender<MsgPeerEvicted>>,
+        msg: Result<MsgPeerEvicted, channel::RecvError>,
+    ) {
+        match msg {
+            Ok(msg) => {
+                trace!(target: "notify", "event peer evicted {:?}", msg);
+                for subscriber in subscribers.values() {
+                    let _ = subscriber.send(Arc::clone(&msg));
+                }
+            }
+            _ => warn!(target: "notify", "peer evicted channel is closed"),
+        }
+    }
+
+    fn handle_notify_reprocess_progress(
+        subscribers: &FnvHashMap<String, Sender<MsgReprocessProgress>>,
+        msg: Result<MsgReprocessProgress, channel::RecvError>,
+    ) {
+        match msg {
+            Ok(msg) => {
+                trace!(target: "notify", "event reprocess progress {:?}", msg);
+                for subscriber in subscribers.values() {
+                    let _ = subscriber.send(Arc::clone(&msg));
+                }
+            }
+            _ => warn!(target: "notify", "reprocess progress channel is closed"),
+        }
+    }
 }
 
 impl NotifyController {
@@ -294,6 +408,17 @@ impl NotifyController {
         Request::call(&self.switch_fork_register, (name.to_string(), 128))
             .expect("Subscribe switch fork failed")
     }
+    pub fn subscribe_peer_evicted<S: ToString>(&self, name: S) -> Receiver<MsgPeerEvicted> {
+        Request::call(&self.peer_evicted_register, (name.to_string(), 128))
+            .expect("Subscribe peer evicted failed")
+    }
+    pub fn subscribe_reprocess_progress<S: ToString>(
+        &self,
+        name: S,
+    ) -> Receiver<MsgReprocessProgress> {
+        Request::call(&self.reprocess_progress_register, (name.to_string(), 128))
+            .expect("Subscribe reprocess progress failed")
+    }
 
     pub fn notify_new_transaction(&self) {
         let _ = self.new_transaction_notifier.send(());
@@ -307,6 +432,12 @@ impl NotifyController {
     pub fn notify_switch_fork(&self, txs: MsgSwitchFork) {
         let _ = self.switch_fork_notifier.send(txs);
     }
+    pub fn notify_peer_evicted(&self, eviction: MsgPeerEvicted) {
+        let _ = self.peer_evicted_notifier.send(eviction);
+    }
+    pub fn notify_reprocess_progress(&self, progress: MsgReprocessProgress) {
+        let _ = self.reprocess_progress_notifier.send(progress);
+    }
 }
 
 #[cfg(test)]
@@ -352,4 +483,37 @@ mod tests {
         notify.stop();
         handle.join().expect("join failed");
     }
+
+    #[test]
+    fn test_peer_evicted() {
+        let eviction = Arc::new(PeerEviction {
+            peer: 1,
+            peer_id: None,
+            reason: "HeadersSyncTimeout".to_string(),
+            timestamp: 0,
+        });
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver = notify.subscribe_peer_evicted("network");
+        notify.notify_peer_evicted(Arc::clone(&eviction));
+        assert_eq!(receiver.recv(), Ok(eviction));
+        notify.stop();
+        handle.join().expect("join failed");
+    }
+
+    #[test]
+    fn test_reprocess_progress() {
+        let progress = Arc::new(ReprocessProgress {
+            from: 5,
+            current: 8,
+            target: 10,
+        });
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver = notify.subscribe_reprocess_progress("reindex");
+        notify.notify_reprocess_progress(Arc::clone(&progress));
+        assert_eq!(receiver.recv(), Ok(progress));
+        notify.stop();
+        handle.join().expect("join failed");
+    }
 }
@@ -251,6 +251,36 @@ pub fn test_add_pool_error() {
     assert_eq!(pool.service.total_size(), 1);
 }
 
+#[test]
+fn test_add_oversized_transaction() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+    let input = CellInput::new(
+        OutPoint::new(pool.tx_hash.clone(), 5),
+        create_valid_script(),
+    );
+    let lock = create_valid_script().type_hash();
+
+    let oversized = TransactionBuilder::default()
+        .input(input.clone())
+        .output(CellOutput::new(
+            100_000,
+            vec![0; 100_000],
+            lock.clone(),
+            None,
+        ))
+        .build();
+    match pool.service.add_transaction(oversized) {
+        Err(PoolError::ExceededMaximumSize) => {}
+        x => panic!("Unexpected result when adding oversized tx: {:?}", x),
+    }
+
+    let transaction = TransactionBuilder::default()
+        .input(input)
+        .output(CellOutput::new(100_000, Vec::new(), lock, None))
+        .build();
+    assert!(pool.service.add_transaction(transaction).is_ok());
+}
+
 // #[test]
 // /// Testing an expected orphan
 // fn test_add_orphan() {
@@ -405,6 +435,75 @@ fn test_block_reconciliation() {
     assert_eq!(1, pool.service.cache_size());
 }
 
+#[test]
+fn test_prune_unresolvable_orphan() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let parent = test_transaction(vec![OutPoint::new(pool.tx_hash.clone(), 0)], 1);
+    // waits for an output the parent does not have
+    let orphan = test_transaction(vec![OutPoint::new(parent.hash().clone(), 5)], 1);
+
+    pool.service.add_to_pool(orphan.clone()).unwrap();
+    assert_eq!(1, pool.service.orphan_size());
+
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(
+            pool.shared.tip_header().read().number() + 1,
+        ))
+        .build();
+    apply_transactions(vec![cellbase, parent], vec![], &mut pool);
+
+    assert_eq!(0, pool.service.orphan_size());
+    assert_eq!(0, pool.service.pool_size());
+}
+
+#[test]
+fn test_promote_orphans() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let parent = test_transaction(vec![OutPoint::new(pool.tx_hash.clone(), 0)], 1);
+    let orphan = test_transaction(vec![OutPoint::new(parent.hash().clone(), 0)], 1);
+
+    pool.service.add_to_pool(orphan.clone()).unwrap();
+    assert_eq!(1, pool.service.orphan_size());
+
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(
+            pool.shared.tip_header().read().number() + 1,
+        ))
+        .build();
+    let block = apply_transactions(vec![cellbase, parent], vec![], &mut pool);
+
+    assert_eq!(0, pool.service.orphan_size());
+    assert_eq!(1, pool.service.pool_size());
+    assert_eq!(vec![orphan], pool.service.promote_orphans(&block));
+    // fetched once
+    assert!(pool.service.promote_orphans(&block).is_empty());
+}
+
+#[cfg(not(disable_faketime))]
+#[test]
+fn test_prune_expired_orphan() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let orphan = test_transaction(
+        vec![OutPoint::new(H256::from_trimmed_hex_str("1").unwrap(), 0)],
+        1,
+    );
+    pool.service.add_to_pool(orphan.clone()).unwrap();
+    assert_eq!(1, pool.service.orphan_size());
+
+    faketime::write_millis(&faketime_file, ORPHAN_TX_EXPIRE_TIME).expect("write millis");
+    apply_transactions(vec![], vec![], &mut pool);
+    assert_eq!(1, pool.service.orphan_size());
+
+    faketime::write_millis(&faketime_file, ORPHAN_TX_EXPIRE_TIME + 1).expect("write millis");
+    apply_transactions(vec![], vec![], &mut pool);
+    assert_eq!(0, pool.service.orphan_size());
+}
+
 // Work only when TRANSACTION_PROPAGATION_TIME = 1, TRANSACTION_PROPAGATION_TIMEOUT = 10
 #[test]
 fn test_switch_fork() {
@@ -500,6 +599,7 @@ impl<CI: ChainIndex + 'static> TestPool<CI> {
                 max_proposal_size: 1000,
                 max_cache_size: 1000,
                 max_pending_size: 1000,
+                max_tx_size: 100_000,
             },
             shared.clone(),
             notify.clone(),
@@ -1,7 +1,7 @@
 //! Top-level Pool type, methods, and tests
 use super::types::{
     InsertionResult, Orphan, PendingQueue, Pool, PoolConfig, PoolError, ProposedQueue, TxStage,
-    TxoStatus,
+    TxoStatus, ORPHAN_TX_EXPIRE_TIME,
 };
 use channel::{self, select, Receiver, Sender};
 use ckb_core::block::Block;
@@ -12,34 +12,48 @@ use ckb_notify::{ForkBlocks, MsgNewTip, MsgSwitchFork, NotifyController};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
 use ckb_verification::{TransactionError, TransactionVerifier};
-use log::error;
+use faketime::unix_time_as_millis;
+use log::{debug, error};
 use lru_cache::LruCache;
 use numext_fixed_hash::H256;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
 #[cfg(test)]
 use ckb_core::BlockNumber;
 
 const TXS_POOL_SUBSCRIBER: &str = "txs_pool";
+// Blocks whose orphan promotions are kept until fetched by promote_orphans
+const PROMOTED_CACHE_SIZE: usize = 16;
 
 pub type TxsArgs = (usize, usize);
 pub type TxsReturn = (Vec<ProposalShortId>, Vec<Transaction>);
+/// Selects pool transactions by their hash, see `get_matching_transactions`
+pub type TransactionMatcher = Box<Fn(&H256) -> bool + Send>;
 
 #[derive(Clone)]
 pub struct TransactionPoolController {
     get_proposal_commit_transactions_sender: Sender<Request<TxsArgs, TxsReturn>>,
     get_potential_transactions_sender: Sender<Request<(), Vec<Transaction>>>,
+    get_transaction_hashes_sender: Sender<Request<(), Vec<H256>>>,
+    get_matching_transactions_sender: Sender<Request<TransactionMatcher, Vec<Transaction>>>,
+    pool_size_sender: Sender<Request<(), usize>>,
     contains_key_sender: Sender<Request<ProposalShortId, bool>>,
     get_transaction_sender: Sender<Request<ProposalShortId, Option<Transaction>>>,
     add_transaction_sender: Sender<Request<Transaction, Result<InsertionResult, PoolError>>>,
+    promote_orphans_sender: Sender<Request<Arc<Block>, Vec<Transaction>>>,
 }
 
 pub struct TransactionPoolReceivers {
     get_proposal_commit_transactions_receiver: Receiver<Request<TxsArgs, TxsReturn>>,
     get_potential_transactions_receiver: Receiver<Request<(), Vec<Transaction>>>,
+    get_transaction_hashes_receiver: Receiver<Request<(), Vec<H256>>>,
+    get_matching_transactions_receiver: Receiver<Request<TransactionMatcher, Vec<Transaction>>>,
+    pool_size_receiver: Receiver<Request<(), usize>>,
     contains_key_receiver: Receiver<Request<ProposalShortId, bool>>,
     get_transaction_receiver: Receiver<Request<ProposalShortId, Option<Transaction>>>,
     add_transaction_receiver: Receiver<Request<Transaction, Result<InsertionResult, PoolError>>>,
+    promote_orphans_receiver: Receiver<Request<Arc<Block>, Vec<Transaction>>>,
 }
 
 impl TransactionPoolController {
@@ -48,25 +62,40 @@ impl TransactionPoolController {
             channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (get_potential_transactions_sender, get_potential_transactions_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (get_transaction_hashes_sender, get_transaction_hashes_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (get_matching_transactions_sender, get_matching_transactions_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (pool_size_sender, pool_size_receiver) = channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (contains_key_sender, contains_key_receiver) = channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (get_transaction_sender, get_transaction_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (add_transaction_sender, add_transaction_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (promote_orphans_sender, promote_orphans_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
         (
             TransactionPoolController {
                 get_proposal_commit_transactions_sender,
                 get_potential_transactions_sender,
+                get_transaction_hashes_sender,
+                get_matching_transactions_sender,
+                pool_size_sender,
                 contains_key_sender,
                 get_transaction_sender,
                 add_transaction_sender,
+                promote_orphans_sender,
             },
             TransactionPoolReceivers {
                 get_proposal_commit_transactions_receiver,
                 get_potential_transactions_receiver,
+                get_transaction_hashes_receiver,
+                get_matching_transactions_receiver,
+                pool_size_receiver,
                 contains_key_receiver,
                 get_transaction_receiver,
                 add_transaction_receiver,
+                promote_orphans_receiver,
             },
         )
     }
@@ -88,6 +117,25 @@ impl TransactionPoolController {
             .expect("get_potential_transactions() failed")
     }
 
+    /// Hashes of the pending transactions and of the potential ones, what the pool would
+    /// relay to a peer
+    pub fn get_transaction_hashes(&self) -> Vec<H256> {
+        Request::call(&self.get_transaction_hashes_sender, ())
+            .expect("get_transaction_hashes() failed")
+    }
+
+    /// The potential transactions `matcher` accepts the hash of, a fraction of a large
+    /// pool is cheaper to get this way than copying all of them
+    pub fn get_matching_transactions(&self, matcher: TransactionMatcher) -> Vec<Transaction> {
+        Request::call(&self.get_matching_transactions_sender, matcher)
+            .expect("get_matching_transactions() failed")
+    }
+
+    /// Number of the potential transactions
+    pub fn pool_size(&self) -> usize {
+        Request::call(&self.pool_size_sender, ()).expect("pool_size() failed")
+    }
+
     pub fn contains_key(&self, id: ProposalShortId) -> bool {
         Request::call(&self.contains_key_sender, id).expect("contains_key() failed")
     }
@@ -99,6 +147,11 @@ impl TransactionPoolController {
     pub fn add_transaction(&self, tx: Transaction) -> Result<InsertionResult, PoolError> {
         Request::call(&self.add_transaction_sender, tx).expect("add_transaction() failed")
     }
+
+    /// Orphans promoted to the pool by the transactions of the accepted `block`
+    pub fn promote_orphans(&self, block: Arc<Block>) -> Vec<Transaction> {
+        Request::call(&self.promote_orphans_sender, block).expect("promote_orphans() failed")
+    }
 }
 
 /// The pool itself.
@@ -114,6 +167,8 @@ pub struct TransactionPoolService<CI> {
     orphan: Orphan,
     /// cache for conflict transaction
     cache: LruCache<ProposalShortId, Transaction>,
+    /// Orphans promoted by the commit transactions of a block
+    promoted: LruCache<H256, Vec<Transaction>>,
 
     shared: Shared<CI>,
     notify: NotifyController,
@@ -158,6 +213,7 @@ where
             pool: Pool::new(),
             orphan: Orphan::new(),
             cache: LruCache::new(cache_size, false),
+            promoted: LruCache::new(PROMOTED_CACHE_SIZE, false),
             shared,
             notify,
         }
@@ -193,6 +249,30 @@ where
                             error!(target: "txs_pool", "channel get_potential_transactions_receiver closed");
                         }
                     },
+                    recv(receivers.get_transaction_hashes_receiver) -> msg => match msg {
+                        Ok(Request { responder, ..}) => {
+                            let _ = responder.send(self.transaction_hashes());
+                        }
+                        _ => {
+                            error!(target: "txs_pool", "channel get_transaction_hashes_receiver closed");
+                        }
+                    },
+                    recv(receivers.get_matching_transactions_receiver) -> msg => match msg {
+                        Ok(Request { responder, arguments: matcher }) => {
+                            let _ = responder.send(self.pool.get_matching_transactions(&*matcher));
+                        }
+                        _ => {
+                            error!(target: "txs_pool", "channel get_matching_transactions_receiver closed");
+                        }
+                    },
+                    recv(receivers.pool_size_receiver) -> msg => match msg {
+                        Ok(Request { responder, ..}) => {
+                            let _ = responder.send(self.pool.size());
+                        }
+                        _ => {
+                            error!(target: "txs_pool", "channel pool_size_receiver closed");
+                        }
+                    },
                     recv(receivers.contains_key_receiver) -> msg => match msg {
                         Ok(Request { responder, arguments: id }) => {
                             let _ = responder.send(self.contains_key(&id));
@@ -216,6 +296,14 @@ where
                         _ => {
                             error!(target: "txs_pool", "channel add_transaction_receiver closed");
                         }
+                    },
+                    recv(receivers.promote_orphans_receiver) -> msg => match msg {
+                        Ok(Request { responder, arguments: block }) => {
+                            let _ = responder.send(self.promote_orphans(&block));
+                        }
+                        _ => {
+                            error!(target: "txs_pool", "channel promote_orphans_receiver closed");
+                        }
                     }
                 }
             }).expect("Start TransactionPoolService failed!")
@@ -372,6 +460,9 @@ where
         &mut self,
         tx: Transaction,
     ) -> Result<InsertionResult, PoolError> {
+        if tx.bytes_len() > self.config.max_tx_size {
+            return Err(PoolError::ExceededMaximumSize);
+        }
         match { self.proposed.insert(tx) } {
             TxStage::Mineable(x) => self.add_to_pool(x),
             TxStage::Unknown(x) => {
@@ -409,6 +500,12 @@ where
         self.pool.get_mineable_transactions(self.pool.size())
     }
 
+    fn transaction_hashes(&self) -> Vec<H256> {
+        let mut hashes = self.pending.hashes();
+        hashes.extend(self.pool.vertices.values().map(|x| x.transaction.hash()));
+        hashes
+    }
+
     /// Attempts to add a transaction to the memory pool.
     pub(crate) fn add_to_pool(&mut self, tx: Transaction) -> Result<InsertionResult, PoolError> {
         // Do we have the capacity to accept this transaction?
@@ -474,19 +571,42 @@ where
         Ok(InsertionResult::Normal)
     }
 
-    /// Updates the pool and orphan pool with new transactions.
-    pub(crate) fn reconcile_orphan(&mut self, tx: &Transaction) {
+    /// Updates the pool and orphan pool with new transactions,
+    /// returns the orphans promoted to the pool.
+    pub(crate) fn reconcile_orphan(&mut self, tx: &Transaction) -> Vec<Transaction> {
         let txs = self.orphan.reconcile_transaction(tx);
+        let mut promoted = Vec::new();
 
         for tx in txs {
             let rtx = self.resolve_transaction(&tx);
             let rs = TransactionVerifier::new(&rtx).verify();
             if rs.is_ok() {
-                self.pool.add_transaction(tx);
+                self.pool.add_transaction(tx.clone());
+                promoted.push(tx);
             } else if rs == Err(TransactionError::DoubleSpent) {
                 self.cache.insert(tx.proposal_short_id(), tx);
             }
         }
+
+        promoted
+    }
+
+    /// Returns the orphans promoted by the commit transactions of `b`, the block may
+    /// not be reconciled yet when asked right after its acceptance.
+    pub(crate) fn promote_orphans(&mut self, b: &Block) -> Vec<Transaction> {
+        if let Some(promoted) = self.promoted.remove(&b.header().hash()) {
+            return promoted;
+        }
+
+        let mut promoted = Vec::new();
+        for tx in b.commit_transactions() {
+            if tx.is_cellbase() {
+                continue;
+            }
+
+            promoted.extend(self.reconcile_orphan(tx));
+        }
+        promoted
     }
 
     /// Updates the pool with the details of a new block.
@@ -498,12 +618,21 @@ where
 
         // must do this first
         {
+            let mut promoted = Vec::new();
             for tx in txs {
                 if tx.is_cellbase() {
                     continue;
                 }
 
-                self.reconcile_orphan(tx);
+                promoted.extend(self.reconcile_orphan(tx));
+            }
+            if !promoted.is_empty() {
+                self.promoted.insert(b.header().hash(), promoted);
+            }
+
+            let expire_before = unix_time_as_millis().saturating_sub(ORPHAN_TX_EXPIRE_TIME);
+            for tx in self.orphan.prune(expire_before, txs) {
+                debug!(target: "txs_pool", "prune orphan transaction {:x}", tx.hash());
             }
         }
 
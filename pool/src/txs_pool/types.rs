@@ -5,8 +5,10 @@ use ckb_chain_spec::consensus::{TRANSACTION_PROPAGATION_TIME, TRANSACTION_PROPAG
 use ckb_core::transaction::{CellOutput, OutPoint, ProposalShortId, Transaction};
 use ckb_core::BlockNumber;
 use ckb_verification::TransactionError;
+use faketime::unix_time_as_millis;
 use fnv::{FnvHashMap, FnvHashSet};
 use linked_hash_map::LinkedHashMap;
+use numext_fixed_hash::H256;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::hash::Hash;
@@ -14,6 +16,9 @@ use std::iter::Iterator;
 
 const BUFF_QUE_LEN: u64 = 100;
 
+/// Orphans which are still missing inputs after this long are dropped
+pub const ORPHAN_TX_EXPIRE_TIME: u64 = 20 * 60 * 1000; // 20 minutes
+
 /// Transaction pool configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PoolConfig {
@@ -23,6 +28,8 @@ pub struct PoolConfig {
     pub max_proposal_size: usize,
     pub max_cache_size: usize,
     pub max_pending_size: usize,
+    /// Transactions larger than this many bytes are rejected
+    pub max_tx_size: usize,
 }
 
 impl Default for PoolConfig {
@@ -33,6 +40,7 @@ impl Default for PoolConfig {
             max_proposal_size: 10000,
             max_cache_size: 1000,
             max_pending_size: 10000,
+            max_tx_size: 512_000,
         }
     }
 }
@@ -82,6 +90,8 @@ pub enum PoolError {
     TimeOut,
     /// Blocknumber is not right
     InvalidBlockNumber,
+    /// Transaction is larger than max_tx_size
+    ExceededMaximumSize,
 }
 
 /// An entry in the transaction pool.
@@ -427,6 +437,16 @@ impl Pool {
             .collect()
     }
 
+    /// Get the transactions whose hash matches, the others are not copied
+    pub fn get_matching_transactions<F: Fn(&H256) -> bool>(&self, matches: F) -> Vec<Transaction> {
+        self.vertices
+            .values()
+            .map(|x| &x.transaction)
+            .filter(|tx| matches(&tx.hash()))
+            .cloned()
+            .collect()
+    }
+
     pub fn inc_ref(&mut self, id: &ProposalShortId) {
         if let Some(x) = self.vertices.get_mut(&id) {
             x.refs_count += 1;
@@ -445,6 +465,8 @@ impl Pool {
 pub struct Orphan {
     pub vertices: FnvHashMap<ProposalShortId, PoolEntry>,
     pub edges: FnvHashMap<OutPoint, Vec<ProposalShortId>>,
+    /// When the orphan was added, in ms
+    pub timestamps: FnvHashMap<ProposalShortId, u64>,
 }
 
 impl Orphan {
@@ -481,10 +503,12 @@ impl Orphan {
             count += 1;
         }
 
+        self.timestamps.insert(id, unix_time_as_millis());
         self.vertices.insert(id, PoolEntry::new(tx, count));
     }
 
     pub fn remove(&mut self, id: &ProposalShortId) -> Option<Transaction> {
+        self.timestamps.remove(id);
         if let Some(x) = self.vertices.remove(id) {
             let tx = x.transaction;
 
@@ -517,6 +541,7 @@ impl Orphan {
                         if let Some(mut x) = self.vertices.remove(&cid) {
                             x.refs_count -= 1;
                             if x.refs_count == 0 {
+                                self.timestamps.remove(&cid);
                                 q.push_back(x.transaction.output_pts());
                                 txs.push(x.transaction);
                             } else {
@@ -531,6 +556,38 @@ impl Orphan {
         txs
     }
 
+    /// Removes orphans added before `expire_before`, and orphans waiting for an output
+    /// which the committed `txs` prove will never exist.
+    pub fn prune(&mut self, expire_before: u64, txs: &[Transaction]) -> Vec<Transaction> {
+        let outputs_len = txs
+            .iter()
+            .map(|tx| (tx.hash(), tx.outputs().len()))
+            .collect::<FnvHashMap<_, _>>();
+
+        let mut ids = self
+            .timestamps
+            .iter()
+            .filter(|(_, timestamp)| **timestamp < expire_before)
+            .map(|(id, _)| *id)
+            .collect::<FnvHashSet<_>>();
+
+        for (o, cids) in &self.edges {
+            let impossible = outputs_len
+                .get(&o.hash)
+                .map_or(false, |len| o.index as usize >= *len);
+            if impossible {
+                ids.extend(cids.iter().cloned());
+            }
+        }
+
+        self.edges.retain(|_, cids| {
+            cids.retain(|id| !ids.contains(id));
+            !cids.is_empty()
+        });
+
+        ids.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
     pub fn resolve_conflict(&mut self, tx: &Transaction) {
         let inputs = tx.input_pts();
 
@@ -583,6 +640,10 @@ impl PendingQueue {
             .map(|x| x.proposal_short_id())
             .collect()
     }
+
+    pub fn hashes(&self) -> Vec<H256> {
+        self.inner.values().map(Transaction::hash).collect()
+    }
 }
 
 #[derive(Default, Debug)]
@@ -1071,4 +1132,21 @@ mod tests {
         mineable = pool.get_mineable_transactions(5);
         assert_eq!(4, mineable.len());
     }
+
+    #[test]
+    fn test_get_matching_transactions() {
+        let tx1 = build_tx(vec![(H256::zero(), 1)], 1);
+        let tx2 = build_tx(vec![(H256::zero(), 2)], 1);
+        let tx3 = build_tx(vec![(H256::zero(), 3)], 1);
+
+        let mut pool = Pool::new();
+        pool.add_transaction(tx1.clone());
+        pool.add_transaction(tx2.clone());
+        pool.add_transaction(tx3.clone());
+
+        let wanted = vec![tx1.hash(), tx3.hash()];
+        let matching = pool.get_matching_transactions(|hash| wanted.contains(hash));
+        assert_eq!(matching, vec![tx1, tx3]);
+        assert!(pool.get_matching_transactions(|_| false).is_empty());
+    }
 }
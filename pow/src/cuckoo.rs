@@ -44,6 +44,10 @@ impl PowEngine for CuckooEngine {
 
     #[inline]
     fn verify(&self, _number: BlockNumber, message: &[u8], proof: &[u8]) -> bool {
+        // a proof of the wrong size can't be read as a cycle
+        if proof.len() != self.cuckoo.cycle_length * 4 {
+            return false;
+        }
         let mut proof_u32 = vec![0u32; self.cuckoo.cycle_length];
         LittleEndian::read_u32_into(&proof, &mut proof_u32);
         self.cuckoo.verify(message, &proof_u32)
@@ -1,14 +1,18 @@
 use crate::protocol_generated::ckb::protocol::{
-    Block as FbsBlock, BlockBuilder, BlockProposalBuilder, BlockTransactionsBuilder,
+    AddFilterBuilder, AnnouncementMode, AnnouncementPreferenceBuilder, Block as FbsBlock,
+    BlockBuilder, BlockInventoryBuilder, BlockProposalBuilder, BlockTransactionsBuilder,
     Bytes as FbsBytes, BytesBuilder, CellInput as FbsCellInput, CellInputBuilder,
-    CellOutput as FbsCellOutput, CellOutputBuilder, CompactBlock, CompactBlockBuilder,
+    CellOutput as FbsCellOutput, CellOutputBuilder, ClearFilterBuilder, CompactBlock,
+    CompactBlockBuilder, CompactBlockVersionBuilder, DisconnectCode, DisconnectReasonBuilder,
     FilteredBlock, FilteredBlockBuilder, GetBlockProposalBuilder, GetBlockTransactionsBuilder,
     GetBlocks as FbsGetBlocks, GetBlocksBuilder, GetHeaders as FbsGetHeaders, GetHeadersBuilder,
-    Header as FbsHeader, HeaderBuilder, Headers as FbsHeaders, HeadersBuilder,
-    IndexTransactionBuilder, OutPoint as FbsOutPoint, OutPointBuilder,
+    GetTipBuilder, GetTransactionProofBuilder, GetTransactionsBuilder, Header as FbsHeader,
+    HeaderBuilder, Headers as FbsHeaders, HeadersBuilder, IndexTransactionBuilder, InventoryType,
+    MempoolSketchBuilder, OutPoint as FbsOutPoint, OutPointBuilder, PingBuilder, PongBuilder,
     ProposalShortId as FbsProposalShortId, RelayMessage, RelayMessageBuilder, RelayPayload,
-    Script as FbsScript, ScriptBuilder, SyncMessage, SyncMessageBuilder, SyncPayload,
-    Transaction as FbsTransaction, TransactionBuilder, UncleBlock as FbsUncleBlock,
+    Script as FbsScript, ScriptBuilder, SetFilterBuilder, SyncMessage, SyncMessageBuilder,
+    SyncPayload, TipBuilder, Transaction as FbsTransaction, TransactionBuilder,
+    TransactionInventoryBuilder, TransactionProofBuilder, UncleBlock as FbsUncleBlock,
     UncleBlockBuilder, H256 as FbsH256,
 };
 use crate::{short_transaction_id, short_transaction_id_keys};
@@ -18,6 +22,7 @@ use ckb_core::script::Script;
 use ckb_core::transaction::{CellInput, CellOutput, OutPoint, ProposalShortId, Transaction};
 use ckb_core::uncle::UncleBlock;
 use flatbuffers::{FlatBufferBuilder, WIPOffset};
+use merkle_root::merkle_proof;
 use numext_fixed_hash::H256;
 use numext_fixed_uint::U256;
 use rand::{thread_rng, Rng};
@@ -41,21 +46,39 @@ impl<'a> FbsBytes<'a> {
 
 impl<'a> FbsHeader<'a> {
     pub fn build<'b>(fbb: &mut FlatBufferBuilder<'b>, header: &Header) -> WIPOffset<FbsHeader<'b>> {
+        Self::build_after(fbb, header, None)
+    }
+
+    // Leaves out the parent hash and the difficulty the header shares with the previous
+    // one, they are taken back from it by Headers::decompress
+    fn build_after<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        header: &Header,
+        previous: Option<&Header>,
+    ) -> WIPOffset<FbsHeader<'b>> {
         let parent_hash = header.parent_hash().into();
         let txs_commit = header.txs_commit().into();
         let txs_proposal = header.txs_proposal().into();
-        let difficulty = FbsBytes::build(fbb, &uint_to_bytes(header.difficulty()));
+        let difficulty = if previous.map_or(false, |p| p.difficulty() == header.difficulty()) {
+            None
+        } else {
+            Some(FbsBytes::build(fbb, &uint_to_bytes(header.difficulty())))
+        };
         let proof = FbsBytes::build(fbb, &header.proof());
         let cellbase_id = header.cellbase_id().into();
         let uncles_hash = header.uncles_hash().into();
         let mut builder = HeaderBuilder::new(fbb);
         builder.add_version(header.version());
-        builder.add_parent_hash(&parent_hash);
+        if previous.map_or(true, |p| &p.hash() != header.parent_hash()) {
+            builder.add_parent_hash(&parent_hash);
+        }
         builder.add_timestamp(header.timestamp());
         builder.add_number(header.number());
         builder.add_txs_commit(&txs_commit);
         builder.add_txs_proposal(&txs_proposal);
-        builder.add_difficulty(difficulty);
+        if let Some(difficulty) = difficulty {
+            builder.add_difficulty(difficulty);
+        }
         builder.add_nonce(header.nonce());
         builder.add_proof(proof);
         builder.add_cellbase_id(&cellbase_id);
@@ -253,12 +276,32 @@ impl<'a> FbsHeaders<'a> {
         builder.add_headers(headers);
         builder.finish()
     }
+
+    pub fn build_compressed<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        headers: &[Header],
+    ) -> WIPOffset<FbsHeaders<'b>> {
+        let vec = headers
+            .iter()
+            .enumerate()
+            .map(|(index, header)| {
+                let previous = index.checked_sub(1).map(|previous| &headers[previous]);
+                FbsHeader::build_after(fbb, header, previous)
+            })
+            .collect::<Vec<_>>();
+        let headers = fbb.create_vector(&vec);
+        let mut builder = HeadersBuilder::new(fbb);
+        builder.add_headers(headers);
+        builder.finish()
+    }
 }
 
 impl<'a> FbsGetHeaders<'a> {
     pub fn build<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
+        version: u32,
         block_locator_hashes: &[H256],
+        compressed_headers: bool,
     ) -> WIPOffset<FbsGetHeaders<'b>> {
         let vec = block_locator_hashes
             .iter()
@@ -266,9 +309,9 @@ impl<'a> FbsGetHeaders<'a> {
             .collect::<Vec<FbsH256>>();
         let block_locator_hashes = fbb.create_vector(&vec);
         let mut builder = GetHeadersBuilder::new(fbb);
-        // TODO remove version from protocol?
-        builder.add_version(0);
+        builder.add_version(version);
         builder.add_block_locator_hashes(block_locator_hashes);
+        builder.add_compressed_headers(compressed_headers);
         // TODO PENDING hash_stop
         // builder.add_hash_stop(...)
         builder.finish()
@@ -279,6 +322,7 @@ impl<'a> FbsGetBlocks<'a> {
     pub fn build<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
         block_hashes: &[H256],
+        inventory_type: InventoryType,
     ) -> WIPOffset<FbsGetBlocks<'b>> {
         let vec = block_hashes
             .iter()
@@ -287,6 +331,7 @@ impl<'a> FbsGetBlocks<'a> {
         let block_hashes = fbb.create_vector(&vec);
         let mut builder = GetBlocksBuilder::new(fbb);
         builder.add_block_hashes(block_hashes);
+        builder.add_inventory_type(inventory_type);
         builder.finish()
     }
 }
@@ -294,9 +339,43 @@ impl<'a> FbsGetBlocks<'a> {
 impl<'a> SyncMessage<'a> {
     pub fn build_get_headers<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
+        version: u32,
         block_locator_hashes: &[H256],
     ) -> WIPOffset<SyncMessage<'b>> {
-        let fbs_get_headers = FbsGetHeaders::build(fbb, block_locator_hashes);
+        let fbs_get_headers = FbsGetHeaders::build(fbb, version, block_locator_hashes, false);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetHeaders);
+        builder.add_payload(fbs_get_headers.as_union_value());
+        builder.finish()
+    }
+
+    // Same as build_get_headers, telling the peer we accept compressed headers
+    pub fn build_get_compressed_headers<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        version: u32,
+        block_locator_hashes: &[H256],
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_get_headers = FbsGetHeaders::build(fbb, version, block_locator_hashes, true);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetHeaders);
+        builder.add_payload(fbs_get_headers.as_union_value());
+        builder.finish()
+    }
+
+    // Asks for up to `count` headers walking back from `hash`, highest first
+    pub fn build_get_ancestor_headers<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        version: u32,
+        hash: &H256,
+        count: u32,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let block_locator_hashes = fbb.create_vector(&[FbsH256::from(hash)]);
+        let mut builder = GetHeadersBuilder::new(fbb);
+        builder.add_version(version);
+        builder.add_block_locator_hashes(block_locator_hashes);
+        builder.add_reverse(true);
+        builder.add_count(count);
+        let fbs_get_headers = builder.finish();
         let mut builder = SyncMessageBuilder::new(fbb);
         builder.add_payload_type(SyncPayload::GetHeaders);
         builder.add_payload(fbs_get_headers.as_union_value());
@@ -314,11 +393,59 @@ impl<'a> SyncMessage<'a> {
         builder.finish()
     }
 
+    pub fn build_compressed_headers<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        headers: &[Header],
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_headers = FbsHeaders::build_compressed(fbb, headers);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Headers);
+        builder.add_payload(fbs_headers.as_union_value());
+        builder.finish()
+    }
+
+    // Empty headers telling the peer its locator already reached our tip
+    pub fn build_up_to_date_headers<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let headers = fbb.create_vector::<WIPOffset<FbsHeader>>(&[]);
+        let mut builder = HeadersBuilder::new(fbb);
+        builder.add_headers(headers);
+        builder.add_up_to_date(true);
+        let fbs_headers = builder.finish();
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Headers);
+        builder.add_payload(fbs_headers.as_union_value());
+        builder.finish()
+    }
+
     pub fn build_get_blocks<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
         block_hashes: &[H256],
     ) -> WIPOffset<SyncMessage<'b>> {
-        let fbs_get_blocks = FbsGetBlocks::build(fbb, block_hashes);
+        let fbs_get_blocks = FbsGetBlocks::build(fbb, block_hashes, InventoryType::Block);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetBlocks);
+        builder.add_payload(fbs_get_blocks.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_get_compact_blocks<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        block_hashes: &[H256],
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_get_blocks = FbsGetBlocks::build(fbb, block_hashes, InventoryType::CompactBlock);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetBlocks);
+        builder.add_payload(fbs_get_blocks.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_get_block_headers<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        block_hashes: &[H256],
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_get_blocks = FbsGetBlocks::build(fbb, block_hashes, InventoryType::Header);
         let mut builder = SyncMessageBuilder::new(fbb);
         builder.add_payload_type(SyncPayload::GetBlocks);
         builder.add_payload(fbs_get_blocks.as_union_value());
@@ -336,6 +463,93 @@ impl<'a> SyncMessage<'a> {
         builder.finish()
     }
 
+    pub fn build_set_filter<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        filter: &[u8],
+        num_hashes: u8,
+        hash_seed: u32,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_filter = fbb.create_vector(filter);
+        let mut builder = SetFilterBuilder::new(fbb);
+        builder.add_filter(fbs_filter);
+        builder.add_num_hashes(num_hashes);
+        builder.add_hash_seed(hash_seed);
+        let set_filter = builder.finish();
+
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::SetFilter);
+        builder.add_payload(set_filter.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_add_filter<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        filter: &[u8],
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let fbs_filter = fbb.create_vector(filter);
+        let mut builder = AddFilterBuilder::new(fbb);
+        builder.add_filter(fbs_filter);
+        let add_filter = builder.finish();
+
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::AddFilter);
+        builder.add_payload(add_filter.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_clear_filter<'b>(fbb: &mut FlatBufferBuilder<'b>) -> WIPOffset<SyncMessage<'b>> {
+        let clear_filter = ClearFilterBuilder::new(fbb).finish();
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::ClearFilter);
+        builder.add_payload(clear_filter.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_disconnect_reason<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        code: DisconnectCode,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let disconnect_reason = {
+            let mut builder = DisconnectReasonBuilder::new(fbb);
+            builder.add_code(code);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::DisconnectReason);
+        builder.add_payload(disconnect_reason.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_ping<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        nonce: u64,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let ping = {
+            let mut builder = PingBuilder::new(fbb);
+            builder.add_nonce(nonce);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Ping);
+        builder.add_payload(ping.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_pong<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        nonce: u64,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let pong = {
+            let mut builder = PongBuilder::new(fbb);
+            builder.add_nonce(nonce);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Pong);
+        builder.add_payload(pong.as_union_value());
+        builder.finish()
+    }
+
     pub fn build_filtered_block<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
         block: &Block,
@@ -347,6 +561,80 @@ impl<'a> SyncMessage<'a> {
         builder.add_payload(filtered_block.as_union_value());
         builder.finish()
     }
+
+    pub fn build_get_transaction_proof<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        transaction_hash: &H256,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let get_transaction_proof = {
+            let fbs_hash = transaction_hash.into();
+            let mut builder = GetTransactionProofBuilder::new(fbb);
+            builder.add_transaction_hash(&fbs_hash);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetTransactionProof);
+        builder.add_payload(get_transaction_proof.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_transaction_proof<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        block: &Block,
+        transaction_index: usize,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let transaction_proof = {
+            let transaction_hashes = block
+                .commit_transactions()
+                .iter()
+                .map(|tx| tx.hash())
+                .collect::<Vec<_>>();
+            let fbs_hash = (&transaction_hashes[transaction_index]).into();
+            let header = FbsHeader::build(fbb, &block.header());
+            let hashes = merkle_proof(&transaction_hashes, &[transaction_index])
+                .unwrap_or_else(Vec::new)
+                .iter()
+                .map(Into::into)
+                .collect::<Vec<FbsH256>>();
+            let fbs_hashes = fbb.create_vector(&hashes);
+
+            let mut builder = TransactionProofBuilder::new(fbb);
+            builder.add_transaction_hash(&fbs_hash);
+            builder.add_header(header);
+            builder.add_transaction_index(transaction_index as u32);
+            builder.add_transactions_count(transaction_hashes.len() as u32);
+            builder.add_hashes(fbs_hashes);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::TransactionProof);
+        builder.add_payload(transaction_proof.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_get_tip<'b>(fbb: &mut FlatBufferBuilder<'b>) -> WIPOffset<SyncMessage<'b>> {
+        let get_tip = GetTipBuilder::new(fbb).finish();
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::GetTip);
+        builder.add_payload(get_tip.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_tip<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        header: &Header,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let tip = {
+            let header = FbsHeader::build(fbb, header);
+            let mut builder = TipBuilder::new(fbb);
+            builder.add_header(header);
+            builder.finish()
+        };
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Tip);
+        builder.add_payload(tip.as_union_value());
+        builder.finish()
+    }
 }
 
 impl<'a> FilteredBlock<'a> {
@@ -369,11 +657,22 @@ impl<'a> FilteredBlock<'a> {
         let header = FbsHeader::build(fbb, &block.header());
         let fbs_transactions = fbb.create_vector(&transactions);
 
+        let transaction_hashes = block
+            .commit_transactions()
+            .iter()
+            .map(|tx| tx.hash())
+            .collect::<Vec<_>>();
+        let hashes = merkle_proof(&transaction_hashes, transactions_index)
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .map(Into::into)
+            .collect::<Vec<FbsH256>>();
+        let fbs_hashes = fbb.create_vector(&hashes);
+
         let mut builder = FilteredBlockBuilder::new(fbb);
         builder.add_header(header);
         builder.add_transactions(fbs_transactions);
-        // TODO waiting for merkle tree proof generation function
-        // builder.add_hashes(...);
+        builder.add_hashes(fbs_hashes);
         builder.finish()
     }
 }
@@ -383,6 +682,7 @@ impl<'a> CompactBlock<'a> {
         fbb: &mut FlatBufferBuilder<'b>,
         block: &Block,
         prefilled_transactions_indexes: &HashSet<usize>,
+        version: u32,
     ) -> WIPOffset<CompactBlock<'b>> {
         let nonce: u64 = thread_rng().gen();
         // always prefill cellbase
@@ -395,7 +695,7 @@ impl<'a> CompactBlock<'a> {
         );
         let mut prefilled_transactions = Vec::with_capacity(prefilled_transactions_len);
 
-        let (key0, key1) = short_transaction_id_keys(block.header().nonce(), nonce);
+        let (key0, key1) = short_transaction_id_keys(version, block.header(), nonce);
         for (transaction_index, transaction) in block.commit_transactions().iter().enumerate() {
             if prefilled_transactions_indexes.contains(&transaction_index)
                 || transaction.is_cellbase()
@@ -436,6 +736,7 @@ impl<'a> CompactBlock<'a> {
         builder.add_prefilled_transactions(prefilled_transactions);
         builder.add_uncles(uncles);
         builder.add_proposal_transactions(proposal_transactions);
+        builder.add_version(version);
         builder.finish()
     }
 }
@@ -445,8 +746,10 @@ impl<'a> RelayMessage<'a> {
         fbb: &mut FlatBufferBuilder<'b>,
         block: &Block,
         prefilled_transactions_indexes: &HashSet<usize>,
+        version: u32,
     ) -> WIPOffset<RelayMessage<'b>> {
-        let compact_block = CompactBlock::build(fbb, block, prefilled_transactions_indexes);
+        let compact_block =
+            CompactBlock::build(fbb, block, prefilled_transactions_indexes, version);
         let mut builder = RelayMessageBuilder::new(fbb);
         builder.add_payload_type(RelayPayload::CompactBlock);
         builder.add_payload(compact_block.as_union_value());
@@ -553,6 +856,125 @@ impl<'a> RelayMessage<'a> {
         builder.add_payload(block_proposal.as_union_value());
         builder.finish()
     }
+
+    pub fn build_announcement_preference<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        mode: AnnouncementMode,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let announcement_preference = {
+            let mut builder = AnnouncementPreferenceBuilder::new(fbb);
+            builder.add_mode(mode);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::AnnouncementPreference);
+        builder.add_payload(announcement_preference.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_compact_block_version<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        version: u32,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let compact_block_version = {
+            let mut builder = CompactBlockVersionBuilder::new(fbb);
+            builder.add_version(version);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::CompactBlockVersion);
+        builder.add_payload(compact_block_version.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_block_inventory<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        block_hashes: &[H256],
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let block_inventory = {
+            let vec = block_hashes
+                .iter()
+                .map(Into::into)
+                .collect::<Vec<FbsH256>>();
+            let block_hashes = fbb.create_vector(&vec);
+            let mut builder = BlockInventoryBuilder::new(fbb);
+            builder.add_block_hashes(block_hashes);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::BlockInventory);
+        builder.add_payload(block_inventory.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_transaction_inventory<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        transaction_hashes: &[H256],
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let transaction_inventory = {
+            let vec = transaction_hashes
+                .iter()
+                .map(Into::into)
+                .collect::<Vec<FbsH256>>();
+            let transaction_hashes = fbb.create_vector(&vec);
+            let mut builder = TransactionInventoryBuilder::new(fbb);
+            builder.add_transaction_hashes(transaction_hashes);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::TransactionInventory);
+        builder.add_payload(transaction_inventory.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_get_transactions<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        transaction_hashes: &[H256],
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let get_transactions = {
+            let vec = transaction_hashes
+                .iter()
+                .map(Into::into)
+                .collect::<Vec<FbsH256>>();
+            let transaction_hashes = fbb.create_vector(&vec);
+            let mut builder = GetTransactionsBuilder::new(fbb);
+            builder.add_transaction_hashes(transaction_hashes);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::GetTransactions);
+        builder.add_payload(get_transactions.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_mempool_sketch<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        counts: &[i32],
+        key_sums: &[H256],
+        check_sums: &[u64],
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let mempool_sketch = {
+            let counts = fbb.create_vector(counts);
+            let vec = key_sums.iter().map(Into::into).collect::<Vec<FbsH256>>();
+            let key_sums = fbb.create_vector(&vec);
+            let check_sums = fbb.create_vector(check_sums);
+            let mut builder = MempoolSketchBuilder::new(fbb);
+            builder.add_counts(counts);
+            builder.add_key_sums(key_sums);
+            builder.add_check_sums(check_sums);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::MempoolSketch);
+        builder.add_payload(mempool_sketch.as_union_value());
+        builder.finish()
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -596,10 +1018,52 @@ mod tests {
         let mut prefilled_transactions_indexes = HashSet::new();
         prefilled_transactions_indexes.insert(0);
         prefilled_transactions_indexes.insert(2);
-        let b = CompactBlock::build(builder, &block, &prefilled_transactions_indexes);
+        let b = CompactBlock::build(builder, &block, &prefilled_transactions_indexes, 0);
         builder.finish(b, None);
 
         let fbs_compact_block = get_root::<CompactBlock>(builder.finished_data());
         assert_eq!(1, fbs_compact_block.prefilled_transactions().unwrap().len());
     }
+
+    #[test]
+    fn build_and_decompress_headers() {
+        let mut headers: Vec<Header> = vec![HeaderBuilder::default().build()];
+        for number in 1..1000u64 {
+            let parent = headers.last().unwrap().clone();
+            // the difficulty changes once in a while
+            let header = HeaderBuilder::default()
+                .parent_hash(parent.hash())
+                .number(number)
+                .timestamp(parent.timestamp() + 1)
+                .difficulty(U256::from(number / 100 + 1))
+                .nonce(number)
+                .build();
+            headers.push(header);
+        }
+
+        let builder = &mut FlatBufferBuilder::new();
+        let b = FbsHeaders::build(builder, &headers);
+        builder.finish(b, None);
+        let uncompressed = builder.finished_data().to_vec();
+
+        let builder = &mut FlatBufferBuilder::new();
+        let b = FbsHeaders::build_compressed(builder, &headers);
+        builder.finish(b, None);
+        let compressed = builder.finished_data().to_vec();
+
+        assert!(compressed.len() < uncompressed.len());
+        let fbs_headers = get_root::<FbsHeaders>(&compressed);
+        assert_eq!(fbs_headers.decompress(), Some(headers.clone()));
+        let fbs_headers = get_root::<FbsHeaders>(&uncompressed);
+        assert_eq!(fbs_headers.decompress(), Some(headers.clone()));
+
+        // the first header of a message keeps the fields shared with its parent
+        let builder = &mut FlatBufferBuilder::new();
+        let b = FbsHeaders::build_compressed(builder, &headers[1..]);
+        builder.finish(b, None);
+        assert_eq!(
+            get_root::<FbsHeaders>(builder.finished_data()).decompress(),
+            Some(headers[1..].to_vec())
+        );
+    }
 }
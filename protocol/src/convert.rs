@@ -148,6 +148,42 @@ impl<'a> From<ckb_protocol::Header<'a>> for ckb_core::header::Header {
     }
 }
 
+impl<'a> ckb_protocol::Headers<'a> {
+    // The headers of the message, a parent hash or a difficulty left out is the one of
+    // the previous header, see FbsHeaders::build_compressed. Returns None when the first
+    // header leaves them out.
+    pub fn decompress(&self) -> Option<Vec<ckb_core::header::Header>> {
+        let mut headers: Vec<ckb_core::header::Header> = Vec::new();
+        for header in FlatbuffersVectorIterator::new(self.headers()?) {
+            let parent_hash = match header.parent_hash() {
+                Some(parent_hash) => parent_hash.into(),
+                None => headers.last()?.hash(),
+            };
+            let difficulty = match header.difficulty().and_then(|b| b.seq()) {
+                Some(seq) => U256::from_little_endian(seq).ok()?,
+                None => headers.last()?.difficulty().clone(),
+            };
+            headers.push(
+                ckb_core::header::HeaderBuilder::default()
+                    .version(header.version())
+                    .parent_hash(parent_hash)
+                    .timestamp(header.timestamp())
+                    .number(header.number())
+                    .txs_commit(header.txs_commit().unwrap().into())
+                    .txs_proposal(header.txs_proposal().unwrap().into())
+                    .difficulty(difficulty)
+                    .cellbase_id(header.cellbase_id().unwrap().into())
+                    .uncles_hash(header.uncles_hash().unwrap().into())
+                    .nonce(header.nonce())
+                    .proof(header.proof().and_then(|b| b.seq()).unwrap().to_vec())
+                    .uncles_count(header.uncles_count())
+                    .build(),
+            );
+        }
+        Some(headers)
+    }
+}
+
 impl<'a> From<ckb_protocol::Transaction<'a>> for ckb_core::transaction::Transaction {
     fn from(transaction: ckb_protocol::Transaction<'a>) -> Self {
         let deps = FlatbuffersVectorIterator::new(transaction.deps().unwrap())
@@ -6,6 +6,7 @@ mod protocol_generated;
 
 pub use crate::protocol_generated::ckb::protocol::*;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use ckb_core::header::Header;
 use ckb_util::u64_to_bytes;
 use hash::sha3_256;
 use numext_fixed_hash::H256;
@@ -39,10 +40,16 @@ impl<'a, T: flatbuffers::Follow<'a> + 'a> Iterator for FlatbuffersVectorIterator
 
 pub type ShortTransactionID = [u8; 6];
 
-pub fn short_transaction_id_keys(header_nonce: u64, random_nonce: u64) -> (u64, u64) {
-    // sha3-256(header nonce + random nonce) in little-endian
+// Keys of the short ids in a compact block of the version. Version 0 keys on the nonce of
+// the header, version 1 on the hash of the whole header so a miner can't pick the nonce
+// colliding the short ids of its block
+pub fn short_transaction_id_keys(version: u32, header: &Header, random_nonce: u64) -> (u64, u64) {
+    // sha3-256(header nonce or header hash + random nonce) in little-endian
     let mut bytes = vec![];
-    bytes.write_u64::<LittleEndian>(header_nonce).unwrap();
+    match version {
+        0 => bytes.write_u64::<LittleEndian>(header.nonce()).unwrap(),
+        _ => bytes.extend_from_slice(header.hash().as_bytes()),
+    }
     bytes.write_u64::<LittleEndian>(random_nonce).unwrap();
     let block_header_with_nonce_hash = sha3_256(bytes);
 
@@ -37,11 +37,18 @@ pub enum SyncPayload {
   AddFilter = 6,
   ClearFilter = 7,
   FilteredBlock = 8,
+  DisconnectReason = 9,
+  Ping = 10,
+  Pong = 11,
+  GetTransactionProof = 12,
+  TransactionProof = 13,
+  GetTip = 14,
+  Tip = 15,
 
 }
 
 const ENUM_MIN_SYNC_PAYLOAD: u8 = 0;
-const ENUM_MAX_SYNC_PAYLOAD: u8 = 8;
+const ENUM_MAX_SYNC_PAYLOAD: u8 = 15;
 
 impl<'a> flatbuffers::Follow<'a> for SyncPayload {
   type Inner = Self;
@@ -75,7 +82,7 @@ impl flatbuffers::Push for SyncPayload {
 }
 
 #[allow(non_camel_case_types)]
-const ENUM_VALUES_SYNC_PAYLOAD:[SyncPayload; 9] = [
+const ENUM_VALUES_SYNC_PAYLOAD:[SyncPayload; 16] = [
   SyncPayload::NONE,
   SyncPayload::GetHeaders,
   SyncPayload::Headers,
@@ -84,11 +91,18 @@ const ENUM_VALUES_SYNC_PAYLOAD:[SyncPayload; 9] = [
   SyncPayload::SetFilter,
   SyncPayload::AddFilter,
   SyncPayload::ClearFilter,
-  SyncPayload::FilteredBlock
+  SyncPayload::FilteredBlock,
+  SyncPayload::DisconnectReason,
+  SyncPayload::Ping,
+  SyncPayload::Pong,
+  SyncPayload::GetTransactionProof,
+  SyncPayload::TransactionProof,
+  SyncPayload::GetTip,
+  SyncPayload::Tip
 ];
 
 #[allow(non_camel_case_types)]
-const ENUM_NAMES_SYNC_PAYLOAD:[&'static str; 9] = [
+const ENUM_NAMES_SYNC_PAYLOAD:[&'static str; 16] = [
     "NONE",
     "GetHeaders",
     "Headers",
@@ -97,7 +111,14 @@ const ENUM_NAMES_SYNC_PAYLOAD:[&'static str; 9] = [
     "SetFilter",
     "AddFilter",
     "ClearFilter",
-    "FilteredBlock"
+    "FilteredBlock",
+    "DisconnectReason",
+    "Ping",
+    "Pong",
+    "GetTransactionProof",
+    "TransactionProof",
+    "GetTip",
+    "Tip"
 ];
 
 pub fn enum_name_sync_payload(e: SyncPayload) -> &'static str {
@@ -117,11 +138,17 @@ pub enum RelayPayload {
   BlockTransactions = 4,
   GetBlockProposal = 5,
   BlockProposal = 6,
+  AnnouncementPreference = 7,
+  BlockInventory = 8,
+  TransactionInventory = 9,
+  GetTransactions = 10,
+  CompactBlockVersion = 11,
+  MempoolSketch = 12,
 
 }
 
 const ENUM_MIN_RELAY_PAYLOAD: u8 = 0;
-const ENUM_MAX_RELAY_PAYLOAD: u8 = 6;
+const ENUM_MAX_RELAY_PAYLOAD: u8 = 12;
 
 impl<'a> flatbuffers::Follow<'a> for RelayPayload {
   type Inner = Self;
@@ -155,25 +182,37 @@ impl flatbuffers::Push for RelayPayload {
 }
 
 #[allow(non_camel_case_types)]
-const ENUM_VALUES_RELAY_PAYLOAD:[RelayPayload; 7] = [
+const ENUM_VALUES_RELAY_PAYLOAD:[RelayPayload; 13] = [
   RelayPayload::NONE,
   RelayPayload::CompactBlock,
   RelayPayload::Transaction,
   RelayPayload::GetBlockTransactions,
   RelayPayload::BlockTransactions,
   RelayPayload::GetBlockProposal,
-  RelayPayload::BlockProposal
+  RelayPayload::BlockProposal,
+  RelayPayload::AnnouncementPreference,
+  RelayPayload::BlockInventory,
+  RelayPayload::TransactionInventory,
+  RelayPayload::GetTransactions,
+  RelayPayload::CompactBlockVersion,
+  RelayPayload::MempoolSketch
 ];
 
 #[allow(non_camel_case_types)]
-const ENUM_NAMES_RELAY_PAYLOAD:[&'static str; 7] = [
+const ENUM_NAMES_RELAY_PAYLOAD:[&'static str; 13] = [
     "NONE",
     "CompactBlock",
     "Transaction",
     "GetBlockTransactions",
     "BlockTransactions",
     "GetBlockProposal",
-    "BlockProposal"
+    "BlockProposal",
+    "AnnouncementPreference",
+    "BlockInventory",
+    "TransactionInventory",
+    "GetTransactions",
+    "CompactBlockVersion",
+    "MempoolSketch"
 ];
 
 pub fn enum_name_relay_payload(e: RelayPayload) -> &'static str {
@@ -182,6 +221,204 @@ pub fn enum_name_relay_payload(e: RelayPayload) -> &'static str {
 }
 
 pub struct RelayPayloadUnionTableOffset {}
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InventoryType {
+  Block = 0,
+  CompactBlock = 1,
+  Header = 2,
+
+}
+
+const ENUM_MIN_INVENTORY_TYPE: u8 = 0;
+const ENUM_MAX_INVENTORY_TYPE: u8 = 2;
+
+impl<'a> flatbuffers::Follow<'a> for InventoryType {
+  type Inner = Self;
+  #[inline]
+  fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+    flatbuffers::read_scalar_at::<Self>(buf, loc)
+  }
+}
+
+impl flatbuffers::EndianScalar for InventoryType {
+  #[inline]
+  fn to_little_endian(self) -> Self {
+    let n = u8::to_le(self as u8);
+    let p = &n as *const u8 as *const InventoryType;
+    unsafe { *p }
+  }
+  #[inline]
+  fn from_little_endian(self) -> Self {
+    let n = u8::from_le(self as u8);
+    let p = &n as *const u8 as *const InventoryType;
+    unsafe { *p }
+  }
+}
+
+impl flatbuffers::Push for InventoryType {
+    type Output = InventoryType;
+    #[inline]
+    fn push(&self, dst: &mut [u8], _rest: &[u8]) {
+        flatbuffers::emplace_scalar::<InventoryType>(dst, *self);
+    }
+}
+
+#[allow(non_camel_case_types)]
+const ENUM_VALUES_INVENTORY_TYPE:[InventoryType; 3] = [
+  InventoryType::Block,
+  InventoryType::CompactBlock,
+  InventoryType::Header
+];
+
+#[allow(non_camel_case_types)]
+const ENUM_NAMES_INVENTORY_TYPE:[&'static str; 3] = [
+    "Block",
+    "CompactBlock",
+    "Header"
+];
+
+pub fn enum_name_inventory_type(e: InventoryType) -> &'static str {
+  let index: usize = e as usize;
+  ENUM_NAMES_INVENTORY_TYPE[index]
+}
+
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnnouncementMode {
+  CompactBlock = 0,
+  Header = 1,
+  Inventory = 2,
+
+}
+
+const ENUM_MIN_ANNOUNCEMENT_MODE: u8 = 0;
+const ENUM_MAX_ANNOUNCEMENT_MODE: u8 = 2;
+
+impl<'a> flatbuffers::Follow<'a> for AnnouncementMode {
+  type Inner = Self;
+  #[inline]
+  fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+    flatbuffers::read_scalar_at::<Self>(buf, loc)
+  }
+}
+
+impl flatbuffers::EndianScalar for AnnouncementMode {
+  #[inline]
+  fn to_little_endian(self) -> Self {
+    let n = u8::to_le(self as u8);
+    let p = &n as *const u8 as *const AnnouncementMode;
+    unsafe { *p }
+  }
+  #[inline]
+  fn from_little_endian(self) -> Self {
+    let n = u8::from_le(self as u8);
+    let p = &n as *const u8 as *const AnnouncementMode;
+    unsafe { *p }
+  }
+}
+
+impl flatbuffers::Push for AnnouncementMode {
+    type Output = AnnouncementMode;
+    #[inline]
+    fn push(&self, dst: &mut [u8], _rest: &[u8]) {
+        flatbuffers::emplace_scalar::<AnnouncementMode>(dst, *self);
+    }
+}
+
+#[allow(non_camel_case_types)]
+const ENUM_VALUES_ANNOUNCEMENT_MODE:[AnnouncementMode; 3] = [
+  AnnouncementMode::CompactBlock,
+  AnnouncementMode::Header,
+  AnnouncementMode::Inventory
+];
+
+#[allow(non_camel_case_types)]
+const ENUM_NAMES_ANNOUNCEMENT_MODE:[&'static str; 3] = [
+    "CompactBlock",
+    "Header",
+    "Inventory"
+];
+
+pub fn enum_name_announcement_mode(e: AnnouncementMode) -> &'static str {
+  let index: usize = e as usize;
+  ENUM_NAMES_ANNOUNCEMENT_MODE[index]
+}
+
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisconnectCode {
+  HeadersSyncTimeout = 0,
+  ChainSyncTimeout = 1,
+  Misbehavior = 2,
+  PingTimeout = 3,
+  BlockDownloadTimeout = 4,
+  Useless = 5,
+
+}
+
+const ENUM_MIN_DISCONNECT_CODE: u8 = 0;
+const ENUM_MAX_DISCONNECT_CODE: u8 = 5;
+
+impl<'a> flatbuffers::Follow<'a> for DisconnectCode {
+  type Inner = Self;
+  #[inline]
+  fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+    flatbuffers::read_scalar_at::<Self>(buf, loc)
+  }
+}
+
+impl flatbuffers::EndianScalar for DisconnectCode {
+  #[inline]
+  fn to_little_endian(self) -> Self {
+    let n = u8::to_le(self as u8);
+    let p = &n as *const u8 as *const DisconnectCode;
+    unsafe { *p }
+  }
+  #[inline]
+  fn from_little_endian(self) -> Self {
+    let n = u8::from_le(self as u8);
+    let p = &n as *const u8 as *const DisconnectCode;
+    unsafe { *p }
+  }
+}
+
+impl flatbuffers::Push for DisconnectCode {
+    type Output = DisconnectCode;
+    #[inline]
+    fn push(&self, dst: &mut [u8], _rest: &[u8]) {
+        flatbuffers::emplace_scalar::<DisconnectCode>(dst, *self);
+    }
+}
+
+#[allow(non_camel_case_types)]
+const ENUM_VALUES_DISCONNECT_CODE:[DisconnectCode; 6] = [
+  DisconnectCode::HeadersSyncTimeout,
+  DisconnectCode::ChainSyncTimeout,
+  DisconnectCode::Misbehavior,
+  DisconnectCode::PingTimeout,
+  DisconnectCode::BlockDownloadTimeout,
+  DisconnectCode::Useless
+];
+
+#[allow(non_camel_case_types)]
+const ENUM_NAMES_DISCONNECT_CODE:[&'static str; 6] = [
+    "HeadersSyncTimeout",
+    "ChainSyncTimeout",
+    "Misbehavior",
+    "PingTimeout",
+    "BlockDownloadTimeout",
+    "Useless"
+];
+
+pub fn enum_name_disconnect_code(e: DisconnectCode) -> &'static str {
+  let index: usize = e as usize;
+  ENUM_NAMES_DISCONNECT_CODE[index]
+}
+
 // struct ProposalShortId, aligned to 1
 #[repr(C, align(1))]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -619,6 +856,76 @@ impl<'a> SyncMessage<'a> {
     }
   }
 
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_disconnect_reason(&'a self) -> Option<DisconnectReason> {
+    if self.payload_type() == SyncPayload::DisconnectReason {
+      self.payload().map(|u| DisconnectReason::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_ping(&'a self) -> Option<Ping> {
+    if self.payload_type() == SyncPayload::Ping {
+      self.payload().map(|u| Ping::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_pong(&'a self) -> Option<Pong> {
+    if self.payload_type() == SyncPayload::Pong {
+      self.payload().map(|u| Pong::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_get_transaction_proof(&'a self) -> Option<GetTransactionProof> {
+    if self.payload_type() == SyncPayload::GetTransactionProof {
+      self.payload().map(|u| GetTransactionProof::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_transaction_proof(&'a self) -> Option<TransactionProof> {
+    if self.payload_type() == SyncPayload::TransactionProof {
+      self.payload().map(|u| TransactionProof::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_get_tip(&'a self) -> Option<GetTip> {
+    if self.payload_type() == SyncPayload::GetTip {
+      self.payload().map(|u| GetTip::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_tip(&'a self) -> Option<Tip> {
+    if self.payload_type() == SyncPayload::Tip {
+      self.payload().map(|u| Tip::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
 }
 
 pub struct SyncMessageArgs {
@@ -768,14 +1075,20 @@ impl<'a> GetHeaders<'a> {
         args: &'args GetHeadersArgs<'args>) -> flatbuffers::WIPOffset<GetHeaders<'bldr>> {
       let mut builder = GetHeadersBuilder::new(_fbb);
       if let Some(x) = args.hash_stop { builder.add_hash_stop(x); }
+      builder.add_count(args.count);
       if let Some(x) = args.block_locator_hashes { builder.add_block_locator_hashes(x); }
       builder.add_version(args.version);
+      builder.add_compressed_headers(args.compressed_headers);
+      builder.add_reverse(args.reverse);
       builder.finish()
     }
 
     pub const VT_VERSION: flatbuffers::VOffsetT = 4;
     pub const VT_BLOCK_LOCATOR_HASHES: flatbuffers::VOffsetT = 6;
     pub const VT_HASH_STOP: flatbuffers::VOffsetT = 8;
+    pub const VT_REVERSE: flatbuffers::VOffsetT = 10;
+    pub const VT_COUNT: flatbuffers::VOffsetT = 12;
+    pub const VT_COMPRESSED_HEADERS: flatbuffers::VOffsetT = 14;
 
   #[inline]
   pub fn version(&self) -> u32 {
@@ -789,12 +1102,27 @@ impl<'a> GetHeaders<'a> {
   pub fn hash_stop(&self) -> Option<&'a H256> {
     self._tab.get::<H256>(GetHeaders::VT_HASH_STOP, None)
   }
+  #[inline]
+  pub fn reverse(&self) -> bool {
+    self._tab.get::<bool>(GetHeaders::VT_REVERSE, Some(false)).unwrap()
+  }
+  #[inline]
+  pub fn count(&self) -> u32 {
+    self._tab.get::<u32>(GetHeaders::VT_COUNT, Some(0)).unwrap()
+  }
+  #[inline]
+  pub fn compressed_headers(&self) -> bool {
+    self._tab.get::<bool>(GetHeaders::VT_COMPRESSED_HEADERS, Some(false)).unwrap()
+  }
 }
 
 pub struct GetHeadersArgs<'a> {
     pub version: u32,
     pub block_locator_hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
     pub hash_stop: Option<&'a  H256>,
+    pub reverse: bool,
+    pub count: u32,
+    pub compressed_headers: bool,
 }
 impl<'a> Default for GetHeadersArgs<'a> {
     #[inline]
@@ -803,6 +1131,9 @@ impl<'a> Default for GetHeadersArgs<'a> {
             version: 0,
             block_locator_hashes: None,
             hash_stop: None,
+            reverse: false,
+            count: 0,
+            compressed_headers: false,
         }
     }
 }
@@ -824,6 +1155,18 @@ impl<'a: 'b, 'b> GetHeadersBuilder<'a, 'b> {
     self.fbb_.push_slot_always::<&H256>(GetHeaders::VT_HASH_STOP, hash_stop);
   }
   #[inline]
+  pub fn add_reverse(&mut self, reverse: bool) {
+    self.fbb_.push_slot::<bool>(GetHeaders::VT_REVERSE, reverse, false);
+  }
+  #[inline]
+  pub fn add_count(&mut self, count: u32) {
+    self.fbb_.push_slot::<u32>(GetHeaders::VT_COUNT, count, 0);
+  }
+  #[inline]
+  pub fn add_compressed_headers(&mut self, compressed_headers: bool) {
+    self.fbb_.push_slot::<bool>(GetHeaders::VT_COMPRESSED_HEADERS, compressed_headers, false);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> GetHeadersBuilder<'a, 'b> {
     let start = _fbb.start_table();
     GetHeadersBuilder {
@@ -868,25 +1211,33 @@ impl<'a> GetBlocks<'a> {
         args: &'args GetBlocksArgs<'args>) -> flatbuffers::WIPOffset<GetBlocks<'bldr>> {
       let mut builder = GetBlocksBuilder::new(_fbb);
       if let Some(x) = args.block_hashes { builder.add_block_hashes(x); }
+      builder.add_inventory_type(args.inventory_type);
       builder.finish()
     }
 
     pub const VT_BLOCK_HASHES: flatbuffers::VOffsetT = 4;
+    pub const VT_INVENTORY_TYPE: flatbuffers::VOffsetT = 6;
 
   #[inline]
   pub fn block_hashes(&self) -> Option<&'a [H256]> {
     self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(GetBlocks::VT_BLOCK_HASHES, None).map(|v| v.safe_slice() )
   }
+  #[inline]
+  pub fn inventory_type(&self) -> InventoryType {
+    self._tab.get::<InventoryType>(GetBlocks::VT_INVENTORY_TYPE, Some(InventoryType::Block)).unwrap()
+  }
 }
 
 pub struct GetBlocksArgs<'a> {
     pub block_hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+    pub inventory_type: InventoryType,
 }
 impl<'a> Default for GetBlocksArgs<'a> {
     #[inline]
     fn default() -> Self {
         GetBlocksArgs {
             block_hashes: None,
+            inventory_type: InventoryType::Block,
         }
     }
 }
@@ -900,6 +1251,10 @@ impl<'a: 'b, 'b> GetBlocksBuilder<'a, 'b> {
     self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(GetBlocks::VT_BLOCK_HASHES, block_hashes);
   }
   #[inline]
+  pub fn add_inventory_type(&mut self, inventory_type: InventoryType) {
+    self.fbb_.push_slot::<InventoryType>(GetBlocks::VT_INVENTORY_TYPE, inventory_type, InventoryType::Block);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> GetBlocksBuilder<'a, 'b> {
     let start = _fbb.start_table();
     GetBlocksBuilder {
@@ -944,25 +1299,33 @@ impl<'a> Headers<'a> {
         args: &'args HeadersArgs<'args>) -> flatbuffers::WIPOffset<Headers<'bldr>> {
       let mut builder = HeadersBuilder::new(_fbb);
       if let Some(x) = args.headers { builder.add_headers(x); }
+      builder.add_up_to_date(args.up_to_date);
       builder.finish()
     }
 
     pub const VT_HEADERS: flatbuffers::VOffsetT = 4;
+    pub const VT_UP_TO_DATE: flatbuffers::VOffsetT = 6;
 
   #[inline]
   pub fn headers(&self) -> Option<flatbuffers::Vector<flatbuffers::ForwardsUOffset<Header<'a>>>> {
     self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<flatbuffers::ForwardsUOffset<Header<'a>>>>>(Headers::VT_HEADERS, None)
   }
+  #[inline]
+  pub fn up_to_date(&self) -> bool {
+    self._tab.get::<bool>(Headers::VT_UP_TO_DATE, Some(false)).unwrap()
+  }
 }
 
 pub struct HeadersArgs<'a> {
     pub headers: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<Header<'a >>>>>,
+    pub up_to_date: bool,
 }
 impl<'a> Default for HeadersArgs<'a> {
     #[inline]
     fn default() -> Self {
         HeadersArgs {
             headers: None,
+            up_to_date: false,
         }
     }
 }
@@ -976,6 +1339,10 @@ impl<'a: 'b, 'b> HeadersBuilder<'a, 'b> {
     self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(Headers::VT_HEADERS, headers);
   }
   #[inline]
+  pub fn add_up_to_date(&mut self, up_to_date: bool) {
+    self.fbb_.push_slot::<bool>(Headers::VT_UP_TO_DATE, up_to_date, false);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> HeadersBuilder<'a, 'b> {
     let start = _fbb.start_table();
     HeadersBuilder {
@@ -2051,6 +2418,66 @@ impl<'a> RelayMessage<'a> {
     }
   }
 
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_announcement_preference(&'a self) -> Option<AnnouncementPreference> {
+    if self.payload_type() == RelayPayload::AnnouncementPreference {
+      self.payload().map(|u| AnnouncementPreference::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_block_inventory(&'a self) -> Option<BlockInventory> {
+    if self.payload_type() == RelayPayload::BlockInventory {
+      self.payload().map(|u| BlockInventory::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_transaction_inventory(&'a self) -> Option<TransactionInventory> {
+    if self.payload_type() == RelayPayload::TransactionInventory {
+      self.payload().map(|u| TransactionInventory::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_get_transactions(&'a self) -> Option<GetTransactions> {
+    if self.payload_type() == RelayPayload::GetTransactions {
+      self.payload().map(|u| GetTransactions::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_compact_block_version(&'a self) -> Option<CompactBlockVersion> {
+    if self.payload_type() == RelayPayload::CompactBlockVersion {
+      self.payload().map(|u| CompactBlockVersion::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_mempool_sketch(&'a self) -> Option<MempoolSketch> {
+    if self.payload_type() == RelayPayload::MempoolSketch {
+      self.payload().map(|u| MempoolSketch::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
 }
 
 pub struct RelayMessageArgs {
@@ -2124,6 +2551,7 @@ impl<'a> CompactBlock<'a> {
         args: &'args CompactBlockArgs<'args>) -> flatbuffers::WIPOffset<CompactBlock<'bldr>> {
       let mut builder = CompactBlockBuilder::new(_fbb);
       builder.add_nonce(args.nonce);
+      builder.add_version(args.version);
       if let Some(x) = args.proposal_transactions { builder.add_proposal_transactions(x); }
       if let Some(x) = args.uncles { builder.add_uncles(x); }
       if let Some(x) = args.prefilled_transactions { builder.add_prefilled_transactions(x); }
@@ -2138,6 +2566,7 @@ impl<'a> CompactBlock<'a> {
     pub const VT_PREFILLED_TRANSACTIONS: flatbuffers::VOffsetT = 10;
     pub const VT_UNCLES: flatbuffers::VOffsetT = 12;
     pub const VT_PROPOSAL_TRANSACTIONS: flatbuffers::VOffsetT = 14;
+    pub const VT_VERSION: flatbuffers::VOffsetT = 16;
 
   #[inline]
   pub fn header(&self) -> Option<Header<'a>> {
@@ -2163,6 +2592,10 @@ impl<'a> CompactBlock<'a> {
   pub fn proposal_transactions(&self) -> Option<&'a [ProposalShortId]> {
     self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<ProposalShortId>>>(CompactBlock::VT_PROPOSAL_TRANSACTIONS, None).map(|v| v.safe_slice() )
   }
+  #[inline]
+  pub fn version(&self) -> u32 {
+    self._tab.get::<u32>(CompactBlock::VT_VERSION, Some(0)).unwrap()
+  }
 }
 
 pub struct CompactBlockArgs<'a> {
@@ -2172,6 +2605,7 @@ pub struct CompactBlockArgs<'a> {
     pub prefilled_transactions: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<IndexTransaction<'a >>>>>,
     pub uncles: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<UncleBlock<'a >>>>>,
     pub proposal_transactions: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , ProposalShortId>>>,
+    pub version: u32,
 }
 impl<'a> Default for CompactBlockArgs<'a> {
     #[inline]
@@ -2183,6 +2617,7 @@ impl<'a> Default for CompactBlockArgs<'a> {
             prefilled_transactions: None,
             uncles: None,
             proposal_transactions: None,
+            version: 0,
         }
     }
 }
@@ -2216,6 +2651,10 @@ impl<'a: 'b, 'b> CompactBlockBuilder<'a, 'b> {
     self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(CompactBlock::VT_PROPOSAL_TRANSACTIONS, proposal_transactions);
   }
   #[inline]
+  pub fn add_version(&mut self, version: u32) {
+    self.fbb_.push_slot::<u32>(CompactBlock::VT_VERSION, version, 0);
+  }
+  #[inline]
   pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> CompactBlockBuilder<'a, 'b> {
     let start = _fbb.start_table();
     CompactBlockBuilder {
@@ -2658,15 +3097,15 @@ impl<'a: 'b, 'b> BlockProposalBuilder<'a, 'b> {
   }
 }
 
-pub enum SetFilterOffset {}
+pub enum AnnouncementPreferenceOffset {}
 #[derive(Copy, Clone, Debug, PartialEq)]
 
-pub struct SetFilter<'a> {
+pub struct AnnouncementPreference<'a> {
   pub _tab: flatbuffers::Table<'a>,
 }
 
-impl<'a> flatbuffers::Follow<'a> for SetFilter<'a> {
-    type Inner = SetFilter<'a>;
+impl<'a> flatbuffers::Follow<'a> for AnnouncementPreference<'a> {
+    type Inner = AnnouncementPreference<'a>;
     #[inline]
     fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
         Self {
@@ -2675,98 +3114,969 @@ impl<'a> flatbuffers::Follow<'a> for SetFilter<'a> {
     }
 }
 
-impl<'a> SetFilter<'a> {
+impl<'a> AnnouncementPreference<'a> {
     #[inline]
     pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
-        SetFilter {
+        AnnouncementPreference {
             _tab: table,
         }
     }
     #[allow(unused_mut)]
     pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
         _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
-        args: &'args SetFilterArgs<'args>) -> flatbuffers::WIPOffset<SetFilter<'bldr>> {
-      let mut builder = SetFilterBuilder::new(_fbb);
-      builder.add_hash_seed(args.hash_seed);
-      if let Some(x) = args.filter { builder.add_filter(x); }
-      builder.add_num_hashes(args.num_hashes);
+        args: &'args AnnouncementPreferenceArgs) -> flatbuffers::WIPOffset<AnnouncementPreference<'bldr>> {
+      let mut builder = AnnouncementPreferenceBuilder::new(_fbb);
+      builder.add_mode(args.mode);
       builder.finish()
     }
 
-    pub const VT_FILTER: flatbuffers::VOffsetT = 4;
+    pub const VT_MODE: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn mode(&self) -> AnnouncementMode {
+    self._tab.get::<AnnouncementMode>(AnnouncementPreference::VT_MODE, Some(AnnouncementMode::CompactBlock)).unwrap()
+  }
+}
+
+pub struct AnnouncementPreferenceArgs {
+    pub mode: AnnouncementMode,
+}
+impl<'a> Default for AnnouncementPreferenceArgs {
+    #[inline]
+    fn default() -> Self {
+        AnnouncementPreferenceArgs {
+            mode: AnnouncementMode::CompactBlock,
+        }
+    }
+}
+pub struct AnnouncementPreferenceBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> AnnouncementPreferenceBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_mode(&mut self, mode: AnnouncementMode) {
+    self.fbb_.push_slot::<AnnouncementMode>(AnnouncementPreference::VT_MODE, mode, AnnouncementMode::CompactBlock);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> AnnouncementPreferenceBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    AnnouncementPreferenceBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<AnnouncementPreference<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum CompactBlockVersionOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct CompactBlockVersion<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for CompactBlockVersion<'a> {
+    type Inner = CompactBlockVersion<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> CompactBlockVersion<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        CompactBlockVersion {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args CompactBlockVersionArgs) -> flatbuffers::WIPOffset<CompactBlockVersion<'bldr>> {
+      let mut builder = CompactBlockVersionBuilder::new(_fbb);
+      builder.add_version(args.version);
+      builder.finish()
+    }
+
+    pub const VT_VERSION: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn version(&self) -> u32 {
+    self._tab.get::<u32>(CompactBlockVersion::VT_VERSION, Some(0)).unwrap()
+  }
+}
+
+pub struct CompactBlockVersionArgs {
+    pub version: u32,
+}
+impl<'a> Default for CompactBlockVersionArgs {
+    #[inline]
+    fn default() -> Self {
+        CompactBlockVersionArgs {
+            version: 0,
+        }
+    }
+}
+pub struct CompactBlockVersionBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> CompactBlockVersionBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_version(&mut self, version: u32) {
+    self.fbb_.push_slot::<u32>(CompactBlockVersion::VT_VERSION, version, 0);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> CompactBlockVersionBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    CompactBlockVersionBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<CompactBlockVersion<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum BlockInventoryOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct BlockInventory<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for BlockInventory<'a> {
+    type Inner = BlockInventory<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> BlockInventory<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        BlockInventory {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args BlockInventoryArgs<'args>) -> flatbuffers::WIPOffset<BlockInventory<'bldr>> {
+      let mut builder = BlockInventoryBuilder::new(_fbb);
+      if let Some(x) = args.block_hashes { builder.add_block_hashes(x); }
+      builder.finish()
+    }
+
+    pub const VT_BLOCK_HASHES: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn block_hashes(&self) -> Option<&'a [H256]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(BlockInventory::VT_BLOCK_HASHES, None).map(|v| v.safe_slice() )
+  }
+}
+
+pub struct BlockInventoryArgs<'a> {
+    pub block_hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+}
+impl<'a> Default for BlockInventoryArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        BlockInventoryArgs {
+            block_hashes: None,
+        }
+    }
+}
+pub struct BlockInventoryBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> BlockInventoryBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_block_hashes(&mut self, block_hashes: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(BlockInventory::VT_BLOCK_HASHES, block_hashes);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> BlockInventoryBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    BlockInventoryBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<BlockInventory<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum TransactionInventoryOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct TransactionInventory<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for TransactionInventory<'a> {
+    type Inner = TransactionInventory<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> TransactionInventory<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        TransactionInventory {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args TransactionInventoryArgs<'args>) -> flatbuffers::WIPOffset<TransactionInventory<'bldr>> {
+      let mut builder = TransactionInventoryBuilder::new(_fbb);
+      if let Some(x) = args.transaction_hashes { builder.add_transaction_hashes(x); }
+      builder.finish()
+    }
+
+    pub const VT_TRANSACTION_HASHES: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn transaction_hashes(&self) -> Option<&'a [H256]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(TransactionInventory::VT_TRANSACTION_HASHES, None).map(|v| v.safe_slice() )
+  }
+}
+
+pub struct TransactionInventoryArgs<'a> {
+    pub transaction_hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+}
+impl<'a> Default for TransactionInventoryArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        TransactionInventoryArgs {
+            transaction_hashes: None,
+        }
+    }
+}
+pub struct TransactionInventoryBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> TransactionInventoryBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_transaction_hashes(&mut self, transaction_hashes: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(TransactionInventory::VT_TRANSACTION_HASHES, transaction_hashes);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> TransactionInventoryBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    TransactionInventoryBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<TransactionInventory<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum GetTransactionsOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct GetTransactions<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for GetTransactions<'a> {
+    type Inner = GetTransactions<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> GetTransactions<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        GetTransactions {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args GetTransactionsArgs<'args>) -> flatbuffers::WIPOffset<GetTransactions<'bldr>> {
+      let mut builder = GetTransactionsBuilder::new(_fbb);
+      if let Some(x) = args.transaction_hashes { builder.add_transaction_hashes(x); }
+      builder.finish()
+    }
+
+    pub const VT_TRANSACTION_HASHES: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn transaction_hashes(&self) -> Option<&'a [H256]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(GetTransactions::VT_TRANSACTION_HASHES, None).map(|v| v.safe_slice() )
+  }
+}
+
+pub struct GetTransactionsArgs<'a> {
+    pub transaction_hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+}
+impl<'a> Default for GetTransactionsArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        GetTransactionsArgs {
+            transaction_hashes: None,
+        }
+    }
+}
+pub struct GetTransactionsBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> GetTransactionsBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_transaction_hashes(&mut self, transaction_hashes: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(GetTransactions::VT_TRANSACTION_HASHES, transaction_hashes);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> GetTransactionsBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    GetTransactionsBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<GetTransactions<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum MempoolSketchOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct MempoolSketch<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for MempoolSketch<'a> {
+    type Inner = MempoolSketch<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> MempoolSketch<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        MempoolSketch {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args MempoolSketchArgs<'args>) -> flatbuffers::WIPOffset<MempoolSketch<'bldr>> {
+      let mut builder = MempoolSketchBuilder::new(_fbb);
+      if let Some(x) = args.check_sums { builder.add_check_sums(x); }
+      if let Some(x) = args.key_sums { builder.add_key_sums(x); }
+      if let Some(x) = args.counts { builder.add_counts(x); }
+      builder.finish()
+    }
+
+    pub const VT_COUNTS: flatbuffers::VOffsetT = 4;
+    pub const VT_KEY_SUMS: flatbuffers::VOffsetT = 6;
+    pub const VT_CHECK_SUMS: flatbuffers::VOffsetT = 8;
+
+  #[inline]
+  pub fn counts(&self) -> Option<flatbuffers::Vector<'a, i32>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, i32>>>(MempoolSketch::VT_COUNTS, None)
+  }
+  #[inline]
+  pub fn key_sums(&self) -> Option<&'a [H256]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(MempoolSketch::VT_KEY_SUMS, None).map(|v| v.safe_slice() )
+  }
+  #[inline]
+  pub fn check_sums(&self) -> Option<flatbuffers::Vector<'a, u64>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u64>>>(MempoolSketch::VT_CHECK_SUMS, None)
+  }
+}
+
+pub struct MempoolSketchArgs<'a> {
+    pub counts: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  i32>>>,
+    pub key_sums: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+    pub check_sums: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u64>>>,
+}
+impl<'a> Default for MempoolSketchArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        MempoolSketchArgs {
+            counts: None,
+            key_sums: None,
+            check_sums: None,
+        }
+    }
+}
+pub struct MempoolSketchBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> MempoolSketchBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_counts(&mut self, counts: flatbuffers::WIPOffset<flatbuffers::Vector<'b , i32>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(MempoolSketch::VT_COUNTS, counts);
+  }
+  #[inline]
+  pub fn add_key_sums(&mut self, key_sums: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(MempoolSketch::VT_KEY_SUMS, key_sums);
+  }
+  #[inline]
+  pub fn add_check_sums(&mut self, check_sums: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u64>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(MempoolSketch::VT_CHECK_SUMS, check_sums);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> MempoolSketchBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    MempoolSketchBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<MempoolSketch<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum SetFilterOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct SetFilter<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for SetFilter<'a> {
+    type Inner = SetFilter<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> SetFilter<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        SetFilter {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args SetFilterArgs<'args>) -> flatbuffers::WIPOffset<SetFilter<'bldr>> {
+      let mut builder = SetFilterBuilder::new(_fbb);
+      builder.add_hash_seed(args.hash_seed);
+      if let Some(x) = args.filter { builder.add_filter(x); }
+      builder.add_num_hashes(args.num_hashes);
+      builder.finish()
+    }
+
+    pub const VT_FILTER: flatbuffers::VOffsetT = 4;
     pub const VT_NUM_HASHES: flatbuffers::VOffsetT = 6;
     pub const VT_HASH_SEED: flatbuffers::VOffsetT = 8;
 
   #[inline]
   pub fn filter(&self) -> Option<&'a [u8]> {
-    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(SetFilter::VT_FILTER, None).map(|v| v.safe_slice())
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(SetFilter::VT_FILTER, None).map(|v| v.safe_slice())
+  }
+  #[inline]
+  pub fn num_hashes(&self) -> u8 {
+    self._tab.get::<u8>(SetFilter::VT_NUM_HASHES, Some(0)).unwrap()
+  }
+  #[inline]
+  pub fn hash_seed(&self) -> u32 {
+    self._tab.get::<u32>(SetFilter::VT_HASH_SEED, Some(0)).unwrap()
+  }
+}
+
+pub struct SetFilterArgs<'a> {
+    pub filter: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u8>>>,
+    pub num_hashes: u8,
+    pub hash_seed: u32,
+}
+impl<'a> Default for SetFilterArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        SetFilterArgs {
+            filter: None,
+            num_hashes: 0,
+            hash_seed: 0,
+        }
+    }
+}
+pub struct SetFilterBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> SetFilterBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_filter(&mut self, filter: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(SetFilter::VT_FILTER, filter);
+  }
+  #[inline]
+  pub fn add_num_hashes(&mut self, num_hashes: u8) {
+    self.fbb_.push_slot::<u8>(SetFilter::VT_NUM_HASHES, num_hashes, 0);
+  }
+  #[inline]
+  pub fn add_hash_seed(&mut self, hash_seed: u32) {
+    self.fbb_.push_slot::<u32>(SetFilter::VT_HASH_SEED, hash_seed, 0);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> SetFilterBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    SetFilterBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<SetFilter<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum AddFilterOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct AddFilter<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for AddFilter<'a> {
+    type Inner = AddFilter<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> AddFilter<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        AddFilter {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args AddFilterArgs<'args>) -> flatbuffers::WIPOffset<AddFilter<'bldr>> {
+      let mut builder = AddFilterBuilder::new(_fbb);
+      if let Some(x) = args.filter { builder.add_filter(x); }
+      builder.finish()
+    }
+
+    pub const VT_FILTER: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn filter(&self) -> Option<&'a [u8]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(AddFilter::VT_FILTER, None).map(|v| v.safe_slice())
+  }
+}
+
+pub struct AddFilterArgs<'a> {
+    pub filter: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u8>>>,
+}
+impl<'a> Default for AddFilterArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        AddFilterArgs {
+            filter: None,
+        }
+    }
+}
+pub struct AddFilterBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> AddFilterBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_filter(&mut self, filter: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(AddFilter::VT_FILTER, filter);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> AddFilterBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    AddFilterBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<AddFilter<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum ClearFilterOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct ClearFilter<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for ClearFilter<'a> {
+    type Inner = ClearFilter<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> ClearFilter<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        ClearFilter {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        _args: &'args ClearFilterArgs) -> flatbuffers::WIPOffset<ClearFilter<'bldr>> {
+      let mut builder = ClearFilterBuilder::new(_fbb);
+      builder.finish()
+    }
+
+}
+
+pub struct ClearFilterArgs {
+}
+impl<'a> Default for ClearFilterArgs {
+    #[inline]
+    fn default() -> Self {
+        ClearFilterArgs {
+        }
+    }
+}
+pub struct ClearFilterBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> ClearFilterBuilder<'a, 'b> {
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> ClearFilterBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    ClearFilterBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<ClearFilter<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum FilteredBlockOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct FilteredBlock<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for FilteredBlock<'a> {
+    type Inner = FilteredBlock<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> FilteredBlock<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        FilteredBlock {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args FilteredBlockArgs<'args>) -> flatbuffers::WIPOffset<FilteredBlock<'bldr>> {
+      let mut builder = FilteredBlockBuilder::new(_fbb);
+      if let Some(x) = args.hashes { builder.add_hashes(x); }
+      if let Some(x) = args.transactions { builder.add_transactions(x); }
+      if let Some(x) = args.header { builder.add_header(x); }
+      builder.finish()
+    }
+
+    pub const VT_HEADER: flatbuffers::VOffsetT = 4;
+    pub const VT_TRANSACTIONS: flatbuffers::VOffsetT = 6;
+    pub const VT_HASHES: flatbuffers::VOffsetT = 8;
+
+  #[inline]
+  pub fn header(&self) -> Option<Header<'a>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<Header<'a>>>(FilteredBlock::VT_HEADER, None)
+  }
+  #[inline]
+  pub fn transactions(&self) -> Option<flatbuffers::Vector<flatbuffers::ForwardsUOffset<IndexTransaction<'a>>>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<flatbuffers::ForwardsUOffset<IndexTransaction<'a>>>>>(FilteredBlock::VT_TRANSACTIONS, None)
+  }
+  #[inline]
+  pub fn hashes(&self) -> Option<&'a [H256]> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(FilteredBlock::VT_HASHES, None).map(|v| v.safe_slice() )
+  }
+}
+
+pub struct FilteredBlockArgs<'a> {
+    pub header: Option<flatbuffers::WIPOffset<Header<'a >>>,
+    pub transactions: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<IndexTransaction<'a >>>>>,
+    pub hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
+}
+impl<'a> Default for FilteredBlockArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        FilteredBlockArgs {
+            header: None,
+            transactions: None,
+            hashes: None,
+        }
+    }
+}
+pub struct FilteredBlockBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> FilteredBlockBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_header(&mut self, header: flatbuffers::WIPOffset<Header<'b >>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Header>>(FilteredBlock::VT_HEADER, header);
+  }
+  #[inline]
+  pub fn add_transactions(&mut self, transactions: flatbuffers::WIPOffset<flatbuffers::Vector<'b , flatbuffers::ForwardsUOffset<IndexTransaction<'b >>>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(FilteredBlock::VT_TRANSACTIONS, transactions);
+  }
+  #[inline]
+  pub fn add_hashes(&mut self, hashes: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(FilteredBlock::VT_HASHES, hashes);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> FilteredBlockBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    FilteredBlockBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<FilteredBlock<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum DisconnectReasonOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct DisconnectReason<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for DisconnectReason<'a> {
+    type Inner = DisconnectReason<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> DisconnectReason<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        DisconnectReason {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args DisconnectReasonArgs) -> flatbuffers::WIPOffset<DisconnectReason<'bldr>> {
+      let mut builder = DisconnectReasonBuilder::new(_fbb);
+      builder.add_code(args.code);
+      builder.finish()
+    }
+
+    pub const VT_CODE: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn code(&self) -> DisconnectCode {
+    self._tab.get::<DisconnectCode>(DisconnectReason::VT_CODE, Some(DisconnectCode::HeadersSyncTimeout)).unwrap()
+  }
+}
+
+pub struct DisconnectReasonArgs {
+    pub code: DisconnectCode,
+}
+impl<'a> Default for DisconnectReasonArgs {
+    #[inline]
+    fn default() -> Self {
+        DisconnectReasonArgs {
+            code: DisconnectCode::HeadersSyncTimeout,
+        }
+    }
+}
+pub struct DisconnectReasonBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> DisconnectReasonBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_code(&mut self, code: DisconnectCode) {
+    self.fbb_.push_slot::<DisconnectCode>(DisconnectReason::VT_CODE, code, DisconnectCode::HeadersSyncTimeout);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> DisconnectReasonBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    DisconnectReasonBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
   }
   #[inline]
-  pub fn num_hashes(&self) -> u8 {
-    self._tab.get::<u8>(SetFilter::VT_NUM_HASHES, Some(0)).unwrap()
+  pub fn finish(self) -> flatbuffers::WIPOffset<DisconnectReason<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
   }
+}
+
+pub enum PingOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct Ping<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for Ping<'a> {
+    type Inner = Ping<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> Ping<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        Ping {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args PingArgs) -> flatbuffers::WIPOffset<Ping<'bldr>> {
+      let mut builder = PingBuilder::new(_fbb);
+      builder.add_nonce(args.nonce);
+      builder.finish()
+    }
+
+    pub const VT_NONCE: flatbuffers::VOffsetT = 4;
+
   #[inline]
-  pub fn hash_seed(&self) -> u32 {
-    self._tab.get::<u32>(SetFilter::VT_HASH_SEED, Some(0)).unwrap()
+  pub fn nonce(&self) -> u64 {
+    self._tab.get::<u64>(Ping::VT_NONCE, Some(0)).unwrap()
   }
 }
 
-pub struct SetFilterArgs<'a> {
-    pub filter: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u8>>>,
-    pub num_hashes: u8,
-    pub hash_seed: u32,
+pub struct PingArgs {
+    pub nonce: u64,
 }
-impl<'a> Default for SetFilterArgs<'a> {
+impl<'a> Default for PingArgs {
     #[inline]
     fn default() -> Self {
-        SetFilterArgs {
-            filter: None,
-            num_hashes: 0,
-            hash_seed: 0,
+        PingArgs {
+            nonce: 0,
         }
     }
 }
-pub struct SetFilterBuilder<'a: 'b, 'b> {
+pub struct PingBuilder<'a: 'b, 'b> {
   fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
   start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
 }
-impl<'a: 'b, 'b> SetFilterBuilder<'a, 'b> {
-  #[inline]
-  pub fn add_filter(&mut self, filter: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
-    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(SetFilter::VT_FILTER, filter);
-  }
-  #[inline]
-  pub fn add_num_hashes(&mut self, num_hashes: u8) {
-    self.fbb_.push_slot::<u8>(SetFilter::VT_NUM_HASHES, num_hashes, 0);
-  }
+impl<'a: 'b, 'b> PingBuilder<'a, 'b> {
   #[inline]
-  pub fn add_hash_seed(&mut self, hash_seed: u32) {
-    self.fbb_.push_slot::<u32>(SetFilter::VT_HASH_SEED, hash_seed, 0);
+  pub fn add_nonce(&mut self, nonce: u64) {
+    self.fbb_.push_slot::<u64>(Ping::VT_NONCE, nonce, 0);
   }
   #[inline]
-  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> SetFilterBuilder<'a, 'b> {
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> PingBuilder<'a, 'b> {
     let start = _fbb.start_table();
-    SetFilterBuilder {
+    PingBuilder {
       fbb_: _fbb,
       start_: start,
     }
   }
   #[inline]
-  pub fn finish(self) -> flatbuffers::WIPOffset<SetFilter<'a>> {
+  pub fn finish(self) -> flatbuffers::WIPOffset<Ping<'a>> {
     let o = self.fbb_.end_table(self.start_);
     flatbuffers::WIPOffset::new(o.value())
   }
 }
 
-pub enum AddFilterOffset {}
+pub enum PongOffset {}
 #[derive(Copy, Clone, Debug, PartialEq)]
 
-pub struct AddFilter<'a> {
+pub struct Pong<'a> {
   pub _tab: flatbuffers::Table<'a>,
 }
 
-impl<'a> flatbuffers::Follow<'a> for AddFilter<'a> {
-    type Inner = AddFilter<'a>;
+impl<'a> flatbuffers::Follow<'a> for Pong<'a> {
+    type Inner = Pong<'a>;
     #[inline]
     fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
         Self {
@@ -2775,74 +4085,74 @@ impl<'a> flatbuffers::Follow<'a> for AddFilter<'a> {
     }
 }
 
-impl<'a> AddFilter<'a> {
+impl<'a> Pong<'a> {
     #[inline]
     pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
-        AddFilter {
+        Pong {
             _tab: table,
         }
     }
     #[allow(unused_mut)]
     pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
         _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
-        args: &'args AddFilterArgs<'args>) -> flatbuffers::WIPOffset<AddFilter<'bldr>> {
-      let mut builder = AddFilterBuilder::new(_fbb);
-      if let Some(x) = args.filter { builder.add_filter(x); }
+        args: &'args PongArgs) -> flatbuffers::WIPOffset<Pong<'bldr>> {
+      let mut builder = PongBuilder::new(_fbb);
+      builder.add_nonce(args.nonce);
       builder.finish()
     }
 
-    pub const VT_FILTER: flatbuffers::VOffsetT = 4;
+    pub const VT_NONCE: flatbuffers::VOffsetT = 4;
 
   #[inline]
-  pub fn filter(&self) -> Option<&'a [u8]> {
-    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(AddFilter::VT_FILTER, None).map(|v| v.safe_slice())
+  pub fn nonce(&self) -> u64 {
+    self._tab.get::<u64>(Pong::VT_NONCE, Some(0)).unwrap()
   }
 }
 
-pub struct AddFilterArgs<'a> {
-    pub filter: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a ,  u8>>>,
+pub struct PongArgs {
+    pub nonce: u64,
 }
-impl<'a> Default for AddFilterArgs<'a> {
+impl<'a> Default for PongArgs {
     #[inline]
     fn default() -> Self {
-        AddFilterArgs {
-            filter: None,
+        PongArgs {
+            nonce: 0,
         }
     }
 }
-pub struct AddFilterBuilder<'a: 'b, 'b> {
+pub struct PongBuilder<'a: 'b, 'b> {
   fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
   start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
 }
-impl<'a: 'b, 'b> AddFilterBuilder<'a, 'b> {
+impl<'a: 'b, 'b> PongBuilder<'a, 'b> {
   #[inline]
-  pub fn add_filter(&mut self, filter: flatbuffers::WIPOffset<flatbuffers::Vector<'b , u8>>) {
-    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(AddFilter::VT_FILTER, filter);
+  pub fn add_nonce(&mut self, nonce: u64) {
+    self.fbb_.push_slot::<u64>(Pong::VT_NONCE, nonce, 0);
   }
   #[inline]
-  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> AddFilterBuilder<'a, 'b> {
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> PongBuilder<'a, 'b> {
     let start = _fbb.start_table();
-    AddFilterBuilder {
+    PongBuilder {
       fbb_: _fbb,
       start_: start,
     }
   }
   #[inline]
-  pub fn finish(self) -> flatbuffers::WIPOffset<AddFilter<'a>> {
+  pub fn finish(self) -> flatbuffers::WIPOffset<Pong<'a>> {
     let o = self.fbb_.end_table(self.start_);
     flatbuffers::WIPOffset::new(o.value())
   }
 }
 
-pub enum ClearFilterOffset {}
+pub enum GetTransactionProofOffset {}
 #[derive(Copy, Clone, Debug, PartialEq)]
 
-pub struct ClearFilter<'a> {
+pub struct GetTransactionProof<'a> {
   pub _tab: flatbuffers::Table<'a>,
 }
 
-impl<'a> flatbuffers::Follow<'a> for ClearFilter<'a> {
-    type Inner = ClearFilter<'a>;
+impl<'a> flatbuffers::Follow<'a> for GetTransactionProof<'a> {
+    type Inner = GetTransactionProof<'a>;
     #[inline]
     fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
         Self {
@@ -2851,61 +4161,74 @@ impl<'a> flatbuffers::Follow<'a> for ClearFilter<'a> {
     }
 }
 
-impl<'a> ClearFilter<'a> {
+impl<'a> GetTransactionProof<'a> {
     #[inline]
     pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
-        ClearFilter {
+        GetTransactionProof {
             _tab: table,
         }
     }
     #[allow(unused_mut)]
     pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
         _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
-        _args: &'args ClearFilterArgs) -> flatbuffers::WIPOffset<ClearFilter<'bldr>> {
-      let mut builder = ClearFilterBuilder::new(_fbb);
+        args: &'args GetTransactionProofArgs<'args>) -> flatbuffers::WIPOffset<GetTransactionProof<'bldr>> {
+      let mut builder = GetTransactionProofBuilder::new(_fbb);
+      if let Some(x) = args.transaction_hash { builder.add_transaction_hash(x); }
       builder.finish()
     }
 
+    pub const VT_TRANSACTION_HASH: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn transaction_hash(&self) -> Option<&'a H256> {
+    self._tab.get::<H256>(GetTransactionProof::VT_TRANSACTION_HASH, None)
+  }
 }
 
-pub struct ClearFilterArgs {
+pub struct GetTransactionProofArgs<'a> {
+    pub transaction_hash: Option<&'a  H256>,
 }
-impl<'a> Default for ClearFilterArgs {
+impl<'a> Default for GetTransactionProofArgs<'a> {
     #[inline]
     fn default() -> Self {
-        ClearFilterArgs {
+        GetTransactionProofArgs {
+            transaction_hash: None,
         }
     }
 }
-pub struct ClearFilterBuilder<'a: 'b, 'b> {
+pub struct GetTransactionProofBuilder<'a: 'b, 'b> {
   fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
   start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
 }
-impl<'a: 'b, 'b> ClearFilterBuilder<'a, 'b> {
+impl<'a: 'b, 'b> GetTransactionProofBuilder<'a, 'b> {
   #[inline]
-  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> ClearFilterBuilder<'a, 'b> {
+  pub fn add_transaction_hash(&mut self, transaction_hash: &'b  H256) {
+    self.fbb_.push_slot_always::<&H256>(GetTransactionProof::VT_TRANSACTION_HASH, transaction_hash);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> GetTransactionProofBuilder<'a, 'b> {
     let start = _fbb.start_table();
-    ClearFilterBuilder {
+    GetTransactionProofBuilder {
       fbb_: _fbb,
       start_: start,
     }
   }
   #[inline]
-  pub fn finish(self) -> flatbuffers::WIPOffset<ClearFilter<'a>> {
+  pub fn finish(self) -> flatbuffers::WIPOffset<GetTransactionProof<'a>> {
     let o = self.fbb_.end_table(self.start_);
     flatbuffers::WIPOffset::new(o.value())
   }
 }
 
-pub enum FilteredBlockOffset {}
+pub enum TransactionProofOffset {}
 #[derive(Copy, Clone, Debug, PartialEq)]
 
-pub struct FilteredBlock<'a> {
+pub struct TransactionProof<'a> {
   pub _tab: flatbuffers::Table<'a>,
 }
 
-impl<'a> flatbuffers::Follow<'a> for FilteredBlock<'a> {
-    type Inner = FilteredBlock<'a>;
+impl<'a> flatbuffers::Follow<'a> for TransactionProof<'a> {
+    type Inner = TransactionProof<'a>;
     #[inline]
     fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
         Self {
@@ -2914,84 +4237,247 @@ impl<'a> flatbuffers::Follow<'a> for FilteredBlock<'a> {
     }
 }
 
-impl<'a> FilteredBlock<'a> {
+impl<'a> TransactionProof<'a> {
     #[inline]
     pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
-        FilteredBlock {
+        TransactionProof {
             _tab: table,
         }
     }
     #[allow(unused_mut)]
     pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
         _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
-        args: &'args FilteredBlockArgs<'args>) -> flatbuffers::WIPOffset<FilteredBlock<'bldr>> {
-      let mut builder = FilteredBlockBuilder::new(_fbb);
+        args: &'args TransactionProofArgs<'args>) -> flatbuffers::WIPOffset<TransactionProof<'bldr>> {
+      let mut builder = TransactionProofBuilder::new(_fbb);
       if let Some(x) = args.hashes { builder.add_hashes(x); }
-      if let Some(x) = args.transactions { builder.add_transactions(x); }
+      builder.add_transactions_count(args.transactions_count);
+      builder.add_transaction_index(args.transaction_index);
       if let Some(x) = args.header { builder.add_header(x); }
+      if let Some(x) = args.transaction_hash { builder.add_transaction_hash(x); }
       builder.finish()
     }
 
-    pub const VT_HEADER: flatbuffers::VOffsetT = 4;
-    pub const VT_TRANSACTIONS: flatbuffers::VOffsetT = 6;
-    pub const VT_HASHES: flatbuffers::VOffsetT = 8;
+    pub const VT_TRANSACTION_HASH: flatbuffers::VOffsetT = 4;
+    pub const VT_HEADER: flatbuffers::VOffsetT = 6;
+    pub const VT_TRANSACTION_INDEX: flatbuffers::VOffsetT = 8;
+    pub const VT_TRANSACTIONS_COUNT: flatbuffers::VOffsetT = 10;
+    pub const VT_HASHES: flatbuffers::VOffsetT = 12;
 
+  #[inline]
+  pub fn transaction_hash(&self) -> Option<&'a H256> {
+    self._tab.get::<H256>(TransactionProof::VT_TRANSACTION_HASH, None)
+  }
   #[inline]
   pub fn header(&self) -> Option<Header<'a>> {
-    self._tab.get::<flatbuffers::ForwardsUOffset<Header<'a>>>(FilteredBlock::VT_HEADER, None)
+    self._tab.get::<flatbuffers::ForwardsUOffset<Header<'a>>>(TransactionProof::VT_HEADER, None)
   }
   #[inline]
-  pub fn transactions(&self) -> Option<flatbuffers::Vector<flatbuffers::ForwardsUOffset<IndexTransaction<'a>>>> {
-    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<flatbuffers::ForwardsUOffset<IndexTransaction<'a>>>>>(FilteredBlock::VT_TRANSACTIONS, None)
+  pub fn transaction_index(&self) -> u32 {
+    self._tab.get::<u32>(TransactionProof::VT_TRANSACTION_INDEX, Some(0)).unwrap()
+  }
+  #[inline]
+  pub fn transactions_count(&self) -> u32 {
+    self._tab.get::<u32>(TransactionProof::VT_TRANSACTIONS_COUNT, Some(0)).unwrap()
   }
   #[inline]
   pub fn hashes(&self) -> Option<&'a [H256]> {
-    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(FilteredBlock::VT_HASHES, None).map(|v| v.safe_slice() )
+    self._tab.get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<H256>>>(TransactionProof::VT_HASHES, None).map(|v| v.safe_slice() )
   }
 }
 
-pub struct FilteredBlockArgs<'a> {
+pub struct TransactionProofArgs<'a> {
+    pub transaction_hash: Option<&'a  H256>,
     pub header: Option<flatbuffers::WIPOffset<Header<'a >>>,
-    pub transactions: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , flatbuffers::ForwardsUOffset<IndexTransaction<'a >>>>>,
+    pub transaction_index: u32,
+    pub transactions_count: u32,
     pub hashes: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a , H256>>>,
 }
-impl<'a> Default for FilteredBlockArgs<'a> {
+impl<'a> Default for TransactionProofArgs<'a> {
     #[inline]
     fn default() -> Self {
-        FilteredBlockArgs {
+        TransactionProofArgs {
+            transaction_hash: None,
             header: None,
-            transactions: None,
+            transaction_index: 0,
+            transactions_count: 0,
             hashes: None,
         }
     }
 }
-pub struct FilteredBlockBuilder<'a: 'b, 'b> {
+pub struct TransactionProofBuilder<'a: 'b, 'b> {
   fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
   start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
 }
-impl<'a: 'b, 'b> FilteredBlockBuilder<'a, 'b> {
+impl<'a: 'b, 'b> TransactionProofBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_transaction_hash(&mut self, transaction_hash: &'b  H256) {
+    self.fbb_.push_slot_always::<&H256>(TransactionProof::VT_TRANSACTION_HASH, transaction_hash);
+  }
   #[inline]
   pub fn add_header(&mut self, header: flatbuffers::WIPOffset<Header<'b >>) {
-    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Header>>(FilteredBlock::VT_HEADER, header);
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Header>>(TransactionProof::VT_HEADER, header);
   }
   #[inline]
-  pub fn add_transactions(&mut self, transactions: flatbuffers::WIPOffset<flatbuffers::Vector<'b , flatbuffers::ForwardsUOffset<IndexTransaction<'b >>>>) {
-    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(FilteredBlock::VT_TRANSACTIONS, transactions);
+  pub fn add_transaction_index(&mut self, transaction_index: u32) {
+    self.fbb_.push_slot::<u32>(TransactionProof::VT_TRANSACTION_INDEX, transaction_index, 0);
+  }
+  #[inline]
+  pub fn add_transactions_count(&mut self, transactions_count: u32) {
+    self.fbb_.push_slot::<u32>(TransactionProof::VT_TRANSACTIONS_COUNT, transactions_count, 0);
   }
   #[inline]
   pub fn add_hashes(&mut self, hashes: flatbuffers::WIPOffset<flatbuffers::Vector<'b , H256>>) {
-    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(FilteredBlock::VT_HASHES, hashes);
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(TransactionProof::VT_HASHES, hashes);
   }
   #[inline]
-  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> FilteredBlockBuilder<'a, 'b> {
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> TransactionProofBuilder<'a, 'b> {
     let start = _fbb.start_table();
-    FilteredBlockBuilder {
+    TransactionProofBuilder {
       fbb_: _fbb,
       start_: start,
     }
   }
   #[inline]
-  pub fn finish(self) -> flatbuffers::WIPOffset<FilteredBlock<'a>> {
+  pub fn finish(self) -> flatbuffers::WIPOffset<TransactionProof<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum GetTipOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct GetTip<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for GetTip<'a> {
+    type Inner = GetTip<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> GetTip<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        GetTip {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        _args: &'args GetTipArgs) -> flatbuffers::WIPOffset<GetTip<'bldr>> {
+      let mut builder = GetTipBuilder::new(_fbb);
+      builder.finish()
+    }
+
+}
+
+pub struct GetTipArgs {
+}
+impl<'a> Default for GetTipArgs {
+    #[inline]
+    fn default() -> Self {
+        GetTipArgs {
+        }
+    }
+}
+pub struct GetTipBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> GetTipBuilder<'a, 'b> {
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> GetTipBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    GetTipBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<GetTip<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum TipOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct Tip<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for Tip<'a> {
+    type Inner = Tip<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> Tip<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        Tip {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args TipArgs<'args>) -> flatbuffers::WIPOffset<Tip<'bldr>> {
+      let mut builder = TipBuilder::new(_fbb);
+      if let Some(x) = args.header { builder.add_header(x); }
+      builder.finish()
+    }
+
+    pub const VT_HEADER: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn header(&self) -> Option<Header<'a>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<Header<'a>>>(Tip::VT_HEADER, None)
+  }
+}
+
+pub struct TipArgs<'a> {
+    pub header: Option<flatbuffers::WIPOffset<Header<'a >>>,
+}
+impl<'a> Default for TipArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        TipArgs {
+            header: None,
+        }
+    }
+}
+pub struct TipBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> TipBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_header(&mut self, header: flatbuffers::WIPOffset<Header<'b >>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Header>>(Tip::VT_HEADER, header);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> TipBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    TipBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<Tip<'a>> {
     let o = self.fbb_.end_table(self.start_);
     flatbuffers::WIPOffset::new(o.value())
   }
@@ -2,15 +2,12 @@ use ckb_chain::chain::ChainController;
 use ckb_core::block::Block;
 use ckb_miner::{AgentController, BlockTemplate};
 use ckb_network::NetworkService;
-use ckb_protocol::RelayMessage;
 use ckb_shared::{index::ChainIndex, shared::Shared};
-use ckb_sync::RELAY_PROTOCOL_ID;
-use flatbuffers::FlatBufferBuilder;
+use ckb_sync::{Relayer, RELAY_PROTOCOL_ID};
 use jsonrpc_core::{Error, Result};
 use jsonrpc_macros::build_rpc_trait;
 use log::debug;
 use numext_fixed_hash::H256;
-use std::collections::HashSet;
 use std::sync::Arc;
 
 build_rpc_trait! {
@@ -25,11 +22,12 @@ build_rpc_trait! {
     }
 }
 
-pub(crate) struct MinerRpcImpl<CI> {
+pub(crate) struct MinerRpcImpl<CI: ChainIndex> {
     pub network: Arc<NetworkService>,
     pub shared: Shared<CI>,
     pub agent: AgentController,
     pub chain: ChainController,
+    pub relayer: Arc<Relayer<CI>>,
 }
 
 impl<CI: ChainIndex + 'static> MinerRpc for MinerRpcImpl<CI> {
@@ -46,17 +44,14 @@ impl<CI: ChainIndex + 'static> MinerRpc for MinerRpcImpl<CI> {
 
     fn submit_block(&self, block: Block) -> Result<H256> {
         let block = Arc::new(block);
-        let ret = self.chain.process_block(Arc::clone(&block));
+        // the relayer announces the block, and ignores it when peers echo it back
+        let ret = self
+            .network
+            .with_protocol_context(RELAY_PROTOCOL_ID, |nc| {
+                self.relayer.submit_mined_block(nc, &block)
+            })
+            .unwrap_or_else(|| self.chain.process_block(Arc::clone(&block)));
         if ret.is_ok() {
-            // announce new block
-            self.network.with_protocol_context(RELAY_PROTOCOL_ID, |nc| {
-                let fbb = &mut FlatBufferBuilder::new();
-                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
-                fbb.finish(message, None);
-                for peer in nc.connected_peers() {
-                    let _ = nc.send(peer, fbb.finished_data().to_vec());
-                }
-            });
             Ok(block.header().hash().clone())
         } else {
             debug!(target: "rpc", "submit_block process_block {:?}", ret);
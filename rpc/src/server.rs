@@ -10,6 +10,7 @@ use ckb_pool::txs_pool::TransactionPoolController;
 use ckb_pow::Clicker;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::Shared;
+use ckb_sync::Relayer;
 use jsonrpc_core::IoHandler;
 use jsonrpc_http_server::ServerBuilder;
 use jsonrpc_server_utils::cors::AccessControlAllowOrigin;
@@ -29,6 +30,7 @@ impl RpcServer {
         tx_pool: TransactionPoolController,
         chain: ChainController,
         agent: AgentController,
+        relayer: Arc<Relayer<CI>>,
         test_engine: Option<Arc<Clicker>>,
     ) where
         CI: ChainIndex,
@@ -60,6 +62,7 @@ impl RpcServer {
                     shared,
                     agent,
                     chain,
+                    relayer,
                     network: Arc::clone(&network),
                 }
                 .to_delegate(),
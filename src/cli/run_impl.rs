@@ -17,7 +17,7 @@ use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
 use ckb_shared::store::ChainKVStore;
 use ckb_sync::{Relayer, Synchronizer, RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID};
 use crypto::secp::Generator;
-use log::info;
+use log::{info, warn};
 use numext_fixed_hash::H256;
 use serde_json;
 use std::sync::Arc;
@@ -39,6 +39,7 @@ pub fn run(setup: Setup) {
 
     let chain_service = ChainBuilder::new(shared.clone())
         .notify(notify.clone())
+        .max_reorg_depth(setup.configs.ckb.max_reorg_depth)
         .build();
     let _handle = chain_service.start(Some("ChainService"), chain_receivers);
 
@@ -51,18 +52,36 @@ pub fn run(setup: Setup) {
     let miner_agent = Agent::new(shared.clone(), tx_pool_controller.clone());
     let _handle = miner_agent.start(Some("MinerAgent"), miner_agent_receivers, &notify);
 
-    let synchronizer = Arc::new(Synchronizer::new(
-        chain_controller.clone(),
-        shared.clone(),
-        setup.configs.sync,
-    ));
+    let synchronizer = Arc::new(
+        Synchronizer::new(chain_controller.clone(), shared.clone(), setup.configs.sync)
+            .notify(notify.clone())
+            .block_server(Some("BlockServer")),
+    );
+
+    let relayer = Arc::new(
+        Relayer::new(
+            chain_controller.clone(),
+            shared.clone(),
+            tx_pool_controller.clone(),
+            synchronizer.peers(),
+            Arc::clone(&synchronizer.config),
+        )
+        .notify(notify.clone())
+        .relay_switch(synchronizer.relay_switch()),
+    );
 
-    let relayer = Arc::new(Relayer::new(
-        chain_controller.clone(),
-        shared.clone(),
-        tx_pool_controller.clone(),
-        synchronizer.peers(),
-    ));
+    let pending_compact_blocks_path = if synchronizer.config.persist_pending_compact_blocks {
+        let path = setup.dirs.join("relay").join("pending_compact_blocks");
+        if path.exists() {
+            match relayer.load_pending_compact_blocks(&path) {
+                Ok(count) => info!(target: "main", "restored {} pending compact blocks", count),
+                Err(err) => warn!(target: "main", "restore pending compact blocks error {}", err),
+            }
+        }
+        Some(path)
+    } else {
+        None
+    };
 
     let network_config = NetworkConfig::from(setup.configs.network);
     let protocol_base_name = "ckb";
@@ -75,7 +94,7 @@ pub fn run(setup: Setup) {
         ),
         CKBProtocol::new(
             protocol_base_name.to_string(),
-            relayer as Arc<_>,
+            Arc::clone(&relayer) as Arc<_>,
             RELAY_PROTOCOL_ID,
             &[1][..],
         ),
@@ -97,11 +116,18 @@ pub fn run(setup: Setup) {
         tx_pool_controller,
         chain_controller,
         miner_agent_controller,
+        Arc::clone(&relayer),
     );
 
     wait_for_exit();
 
     info!(target: "main", "Finishing work, please wait...");
+
+    if let Some(path) = pending_compact_blocks_path {
+        if let Err(err) = relayer.save_pending_compact_blocks(&path) {
+            warn!(target: "main", "save pending compact blocks error {}", err);
+        }
+    }
 }
 
 fn setup_rpc<CI: ChainIndex + 'static>(
@@ -112,6 +138,7 @@ fn setup_rpc<CI: ChainIndex + 'static>(
     tx_pool: TransactionPoolController,
     chain: ChainController,
     agent: AgentController,
+    relayer: Arc<Relayer<CI>>,
 ) {
     use ckb_pow::Clicker;
 
@@ -123,7 +150,7 @@ fn setup_rpc<CI: ChainIndex + 'static>(
 
     let _ = thread::Builder::new().name("rpc".to_string()).spawn({
         move || {
-            server.start(network, shared, tx_pool, chain, agent, pow);
+            server.start(network, shared, tx_pool, chain, agent, relayer, pow);
         }
     });
 }
@@ -1,4 +1,5 @@
 use ckb_chain_spec::ChainSpec;
+use ckb_core::header::BlockNumber;
 use ckb_miner::Config as MinerConfig;
 use ckb_network::Config as NetworkConfig;
 use ckb_pool::txs_pool::PoolConfig;
@@ -24,6 +25,8 @@ pub struct Setup {
 #[derive(Clone, Debug, Deserialize)]
 pub struct CKB {
     pub chain: PathBuf,
+    // Blocks a reorg may roll back at most, 0 for no limit
+    pub max_reorg_depth: BlockNumber,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -148,7 +151,7 @@ pub mod test {
     fn write_file<P: AsRef<Path>>(file: P, content: &str) {
         let mut file = File::create(file).expect("test dir clean");
         file.write_all(content.as_bytes())
-            .expect("write test content");;
+            .expect("write test content");
     }
 
     fn test_chain_spec() -> &'static str {
@@ -1,14 +1,125 @@
+use crate::{
+    BLOCK_TRANSACTIONS_TIMEOUT, COMPETING_TIPS_MARGIN, INITIAL_HEADERS_RESPONSE_TIMEOUT,
+    MAX_BLOCKS_IN_TRANSIT_PER_PEER, MAX_BLOCKS_PER_GETDATA, MAX_BLOCK_DOWNLOAD_PEERS,
+    MAX_BLOCK_REQUEST_RETRIES, MAX_BLOCK_TRANSACTIONS_RESPONSE_SIZE, MAX_FUTURE_BLOCK_TIME,
+    MAX_HEADER_LOOKAHEAD, MAX_INBOUND_PER_OUTBOUND, MAX_ORPHAN_CHAIN_DEPTH,
+    MAX_PENDING_COMPACT_BLOCKS, MAX_PENDING_COMPACT_BLOCKS_PER_PEER, MAX_PENDING_REQUESTS_PER_PEER,
+    MIN_PEERS_TO_START_DOWNLOAD, PREFILL_LARGE_TRANSACTION_SIZE, RECONNECT_GRACE_PERIOD,
+    TARGETED_RECONSTRUCT_POOL_RATIO,
+};
 use serde_derive::Deserialize;
 
+// Transactions sent in full in the compact blocks we announce, besides the cellbase which
+// is never in the pool of a peer and always is
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+pub enum PrefillStrategy {
+    Cellbase,
+    // Transactions we relayed recently, they may not have reached every peer yet
+    RecentlyBroadcast,
+    // Transactions of at least prefill_large_transaction_size bytes, which pools are
+    // less likely to hold
+    LargeTransactions,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub orphan_block_limit: usize,
+    // Orphans chained off the same missing ancestor beyond this depth are dropped
+    pub max_orphan_chain_depth: usize,
+    // Blocks waiting to be processed are taken by ascending height rather than in the
+    // order they arrived, so parents go first and fewer blocks end up orphans
+    pub process_blocks_by_height: bool,
+    // Headers with a timestamp further than this in the future are rejected, in ms
+    pub max_future_block_time: u64,
+    // BlockTransactions requests for more bytes than this are answered with no transactions
+    pub max_block_transactions_response_size: usize,
+    // Compact blocks waiting for their missing transactions, the oldest ones are evicted
+    pub max_pending_compact_blocks: usize,
+    pub max_pending_compact_blocks_per_peer: usize,
+    // Missing transactions of a pending compact block are asked from another peer which
+    // announced it when not received within this, in ms
+    pub block_transactions_timeout: u64,
+    // Compact blocks still waiting for transactions are written to the data dir on
+    // shutdown, after a restart the missing transactions are asked again
+    pub persist_pending_compact_blocks: bool,
+    // A peer with this many pending requests is ignored until some of them are done
+    pub max_pending_requests_per_peer: usize,
+    // Upper bound of the block hashes in a single getblocks request
+    pub max_blocks_per_getdata: usize,
+    // Blocks asked from a single peer and not received yet, no more are asked from it
+    // until some arrive or time out
+    pub max_blocks_in_transit_per_peer: usize,
+    // Block download waits until this many peers announced their best header
+    pub min_peers_to_start_download: usize,
+    // Peers asked for blocks in a single tick, the syncing peers take turns beyond it
+    pub max_block_download_peers: usize,
+    // When more peers sync than are asked in a tick, a peer which delivered more blocks
+    // lately takes the turn of the slowest one due
+    pub evict_slow_download_peers: bool,
+    // A getheaders starting from a block we know off our main chain is answered from
+    // where it forked, the main chain header at the height of the peer's tip telling it
+    // the tip is stale, instead of being left unanswered as an unknown starting point
+    pub notify_stale_tips: bool,
+    // A peer reconnecting within this period keeps its best known header and the modes
+    // it negotiated, in ms
+    pub reconnect_grace_period: u64,
+    // How far ahead of our tip headers are kept before their blocks are downloaded
+    pub max_header_lookahead: u64,
+    // Peer ids in base58 of the peers we never evict, they are always protected
+    pub trusted_peers: Vec<String>,
+    // Inbound peers tolerated per outbound one, beyond it misbehaving or idle inbound
+    // peers are evicted to make room for outbound connections
+    pub max_inbound_per_outbound: usize,
+    // Known branches are reported as competing tips when their total difficulty is
+    // within this many blocks, at the difficulty of our tip, of our own
+    pub competing_tips_margin: u64,
+    // A peer not answering the first getheaders we send within this is disconnected, in ms
+    pub initial_headers_response_timeout: u64,
+    pub prefill_strategy: PrefillStrategy,
+    pub prefill_large_transaction_size: usize,
+    // Compact blocks with fewer short ids than the pool size divided by this are rebuilt
+    // from the matching pool transactions only, 0 always copies the whole pool
+    pub targeted_reconstruct_pool_ratio: usize,
+    // Requests of a block timing out across peers before we stop asking for it a while
+    pub max_block_request_retries: u32,
+    // Peer ids in base58 of the peers relayed every transaction, whatever their filter
+    pub relay_whitelist: Vec<String>,
+    // Peers are asked to announce new blocks by hash rather than as compact blocks, the
+    // ones we don't have are then fetched as compact blocks
+    pub inventory_announcements: bool,
 }
 
 impl Config {
     pub fn default() -> Self {
         Config {
             orphan_block_limit: 1024,
+            max_orphan_chain_depth: MAX_ORPHAN_CHAIN_DEPTH,
+            process_blocks_by_height: true,
+            max_future_block_time: MAX_FUTURE_BLOCK_TIME,
+            max_block_transactions_response_size: MAX_BLOCK_TRANSACTIONS_RESPONSE_SIZE,
+            max_pending_compact_blocks: MAX_PENDING_COMPACT_BLOCKS,
+            max_pending_compact_blocks_per_peer: MAX_PENDING_COMPACT_BLOCKS_PER_PEER,
+            block_transactions_timeout: BLOCK_TRANSACTIONS_TIMEOUT,
+            persist_pending_compact_blocks: false,
+            max_pending_requests_per_peer: MAX_PENDING_REQUESTS_PER_PEER,
+            max_blocks_per_getdata: MAX_BLOCKS_PER_GETDATA,
+            max_blocks_in_transit_per_peer: MAX_BLOCKS_IN_TRANSIT_PER_PEER,
+            min_peers_to_start_download: MIN_PEERS_TO_START_DOWNLOAD,
+            max_block_download_peers: MAX_BLOCK_DOWNLOAD_PEERS,
+            evict_slow_download_peers: true,
+            notify_stale_tips: false,
+            reconnect_grace_period: RECONNECT_GRACE_PERIOD,
+            max_header_lookahead: MAX_HEADER_LOOKAHEAD,
+            trusted_peers: Vec::new(),
+            max_inbound_per_outbound: MAX_INBOUND_PER_OUTBOUND,
+            competing_tips_margin: COMPETING_TIPS_MARGIN,
+            initial_headers_response_timeout: INITIAL_HEADERS_RESPONSE_TIMEOUT,
+            prefill_strategy: PrefillStrategy::Cellbase,
+            prefill_large_transaction_size: PREFILL_LARGE_TRANSACTION_SIZE,
+            targeted_reconstruct_pool_ratio: TARGETED_RECONSTRUCT_POOL_RATIO,
+            max_block_request_retries: MAX_BLOCK_REQUEST_RETRIES,
+            relay_whitelist: Vec::new(),
+            inventory_announcements: false,
         }
     }
 }
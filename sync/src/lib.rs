@@ -4,6 +4,7 @@
 //! https://github.com/nervosnetwork/rfcs/tree/master/rfcs/0000-block-sync-protocol
 
 mod config;
+mod log_context;
 mod relayer;
 mod synchronizer;
 mod types;
@@ -11,13 +12,15 @@ mod types;
 #[cfg(test)]
 mod tests;
 
-pub use crate::config::Config;
+pub use crate::config::{Config, PrefillStrategy};
 pub use crate::relayer::Relayer;
-pub use crate::synchronizer::Synchronizer;
+pub use crate::synchronizer::{ReprocessError, RevalidateError, Synchronizer};
 
 use ckb_network::ProtocolId;
 
 pub const MAX_HEADERS_LEN: usize = 2_000;
+// Headers responses shorter than this are sent uncompressed, there is little to save
+pub const MIN_COMPRESSED_HEADERS_LEN: usize = 16;
 pub const MAX_INVENTORY_LEN: usize = 50_000;
 pub const MAX_SCHEDULED_LEN: usize = 4 * 1024;
 pub const MAX_BLOCKS_TO_ANNOUNCE: usize = 8;
@@ -26,14 +29,43 @@ pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
 pub const MAX_TIP_AGE: u64 = 60 * 60 * 1000;
 pub const STALE_RELAY_AGE_LIMIT: u64 = 30 * 24 * 60 * 60 * 1000;
 pub const BLOCK_DOWNLOAD_WINDOW: u64 = 1024;
+// Blocks downloaded out of order are orphans at most the download window deep
+pub const MAX_ORPHAN_CHAIN_DEPTH: usize = BLOCK_DOWNLOAD_WINDOW as usize;
+// Headers further than this ahead of our tip are deferred until the blocks catch up
+pub const MAX_HEADER_LOOKAHEAD: u64 = 100_000;
 pub const PER_FETCH_BLOCK_LIMIT: usize = 128;
+// Blocks asked in a single getblocks, the rest of the window is fetched on later ticks
+pub const MAX_BLOCKS_PER_GETDATA: usize = 16;
+// Peers that must have announced their best header before blocks are downloaded, unless
+// we have been waiting for them longer than the timeout
+pub const MIN_PEERS_TO_START_DOWNLOAD: usize = 1;
+// Peers we download blocks from in a single tick, the others take their turn later
+pub const MAX_BLOCK_DOWNLOAD_PEERS: usize = 8;
+pub const START_DOWNLOAD_TIMEOUT: u64 = 60 * 1000; // 1 minute
+
+// A peer reconnecting within this period gets back what we knew about it
+pub const RECONNECT_GRACE_PERIOD: u64 = 60 * 1000; // 1 minute
+
+// Branches whose tip is within this many blocks of work of our tip are reported as forks
+pub const COMPETING_TIPS_MARGIN: u64 = 6;
+
 pub const SYNC_PROTOCOL_ID: ProtocolId = *b"syn";
+// Latest getheaders version we understand, requests with a newer version are ignored
+pub const GET_HEADERS_VERSION: u32 = 0;
 pub const RELAY_PROTOCOL_ID: ProtocolId = *b"rel";
+// Latest compact block version we understand, a peer is sent version 0 until it tells
+// us the latest one it understands
+pub const COMPACT_BLOCK_VERSION: u32 = 1;
 
 //  Timeout = base + per_header * (expected number of headers)
 pub const HEADERS_DOWNLOAD_TIMEOUT_BASE: u64 = 15 * 60 * 1000; // 15 minutes
 pub const HEADERS_DOWNLOAD_TIMEOUT_PER_HEADER: u64 = 1; //1ms/header
+
+// A peer leaving the first getheaders we send unanswered this long is dropped
+pub const INITIAL_HEADERS_RESPONSE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
+
 pub const POW_SPACE: u64 = 10_000; //10s
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 15 * 1000; // 15s
 
 // Protect at least this many outbound peers from disconnection due to slow
 // behind headers chain.
@@ -41,7 +73,147 @@ pub const MAX_OUTBOUND_PEERS_TO_PROTECT_FROM_DISCONNECT: usize = 4;
 pub const CHAIN_SYNC_TIMEOUT: u64 = 20 * 60 * 1000; // 20 minutes
 pub const EVICTION_HEADERS_RESPONSE_TIME: u64 = 120 * 1000; // 2 minutes
 
-//The maximum number of entries in a locator
+//The maximum number of entries in a locator, only the most recent ones of a longer
+// locator are looked up
 pub const MAX_LOCATOR_SIZE: usize = 101;
+// Locators beyond this size are no mistake, the peer is scored
+pub const MAX_TOLERATED_LOCATOR_SIZE: usize = 2 * MAX_LOCATOR_SIZE;
+// Consecutive hashes at the head of a locator before the steps start doubling
+pub const LOCATOR_DENSE_PREFIX: usize = 10;
+// Empty headers from a peer ahead of us are retried with a locator twice as dense
+// each time, up to this many times before the peer is scored
+pub const MAX_EMPTY_HEADERS_RETRIES: u32 = 3;
 
 pub const BLOCK_DOWNLOAD_TIMEOUT: u64 = 30 * 1000; // 30s
+
+// Block requests to a peer timing out in a row after which it no longer counts as a
+// source of blocks
+pub const MAX_BLOCK_DOWNLOAD_TIMEOUTS: u32 = 3;
+
+// Requests of a block timing out across peers after which we stop asking for it, until
+// the retry delay elapsed
+pub const MAX_BLOCK_REQUEST_RETRIES: u32 = 5;
+pub const BLOCK_REQUEST_RETRY_DELAY: u64 = 10 * 60 * 1000; // 10 minutes
+
+// Every peer is pinged this often, one leaving a ping unanswered for the timeout is evicted
+pub const PING_INTERVAL: u64 = 2 * 60 * 1000; // 2 minutes
+pub const PING_TIMEOUT: u64 = 20 * 60 * 1000; // 20 minutes
+
+// A peer which sent us no headers, blocks or transactions we accepted for this long is
+// evicted to free its slot for a productive one
+pub const USELESS_PEER_TIMEOUT: u64 = 60 * 60 * 1000; // 1 hour
+
+// Inbound peers tolerated per outbound one, beyond it the misbehaving or idle inbound
+// peers are evicted, together they could be eclipsing us
+pub const MAX_INBOUND_PER_OUTBOUND: usize = 8;
+// An inbound peer which sent us nothing useful for this long, 10 minutes, counts as idle
+pub const IDLE_INBOUND_PEER_TIMEOUT: u64 = 10 * 60 * 1000;
+// An inbound peer scored at least this counts as misbehaving
+pub const MISBEHAVING_INBOUND_PEER_SCORE: u32 = 20;
+
+// Minimal interval between two getheaders responses to the same peer, requests
+// in between are dropped and the peer is scored once it keeps spamming
+pub const GET_HEADERS_MIN_INTERVAL: u64 = 100; // 100ms
+pub const MAX_THROTTLED_GET_HEADERS: u32 = 10;
+
+// Messages without a recognized payload tolerated from a peer before it is scored
+pub const MAX_UNKNOWN_PAYLOADS: u32 = 10;
+
+// Invalid blocks a peer may serve before it is disconnected, each one scores more than
+// the previous
+pub const MAX_INVALID_BLOCKS: u32 = 3;
+
+// Hashes of the blocks the chain rejected, headers announcing one of them are turned
+// away without being evaluated again
+pub const MAX_KNOWN_INVALID_BLOCKS: usize = 1024;
+
+// GetBlocks requests waiting for the block server, more are dropped until it catches up
+pub const MAX_QUEUED_GET_BLOCKS: usize = 256;
+
+// Bytes sent to each peer are accounted over a sliding window, a peer using more than
+// the average of the active peers plus this allowance isn't served until the others catch up
+pub const BANDWIDTH_WINDOW: u64 = 60 * 1000; // 1 minute
+pub const MAX_BANDWIDTH_ABOVE_AVERAGE: u64 = 8 * 1024 * 1024; // 8MB
+
+// Maximum number of peers we start headers sync with per timer tick
+pub const MAX_SYNC_PEERS_PER_TICK: usize = 4;
+
+// Upper bounds of a transaction filter loaded by a peer, same as BIP37
+pub const MAX_FILTER_SIZE: usize = 36_000;
+pub const MAX_FILTER_NUM_HASHES: usize = 50;
+
+// Upper bound of the transactions bytes served in a single BlockTransactions response
+pub const MAX_BLOCK_TRANSACTIONS_RESPONSE_SIZE: usize = 4 * 1024 * 1024; // 4MB
+
+// Compact blocks kept waiting for their missing transactions, in total and per peer
+pub const MAX_PENDING_COMPACT_BLOCKS: usize = 64;
+pub const MAX_PENDING_COMPACT_BLOCKS_PER_PEER: usize = 8;
+
+// Pending state a single peer may make us keep, its compact blocks waiting for
+// transactions plus the proposals it asked we don't have yet
+pub const MAX_PENDING_REQUESTS_PER_PEER: usize = 256;
+
+// Nonces of the last compact blocks of each peer, a different block reusing one of them
+// is rejected
+pub const MAX_RECENT_COMPACT_BLOCK_NONCES: usize = 16;
+
+// Consecutive send failures after which a relay peer is reported and skipped
+pub const MAX_RELAY_SEND_FAILURES: u32 = 3;
+
+// Hashes of the blocks recently mined or relayed by ourselves, echoes of them are ignored
+pub const MAX_RECEIVED_BLOCKS: usize = 1024;
+
+// Hashes of the transactions committed in the blocks we recently accepted
+pub const MAX_RECENTLY_CONFIRMED_TXS: usize = 16 * 1024;
+
+// Hashes of the transactions we recently relayed, see PrefillStrategy::RecentlyBroadcast
+pub const MAX_RECENTLY_RELAYED_TXS: usize = 1024;
+
+// A compact block listing fewer short ids than our pool size divided by this takes only
+// the matching transactions from the pool rather than copying all of them
+pub const TARGETED_RECONSTRUCT_POOL_RATIO: usize = 8;
+
+// Default size from which PrefillStrategy::LargeTransactions prefills a transaction
+pub const PREFILL_LARGE_TRANSACTION_SIZE: usize = 16 * 1024; // 16KB
+
+// Transactions accepted since the last flush are announced to each peer in a single
+// inventory at this interval
+pub const TX_ANNOUNCEMENT_INTERVAL: u64 = 500; // 500ms
+
+// Missing transactions of a compact block not received within this are asked from
+// another peer which announced the block
+pub const BLOCK_TRANSACTIONS_TIMEOUT: u64 = 2000; // 2s
+
+// The pending compact blocks are checked for timed out requests at this interval
+pub const BLOCK_TRANSACTIONS_TIMEOUT_CHECK_INTERVAL: u64 = 500; // 500ms
+
+// Announced transactions asked from a peer aren't asked again from another one until
+// the request times out
+pub const TX_REQUEST_TIMEOUT: u64 = 30 * 1000; // 30s
+pub const MAX_INFLIGHT_TX_REQUESTS: usize = 16 * 1024;
+
+// Transaction hashes in a single inventory, larger announcements are split. Peers
+// sending more, or asking for more at once, are scored
+pub const MAX_TRANSACTION_INVENTORY_LEN: usize = 1024;
+pub const MAX_GET_TRANSACTIONS: usize = MAX_TRANSACTION_INVENTORY_LEN;
+
+// Ancestors still in our pool announced ahead of a relayed transaction, peers missing
+// one of them could not accept it
+pub const MAX_PACKAGE_ANCESTORS: usize = 25;
+
+// Peers exchange a sketch of their pools at this interval, the transactions missed from
+// the announcements are then fetched without sending the whole inventory
+pub const MEMPOOL_RECONCILIATION_INTERVAL: u64 = 60 * 1000; // 60s
+
+// Cells of the sketch we send, decoding up to about two thirds as many differences
+pub const MEMPOOL_SKETCH_CELLS: usize = 120;
+pub const MAX_MEMPOOL_SKETCH_CELLS: usize = 3 * 1024;
+
+// A sketch of the peer too far from our pool to decode is answered with at most this
+// many of our hashes, once per interval. Peers whose sketches keep failing are scored
+pub const MAX_SKETCH_FALLBACK_HASHES: usize = 4 * MAX_TRANSACTION_INVENTORY_LEN;
+pub const SKETCH_FALLBACK_INTERVAL: u64 = 10 * 60 * 1000; // 10min
+pub const MAX_SKETCH_DECODE_FAILURES: u32 = 5;
+
+// Blocks reprocessed between two progress notifications
+pub const REPROCESS_PROGRESS_INTERVAL: u64 = 1000;
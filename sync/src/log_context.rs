@@ -0,0 +1,60 @@
+use ckb_network::PeerIndex;
+use numext_fixed_hash::H256;
+use std::fmt;
+
+// What a sync or relay log line is about, the peer, the message being handled and the
+// block or transaction when there is one. Displayed as `key=value` fields leading the
+// line, so the lines of a peer or an item can be grepped and correlated
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogContext {
+    peer: PeerIndex,
+    message: &'static str,
+    hash: Option<H256>,
+}
+
+impl LogContext {
+    pub fn new(peer: PeerIndex, message: &'static str) -> Self {
+        LogContext {
+            peer,
+            message,
+            hash: None,
+        }
+    }
+
+    pub fn with_hash(&self, hash: &H256) -> Self {
+        LogContext {
+            hash: Some(hash.clone()),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer={} message={}", self.peer, self.message)?;
+        if let Some(ref hash) = self.hash {
+            write!(f, " hash={:x}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_protocol::{enum_name_relay_payload, RelayPayload};
+
+    #[test]
+    fn test_log_context_fields() {
+        let context = LogContext::new(3, enum_name_relay_payload(RelayPayload::CompactBlock));
+        assert_eq!(context.to_string(), "peer=3 message=CompactBlock");
+
+        let hash = H256::from_trimmed_hex_str("ab").unwrap();
+        assert_eq!(
+            context.with_hash(&hash).to_string(),
+            format!("peer=3 message=CompactBlock hash={:x}", hash)
+        );
+        // the context it derives from is left untouched
+        assert_eq!(context.to_string(), "peer=3 message=CompactBlock");
+    }
+}
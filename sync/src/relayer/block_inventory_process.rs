@@ -0,0 +1,76 @@
+use crate::log_context::LogContext;
+use crate::relayer::Relayer;
+use crate::{MAX_BLOCKS_TO_ANNOUNCE, SYNC_PROTOCOL_ID};
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{enum_name_relay_payload, BlockInventory, RelayPayload, SyncMessage};
+use ckb_shared::index::ChainIndex;
+use flatbuffers::FlatBufferBuilder;
+use log::debug;
+use numext_fixed_hash::H256;
+
+pub struct BlockInventoryProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a BlockInventory<'a>,
+    relayer: &'a Relayer<CI>,
+    peer: PeerIndex,
+    log_context: LogContext,
+    nc: &'a CKBProtocolContext,
+}
+
+impl<'a, CI> BlockInventoryProcess<'a, CI>
+where
+    CI: ChainIndex + 'static,
+{
+    pub fn new(
+        message: &'a BlockInventory,
+        relayer: &'a Relayer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        BlockInventoryProcess {
+            message,
+            relayer,
+            peer,
+            nc,
+            log_context: LogContext::new(
+                peer,
+                enum_name_relay_payload(RelayPayload::BlockInventory),
+            ),
+        }
+    }
+
+    // Unknown blocks are asked as compact blocks over the sync protocol, the answer comes
+    // back as a relay compact block, which also verifies the header we haven't seen yet
+    pub fn execute(self) {
+        let block_hashes = self.message.block_hashes().unwrap();
+        // we announce the blocks one at a time, a long inventory only makes us look up
+        // the hashes
+        if block_hashes.len() > MAX_BLOCKS_TO_ANNOUNCE {
+            debug!(target: "relay", "{} rejected, {} blocks announced at once", self.log_context, block_hashes.len());
+            self.relayer.peers.misbehavior(self.peer, 10);
+            return;
+        }
+        let unknown = {
+            let pending_compact_blocks = self.relayer.state.pending_compact_blocks.read();
+            block_hashes
+                .iter()
+                .map(Into::into)
+                .filter(|hash: &H256| {
+                    !pending_compact_blocks.contains_key(hash)
+                        && !self.relayer.is_received(hash)
+                        && self.relayer.get_block(hash).is_none()
+                })
+                .collect::<Vec<_>>()
+        };
+        if unknown.is_empty() {
+            return;
+        }
+
+        debug!(target: "relay", "{} request {} announced blocks", self.log_context, unknown.len());
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_compact_blocks(fbb, &unknown);
+        fbb.finish(message, None);
+        let _ = self
+            .nc
+            .send_protocol(self.peer, SYNC_PROTOCOL_ID, fbb.finished_data().to_vec());
+    }
+}
@@ -33,21 +33,30 @@ where
 
     pub fn execute(self) {
         let hash = self.message.hash().unwrap().into();
-        if let Some(compact_block) = self
+        if let Some((compact_block, block_transactions)) = self
             .relayer
             .state
             .pending_compact_blocks
             .write()
             .remove(&hash)
+            .map(|pending| (pending.compact_block, pending.block_transactions))
         {
             let transactions: Vec<Transaction> =
                 FlatbuffersVectorIterator::new(self.message.transactions().unwrap())
                     .map(Into::into)
                     .collect();
 
-            if let (Some(block), _) = self.relayer.reconstruct_block(&compact_block, transactions) {
+            if let (Some(block), _) = self.relayer.reconstruct_pending_block(
+                &compact_block,
+                block_transactions,
+                transactions,
+            ) {
                 self.relayer
                     .accept_block(self.nc, self.peer, &Arc::new(block));
+            } else {
+                // Still missing transactions, or the block mismatches its header
+                self.relayer
+                    .request_full_block(self.nc, self.peer, &compact_block.header);
             }
         }
     }
@@ -1,9 +1,18 @@
 use ckb_core::header::Header;
-use ckb_core::transaction::{IndexTransaction, ProposalShortId};
+use ckb_core::transaction::{IndexTransaction, ProposalShortId, Transaction};
 use ckb_core::uncle::UncleBlock;
-use ckb_protocol::{self, FlatbuffersVectorIterator};
+use ckb_network::PeerIndex;
+use ckb_protocol::{
+    self, short_transaction_id, short_transaction_id_keys, FlatbuffersVectorIterator,
+};
+use numext_fixed_hash::H256;
+use serde_derive::{Deserialize, Serialize};
+use std::mem;
 
 pub type ShortTransactionID = [u8; 6];
+// The transaction placed into a slot of a block being reconstructed and whether it came
+// from our pool, None while it is missing
+pub type TransactionSlot = Option<(Transaction, bool)>;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CompactBlock {
@@ -13,6 +22,129 @@ pub struct CompactBlock {
     pub short_ids: Vec<ShortTransactionID>,
     pub prefilled_transactions: Vec<IndexTransaction>,
     pub proposal_transactions: Vec<ProposalShortId>,
+    // How the short ids are derived, see short_transaction_id_keys
+    pub version: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PendingCompactBlock {
+    pub compact_block: CompactBlock,
+    // Slots filled by the first reconstruction pass, the transactions the peer sends
+    // only go into the ones still missing
+    pub block_transactions: Vec<TransactionSlot>,
+    pub peer: PeerIndex,
+    pub timestamp: u64, // ms
+    // Peers which announced the block, the first one is `peer`. Any of them can send
+    // the missing transactions
+    pub announcers: Vec<PeerIndex>,
+    // The peer the missing transactions were last asked from, and when, in ms
+    pub requested_from: PeerIndex,
+    pub requested_at: u64,
+}
+
+impl PendingCompactBlock {
+    pub fn missing_indexes(&self) -> Vec<u32> {
+        self.block_transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    // The announcer after the one asked last, in turn
+    pub fn next_announcer(&self) -> Option<PeerIndex> {
+        let next = self
+            .announcers
+            .iter()
+            .position(|peer| *peer == self.requested_from)
+            .map_or(0, |position| (position + 1) % self.announcers.len());
+        self.announcers
+            .get(next)
+            .cloned()
+            .filter(|peer| *peer != self.requested_from)
+    }
+}
+
+// A pending compact block as written on shutdown. The peers which announced it are gone
+// with their connections, so only the block and the transactions placed so far are kept
+#[derive(Serialize, Deserialize)]
+pub struct StoredCompactBlock {
+    header: Header,
+    uncles: Vec<UncleBlock>,
+    nonce: u64,
+    short_ids: Vec<ShortTransactionID>,
+    prefilled_transactions: Vec<(usize, Transaction)>,
+    proposal_transactions: Vec<ProposalShortId>,
+    version: u32,
+    block_transactions: Vec<TransactionSlot>,
+}
+
+impl<'a> From<&'a PendingCompactBlock> for StoredCompactBlock {
+    fn from(pending: &'a PendingCompactBlock) -> Self {
+        let compact_block = &pending.compact_block;
+        StoredCompactBlock {
+            header: compact_block.header.clone(),
+            uncles: compact_block.uncles.clone(),
+            nonce: compact_block.nonce,
+            short_ids: compact_block.short_ids.clone(),
+            prefilled_transactions: compact_block
+                .prefilled_transactions
+                .iter()
+                .map(|prefilled| (prefilled.index, prefilled.transaction.clone()))
+                .collect(),
+            proposal_transactions: compact_block.proposal_transactions.clone(),
+            version: compact_block.version,
+            block_transactions: pending.block_transactions.clone(),
+        }
+    }
+}
+
+impl StoredCompactBlock {
+    pub fn into_parts(self) -> (CompactBlock, Vec<TransactionSlot>) {
+        let compact_block = CompactBlock {
+            header: self.header,
+            uncles: self.uncles,
+            nonce: self.nonce,
+            short_ids: self.short_ids,
+            prefilled_transactions: self
+                .prefilled_transactions
+                .into_iter()
+                .map(|(index, transaction)| IndexTransaction { index, transaction })
+                .collect(),
+            proposal_transactions: self.proposal_transactions,
+            version: self.version,
+        };
+        (compact_block, self.block_transactions)
+    }
+}
+
+impl CompactBlock {
+    // Short id the transaction has in this compact block, the one pool transactions
+    // are matched by when the block is reconstructed
+    pub fn short_id_for(&self, tx: &Transaction) -> ShortTransactionID {
+        let (key0, key1) = short_transaction_id_keys(self.version, &self.header, self.nonce);
+        short_transaction_id(key0, key1, &tx.hash())
+    }
+}
+
+// The conversion from the message expects every short id to have the size of a
+// ShortTransactionID, a peer can send any length
+pub fn has_valid_short_ids(b: &ckb_protocol::CompactBlock) -> bool {
+    b.short_ids().map_or(false, |short_ids| {
+        FlatbuffersVectorIterator::new(short_ids).all(|bytes| {
+            bytes.seq().map_or(false, |seq| {
+                seq.len() == mem::size_of::<ShortTransactionID>()
+            })
+        })
+    })
+}
+
+// Hashes of the transactions placed into a reconstructed block, by where they came from
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ReconstructSources {
+    pub pool: Vec<H256>,
+    pub peer: Vec<H256>,
 }
 
 impl<'a> From<ckb_protocol::CompactBlock<'a>> for CompactBlock {
@@ -32,17 +164,16 @@ impl<'a> From<ckb_protocol::CompactBlock<'a>> for CompactBlock {
             )
             .map(Into::into)
             .collect(),
-
             uncles: FlatbuffersVectorIterator::new(b.uncles().unwrap())
                 .map(Into::into)
                 .collect(),
-
             proposal_transactions: b
                 .proposal_transactions()
                 .unwrap()
                 .iter()
                 .map(Into::into)
                 .collect(),
+            version: b.version(),
         }
     }
 }
@@ -1,13 +1,15 @@
-use super::compact_block::CompactBlock;
+use super::compact_block::{has_valid_short_ids, CompactBlock};
+use crate::log_context::LogContext;
 use crate::relayer::Relayer;
+use crate::COMPACT_BLOCK_VERSION;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{CompactBlock as FbsCompactBlock, RelayMessage};
+use ckb_protocol::{enum_name_relay_payload, CompactBlock as FbsCompactBlock, RelayPayload};
 use ckb_shared::block_median_time_context::BlockMedianTimeContext;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::ChainProvider;
 use ckb_util::RwLockUpgradableReadGuard;
 use ckb_verification::{HeaderResolverWrapper, HeaderVerifier, Verifier};
-use flatbuffers::FlatBufferBuilder;
+use log::debug;
 use numext_fixed_hash::H256;
 use std::sync::Arc;
 
@@ -15,6 +17,7 @@ pub struct CompactBlockProcess<'a, CI: ChainIndex + 'a> {
     message: &'a FbsCompactBlock<'a>,
     relayer: &'a Relayer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
     nc: &'a CKBProtocolContext,
 }
 
@@ -33,16 +36,48 @@ where
             nc,
             relayer,
             peer,
+            log_context: LogContext::new(peer, enum_name_relay_payload(RelayPayload::CompactBlock)),
         }
     }
 
     pub fn execute(self) {
+        let version = self.message.version();
+        if version > COMPACT_BLOCK_VERSION {
+            debug!(target: "relay", "{} ignored, unsupported version {}", self.log_context, version);
+            return;
+        }
+        if !self.relayer.within_pending_budget(self.peer) {
+            return;
+        }
+        if !has_valid_short_ids(self.message) {
+            debug!(target: "relay", "{} rejected, malformed short ids", self.log_context);
+            self.relayer.peers.misbehavior(self.peer, 10);
+            return;
+        }
         let compact_block: CompactBlock = (*self.message).into();
         let block_hash = compact_block.header.hash();
-        let pending_compact_blocks = self.relayer.state.pending_compact_blocks.upgradable_read();
-        if pending_compact_blocks.get(&block_hash).is_none()
-            && self.relayer.get_block(&block_hash).is_none()
+        // a block we mined or relayed ourselves echoed back
+        if self.relayer.is_received(&block_hash) {
+            return;
+        }
+        if !self
+            .relayer
+            .check_compact_block_nonce(self.peer, &compact_block)
         {
+            return;
+        }
+        let pending_compact_blocks = self.relayer.state.pending_compact_blocks.upgradable_read();
+        // the peer can send the missing transactions if the one asked first doesn't
+        if pending_compact_blocks.contains_key(&block_hash) {
+            let mut write_guard = RwLockUpgradableReadGuard::upgrade(pending_compact_blocks);
+            if let Some(pending) = write_guard.get_mut(&block_hash) {
+                if !pending.announcers.contains(&self.peer) {
+                    pending.announcers.push(self.peer);
+                }
+            }
+            return;
+        }
+        if self.relayer.get_block(&block_hash).is_none() {
             let resolver =
                 HeaderResolverWrapper::new(&compact_block.header, self.relayer.shared.clone());
             let header_verifier = HeaderVerifier::new(
@@ -56,29 +91,36 @@ where
                 self.relayer
                     .request_proposal_txs(self.nc, self.peer, &compact_block);
 
-                match self.relayer.reconstruct_block(&compact_block, Vec::new()) {
-                    (Some(block), _) => {
+                match self.relayer.reconstruct_block_slots(&compact_block) {
+                    (Some(block), _, _) => {
                         self.relayer
-                            .accept_block(self.nc, self.peer, &Arc::new(block))
+                            .accept_block(self.nc, self.peer, &Arc::new(block));
                     }
-                    (None, missing_indexes) => {
+                    // Nothing missing but the transactions mismatch the header
+                    (None, ref missing_indexes, _) if missing_indexes.is_empty() => self
+                        .relayer
+                        .request_full_block(self.nc, self.peer, &compact_block.header),
+                    (None, missing_indexes, block_transactions) => {
                         {
                             let mut write_guard =
                                 RwLockUpgradableReadGuard::upgrade(pending_compact_blocks);
-                            write_guard.insert(block_hash.clone(), compact_block.clone());
+                            self.relayer.insert_pending_compact_block(
+                                &mut write_guard,
+                                self.peer,
+                                compact_block.clone(),
+                                block_transactions,
+                            );
                         }
 
-                        let fbb = &mut FlatBufferBuilder::new();
-                        let message = RelayMessage::build_get_block_transactions(
-                            fbb,
+                        self.relayer.request_block_transactions(
+                            self.nc,
+                            self.peer,
                             &block_hash,
                             &missing_indexes
                                 .into_iter()
                                 .map(|i| i as u32)
                                 .collect::<Vec<_>>(),
                         );
-                        fbb.finish(message, None);
-                        let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
                     }
                 }
             }
@@ -117,7 +159,7 @@ where
             .pending_compact_blocks
             .read()
             .get(hash)
-            .map(|cb| cb.header.timestamp())
+            .map(|pending| pending.compact_block.header.timestamp())
             .or_else(|| {
                 self.relayer
                     .shared
@@ -131,7 +173,7 @@ where
             .pending_compact_blocks
             .read()
             .get(hash)
-            .map(|cb| cb.header.parent_hash().to_owned())
+            .map(|pending| pending.compact_block.header.parent_hash().to_owned())
             .or_else(|| {
                 self.relayer
                     .shared
@@ -30,6 +30,10 @@ where
     }
 
     pub fn execute(self) {
+        let mut budget = self.relayer.pending_budget(self.peer);
+        if budget == 0 {
+            return;
+        }
         let mut pending_proposals_request = self.relayer.state.pending_proposals_request.lock();
 
         let transactions = {
@@ -39,11 +43,16 @@ where
                 .iter()
                 .map(Into::into)
                 .filter_map(|short_id| {
-                    self.relayer.tx_pool.get_transaction(short_id).or({
-                        pending_proposals_request
-                            .entry(short_id)
-                            .or_insert_with(Default::default)
-                            .insert(self.peer);
+                    self.relayer.tx_pool.get_transaction(short_id).or_else(|| {
+                        // ids past the budget are neither kept nor answered later
+                        if budget > 0
+                            && pending_proposals_request
+                                .entry(short_id)
+                                .or_insert_with(Default::default)
+                                .insert(self.peer)
+                        {
+                            budget -= 1;
+                        }
                         None
                     })
                 })
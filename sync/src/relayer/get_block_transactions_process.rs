@@ -1,6 +1,7 @@
+use crate::log_context::LogContext;
 use crate::relayer::Relayer;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{GetBlockTransactions, RelayMessage};
+use ckb_protocol::{enum_name_relay_payload, GetBlockTransactions, RelayMessage, RelayPayload};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use log::debug;
@@ -9,6 +10,7 @@ pub struct GetBlockTransactionsProcess<'a, CI: ChainIndex + 'a> {
     message: &'a GetBlockTransactions<'a>,
     relayer: &'a Relayer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
     nc: &'a CKBProtocolContext,
 }
 
@@ -27,23 +29,46 @@ where
             nc,
             peer,
             relayer,
+            log_context: LogContext::new(
+                peer,
+                enum_name_relay_payload(RelayPayload::GetBlockTransactions),
+            ),
         }
     }
 
     pub fn execute(self) {
         let hash = self.message.hash().unwrap().into();
-        debug!(target: "relay", "get_block_transactions {:?}", hash);
+        let log_context = self.log_context.with_hash(&hash);
+        debug!(target: "relay", "{} requested", log_context);
 
         if let Some(block) = self.relayer.get_block(&hash) {
-            let transactions = self
-                .message
-                .indexes()
-                .unwrap()
-                .safe_slice()
+            let indexes = self.message.indexes().unwrap().safe_slice();
+            let txs_len = block.commit_transactions().len();
+            // Indexes are compared widened to u64, so the usize conversion below can't
+            // truncate. A request we can't fully serve, out of range or too large, is answered
+            // with no transactions, which makes the peer fall back to asking for the full block.
+            let max_size = self.relayer.config.max_block_transactions_response_size;
+            let transactions = if let Some(index) = indexes
                 .iter()
-                .filter_map(|i| block.commit_transactions().get(*i as usize).cloned())
-                .map(Into::into)
-                .collect::<Vec<_>>();
+                .find(|index| u64::from(**index) >= txs_len as u64)
+            {
+                debug!(target: "relay", "{} references index {} of {} transactions", log_context, index, txs_len);
+                Vec::new()
+            } else {
+                let transactions = indexes
+                    .iter()
+                    .map(|index| &block.commit_transactions()[*index as usize]);
+                let size = transactions
+                    .clone()
+                    .map(|transaction| transaction.bytes_len())
+                    .sum::<usize>();
+                if size > max_size {
+                    debug!(target: "relay", "{} {} bytes exceed {}, the full block is left to the peer", log_context, size, max_size);
+                    Vec::new()
+                } else {
+                    transactions.cloned().collect()
+                }
+            };
             let fbb = &mut FlatBufferBuilder::new();
             let message = RelayMessage::build_block_transactions(fbb, &hash, &transactions);
             fbb.finish(message, None);
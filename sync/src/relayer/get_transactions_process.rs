@@ -0,0 +1,77 @@
+use crate::log_context::LogContext;
+use crate::relayer::Relayer;
+use crate::MAX_GET_TRANSACTIONS;
+use ckb_core::transaction::ProposalShortId;
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{enum_name_relay_payload, GetTransactions, RelayMessage, RelayPayload};
+use ckb_shared::index::ChainIndex;
+use flatbuffers::FlatBufferBuilder;
+use fnv::FnvHashSet;
+use log::debug;
+use numext_fixed_hash::H256;
+
+pub struct GetTransactionsProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a GetTransactions<'a>,
+    relayer: &'a Relayer<CI>,
+    peer: PeerIndex,
+    log_context: LogContext,
+    nc: &'a CKBProtocolContext,
+}
+
+impl<'a, CI> GetTransactionsProcess<'a, CI>
+where
+    CI: ChainIndex + 'static,
+{
+    pub fn new(
+        message: &'a GetTransactions,
+        relayer: &'a Relayer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        GetTransactionsProcess {
+            message,
+            relayer,
+            peer,
+            nc,
+            log_context: LogContext::new(
+                peer,
+                enum_name_relay_payload(RelayPayload::GetTransactions),
+            ),
+        }
+    }
+
+    // Each transaction still in the pool is sent back in a Transaction message of its own,
+    // the ones gone since the announcement are silently skipped. A request for more than
+    // MAX_GET_TRANSACTIONS is scored and only its first hashes are answered, each of them
+    // once
+    pub fn execute(self) {
+        let transaction_hashes = self.message.transaction_hashes().unwrap();
+        if transaction_hashes.len() > MAX_GET_TRANSACTIONS {
+            debug!(target: "relay", "{} too many transactions requested {}", self.log_context, transaction_hashes.len());
+            self.relayer.peers.misbehavior(self.peer, 10);
+        }
+        let mut requested = FnvHashSet::default();
+        for hash in transaction_hashes.iter().take(MAX_GET_TRANSACTIONS) {
+            let hash: H256 = hash.into();
+            if !requested.insert(hash.clone()) {
+                continue;
+            }
+            if !self.relayer.peers.within_fair_share(self.peer) {
+                debug!(target: "relay", "{} dropped, over the bandwidth share of the peer", self.log_context.with_hash(&hash));
+                break;
+            }
+            if let Some(tx) = self
+                .relayer
+                .tx_pool
+                .get_transaction(ProposalShortId::from_h256(&hash))
+            {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_transaction(fbb, &tx);
+                fbb.finish(message, None);
+                let data = fbb.finished_data().to_vec();
+                self.relayer.peers.bytes_sent(self.peer, data.len());
+                let _ = self.nc.send(self.peer, data);
+            }
+        }
+    }
+}
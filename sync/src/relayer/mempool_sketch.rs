@@ -0,0 +1,201 @@
+use fnv::FnvHasher;
+use numext_fixed_hash::H256;
+use std::hash::Hasher;
+
+// Cells each hash is added to, one in each part of the table
+const HASH_COUNT: usize = 3;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cell {
+    pub count: i32,
+    pub key_sum: [u8; 32],
+    pub check_sum: u64,
+}
+
+impl Cell {
+    fn add(&mut self, key: &[u8; 32], count: i32) {
+        self.count = self.count.wrapping_add(count);
+        for (sum, byte) in self.key_sum.iter_mut().zip(key.iter()) {
+            *sum ^= byte;
+        }
+        self.check_sum ^= check_sum(key);
+    }
+
+    // holds a single hash, of our pool when the count is 1 and of theirs when it is -1
+    fn is_pure(&self) -> bool {
+        (self.count == 1 || self.count == -1) && self.check_sum == check_sum(&self.key_sum)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.check_sum == 0 && self.key_sum == [0u8; 32]
+    }
+}
+
+fn check_sum(key: &[u8; 32]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(key);
+    hasher.finish()
+}
+
+// Invertible bloom lookup table of the transaction hashes of a pool. Subtracting the
+// sketch of another pool leaves only the hashes one of them holds, which are decoded
+// back as long as they are few enough for the size of the table
+#[derive(Clone, Debug, PartialEq)]
+pub struct MempoolSketch {
+    cells: Vec<Cell>,
+}
+
+impl MempoolSketch {
+    // `size` is rounded up to a multiple of HASH_COUNT
+    pub fn new(size: usize) -> Self {
+        let size = (size + HASH_COUNT - 1) / HASH_COUNT * HASH_COUNT;
+        MempoolSketch {
+            cells: vec![Cell::default(); size],
+        }
+    }
+
+    pub fn from_hashes(size: usize, hashes: &[H256]) -> Self {
+        let mut sketch = MempoolSketch::new(size);
+        for hash in hashes {
+            sketch.insert(hash);
+        }
+        sketch
+    }
+
+    // None unless the cells split evenly between the hashes, as sent by a peer
+    pub fn from_cells(cells: Vec<Cell>) -> Option<Self> {
+        if cells.is_empty() || cells.len() % HASH_COUNT != 0 {
+            return None;
+        }
+        Some(MempoolSketch { cells })
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn insert(&mut self, hash: &H256) {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(hash.as_bytes());
+        self.add(&key, 1);
+    }
+
+    // Leaves the hashes only one of the sketches holds, both must have the same size
+    pub fn subtract(&mut self, other: &MempoolSketch) {
+        assert_eq!(
+            self.len(),
+            other.len(),
+            "subtract sketches of different sizes"
+        );
+        for (cell, other) in self.cells.iter_mut().zip(other.cells.iter()) {
+            cell.count = cell.count.wrapping_sub(other.count);
+            for (sum, byte) in cell.key_sum.iter_mut().zip(other.key_sum.iter()) {
+                *sum ^= byte;
+            }
+            cell.check_sum ^= other.check_sum;
+        }
+    }
+
+    // The hashes of a subtracted sketch, those only we hold and those only they hold, or
+    // None when there are too many of them to be peeled off the cells. A sketch crafted
+    // to peel forever gives up after as many hashes as cells
+    pub fn decode(mut self) -> Option<(Vec<H256>, Vec<H256>)> {
+        let mut ours = Vec::new();
+        let mut theirs = Vec::new();
+        loop {
+            let pure = match self.cells.iter().find(|cell| cell.is_pure()) {
+                Some(cell) => cell.clone(),
+                None => break,
+            };
+            if ours.len() + theirs.len() >= self.cells.len() {
+                return None;
+            }
+            let hash = H256::from_slice(&pure.key_sum).expect("32 bytes key");
+            if pure.count == 1 {
+                ours.push(hash);
+            } else {
+                theirs.push(hash);
+            }
+            self.add(&pure.key_sum, -pure.count);
+        }
+        if self.cells.iter().all(Cell::is_empty) {
+            Some((ours, theirs))
+        } else {
+            None
+        }
+    }
+
+    fn add(&mut self, key: &[u8; 32], count: i32) {
+        let part = self.cells.len() / HASH_COUNT;
+        for i in 0..HASH_COUNT {
+            let index = key[8 * i..8 * i + 8]
+                .iter()
+                .fold(0u64, |index, byte| (index << 8) | u64::from(*byte));
+            self.cells[i * part + (index % part as u64) as usize].add(key, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn hashes(range: std::ops::Range<u64>) -> Vec<H256> {
+        range
+            .map(|i| {
+                let mut hasher = FnvHasher::default();
+                hasher.write_u64(i);
+                let mut bytes = [0u8; 32];
+                for byte in bytes.iter_mut() {
+                    hasher.write_u8(*byte);
+                    *byte = hasher.finish() as u8;
+                }
+                H256::from_slice(&bytes).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_differences() {
+        let common = hashes(0..200);
+        let ours = hashes(200..205);
+        let theirs = hashes(300..303);
+
+        let mut sketch = MempoolSketch::from_hashes(90, &[common.clone(), ours.clone()].concat());
+        sketch.subtract(&MempoolSketch::from_hashes(
+            90,
+            &[common, theirs.clone()].concat(),
+        ));
+        let (decoded_ours, decoded_theirs) = sketch.decode().expect("decode");
+        assert_eq!(
+            decoded_ours.into_iter().collect::<HashSet<_>>(),
+            ours.into_iter().collect()
+        );
+        assert_eq!(
+            decoded_theirs.into_iter().collect::<HashSet<_>>(),
+            theirs.into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_decode_too_many_differences() {
+        let mut sketch = MempoolSketch::from_hashes(30, &hashes(0..100));
+        sketch.subtract(&MempoolSketch::new(30));
+        assert_eq!(sketch.decode(), None);
+    }
+
+    #[test]
+    fn test_from_cells() {
+        assert!(MempoolSketch::from_cells(Vec::new()).is_none());
+        assert!(MempoolSketch::from_cells(vec![Cell::default(); 4]).is_none());
+        assert_eq!(
+            MempoolSketch::from_cells(vec![Cell::default(); 6]),
+            Some(MempoolSketch::new(6))
+        );
+    }
+}
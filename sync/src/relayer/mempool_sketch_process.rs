@@ -0,0 +1,137 @@
+use super::mempool_sketch::{Cell, MempoolSketch};
+use crate::log_context::LogContext;
+use crate::relayer::Relayer;
+use crate::{
+    MAX_GET_TRANSACTIONS, MAX_MEMPOOL_SKETCH_CELLS, MAX_SKETCH_FALLBACK_HASHES,
+    MAX_TRANSACTION_INVENTORY_LEN,
+};
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{
+    enum_name_relay_payload, MempoolSketch as FbsMempoolSketch, RelayMessage, RelayPayload,
+};
+use ckb_shared::index::ChainIndex;
+use flatbuffers::FlatBufferBuilder;
+use log::debug;
+use numext_fixed_hash::H256;
+
+pub struct MempoolSketchProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a FbsMempoolSketch<'a>,
+    relayer: &'a Relayer<CI>,
+    peer: PeerIndex,
+    log_context: LogContext,
+    nc: &'a CKBProtocolContext,
+}
+
+impl<'a, CI> MempoolSketchProcess<'a, CI>
+where
+    CI: ChainIndex + 'static,
+{
+    pub fn new(
+        message: &'a FbsMempoolSketch,
+        relayer: &'a Relayer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        MempoolSketchProcess {
+            message,
+            relayer,
+            peer,
+            nc,
+            log_context: LogContext::new(
+                peer,
+                enum_name_relay_payload(RelayPayload::MempoolSketch),
+            ),
+        }
+    }
+
+    // The transactions only the peer holds are asked for and the ones only we hold are
+    // announced to it. A sketch too small for the differences falls back to announcing
+    // part of our pool, like the peer would have heard of it from the announcements
+    pub fn execute(self) {
+        // the peer only wants the transactions matching its filter
+        if self
+            .relayer
+            .peers
+            .transaction_filters
+            .read()
+            .contains_key(&self.peer)
+        {
+            return;
+        }
+        let theirs = match self.sketch() {
+            Some(sketch) => sketch,
+            None => {
+                debug!(target: "relay", "{} malformed sketch", self.log_context);
+                self.relayer.peers.misbehavior(self.peer, 10);
+                return;
+            }
+        };
+
+        let hashes = self.relayer.tx_pool.get_transaction_hashes();
+        let mut sketch = MempoolSketch::from_hashes(theirs.len(), &hashes);
+        sketch.subtract(&theirs);
+        let (only_ours, only_theirs) = match sketch.decode() {
+            Some(differences) => {
+                self.relayer.peers.sketch_decoded(self.peer);
+                differences
+            }
+            None => {
+                if !self.relayer.peers.sketch_decode_failed(self.peer) {
+                    debug!(target: "relay", "{} too many differences to decode, already announced our pool", self.log_context);
+                    return;
+                }
+                let mut hashes = hashes;
+                hashes.truncate(MAX_SKETCH_FALLBACK_HASHES);
+                debug!(target: "relay", "{} too many differences to decode, announce our {} transactions", self.log_context, hashes.len());
+                (hashes, Vec::new())
+            }
+        };
+
+        let unknown = self.relayer.unknown_transactions(only_theirs);
+        if !unknown.is_empty() {
+            debug!(target: "relay", "{} request {} transactions missing from our pool", self.log_context, unknown.len());
+            for unknown in unknown.chunks(MAX_GET_TRANSACTIONS) {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_get_transactions(fbb, unknown);
+                fbb.finish(message, None);
+                let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+            }
+        }
+        if !only_ours.is_empty() {
+            debug!(target: "relay", "{} announce {} transactions missing from the pool of the peer", self.log_context, only_ours.len());
+            for only_ours in only_ours.chunks(MAX_TRANSACTION_INVENTORY_LEN) {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_transaction_inventory(fbb, only_ours);
+                fbb.finish(message, None);
+                let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+            }
+        }
+    }
+
+    fn sketch(&self) -> Option<MempoolSketch> {
+        let counts = self.message.counts()?;
+        let key_sums = self.message.key_sums()?;
+        let check_sums = self.message.check_sums()?;
+        if counts.len() != key_sums.len()
+            || counts.len() != check_sums.len()
+            || counts.len() > MAX_MEMPOOL_SKETCH_CELLS
+        {
+            return None;
+        }
+        let cells = key_sums
+            .iter()
+            .enumerate()
+            .map(|(index, key_sum)| {
+                let mut cell = Cell {
+                    count: counts.get(index),
+                    key_sum: [0u8; 32],
+                    check_sum: check_sums.get(index),
+                };
+                let key_sum: H256 = key_sum.into();
+                cell.key_sum.copy_from_slice(key_sum.as_bytes());
+                cell
+            })
+            .collect();
+        MempoolSketch::from_cells(cells)
+    }
+}
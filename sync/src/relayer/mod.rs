@@ -1,39 +1,101 @@
 #![allow(clippy::needless_pass_by_value)]
 
+mod block_inventory_process;
 mod block_proposal_process;
 mod block_transactions_process;
 pub mod compact_block;
 mod compact_block_process;
 mod get_block_proposal_process;
 mod get_block_transactions_process;
+mod get_transactions_process;
+mod mempool_sketch;
+mod mempool_sketch_process;
+mod transaction_inventory_process;
 mod transaction_process;
 
+use self::block_inventory_process::BlockInventoryProcess;
 use self::block_proposal_process::BlockProposalProcess;
 use self::block_transactions_process::BlockTransactionsProcess;
-use self::compact_block::CompactBlock;
+use self::compact_block::{
+    CompactBlock, PendingCompactBlock, ReconstructSources, StoredCompactBlock, TransactionSlot,
+};
 use self::compact_block_process::CompactBlockProcess;
 use self::get_block_proposal_process::GetBlockProposalProcess;
 use self::get_block_transactions_process::GetBlockTransactionsProcess;
+use self::get_transactions_process::GetTransactionsProcess;
+use self::mempool_sketch::MempoolSketch;
+use self::mempool_sketch_process::MempoolSketchProcess;
+use self::transaction_inventory_process::TransactionInventoryProcess;
 use self::transaction_process::TransactionProcess;
+use crate::config::{Config, PrefillStrategy};
+use crate::log_context::LogContext;
+use crate::synchronizer::BlockProcessResult;
 use crate::types::Peers;
+use crate::{
+    BLOCK_TRANSACTIONS_TIMEOUT_CHECK_INTERVAL, COMPACT_BLOCK_VERSION, MAX_INFLIGHT_TX_REQUESTS,
+    MAX_PACKAGE_ANCESTORS, MAX_RECEIVED_BLOCKS, MAX_RECENTLY_CONFIRMED_TXS,
+    MAX_RECENTLY_RELAYED_TXS, MAX_RECENT_COMPACT_BLOCK_NONCES, MAX_RELAY_SEND_FAILURES,
+    MAX_TRANSACTION_INVENTORY_LEN, MEMPOOL_RECONCILIATION_INTERVAL, MEMPOOL_SKETCH_CELLS,
+    SYNC_PROTOCOL_ID, TX_ANNOUNCEMENT_INTERVAL, TX_REQUEST_TIMEOUT,
+};
+use bincode::{deserialize, serialize};
+use channel::{select, Receiver};
 use ckb_chain::chain::ChainController;
+use ckb_chain::error::ProcessBlockError;
 use ckb_core::block::{Block, BlockBuilder};
+use ckb_core::header::Header;
 use ckb_core::transaction::{ProposalShortId, Transaction};
-use ckb_network::{CKBProtocolContext, CKBProtocolHandler, PeerIndex, TimerToken};
-use ckb_pool::txs_pool::TransactionPoolController;
-use ckb_protocol::{short_transaction_id, short_transaction_id_keys, RelayMessage, RelayPayload};
+use ckb_network::{
+    CKBProtocolContext, CKBProtocolHandler, PeerIndex, Severity, TaskPriority, TimerToken,
+};
+use ckb_notify::{ForkBlocks, MsgNewTip, MsgSwitchFork, NotifyController};
+use ckb_pool::txs_pool::{PoolError, TransactionPoolController};
+use ckb_protocol::{
+    enum_name_relay_payload, short_transaction_id, short_transaction_id_keys, AnnouncementMode,
+    RelayMessage, RelayPayload, SyncMessage,
+};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
 use ckb_util::{Mutex, RwLock};
+use faketime::unix_time_as_millis;
 use flatbuffers::{get_root, FlatBufferBuilder};
 use fnv::{FnvHashMap, FnvHashSet};
-use log::{debug, info};
+use log::{debug, info, warn};
+use lru_cache::LruCache;
+use merkle_root::merkle_root;
 use numext_fixed_hash::H256;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
 pub const TX_PROPOSAL_TOKEN: TimerToken = 0;
+pub const TX_ANNOUNCEMENT_TOKEN: TimerToken = 1;
+pub const BLOCK_TRANSACTIONS_TIMEOUT_TOKEN: TimerToken = 2;
+pub const MEMPOOL_RECONCILIATION_TOKEN: TimerToken = 3;
+
+const RELAYER_SUBSCRIBER: &str = "relayer";
+
+// Where an item we relay comes from, the peer which sent it doesn't need it back
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelaySource {
+    // Mined by ourselves or submitted through RPC, every peer gets it
+    Local,
+    Peer(PeerIndex),
+}
+
+impl RelaySource {
+    fn is_peer(self, peer: PeerIndex) -> bool {
+        self == RelaySource::Peer(peer)
+    }
+}
 
 #[derive(Clone)]
 pub struct Relayer<CI: ChainIndex> {
@@ -43,6 +105,13 @@ pub struct Relayer<CI: ChainIndex> {
     state: Arc<RelayState>,
     // TODO refactor shared Peers struct with Synchronizer
     peers: Arc<Peers>,
+    // Paused by the operator, see set_relay_enabled
+    relay_enabled: Arc<AtomicBool>,
+    // Set by the synchronizer once initial block download finishes, see relay_switch
+    ibd_finished: Arc<AtomicBool>,
+    pub config: Arc<Config>,
+    switch_fork_receiver: Option<Receiver<MsgSwitchFork>>,
+    new_tip_receiver: Option<Receiver<MsgNewTip>>,
 }
 
 impl<CI> Relayer<CI>
@@ -54,6 +123,7 @@ where
         shared: Shared<CI>,
         tx_pool: TransactionPoolController,
         peers: Arc<Peers>,
+        config: Arc<Config>,
     ) -> Self {
         Relayer {
             chain,
@@ -61,9 +131,41 @@ where
             tx_pool,
             state: Arc::new(RelayState::default()),
             peers,
+            relay_enabled: Arc::new(AtomicBool::new(true)),
+            ibd_finished: Arc::new(AtomicBool::new(true)),
+            config,
+            switch_fork_receiver: None,
+            new_tip_receiver: None,
         }
     }
 
+    // Reorgs published by this notify service are reconciled with the relay state, and
+    // the orphans connected by new tips relayed, on a dedicated thread started with the
+    // protocol
+    pub fn notify(mut self, notify: NotifyController) -> Self {
+        self.switch_fork_receiver = Some(notify.subscribe_switch_fork(RELAYER_SUBSCRIBER));
+        self.new_tip_receiver = Some(notify.subscribe_new_tip(RELAYER_SUBSCRIBER));
+        self
+    }
+
+    // Relay stays disabled until the synchronizer tells initial block download finished,
+    // whatever the operator asks through set_relay_enabled
+    pub fn relay_switch(mut self, ibd_finished: Arc<AtomicBool>) -> Self {
+        self.ibd_finished = ibd_finished;
+        self
+    }
+
+    // Blocks and transactions are still ingested while relay is disabled,
+    // they are just not forwarded to other peers.
+    pub fn set_relay_enabled(&self, enabled: bool) {
+        info!(target: "relay", "set relay enabled {}", enabled);
+        self.relay_enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn is_relay_enabled(&self) -> bool {
+        self.relay_enabled.load(Ordering::Acquire) && self.ibd_finished.load(Ordering::Acquire)
+    }
+
     fn process(&self, nc: &CKBProtocolContext, peer: PeerIndex, message: RelayMessage) {
         match message.payload_type() {
             RelayPayload::CompactBlock => CompactBlockProcess::new(
@@ -102,7 +204,51 @@ where
                 BlockProposalProcess::new(&message.payload_as_block_proposal().unwrap(), self)
                     .execute()
             }
-            RelayPayload::NONE => {}
+            RelayPayload::AnnouncementPreference => {
+                let mode = message.payload_as_announcement_preference().unwrap().mode();
+                debug!(target: "relay", "peer={} prefers {:?} block announcements", peer, mode);
+                self.peers.announcement_modes.write().insert(peer, mode);
+            }
+            RelayPayload::CompactBlockVersion => {
+                let version = message
+                    .payload_as_compact_block_version()
+                    .unwrap()
+                    .version();
+                debug!(target: "relay", "peer={} understands compact blocks up to version {}", peer, version);
+                self.peers.set_compact_block_version(peer, version);
+            }
+            RelayPayload::BlockInventory => BlockInventoryProcess::new(
+                &message.payload_as_block_inventory().unwrap(),
+                self,
+                peer,
+                nc,
+            )
+            .execute(),
+            RelayPayload::TransactionInventory => TransactionInventoryProcess::new(
+                &message.payload_as_transaction_inventory().unwrap(),
+                self,
+                peer,
+                nc,
+            )
+            .execute(),
+            RelayPayload::GetTransactions => GetTransactionsProcess::new(
+                &message.payload_as_get_transactions().unwrap(),
+                self,
+                peer,
+                nc,
+            )
+            .execute(),
+            RelayPayload::MempoolSketch => MempoolSketchProcess::new(
+                &message.payload_as_mempool_sketch().unwrap(),
+                self,
+                peer,
+                nc,
+            )
+            .execute(),
+            RelayPayload::NONE => {
+                warn!(target: "relay", "peer={} sent a message without payload", peer);
+                self.peers.unknown_payload(peer);
+            }
         }
     }
 
@@ -134,78 +280,754 @@ where
         let _ = nc.send(peer, fbb.finished_data().to_vec());
     }
 
-    pub fn accept_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, block: &Arc<Block>) {
-        let ret = self.chain.process_block(Arc::clone(&block));
-        if ret.is_ok() {
+    // Keeps the compact block until its missing transactions arrive, a peer at its limit
+    // gives up its own oldest pending block, otherwise the oldest one overall goes
+    pub fn insert_pending_compact_block(
+        &self,
+        pending_compact_blocks: &mut FnvHashMap<H256, PendingCompactBlock>,
+        peer: PeerIndex,
+        compact_block: CompactBlock,
+        block_transactions: Vec<TransactionSlot>,
+    ) {
+        let oldest = |peer: Option<PeerIndex>| {
+            pending_compact_blocks
+                .iter()
+                .filter(|(_, pending)| peer.map_or(true, |peer| pending.peer == peer))
+                .min_by_key(|(_, pending)| pending.timestamp)
+                .map(|(hash, _)| hash.clone())
+        };
+
+        let peer_pending = pending_compact_blocks
+            .values()
+            .filter(|pending| pending.peer == peer)
+            .count();
+        let evicted = if peer_pending >= self.config.max_pending_compact_blocks_per_peer {
+            oldest(Some(peer))
+        } else if pending_compact_blocks.len() >= self.config.max_pending_compact_blocks {
+            oldest(None)
+        } else {
+            None
+        };
+        if let Some(hash) = evicted {
+            debug!(target: "relay", "evict pending compact block {:x}", hash);
+            pending_compact_blocks.remove(&hash);
+        }
+
+        let now = unix_time_as_millis();
+        pending_compact_blocks.insert(
+            compact_block.header.hash(),
+            PendingCompactBlock {
+                compact_block,
+                block_transactions,
+                peer,
+                timestamp: now,
+                announcers: vec![peer],
+                requested_from: peer,
+                requested_at: now,
+            },
+        );
+    }
+
+    pub fn request_block_transactions(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        hash: &H256,
+        indexes: &[u32],
+    ) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_transactions(fbb, hash, indexes);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+    }
+
+    // Missing transactions not received in time are asked again from the next peer which
+    // announced the block, a slow or silent peer doesn't hold the block back
+    pub fn request_timed_out_block_transactions(&self, nc: &CKBProtocolContext) {
+        let now = unix_time_as_millis();
+        let mut pending_compact_blocks = self.state.pending_compact_blocks.write();
+        for (hash, pending) in pending_compact_blocks.iter_mut() {
+            if now.saturating_sub(pending.requested_at) < self.config.block_transactions_timeout {
+                continue;
+            }
+            if let Some(peer) = pending.next_announcer() {
+                debug!(
+                    target: "relay",
+                    "peer={} didn't send the missing transactions of block {:x}, ask peer={}",
+                    pending.requested_from,
+                    hash,
+                    peer
+                );
+                pending.requested_from = peer;
+                pending.requested_at = now;
+                self.request_block_transactions(nc, peer, hash, &pending.missing_indexes());
+            }
+        }
+    }
+
+    // Written on shutdown, so a restart doesn't throw away the transactions of the pending
+    // compact blocks we already have
+    pub fn save_pending_compact_blocks<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let stored = self
+            .state
+            .pending_compact_blocks
+            .read()
+            .values()
+            .map(StoredCompactBlock::from)
+            .collect::<Vec<_>>();
+        let bytes =
+            serialize(&stored).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, bytes)
+    }
+
+    // The blocks are kept aside until a peer connects, they are then asked from it. Blocks
+    // stored meanwhile are left out, returns how many are kept
+    pub fn load_pending_compact_blocks<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let bytes = fs::read(path)?;
+        let stored: Vec<StoredCompactBlock> =
+            deserialize(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut restored = self.state.restored_compact_blocks.lock();
+        for stored in stored {
+            let (compact_block, block_transactions) = stored.into_parts();
+            if self
+                .shared
+                .block_header(&compact_block.header.hash())
+                .is_none()
+            {
+                restored.push((compact_block, block_transactions));
+            }
+        }
+        Ok(restored.len())
+    }
+
+    // The restored blocks become pending blocks announced by the peer, which is asked for
+    // their missing transactions. A peer which doesn't have a block won't answer, the block
+    // then waits for another peer to announce it
+    pub fn resume_restored_compact_blocks(&self, nc: &CKBProtocolContext, peer: PeerIndex) {
+        let restored = mem::replace(&mut *self.state.restored_compact_blocks.lock(), Vec::new());
+        if restored.is_empty() {
+            return;
+        }
+        let mut pending_compact_blocks = self.state.pending_compact_blocks.write();
+        for (compact_block, block_transactions) in restored {
+            let hash = compact_block.header.hash();
+            if pending_compact_blocks.contains_key(&hash) {
+                continue;
+            }
+            self.insert_pending_compact_block(
+                &mut pending_compact_blocks,
+                peer,
+                compact_block,
+                block_transactions,
+            );
+            if let Some(pending) = pending_compact_blocks.get(&hash) {
+                debug!(target: "relay", "ask peer={} the missing transactions of restored block {:x}", peer, hash);
+                self.request_block_transactions(nc, peer, &hash, &pending.missing_indexes());
+            }
+        }
+    }
+
+    // The peer is blamed for invalid blocks like in BlockProcess, and disconnected once it
+    // served MAX_INVALID_BLOCKS of them
+    pub fn accept_block(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        block: &Arc<Block>,
+    ) -> BlockProcessResult {
+        // the block no longer has to be fetched in full
+        self.peers.compact_block_completed(&block.header().hash());
+        if self.shared.output_root(&block.header().hash()).is_some() {
+            return BlockProcessResult::AlreadyStored;
+        }
+        // the synchronizer fetches the missing ancestors
+        if self
+            .shared
+            .output_root(&block.header().parent_hash())
+            .is_none()
+        {
+            debug!(target: "relay", "peer={} accept_block unknown parent of {:x}", peer, block.header().hash());
+            return BlockProcessResult::Orphan;
+        }
+        match self.chain.process_block(Arc::clone(&block)) {
+            Ok(()) => {
+                self.peers.useful_message_received(peer);
+                self.mark_received(block.header().hash());
+                self.mark_confirmed(block);
+                self.announce_block(nc, peer, block);
+                BlockProcessResult::Stored
+            }
+            Err(err) => {
+                debug!(target: "relay", "peer={} accept_block verify error {:?}", peer, err);
+                let result = BlockProcessResult::Invalid(err);
+                if result.is_misbehavior() && self.peers.invalid_block_received(peer) {
+                    nc.report_peer(peer, Severity::Bad("served invalid blocks"));
+                }
+                result
+            }
+        }
+    }
+
+    // Process a block mined by ourselves and announce it to every peer. The block is
+    // marked received beforehand, so peers echoing it back are ignored
+    pub fn submit_mined_block(
+        &self,
+        nc: &CKBProtocolContext,
+        block: &Arc<Block>,
+    ) -> Result<(), ProcessBlockError> {
+        self.mark_received(block.header().hash());
+        self.chain.process_block(Arc::clone(&block))?;
+        self.mark_confirmed(block);
+        self.announce_block_from(nc, RelaySource::Local, block);
+        Ok(())
+    }
+
+    pub fn mark_received(&self, hash: H256) {
+        self.state.received_blocks.lock().insert(hash, ());
+    }
+
+    pub fn is_received(&self, hash: &H256) -> bool {
+        self.state.received_blocks.lock().contains_key(hash)
+    }
+
+    // Transactions committed in a block we just accepted are neither added to the pool
+    // nor relayed when some peer sends them again
+    fn mark_confirmed(&self, block: &Block) {
+        let mut recently_confirmed = self.state.recently_confirmed.lock();
+        for tx in block.commit_transactions() {
+            recently_confirmed.insert(tx.hash(), ());
+        }
+    }
+
+    pub fn is_confirmed(&self, hash: &H256) -> bool {
+        self.state.recently_confirmed.lock().contains_key(hash)
+    }
+
+    // Handle the reorgs and new tips as soon as they are published rather than on the
+    // next timer, the handler threads never wait for them. Stops once the notify service
+    // is gone
+    fn start_chain_listener(&self, nc: Box<CKBProtocolContext>) {
+        let (switch_fork_receiver, new_tip_receiver) =
+            match (&self.switch_fork_receiver, &self.new_tip_receiver) {
+                (Some(switch_fork_receiver), Some(new_tip_receiver)) => {
+                    (switch_fork_receiver.clone(), new_tip_receiver.clone())
+                }
+                _ => return,
+            };
+        let relayer = self.clone();
+        thread::Builder::new()
+            .name(RELAYER_SUBSCRIBER.to_string())
+            .spawn(move || loop {
+                select! {
+                    recv(switch_fork_receiver) -> msg => match msg {
+                        Ok(fork) => relayer.reconcile_fork(nc.as_ref(), &fork),
+                        Err(_) => break,
+                    },
+                    recv(new_tip_receiver) -> msg => match msg {
+                        Ok(block) => relayer.relay_orphans_promoted_by(nc.as_ref(), block),
+                        Err(_) => break,
+                    },
+                }
+            })
+            .expect("Start relayer chain listener failed");
+    }
+
+    pub fn relay_promoted_orphans(&self, nc: &CKBProtocolContext) {
+        if let Some(ref receiver) = self.new_tip_receiver {
+            while let Ok(block) = receiver.try_recv() {
+                self.relay_orphans_promoted_by(nc, block);
+            }
+        }
+    }
+
+    // A new tip may connect orphans of the pool, whether the relayer or the synchronizer
+    // accepted it, the promoted ones are relayed
+    fn relay_orphans_promoted_by(&self, nc: &CKBProtocolContext, block: MsgNewTip) {
+        for tx in self.tx_pool.promote_orphans(block) {
+            self.relay_transaction_from(nc, RelaySource::Local, &tx);
+        }
+    }
+
+    pub fn reconcile_forks(&self, nc: &CKBProtocolContext) {
+        if let Some(ref receiver) = self.switch_fork_receiver {
+            while let Ok(fork) = receiver.try_recv() {
+                self.reconcile_fork(nc, &fork);
+            }
+        }
+    }
+
+    // The transactions of the blocks a reorg disconnected are back in the pool, they are
+    // relayed again unless the new blocks commit them too, which are confirmed instead
+    // and no longer announced
+    pub fn reconcile_fork(&self, nc: &CKBProtocolContext, fork: &ForkBlocks) {
+        let mut committed = FnvHashSet::default();
+        for block in fork.new_blks() {
+            self.mark_confirmed(block);
+            committed.extend(block.commit_transactions().iter().map(Transaction::hash));
+        }
+        for hashes in self.state.pending_announcements.lock().values_mut() {
+            hashes.retain(|hash| !committed.contains(hash));
+        }
+
+        let returned = fork
+            .old_blks()
+            .iter()
+            .flat_map(|block| block.commit_transactions())
+            .filter(|tx| !tx.is_cellbase() && !committed.contains(&tx.hash()))
+            .collect::<Vec<_>>();
+        debug!(target: "relay", "reorg returned {} transactions to the pool", returned.len());
+        for tx in returned {
+            self.state.recently_confirmed.lock().remove(&tx.hash());
+            self.relay_transaction_from(nc, RelaySource::Local, tx);
+        }
+    }
+
+    // Announce a block to every peer but its source, the way each peer asked for: a
+    // compact block, its header over the sync protocol, or an inventory the peer asks
+    // the block back for
+    pub fn announce_block(&self, nc: &CKBProtocolContext, source: PeerIndex, block: &Block) {
+        self.announce_block_from(nc, RelaySource::Peer(source), block);
+    }
+
+    fn announce_block_from(&self, nc: &CKBProtocolContext, source: RelaySource, block: &Block) {
+        // compact blocks are built once for each version the peers negotiated
+        let mut compact_block_peers = FnvHashMap::default();
+        let mut header_peers = Vec::new();
+        let mut inventory_peers = Vec::new();
+        for peer in nc
+            .connected_peers()
+            .into_iter()
+            .filter(|peer| !source.is_peer(*peer))
+        {
+            match self.peers.announcement_mode(peer) {
+                AnnouncementMode::CompactBlock => compact_block_peers
+                    .entry(self.peers.compact_block_version(peer))
+                    .or_insert_with(Vec::new)
+                    .push(peer),
+                AnnouncementMode::Header => header_peers.push(peer),
+                AnnouncementMode::Inventory => inventory_peers.push(peer),
+            }
+        }
+
+        if !compact_block_peers.is_empty() {
+            let prefilled_transactions_indexes = self.prefilled_transactions_indexes(block);
+            for (version, peers) in compact_block_peers {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_compact_block(
+                    fbb,
+                    block,
+                    &prefilled_transactions_indexes,
+                    version,
+                );
+                fbb.finish(message, None);
+                self.relay(nc, &peers, fbb.finished_data());
+            }
+        }
+
+        if !inventory_peers.is_empty() {
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = RelayMessage::build_block_inventory(fbb, &[block.header().hash()]);
+            fbb.finish(message, None);
+            self.relay(nc, &inventory_peers, fbb.finished_data());
+        }
+
+        if !header_peers.is_empty() && self.is_relay_enabled() {
             let fbb = &mut FlatBufferBuilder::new();
-            let message = RelayMessage::build_compact_block(fbb, block, &HashSet::new());
+            let message = SyncMessage::build_headers(fbb, &[block.header().clone()]);
             fbb.finish(message, None);
+            for peer in header_peers {
+                let _ = nc.send_protocol(peer, SYNC_PROTOCOL_ID, fbb.finished_data().to_vec());
+            }
+        }
+    }
+
+    // Indexes of the transactions of the block sent in full in its compact block, chosen
+    // by the configured PrefillStrategy
+    pub fn prefilled_transactions_indexes(&self, block: &Block) -> HashSet<usize> {
+        let transactions = block.commit_transactions().iter().enumerate().skip(1);
+        let mut indexes = match self.config.prefill_strategy {
+            PrefillStrategy::Cellbase => HashSet::new(),
+            PrefillStrategy::RecentlyBroadcast => transactions
+                .filter(|(_, tx)| self.is_recently_relayed(&tx.hash()))
+                .map(|(index, _)| index)
+                .collect(),
+            PrefillStrategy::LargeTransactions => transactions
+                .filter(|(_, tx)| tx.bytes_len() >= self.config.prefill_large_transaction_size)
+                .map(|(index, _)| index)
+                .collect(),
+        };
+        indexes.insert(0);
+        indexes
+    }
+
+    pub fn is_recently_relayed(&self, hash: &H256) -> bool {
+        self.state.recently_relayed.lock().contains_key(hash)
+    }
+
+    // Queue the announcement of a transaction to every connected peer except the source,
+    // honouring the transaction filters loaded by peers
+    pub fn relay_transaction(&self, nc: &CKBProtocolContext, source: PeerIndex, tx: &Transaction) {
+        self.relay_transaction_from(nc, RelaySource::Peer(source), tx);
+    }
+
+    // Queue the announcement of a transaction submitted locally, e.g. through RPC, to every
+    // connected peer, it is added to the pool first unless already there
+    pub fn broadcast_transaction(
+        &self,
+        nc: &CKBProtocolContext,
+        tx: &Transaction,
+    ) -> Result<(), PoolError> {
+        if !self.tx_pool.contains_key(tx.proposal_short_id()) {
+            self.tx_pool.add_transaction(tx.clone())?;
+        }
+        self.relay_transaction_from(nc, RelaySource::Local, tx);
+        Ok(())
+    }
 
-            for peer_id in nc.connected_peers() {
-                if peer_id != peer {
-                    let _ = nc.send(peer_id, fbb.finished_data().to_vec());
+    // The transaction is announced as a package, after its ancestors still in our pool,
+    // so that peers ask the parents ahead of a child they could not accept otherwise
+    fn relay_transaction_from(
+        &self,
+        nc: &CKBProtocolContext,
+        source: RelaySource,
+        tx: &Transaction,
+    ) {
+        let package = self.transaction_package(tx);
+        {
+            let mut recently_relayed = self.state.recently_relayed.lock();
+            for hash in &package {
+                recently_relayed.insert(hash.clone(), ());
+            }
+        }
+        let transaction_filters = self.peers.transaction_filters.read();
+        let mut pending_announcements = self.state.pending_announcements.lock();
+        for peer in nc.connected_peers() {
+            if !source.is_peer(peer)
+                && (self.is_whitelisted(nc, peer)
+                    || transaction_filters
+                        .get(&peer)
+                        .map_or(true, |filter| filter.contains(tx)))
+            {
+                let hashes = pending_announcements.entry(peer).or_insert_with(Vec::new);
+                for hash in &package {
+                    if !hashes.contains(hash) {
+                        hashes.push(hash.clone());
+                    }
                 }
             }
-        } else {
-            debug!(target: "relay", "accept_block verify error {:?}", ret);
         }
     }
 
-    pub fn reconstruct_block(
+    // Hashes of the ancestors of the transaction found in the pool, orphans included,
+    // parents ahead of their children and the transaction itself last
+    pub fn transaction_package(&self, tx: &Transaction) -> Vec<H256> {
+        let mut package = Vec::new();
+        let mut visited = FnvHashSet::default();
+        self.collect_ancestors(tx, &mut visited, &mut package);
+        package.push(tx.hash());
+        package
+    }
+
+    fn collect_ancestors(
         &self,
-        compact_block: &CompactBlock,
-        transactions: Vec<Transaction>,
-    ) -> (Option<Block>, Vec<usize>) {
-        let (key0, key1) =
-            short_transaction_id_keys(compact_block.header.nonce(), compact_block.nonce);
+        tx: &Transaction,
+        visited: &mut FnvHashSet<H256>,
+        package: &mut Vec<H256>,
+    ) {
+        for input in tx.inputs() {
+            let out_point = &input.previous_output;
+            if out_point.is_null()
+                || package.len() >= MAX_PACKAGE_ANCESTORS
+                || !visited.insert(out_point.hash.clone())
+            {
+                continue;
+            }
+            if let Some(parent) = self
+                .tx_pool
+                .get_transaction(ProposalShortId::from_h256(&out_point.hash))
+            {
+                self.collect_ancestors(&parent, visited, package);
+                if package.len() < MAX_PACKAGE_ANCESTORS {
+                    package.push(parent.hash());
+                }
+            }
+        }
+    }
 
-        let mut txs = transactions;
-        txs.extend(self.tx_pool.get_potential_transactions());
+    pub fn is_whitelisted(&self, nc: &CKBProtocolContext, peer: PeerIndex) -> bool {
+        nc.session_info(peer).map_or(false, |session| {
+            let peer_id = session.peer.peer_id.to_base58();
+            self.config.relay_whitelist.contains(&peer_id)
+        })
+    }
 
-        let mut txs_map = FnvHashMap::default();
-        for tx in txs {
-            let short_id = short_transaction_id(key0, key1, &tx.hash());
-            txs_map.insert(short_id, tx);
+    // Announce the transactions queued since the last flush, a single inventory per peer,
+    // the peers in random order like in relay
+    pub fn flush_transaction_announcements(&self, nc: &CKBProtocolContext) {
+        let mut pending_announcements = mem::replace(
+            &mut *self.state.pending_announcements.lock(),
+            FnvHashMap::default(),
+        )
+        .into_iter()
+        .collect::<Vec<_>>();
+        pending_announcements.shuffle(&mut thread_rng());
+        for (peer, hashes) in pending_announcements {
+            for hashes in hashes.chunks(MAX_TRANSACTION_INVENTORY_LEN) {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_transaction_inventory(fbb, hashes);
+                fbb.finish(message, None);
+                self.relay(nc, &[peer], fbb.finished_data());
+            }
         }
+    }
+
+    // Transactions neither in our pool nor recently confirmed, nor already asked from a
+    // peer which still has time to answer. They are marked as asked from now on
+    pub fn unknown_transactions(&self, hashes: Vec<H256>) -> Vec<H256> {
+        let now = unix_time_as_millis();
+        let mut inflight_transactions = self.state.inflight_transactions.lock();
+        hashes
+            .into_iter()
+            .filter(|hash| {
+                if self.is_confirmed(hash)
+                    || self.tx_pool.contains_key(ProposalShortId::from_h256(hash))
+                {
+                    return false;
+                }
+                let expired = inflight_transactions
+                    .get_mut(hash)
+                    .map_or(true, |asked_at| *asked_at + TX_REQUEST_TIMEOUT < now);
+                if expired {
+                    inflight_transactions.insert(hash.clone(), now);
+                }
+                expired
+            })
+            .collect()
+    }
 
-        let short_ids_iter = &mut compact_block.short_ids.iter();
-        let mut block_transactions = Vec::with_capacity(
-            compact_block.prefilled_transactions.len() + compact_block.short_ids.len(),
+    // Send the sketch of our pool to the peers relaying every transaction, each answers
+    // with what the two pools don't have in common
+    pub fn send_mempool_sketches(&self, nc: &CKBProtocolContext) {
+        let peers = {
+            let transaction_filters = self.peers.transaction_filters.read();
+            nc.connected_peers()
+                .into_iter()
+                .filter(|peer| !transaction_filters.contains_key(peer))
+                .collect::<Vec<_>>()
+        };
+        if peers.is_empty() {
+            return;
+        }
+        let sketch = MempoolSketch::from_hashes(
+            MEMPOOL_SKETCH_CELLS,
+            &self.tx_pool.get_transaction_hashes(),
         );
+        let counts = sketch
+            .cells()
+            .iter()
+            .map(|cell| cell.count)
+            .collect::<Vec<_>>();
+        let key_sums = sketch
+            .cells()
+            .iter()
+            .map(|cell| H256::from_slice(&cell.key_sum).expect("32 bytes key"))
+            .collect::<Vec<_>>();
+        let check_sums = sketch
+            .cells()
+            .iter()
+            .map(|cell| cell.check_sum)
+            .collect::<Vec<_>>();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_mempool_sketch(fbb, &counts, &key_sums, &check_sums);
+        fbb.finish(message, None);
+        self.relay(nc, &peers, fbb.finished_data());
+    }
 
-        // fill transactions gap
-        compact_block.prefilled_transactions.iter().for_each(|pt| {
-            let gap = pt.index - block_transactions.len();
-            if gap > 0 {
-                short_ids_iter
-                    .take(gap)
-                    .for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
+    // Send data to each peer, a peer failing MAX_RELAY_SEND_FAILURES times in a row
+    // is reported and skipped until it disconnects. The peers are shuffled for every
+    // item, the order in which they hear of it would otherwise leak our connections
+    pub fn relay(&self, nc: &CKBProtocolContext, peers: &[PeerIndex], data: &[u8]) {
+        if !self.is_relay_enabled() {
+            debug!(target: "relay", "relay disabled, skip relaying to {} peers", peers.len());
+            return;
+        }
+        let mut peers = peers.to_vec();
+        peers.shuffle(&mut thread_rng());
+        let mut send_failures = self.state.send_failures.lock();
+        for peer in &peers {
+            if send_failures.get(peer).cloned().unwrap_or(0) >= MAX_RELAY_SEND_FAILURES {
+                continue;
             }
-            block_transactions.push(Some(pt.transaction.clone()));
-        });
+            match nc.send(*peer, data.to_vec()) {
+                Ok(_) => {
+                    send_failures.remove(peer);
+                }
+                Err(err) => {
+                    debug!(target: "relay", "relay to peer={} error {:?}", peer, err);
+                    let failures = send_failures.entry(*peer).or_insert(0);
+                    *failures += 1;
+                    if *failures >= MAX_RELAY_SEND_FAILURES {
+                        warn!(target: "relay", "relay to peer={} failed {} times", peer, failures);
+                        if let Some(state) = self.peers.state.write().get_mut(peer) {
+                            state.disconnect = true;
+                        }
+                        nc.report_peer(*peer, Severity::Timeout);
+                    }
+                }
+            }
+        }
+    }
 
-        // append remain transactions
-        short_ids_iter.for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
+    // Pending compact blocks and unanswered proposal requests kept for the peer
+    pub fn pending_requests(&self, peer: PeerIndex) -> usize {
+        let compact_blocks = self
+            .state
+            .pending_compact_blocks
+            .read()
+            .values()
+            .filter(|pending| pending.peer == peer)
+            .count();
+        let proposals = self
+            .state
+            .pending_proposals_request
+            .lock()
+            .values()
+            .filter(|peers| peers.contains(&peer))
+            .count();
+        compact_blocks + proposals
+    }
 
-        let mut missing_indexes = Vec::new();
-        for (i, t) in block_transactions.iter().enumerate() {
-            if t.is_none() {
-                missing_indexes.push(i);
-            }
+    // How many more entries the peer may make us keep, each one inserted for it is charged
+    // against this. A peer at its budget is scored and ignored until its pending requests
+    // drain
+    pub fn pending_budget(&self, peer: PeerIndex) -> usize {
+        let pending = self.pending_requests(peer);
+        let max = self.config.max_pending_requests_per_peer;
+        if pending >= max {
+            debug!(target: "relay", "peer={} has {} pending requests, ignore it", peer, pending);
+            self.peers.misbehavior(peer, 10);
+            0
+        } else {
+            max - pending
         }
+    }
 
-        if missing_indexes.is_empty() {
-            let block = BlockBuilder::default()
-                .header(compact_block.header.clone())
-                .uncles(compact_block.uncles.clone())
-                .commit_transactions(block_transactions.into_iter().map(|t| t.unwrap()).collect())
-                .proposal_transactions(compact_block.proposal_transactions.clone())
-                .build();
+    // A compact block keeps a single entry for the peer
+    pub fn within_pending_budget(&self, peer: PeerIndex) -> bool {
+        self.pending_budget(peer) > 0
+    }
 
-            (Some(block), missing_indexes)
+    // The nonce salts the short ids of a compact block, an honest peer picks a fresh one for
+    // each block it announces. A peer reusing a recent one for another block could be
+    // aiming short id collisions at our pool, its compact block is rejected and the peer
+    // scored. Announcing the same block again with its nonce is fine
+    pub fn check_compact_block_nonce(&self, peer: PeerIndex, compact_block: &CompactBlock) -> bool {
+        let block_hash = compact_block.header.hash();
+        let mut compact_block_nonces = self.state.compact_block_nonces.lock();
+        let nonces = compact_block_nonces
+            .entry(peer)
+            .or_insert_with(|| LruCache::new(MAX_RECENT_COMPACT_BLOCK_NONCES, false));
+        let reused = nonces
+            .get_mut(&compact_block.nonce)
+            .map_or(false, |hash| *hash != block_hash);
+        if reused {
+            debug!(target: "relay", "peer={} reused nonce {} for block {:x}", peer, compact_block.nonce, block_hash);
+            self.peers.misbehavior(peer, 10);
+            false
         } else {
-            (None, missing_indexes)
+            nonces.insert(compact_block.nonce, block_hash);
+            true
+        }
+    }
+
+    // Ask the peer for the whole block over the sync protocol when the compact block can't
+    // be reconstructed, the block it answers with is checked against this header
+    pub fn request_full_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, header: &Header) {
+        let hash = header.hash();
+        debug!(target: "relay", "reconstruct block {:?} failed, fetch it in full from peer={}", hash, peer);
+        self.peers.compact_block_fallback(peer, header);
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_blocks(fbb, &[hash]);
+        fbb.finish(message, None);
+        let _ = nc.send_protocol(peer, SYNC_PROTOCOL_ID, fbb.finished_data().to_vec());
+    }
+
+    pub fn reconstruct_block(
+        &self,
+        compact_block: &CompactBlock,
+        transactions: Vec<Transaction>,
+    ) -> (Option<Block>, Vec<usize>) {
+        let (block, missing_indexes, _) =
+            self.reconstruct_block_with_sources(compact_block, transactions);
+        (block, missing_indexes)
+    }
+
+    // Same as reconstruct_block, also tells which transactions were reused from
+    // our pool and which were provided by the peer
+    pub fn reconstruct_block_with_sources(
+        &self,
+        compact_block: &CompactBlock,
+        transactions: Vec<Transaction>,
+    ) -> (Option<Block>, Vec<usize>, ReconstructSources) {
+        reconstruct_from(
+            compact_block,
+            transactions,
+            self.pool_transactions_for(compact_block),
+        )
+    }
+
+    // The pool transactions the compact block may contain. A small block against a large
+    // pool only takes the transactions matching its short ids, sparing a copy of the
+    // whole pool
+    fn pool_transactions_for(&self, compact_block: &CompactBlock) -> Vec<Transaction> {
+        let ratio = self.config.targeted_reconstruct_pool_ratio;
+        if ratio == 0
+            || compact_block.short_ids.len().saturating_mul(ratio) >= self.tx_pool.pool_size()
+        {
+            return self.tx_pool.get_potential_transactions();
         }
+        let (key0, key1) = short_transaction_id_keys(
+            compact_block.version,
+            &compact_block.header,
+            compact_block.nonce,
+        );
+        let short_ids = compact_block
+            .short_ids
+            .iter()
+            .cloned()
+            .collect::<FnvHashSet<_>>();
+        self.tx_pool
+            .get_matching_transactions(Box::new(move |hash: &H256| {
+                short_ids.contains(&short_transaction_id(key0, key1, hash))
+            }))
+    }
+
+    // First reconstruction pass of a compact block from our pool, the returned slots are
+    // kept with the pending block when some of its transactions are missing
+    pub fn reconstruct_block_slots(
+        &self,
+        compact_block: &CompactBlock,
+    ) -> (Option<Block>, Vec<usize>, Vec<TransactionSlot>) {
+        let block_transactions = place_transactions(
+            compact_block,
+            Vec::new(),
+            self.pool_transactions_for(compact_block),
+        );
+        let (block, missing_indexes, _) = assemble_block(compact_block, &block_transactions);
+        (block, missing_indexes, block_transactions)
+    }
+
+    // Second pass with the missing transactions sent by the peer, they only fill the slots
+    // left empty by the first one so our pool isn't scanned again
+    pub fn reconstruct_pending_block(
+        &self,
+        compact_block: &CompactBlock,
+        block_transactions: Vec<TransactionSlot>,
+        transactions: Vec<Transaction>,
+    ) -> (Option<Block>, Vec<usize>) {
+        let block_transactions = fill_missing(compact_block, block_transactions, transactions);
+        let (block, missing_indexes, _) = assemble_block(compact_block, &block_transactions);
+        (block, missing_indexes)
     }
 
     fn prune_tx_proposal_request(&self, nc: &CKBProtocolContext) {
@@ -254,36 +1076,1509 @@ where
 {
     fn initialize(&self, nc: Box<CKBProtocolContext>) {
         let _ = nc.register_timer(TX_PROPOSAL_TOKEN, Duration::from_millis(100));
+        let _ = nc.register_timer(
+            TX_ANNOUNCEMENT_TOKEN,
+            Duration::from_millis(TX_ANNOUNCEMENT_INTERVAL),
+        );
+        let _ = nc.register_timer(
+            BLOCK_TRANSACTIONS_TIMEOUT_TOKEN,
+            Duration::from_millis(BLOCK_TRANSACTIONS_TIMEOUT_CHECK_INTERVAL),
+        );
+        let _ = nc.register_timer(
+            MEMPOOL_RECONCILIATION_TOKEN,
+            Duration::from_millis(MEMPOOL_RECONCILIATION_INTERVAL),
+        );
+        self.start_chain_listener(nc);
     }
 
     fn received(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex, data: &[u8]) {
         // TODO use flatbuffers verifier
         let msg = get_root::<RelayMessage>(data);
-        debug!(target: "relay", "msg {:?}", msg.payload_type());
+        debug!(
+            target: "relay",
+            "{} received",
+            LogContext::new(peer, enum_name_relay_payload(msg.payload_type()))
+        );
         self.process(nc.as_ref(), peer, msg);
     }
 
-    fn connected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
+    fn connected(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "relay", "peer={} RelayProtocol.connected", peer);
-        // do nothing
+        // the peer sends us the compact blocks of the latest version we understand
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block_version(fbb, COMPACT_BLOCK_VERSION);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+        // and how it announces new blocks to us
+        let mode = if self.config.inventory_announcements {
+            AnnouncementMode::Inventory
+        } else {
+            AnnouncementMode::CompactBlock
+        };
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_announcement_preference(fbb, mode);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+        self.resume_restored_compact_blocks(nc.as_ref(), peer);
     }
 
     fn disconnected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "relay", "peer={} RelayProtocol.disconnected", peer);
-        // TODO
+        self.state.send_failures.lock().remove(&peer);
+        self.state.pending_announcements.lock().remove(&peer);
+        self.state.compact_block_nonces.lock().remove(&peer);
+        // the missing transactions of the pending compact blocks only it announced will
+        // never come, the others are asked from another announcer on the next timeout
+        self.state
+            .pending_compact_blocks
+            .write()
+            .retain(|_, pending| {
+                pending.announcers.retain(|announcer| *announcer != peer);
+                if pending.requested_from == peer {
+                    pending.requested_at = 0;
+                }
+                match pending.announcers.first() {
+                    Some(announcer) => {
+                        if pending.peer == peer {
+                            pending.peer = *announcer;
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            });
     }
 
     fn timer_triggered(&self, nc: Box<CKBProtocolContext>, token: TimerToken) {
         match token as usize {
             TX_PROPOSAL_TOKEN => self.prune_tx_proposal_request(nc.as_ref()),
+            TX_ANNOUNCEMENT_TOKEN => self.flush_transaction_announcements(nc.as_ref()),
+            BLOCK_TRANSACTIONS_TIMEOUT_TOKEN => {
+                self.request_timed_out_block_transactions(nc.as_ref())
+            }
+            MEMPOOL_RECONCILIATION_TOKEN => self.send_mempool_sketches(nc.as_ref()),
             _ => unreachable!(),
         }
     }
+
+    // Transactions are shed first under load, peers announce them again and they are
+    // fetched back once the node catches up, the compact blocks are what keeps us in sync
+    fn task_priority(&self, data: &[u8]) -> TaskPriority {
+        match get_root::<RelayMessage>(data).payload_type() {
+            RelayPayload::Transaction
+            | RelayPayload::TransactionInventory
+            | RelayPayload::GetTransactions
+            | RelayPayload::MempoolSketch => TaskPriority::Low,
+            _ => TaskPriority::High,
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct RelayState {
-    pub pending_compact_blocks: RwLock<FnvHashMap<H256, CompactBlock>>,
+    pub pending_compact_blocks: RwLock<FnvHashMap<H256, PendingCompactBlock>>,
     pub inflight_proposals: Mutex<FnvHashSet<ProposalShortId>>,
     pub pending_proposals_request: Mutex<FnvHashMap<ProposalShortId, FnvHashSet<PeerIndex>>>,
+    pub send_failures: Mutex<FnvHashMap<PeerIndex, u32>>,
+    pub received_blocks: Mutex<LruCache<H256, ()>>,
+    pub recently_confirmed: Mutex<LruCache<H256, ()>>,
+    pub recently_relayed: Mutex<LruCache<H256, ()>>,
+    // hashes of the transactions to announce to each peer on the next flush
+    pub pending_announcements: Mutex<FnvHashMap<PeerIndex, Vec<H256>>>,
+    // announced transactions we asked for, with the time they were asked
+    pub inflight_transactions: Mutex<LruCache<H256, u64>>,
+    // pending compact blocks loaded after a restart, waiting for a peer to ask
+    pub restored_compact_blocks: Mutex<Vec<(CompactBlock, Vec<TransactionSlot>)>>,
+    // block hashes by the nonce of the recent compact blocks of each peer
+    pub compact_block_nonces: Mutex<FnvHashMap<PeerIndex, LruCache<u64, H256>>>,
+}
+
+impl Default for RelayState {
+    fn default() -> Self {
+        RelayState {
+            pending_compact_blocks: RwLock::new(FnvHashMap::default()),
+            inflight_proposals: Mutex::new(FnvHashSet::default()),
+            pending_proposals_request: Mutex::new(FnvHashMap::default()),
+            send_failures: Mutex::new(FnvHashMap::default()),
+            received_blocks: Mutex::new(LruCache::new(MAX_RECEIVED_BLOCKS, false)),
+            recently_confirmed: Mutex::new(LruCache::new(MAX_RECENTLY_CONFIRMED_TXS, false)),
+            recently_relayed: Mutex::new(LruCache::new(MAX_RECENTLY_RELAYED_TXS, false)),
+            pending_announcements: Mutex::new(FnvHashMap::default()),
+            inflight_transactions: Mutex::new(LruCache::new(MAX_INFLIGHT_TX_REQUESTS, false)),
+            restored_compact_blocks: Mutex::new(Vec::new()),
+            compact_block_nonces: Mutex::new(FnvHashMap::default()),
+        }
+    }
+}
+
+fn reconstruct_from(
+    compact_block: &CompactBlock,
+    transactions: Vec<Transaction>,
+    pool_transactions: Vec<Transaction>,
+) -> (Option<Block>, Vec<usize>, ReconstructSources) {
+    let block_transactions = place_transactions(compact_block, transactions, pool_transactions);
+    assemble_block(compact_block, &block_transactions)
+}
+
+// Places the transactions into the slots of the compact block by their short ids, the
+// prefilled ones at their indexes
+fn place_transactions(
+    compact_block: &CompactBlock,
+    transactions: Vec<Transaction>,
+    pool_transactions: Vec<Transaction>,
+) -> Vec<TransactionSlot> {
+    let (key0, key1) = short_transaction_id_keys(
+        compact_block.version,
+        &compact_block.header,
+        compact_block.nonce,
+    );
+
+    // transactions provided by the peer take precedence over the pool copies
+    let mut txs_map = FnvHashMap::default();
+    let pool_txs = pool_transactions.into_iter().map(|tx| (tx, true));
+    let peer_txs = transactions.into_iter().map(|tx| (tx, false));
+    for (tx, from_pool) in pool_txs.chain(peer_txs) {
+        let short_id = short_transaction_id(key0, key1, &tx.hash());
+        txs_map.insert(short_id, (tx, from_pool));
+    }
+
+    let short_ids_iter = &mut compact_block.short_ids.iter();
+    let mut block_transactions = Vec::with_capacity(
+        compact_block.prefilled_transactions.len() + compact_block.short_ids.len(),
+    );
+
+    // fill transactions gap
+    compact_block.prefilled_transactions.iter().for_each(|pt| {
+        let gap = pt.index - block_transactions.len();
+        if gap > 0 {
+            short_ids_iter
+                .take(gap)
+                .for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
+        }
+        block_transactions.push(Some((pt.transaction.clone(), false)));
+    });
+
+    // append remain transactions
+    short_ids_iter.for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
+    block_transactions
+}
+
+// Fills the slots left empty by place_transactions with the transactions sent by the peer
+fn fill_missing(
+    compact_block: &CompactBlock,
+    mut block_transactions: Vec<TransactionSlot>,
+    transactions: Vec<Transaction>,
+) -> Vec<TransactionSlot> {
+    let (key0, key1) = short_transaction_id_keys(
+        compact_block.version,
+        &compact_block.header,
+        compact_block.nonce,
+    );
+    let mut txs_map = transactions
+        .into_iter()
+        .map(|tx| (short_transaction_id(key0, key1, &tx.hash()), tx))
+        .collect::<FnvHashMap<_, _>>();
+    let prefilled = compact_block
+        .prefilled_transactions
+        .iter()
+        .map(|pt| pt.index)
+        .collect::<FnvHashSet<_>>();
+    let slots = (0..block_transactions.len()).filter(|i| !prefilled.contains(i));
+    for (i, short_id) in slots.zip(compact_block.short_ids.iter()) {
+        if block_transactions[i].is_none() {
+            block_transactions[i] = txs_map.remove(short_id).map(|tx| (tx, false));
+        }
+    }
+    block_transactions
+}
+
+fn assemble_block(
+    compact_block: &CompactBlock,
+    block_transactions: &[TransactionSlot],
+) -> (Option<Block>, Vec<usize>, ReconstructSources) {
+    let mut sources = ReconstructSources::default();
+    let mut missing_indexes = Vec::new();
+    for (i, t) in block_transactions.iter().enumerate() {
+        match t {
+            Some((tx, true)) => sources.pool.push(tx.hash()),
+            Some((tx, false)) => sources.peer.push(tx.hash()),
+            None => missing_indexes.push(i),
+        }
+    }
+
+    if missing_indexes.is_empty() {
+        // A short id collision or a bogus prefilled transaction gives a block which doesn't
+        // match its header, report it as a failure with nothing missing
+        let commits = block_transactions
+            .iter()
+            .map(|t| t.as_ref().unwrap().0.hash())
+            .collect::<Vec<_>>();
+        if compact_block.header.txs_commit() != &merkle_root(&commits[..]) {
+            debug!(target: "relay", "reconstructed block {:x} mismatches txs_commit", compact_block.header.hash());
+            return (None, missing_indexes, sources);
+        }
+
+        let block = BlockBuilder::default()
+            .header(compact_block.header.clone())
+            .uncles(compact_block.uncles.clone())
+            .commit_transactions(
+                block_transactions
+                    .iter()
+                    .map(|t| t.as_ref().unwrap().0.clone())
+                    .collect(),
+            )
+            .proposal_transactions(compact_block.proposal_transactions.clone())
+            .build();
+
+        (Some(block), missing_indexes, sources)
+    } else {
+        (None, missing_indexes, sources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Config, Synchronizer, MAX_BLOCKS_TO_ANNOUNCE, MAX_FILTER_SIZE, MAX_GET_TRANSACTIONS,
+        MAX_SKETCH_DECODE_FAILURES, MAX_TRANSACTION_INVENTORY_LEN,
+    };
+    use ckb_chain_spec::consensus::Consensus;
+    use ckb_core::header::HeaderBuilder;
+    use ckb_core::transaction::{CellOutput, IndexTransaction, TransactionBuilder};
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use ckb_network::{
+        random_peer_id, Endpoint, Error as NetworkError, ErrorKind, PeerId, PeerInfo, ProtocolId,
+        SessionInfo, ToMultiaddr,
+    };
+    use ckb_notify::NotifyService;
+    use ckb_pool::txs_pool::{PoolConfig, TransactionPoolService};
+    use ckb_protocol::{
+        Bytes as FbsBytes, CompactBlockBuilder, FlatbuffersVectorIterator, RelayMessageBuilder,
+        SyncMessage,
+    };
+    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::store::ChainKVStore;
+    use flatbuffers::WIPOffset;
+
+    #[derive(Default)]
+    struct MockNetworkContext {
+        connected: Vec<PeerIndex>,
+        broken: FnvHashSet<PeerIndex>,
+        sent: Mutex<Vec<(PeerIndex, Vec<u8>)>>,
+        reported: Mutex<Vec<PeerIndex>>,
+        sessions: FnvHashMap<PeerIndex, SessionInfo>,
+    }
+
+    impl MockNetworkContext {
+        fn new(connected: Vec<PeerIndex>) -> Self {
+            MockNetworkContext {
+                connected,
+                ..Default::default()
+            }
+        }
+
+        fn sent_to(&self, peer: PeerIndex) -> usize {
+            self.sent.lock().iter().filter(|(p, _)| *p == peer).count()
+        }
+
+        // the hashes of each transaction inventory sent to the peer
+        fn announced_to(&self, peer: PeerIndex) -> Vec<Vec<H256>> {
+            self.sent
+                .lock()
+                .iter()
+                .filter(|(p, _)| *p == peer)
+                .filter_map(|(_, data)| {
+                    get_root::<RelayMessage>(data)
+                        .payload_as_transaction_inventory()
+                        .map(|inventory| {
+                            inventory
+                                .transaction_hashes()
+                                .unwrap()
+                                .iter()
+                                .map(Into::into)
+                                .collect::<Vec<H256>>()
+                        })
+                })
+                .collect()
+        }
+    }
+
+    impl CKBProtocolContext for MockNetworkContext {
+        fn send(&self, peer: PeerIndex, data: Vec<u8>) -> Result<(), NetworkError> {
+            if self.broken.contains(&peer) {
+                return Err(ErrorKind::PeerNotFound.into());
+            }
+            self.sent.lock().push((peer, data));
+            Ok(())
+        }
+        fn send_protocol(
+            &self,
+            _peer: PeerIndex,
+            _protocol: ProtocolId,
+            _data: Vec<u8>,
+        ) -> Result<(), NetworkError> {
+            Ok(())
+        }
+        fn report_peer(&self, peer: PeerIndex, _reason: Severity) {
+            self.reported.lock().push(peer);
+        }
+        fn ban_peer(&self, _peer: PeerIndex, _duration: Duration) {}
+        fn disconnect(&self, _peer: PeerIndex) {}
+        fn register_timer(&self, _token: TimerToken, _delay: Duration) -> Result<(), NetworkError> {
+            Ok(())
+        }
+        fn session_info(&self, peer: PeerIndex) -> Option<SessionInfo> {
+            self.sessions.get(&peer).cloned()
+        }
+        fn protocol_version(&self, _peer: PeerIndex, _protocol: ProtocolId) -> Option<u8> {
+            None
+        }
+        fn protocol_id(&self) -> ProtocolId {
+            crate::RELAY_PROTOCOL_ID
+        }
+        fn connected_peers(&self) -> Vec<PeerIndex> {
+            self.connected.clone()
+        }
+        fn local_peer_id(&self) -> Option<PeerId> {
+            None
+        }
+    }
+
+    fn gen_synchronizer_and_relayer(
+        consensus: Consensus,
+    ) -> (
+        Synchronizer<ChainKVStore<MemoryKeyValueDB>>,
+        Relayer<ChainKVStore<MemoryKeyValueDB>>,
+    ) {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (chain_controller, _chain_receivers) = ChainController::build();
+        let (tx_pool_controller, _tx_pool_receivers) = TransactionPoolController::build();
+        let synchronizer =
+            Synchronizer::new(chain_controller.clone(), shared.clone(), Config::default());
+        let relayer = Relayer::new(
+            chain_controller,
+            shared,
+            tx_pool_controller,
+            synchronizer.peers(),
+            Arc::clone(&synchronizer.config),
+        );
+        (synchronizer, relayer)
+    }
+
+    fn gen_relayer() -> Relayer<ChainKVStore<MemoryKeyValueDB>> {
+        gen_synchronizer_and_relayer(Consensus::default()).1
+    }
+
+    // For the processes asking the pool, the one of gen_relayer has no service behind it
+    fn gen_relayer_with_pool() -> Relayer<ChainKVStore<MemoryKeyValueDB>> {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(Consensus::default())
+            .build();
+        let (chain_controller, _chain_receivers) = ChainController::build();
+        let (tx_pool_controller, tx_pool_receivers) = TransactionPoolController::build();
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let tx_pool_service =
+            TransactionPoolService::new(PoolConfig::default(), shared.clone(), notify);
+        let _handle = tx_pool_service.start::<&str>(None, tx_pool_receivers);
+        let synchronizer =
+            Synchronizer::new(chain_controller.clone(), shared.clone(), Config::default());
+        Relayer::new(
+            chain_controller,
+            shared,
+            tx_pool_controller,
+            synchronizer.peers(),
+            Arc::clone(&synchronizer.config),
+        )
+    }
+
+    fn send_sync_message<F>(
+        synchronizer: &Synchronizer<ChainKVStore<MemoryKeyValueDB>>,
+        peer: PeerIndex,
+        build: F,
+    ) where
+        F: for<'b> FnOnce(&mut FlatBufferBuilder<'b>) -> WIPOffset<SyncMessage<'b>>,
+    {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = build(fbb);
+        fbb.finish(message, None);
+        synchronizer.received(
+            Box::new(MockNetworkContext::default()),
+            peer,
+            fbb.finished_data(),
+        );
+    }
+
+    fn gen_transaction(lock: H256) -> Transaction {
+        TransactionBuilder::default()
+            .output(CellOutput::new(0, Vec::new(), lock, None))
+            .build()
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_pending_compact_blocks_limit() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let relayer = gen_relayer();
+        let max = relayer.config.max_pending_compact_blocks;
+        let max_per_peer = relayer.config.max_pending_compact_blocks_per_peer;
+        let mut timestamp = 0;
+        let mut insert = |peer: PeerIndex| {
+            timestamp += 1;
+            faketime::write_millis(&faketime_file, timestamp).expect("write millis");
+            let compact_block = CompactBlock {
+                header: HeaderBuilder::default().nonce(timestamp).build(),
+                uncles: Vec::new(),
+                nonce: 0,
+                short_ids: Vec::new(),
+                prefilled_transactions: Vec::new(),
+                proposal_transactions: Vec::new(),
+                version: 0,
+            };
+            let hash = compact_block.header.hash();
+            relayer.insert_pending_compact_block(
+                &mut relayer.state.pending_compact_blocks.write(),
+                peer,
+                compact_block,
+                Vec::new(),
+            );
+            hash
+        };
+
+        // a single peer only replaces its own oldest pending block
+        let first = insert(0);
+        let second = insert(0);
+        for _ in 2..=max_per_peer {
+            insert(0);
+        }
+        {
+            let pending_compact_blocks = relayer.state.pending_compact_blocks.read();
+            assert_eq!(pending_compact_blocks.len(), max_per_peer);
+            assert!(!pending_compact_blocks.contains_key(&first));
+        }
+
+        let mut peer = 1;
+        while relayer.state.pending_compact_blocks.read().len() < max {
+            for _ in 0..max_per_peer {
+                insert(peer);
+            }
+            peer += 1;
+        }
+        assert!(relayer
+            .state
+            .pending_compact_blocks
+            .read()
+            .contains_key(&second));
+
+        let last = insert(peer);
+        let pending_compact_blocks = relayer.state.pending_compact_blocks.read();
+        assert_eq!(pending_compact_blocks.len(), max);
+        assert!(!pending_compact_blocks.contains_key(&second));
+        assert!(pending_compact_blocks.contains_key(&last));
+    }
+
+    #[test]
+    fn test_reconstruct_block_sources() {
+        let transactions = (0..4u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let header = HeaderBuilder::default()
+            .nonce(1)
+            .txs_commit(merkle_root(
+                &transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+            ))
+            .build();
+        let nonce = 2;
+        let (key0, key1) = short_transaction_id_keys(0, &header, nonce);
+        let compact_block = CompactBlock {
+            header,
+            uncles: Vec::new(),
+            nonce,
+            short_ids: transactions[1..]
+                .iter()
+                .map(|tx| short_transaction_id(key0, key1, &tx.hash()))
+                .collect(),
+            prefilled_transactions: vec![IndexTransaction {
+                index: 0,
+                transaction: transactions[0].clone(),
+            }],
+            proposal_transactions: Vec::new(),
+            version: 0,
+        };
+        let unrelated = gen_transaction(H256::zero());
+
+        let (block, missing_indexes, sources) = reconstruct_from(
+            &compact_block,
+            vec![transactions[2].clone()],
+            vec![
+                transactions[1].clone(),
+                unrelated,
+                transactions[2].clone(),
+                transactions[3].clone(),
+            ],
+        );
+
+        assert!(missing_indexes.is_empty());
+        assert_eq!(block.unwrap().commit_transactions(), &transactions[..]);
+        assert_eq!(
+            sources.pool,
+            vec![transactions[1].hash(), transactions[3].hash()]
+        );
+        assert_eq!(
+            sources.peer,
+            vec![transactions[0].hash(), transactions[2].hash()]
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_pending_block_reuses_slots() {
+        let transactions = (0..4u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let header = HeaderBuilder::default()
+            .nonce(1)
+            .txs_commit(merkle_root(
+                &transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+            ))
+            .build();
+        let nonce = 2;
+        let (key0, key1) = short_transaction_id_keys(0, &header, nonce);
+        let compact_block = CompactBlock {
+            header,
+            uncles: Vec::new(),
+            nonce,
+            short_ids: transactions[1..]
+                .iter()
+                .map(|tx| short_transaction_id(key0, key1, &tx.hash()))
+                .collect(),
+            prefilled_transactions: vec![IndexTransaction {
+                index: 0,
+                transaction: transactions[0].clone(),
+            }],
+            proposal_transactions: Vec::new(),
+            version: 0,
+        };
+
+        let block_transactions = place_transactions(
+            &compact_block,
+            Vec::new(),
+            vec![transactions[1].clone(), transactions[3].clone()],
+        );
+        let (block, missing_indexes, _) = assemble_block(&compact_block, &block_transactions);
+        assert!(block.is_none());
+        assert_eq!(missing_indexes, vec![2]);
+
+        // the pool of gen_relayer has no service behind it, scanning it again would panic
+        let relayer = gen_relayer();
+        let (block, missing_indexes) = relayer.reconstruct_pending_block(
+            &compact_block,
+            block_transactions.clone(),
+            vec![transactions[2].clone()],
+        );
+        assert!(missing_indexes.is_empty());
+        assert_eq!(block.unwrap().commit_transactions(), &transactions[..]);
+
+        // the cached transactions are still attributed to the pool
+        let block_transactions = fill_missing(
+            &compact_block,
+            block_transactions,
+            vec![transactions[2].clone()],
+        );
+        let (_, _, sources) = assemble_block(&compact_block, &block_transactions);
+        assert_eq!(
+            sources.pool,
+            vec![transactions[1].hash(), transactions[3].hash()]
+        );
+        assert_eq!(
+            sources.peer,
+            vec![transactions[0].hash(), transactions[2].hash()]
+        );
+    }
+
+    #[test]
+    fn test_prefill_strategy() {
+        let mut relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0, 1]);
+        let relayed = gen_transaction(H256::from_trimmed_hex_str("1").unwrap());
+        let large = TransactionBuilder::default()
+            .output(CellOutput::new(
+                0,
+                vec![0; relayer.config.prefill_large_transaction_size],
+                H256::zero(),
+                None,
+            ))
+            .build();
+        let block = BlockBuilder::default()
+            .commit_transactions(vec![
+                gen_transaction(H256::zero()),
+                relayed.clone(),
+                large,
+                gen_transaction(H256::from_trimmed_hex_str("2").unwrap()),
+            ])
+            .build();
+        relayer.relay_transaction(&nc, 0, &relayed);
+
+        for (prefill_strategy, expected) in vec![
+            (PrefillStrategy::Cellbase, vec![0]),
+            (PrefillStrategy::RecentlyBroadcast, vec![0, 1]),
+            (PrefillStrategy::LargeTransactions, vec![0, 2]),
+        ] {
+            let mut config = Config::default();
+            config.prefill_strategy = prefill_strategy;
+            relayer.config = Arc::new(config);
+            assert_eq!(
+                relayer.prefilled_transactions_indexes(&block),
+                expected.into_iter().collect::<HashSet<_>>(),
+                "{:?}",
+                prefill_strategy
+            );
+        }
+    }
+
+    #[test]
+    fn test_short_id_for() {
+        let transactions = (0..4u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let block = BlockBuilder::default()
+            .header(HeaderBuilder::default().nonce(1).build())
+            .commit_transactions(transactions.clone())
+            .build();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
+        fbb.finish(message, None);
+        let compact_block: CompactBlock = get_root::<RelayMessage>(fbb.finished_data())
+            .payload_as_compact_block()
+            .unwrap()
+            .into();
+
+        assert_eq!(
+            compact_block.short_ids,
+            transactions[1..]
+                .iter()
+                .map(|tx| compact_block.short_id_for(tx))
+                .collect::<Vec<_>>()
+        );
+        let unrelated = gen_transaction(H256::zero());
+        assert!(!compact_block
+            .short_ids
+            .contains(&compact_block.short_id_for(&unrelated)));
+    }
+
+    #[test]
+    fn test_task_priority() {
+        let relayer = gen_relayer();
+        let tx = gen_transaction(H256::zero());
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_transaction(fbb, &tx);
+        fbb.finish(message, None);
+        assert_eq!(
+            relayer.task_priority(fbb.finished_data()),
+            TaskPriority::Low
+        );
+
+        let block = BlockBuilder::default().commit_transaction(tx).build();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
+        fbb.finish(message, None);
+        assert_eq!(
+            relayer.task_priority(fbb.finished_data()),
+            TaskPriority::High
+        );
+    }
+
+    #[test]
+    fn test_relay_whitelist() {
+        let (synchronizer, mut relayer) = gen_synchronizer_and_relayer(Consensus::default());
+        let mut nc = MockNetworkContext::new(vec![0, 1, 2]);
+        let session = SessionInfo {
+            peer: PeerInfo {
+                peer_id: random_peer_id().unwrap(),
+                endpoint_role: Endpoint::Listener,
+                last_ping_time: None,
+                connected_addr: "/ip4/127.0.0.1".to_multiaddr().unwrap(),
+                identify_info: None,
+            },
+            protocol_version: None,
+        };
+        relayer.config = Arc::new(Config {
+            relay_whitelist: vec![session.peer.peer_id.to_base58()],
+            ..Config::default()
+        });
+        nc.sessions.insert(1, session);
+        let tx = gen_transaction(H256::from_trimmed_hex_str("1").unwrap());
+
+        // the empty filters of peers 1 and 2 match nothing
+        for peer in 1..=2 {
+            send_sync_message(&synchronizer, peer, |fbb| {
+                SyncMessage::build_set_filter(fbb, &[0; 1024], 3, 1)
+            });
+        }
+        relayer.relay_transaction(&nc, 0, &tx);
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.announced_to(1), vec![vec![tx.hash()]]);
+        assert!(nc.announced_to(2).is_empty());
+
+        // still not sent back to where it came from
+        relayer.relay_transaction(&nc, 1, &tx);
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.announced_to(1).len(), 1);
+    }
+
+    #[test]
+    fn test_compact_block_versions() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0]);
+        let transactions = (0..4u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let block = BlockBuilder::default()
+            .commit_transactions(transactions.clone())
+            .with_header_builder(HeaderBuilder::default().nonce(1));
+
+        let build = |version| {
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), version);
+            fbb.finish(message, None);
+            fbb.finished_data().to_vec()
+        };
+        let mut short_ids = Vec::new();
+        for version in 0..=COMPACT_BLOCK_VERSION {
+            let data = build(version);
+            let compact_block: CompactBlock = get_root::<RelayMessage>(&data)
+                .payload_as_compact_block()
+                .unwrap()
+                .into();
+            assert_eq!(compact_block.version, version);
+            let (reconstructed, missing_indexes, _) =
+                reconstruct_from(&compact_block, Vec::new(), transactions[1..].to_vec());
+            assert_eq!(reconstructed, Some(block.clone()));
+            assert!(missing_indexes.is_empty());
+            short_ids.push(compact_block.short_id_for(&transactions[1]));
+        }
+        // the short ids are derived differently by each version
+        assert_ne!(short_ids[0], short_ids[1]);
+
+        let data = build(COMPACT_BLOCK_VERSION + 1);
+        relayer.process(&nc, 0, get_root::<RelayMessage>(&data));
+        assert!(relayer.state.pending_compact_blocks.read().is_empty());
+        assert!(nc.sent.lock().is_empty());
+        assert!(relayer.get_block(&block.header().hash()).is_none());
+    }
+
+    #[test]
+    fn test_compact_block_version_negotiation() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0, 1, 2]);
+
+        // peer 1 understands versions we don't know yet, peer 2 never tells us
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block_version(fbb, COMPACT_BLOCK_VERSION + 1);
+        fbb.finish(message, None);
+        relayer.process(&nc, 1, get_root::<RelayMessage>(fbb.finished_data()));
+        assert_eq!(
+            relayer.peers.compact_block_version(1),
+            COMPACT_BLOCK_VERSION
+        );
+        assert_eq!(relayer.peers.compact_block_version(2), 0);
+
+        let block = BlockBuilder::default()
+            .commit_transaction(gen_transaction(H256::zero()))
+            .build();
+        relayer.announce_block(&nc, 0, &block);
+        let sent = nc.sent.lock();
+        let version = |peer| {
+            let (_, data) = sent.iter().find(|(p, _)| *p == peer).expect("announced");
+            get_root::<RelayMessage>(data)
+                .payload_as_compact_block()
+                .expect("compact block")
+                .version()
+        };
+        assert_eq!(version(1), COMPACT_BLOCK_VERSION);
+        assert_eq!(version(2), 0);
+    }
+
+    #[test]
+    fn test_respond_from_detached_task() {
+        let relayer = gen_relayer();
+        let genesis_hash = relayer.shared.block_hash(0).unwrap();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_transactions(fbb, &genesis_hash, &[]);
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+
+        // the network handles every message in a task of its own, the response is sent
+        // to the peer that asked, not tied to the message being handled
+        let nc = ::std::thread::spawn(move || {
+            let nc = MockNetworkContext::new(vec![0, 1, 2]);
+            relayer.process(&nc, 2, get_root::<RelayMessage>(&data));
+            nc
+        })
+        .join()
+        .expect("task");
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, 2);
+        let hash: H256 = get_root::<RelayMessage>(&sent[0].1)
+            .payload_as_block_transactions()
+            .expect("block transactions")
+            .hash()
+            .unwrap()
+            .into();
+        assert_eq!(hash, genesis_hash);
+    }
+
+    #[test]
+    fn test_relay_order() {
+        let relayer = gen_relayer();
+        let peers = vec![0, 1, 2, 3];
+        let nc = MockNetworkContext::new(peers.clone());
+        let relays = 400;
+        for _ in 0..relays {
+            relayer.relay(&nc, &peers, b"payload");
+        }
+
+        // each peer is the first to hear of about a quarter of the items
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), relays * peers.len());
+        let mut first = FnvHashMap::default();
+        for item in sent.chunks(peers.len()) {
+            *first.entry(item[0].0).or_insert(0) += 1;
+        }
+        for peer in peers {
+            let count = first.get(&peer).cloned().unwrap_or(0);
+            assert!(
+                count > 50 && count < 150,
+                "peer {} first {} times",
+                peer,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn test_relay_source() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0, 1, 2]);
+
+        // a local transaction reaches peer 0 too, whatever index a placeholder source had
+        let local = gen_transaction(H256::zero());
+        relayer.relay_transaction_from(&nc, RelaySource::Local, &local);
+        let received = gen_transaction(H256::from_trimmed_hex_str("1").unwrap());
+        relayer.relay_transaction_from(&nc, RelaySource::Peer(0), &received);
+        relayer.flush_transaction_announcements(&nc);
+
+        assert_eq!(nc.announced_to(0), vec![vec![local.hash()]]);
+        for peer in 1..3 {
+            assert_eq!(
+                nc.announced_to(peer),
+                vec![vec![local.hash(), received.hash()]]
+            );
+        }
+    }
+
+    #[test]
+    fn test_relay_send_failure() {
+        let relayer = gen_relayer();
+        let mut nc = MockNetworkContext::new(vec![0, 1, 2]);
+        nc.broken.insert(1);
+
+        for _ in 0..MAX_RELAY_SEND_FAILURES {
+            relayer.relay(&nc, &[0, 1, 2], b"payload");
+        }
+        assert_eq!(*nc.reported.lock(), vec![1]);
+
+        // peer 1 is skipped from now on, others keep receiving
+        relayer.relay(&nc, &[0, 1, 2], b"payload");
+        assert_eq!(
+            relayer.state.send_failures.lock().get(&1),
+            Some(&MAX_RELAY_SEND_FAILURES)
+        );
+        assert_eq!(nc.sent_to(0), MAX_RELAY_SEND_FAILURES as usize + 1);
+        assert_eq!(nc.sent_to(2), MAX_RELAY_SEND_FAILURES as usize + 1);
+        assert_eq!(nc.sent_to(1), 0);
+    }
+
+    #[test]
+    fn test_set_relay_enabled() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0, 1]);
+
+        relayer.relay(&nc, &[0, 1], b"payload");
+        assert_eq!(nc.sent.lock().len(), 2);
+
+        relayer.set_relay_enabled(false);
+        relayer.relay(&nc, &[0, 1], b"payload");
+        assert_eq!(nc.sent.lock().len(), 2);
+
+        relayer.set_relay_enabled(true);
+        relayer.relay(&nc, &[0, 1], b"payload");
+        assert_eq!(nc.sent.lock().len(), 4);
+    }
+
+    #[test]
+    fn test_relay_transaction_filter() {
+        let (synchronizer, relayer) = gen_synchronizer_and_relayer(Consensus::default());
+        let nc = MockNetworkContext::new(vec![0, 1, 2]);
+        let tx1 = gen_transaction(H256::from_trimmed_hex_str("1").unwrap());
+        let tx2 = gen_transaction(H256::from_trimmed_hex_str("2").unwrap());
+
+        // an empty filter matches nothing, peer 1 only asks for tx1
+        send_sync_message(&synchronizer, 1, |fbb| {
+            SyncMessage::build_set_filter(fbb, &[0; 1024], 3, 1)
+        });
+        synchronizer
+            .peers
+            .transaction_filters
+            .write()
+            .get_mut(&1)
+            .unwrap()
+            .insert(&tx1.hash());
+
+        relayer.relay_transaction(&nc, 0, &tx1);
+        relayer.relay_transaction(&nc, 0, &tx2);
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.sent_to(0), 0);
+        assert_eq!(nc.announced_to(1), vec![vec![tx1.hash()]]);
+        assert_eq!(nc.announced_to(2), vec![vec![tx1.hash(), tx2.hash()]]);
+
+        send_sync_message(&synchronizer, 1, SyncMessage::build_clear_filter);
+        relayer.relay_transaction(&nc, 0, &tx2);
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.announced_to(1)[1], vec![tx2.hash()]);
+        assert_eq!(nc.announced_to(2)[1], vec![tx2.hash()]);
+    }
+
+    #[test]
+    fn test_batched_transaction_announcements() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::new(vec![0, 1, 2]);
+        let transactions = (1..4u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i)).unwrap()))
+            .collect::<Vec<_>>();
+        let hashes = transactions
+            .iter()
+            .map(Transaction::hash)
+            .collect::<Vec<_>>();
+
+        // nothing is sent until the next flush
+        for tx in &transactions {
+            relayer.relay_transaction(&nc, 0, tx);
+        }
+        assert!(nc.sent.lock().is_empty());
+
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.sent_to(0), 0);
+        assert_eq!(nc.announced_to(1), vec![hashes.clone()]);
+        assert_eq!(nc.announced_to(2), vec![hashes]);
+
+        // the queues are emptied by the flush
+        relayer.flush_transaction_announcements(&nc);
+        assert_eq!(nc.sent.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_oversized_filter() {
+        let (synchronizer, _relayer) = gen_synchronizer_and_relayer(Consensus::default());
+
+        send_sync_message(&synchronizer, 1, |fbb| {
+            SyncMessage::build_set_filter(fbb, &[0; MAX_FILTER_SIZE + 1], 3, 1)
+        });
+        assert!(synchronizer
+            .peers
+            .transaction_filters
+            .read()
+            .get(&1)
+            .is_none());
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&1), Some(&100));
+
+        send_sync_message(&synchronizer, 2, |fbb| {
+            SyncMessage::build_set_filter(fbb, &[0; 8], 3, 1)
+        });
+        send_sync_message(&synchronizer, 2, |fbb| {
+            SyncMessage::build_add_filter(fbb, &[0; MAX_FILTER_SIZE + 1])
+        });
+        assert!(synchronizer
+            .peers
+            .transaction_filters
+            .read()
+            .get(&2)
+            .is_some());
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&2), Some(&100));
+    }
+
+    #[test]
+    fn test_block_transactions_response_size() {
+        let transactions = (0..3u64)
+            .map(|i| {
+                TransactionBuilder::default()
+                    .output(CellOutput::new(i, vec![0; 100], H256::zero(), None))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let genesis = BlockBuilder::default()
+            .commit_transactions(transactions.clone())
+            .with_header_builder(HeaderBuilder::default());
+        let (_synchronizer, mut relayer) =
+            gen_synchronizer_and_relayer(Consensus::default().set_genesis_block(genesis.clone()));
+        relayer.config = Arc::new(Config {
+            max_block_transactions_response_size: transactions[0].bytes_len() * 2,
+            ..Config::default()
+        });
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message =
+            RelayMessage::build_get_block_transactions(fbb, &genesis.header().hash(), &[0, 1, 2]);
+        fbb.finish(message, None);
+        let message = get_root::<RelayMessage>(fbb.finished_data());
+        let nc = MockNetworkContext::new(vec![0]);
+        GetBlockTransactionsProcess::new(
+            &message.payload_as_get_block_transactions().unwrap(),
+            &relayer,
+            0,
+            &nc,
+        )
+        .execute();
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let block_transactions = get_root::<RelayMessage>(&sent[0].1)
+            .payload_as_block_transactions()
+            .unwrap();
+        // a truncated response would only delay the fallback to the full block
+        assert_eq!(block_transactions.transactions().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_block_transactions_index_out_of_range() {
+        let transactions = (0..3u64)
+            .map(|i| {
+                TransactionBuilder::default()
+                    .output(CellOutput::new(i, Vec::new(), H256::zero(), None))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let genesis = BlockBuilder::default()
+            .commit_transactions(transactions.clone())
+            .with_header_builder(HeaderBuilder::default());
+        let (_synchronizer, relayer) =
+            gen_synchronizer_and_relayer(Consensus::default().set_genesis_block(genesis.clone()));
+
+        let request = |indexes: &[u32]| {
+            let fbb = &mut FlatBufferBuilder::new();
+            let message =
+                RelayMessage::build_get_block_transactions(fbb, &genesis.header().hash(), indexes);
+            fbb.finish(message, None);
+            let message = get_root::<RelayMessage>(fbb.finished_data());
+            let nc = MockNetworkContext::new(vec![0]);
+            GetBlockTransactionsProcess::new(
+                &message.payload_as_get_block_transactions().unwrap(),
+                &relayer,
+                0,
+                &nc,
+            )
+            .execute();
+
+            let sent = nc.sent.lock();
+            assert_eq!(sent.len(), 1);
+            FlatbuffersVectorIterator::new(
+                get_root::<RelayMessage>(&sent[0].1)
+                    .payload_as_block_transactions()
+                    .unwrap()
+                    .transactions()
+                    .unwrap(),
+            )
+            .map(Into::into)
+            .collect::<Vec<Transaction>>()
+        };
+
+        // the last index is served, the one equal to the transactions count is not
+        assert_eq!(request(&[2]), vec![transactions[2].clone()]);
+        assert!(request(&[0, 3]).is_empty());
+        assert!(request(&[u32::max_value()]).is_empty());
+    }
+
+    #[test]
+    fn test_pending_compact_blocks_dropped_on_disconnect() {
+        let relayer = gen_relayer();
+        let insert = |peer: PeerIndex, nonce: u64| {
+            let compact_block = CompactBlock {
+                header: HeaderBuilder::default().nonce(nonce).build(),
+                uncles: Vec::new(),
+                nonce: 0,
+                short_ids: Vec::new(),
+                prefilled_transactions: Vec::new(),
+                proposal_transactions: Vec::new(),
+                version: 0,
+            };
+            let hash = compact_block.header.hash();
+            relayer.insert_pending_compact_block(
+                &mut relayer.state.pending_compact_blocks.write(),
+                peer,
+                compact_block,
+                Vec::new(),
+            );
+            hash
+        };
+        let hash0 = insert(0, 0);
+        let hash1 = insert(1, 1);
+        // also announced by peer 1, which is asked next for the missing transactions
+        let hash2 = insert(0, 2);
+        relayer
+            .state
+            .pending_compact_blocks
+            .write()
+            .get_mut(&hash2)
+            .unwrap()
+            .announcers
+            .push(1);
+
+        relayer.disconnected(Box::new(MockNetworkContext::new(vec![1])), 0);
+
+        let pending_compact_blocks = relayer.state.pending_compact_blocks.read();
+        assert!(!pending_compact_blocks.contains_key(&hash0));
+        assert!(pending_compact_blocks.contains_key(&hash1));
+        let pending = &pending_compact_blocks[&hash2];
+        assert_eq!(pending.peer, 1);
+        assert_eq!(pending.requested_at, 0);
+        assert_eq!(pending.next_announcer(), Some(1));
+    }
+
+    #[test]
+    fn test_pending_compact_blocks_restored() {
+        let relayer = gen_relayer();
+        let transactions = (0..2u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let compact_block = CompactBlock {
+            header: HeaderBuilder::default().nonce(1).build(),
+            uncles: Vec::new(),
+            nonce: 0,
+            short_ids: vec![[0u8; 6], [1u8; 6]],
+            prefilled_transactions: Vec::new(),
+            proposal_transactions: Vec::new(),
+            version: 0,
+        };
+        let hash = compact_block.header.hash();
+        // the first transaction came from our pool, the second one is missing
+        relayer.insert_pending_compact_block(
+            &mut relayer.state.pending_compact_blocks.write(),
+            0,
+            compact_block.clone(),
+            vec![Some((transactions[0].clone(), true)), None],
+        );
+
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_pending_compact_blocks_restored")
+            .tempdir()
+            .unwrap();
+        let path = tmp_dir.path().join("pending_compact_blocks");
+        relayer.save_pending_compact_blocks(&path).unwrap();
+
+        let restarted = gen_relayer();
+        assert_eq!(restarted.load_pending_compact_blocks(&path).unwrap(), 1);
+        assert!(restarted.state.pending_compact_blocks.read().is_empty());
+
+        // the first peer connecting is asked for the missing transaction only
+        let nc = MockNetworkContext::new(vec![1]);
+        restarted.resume_restored_compact_blocks(&nc, 1);
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, 1);
+        let message = get_root::<RelayMessage>(&sent[0].1)
+            .payload_as_get_block_transactions()
+            .unwrap();
+        assert_eq!(H256::from(message.hash().unwrap()), hash);
+        assert_eq!(message.indexes().unwrap().safe_slice(), &[1]);
+
+        let pending_compact_blocks = restarted.state.pending_compact_blocks.read();
+        let pending = &pending_compact_blocks[&hash];
+        assert_eq!(pending.compact_block, compact_block);
+        assert_eq!(
+            pending.block_transactions,
+            vec![Some((transactions[0].clone(), true)), None]
+        );
+        assert_eq!(pending.announcers, vec![1]);
+        assert_eq!(pending.requested_from, 1);
+        assert!(restarted.state.restored_compact_blocks.lock().is_empty());
+    }
+
+    #[test]
+    fn test_reconstruct_block_txs_commit_mismatch() {
+        let transactions = (0..2u64)
+            .map(|i| gen_transaction(H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap()))
+            .collect::<Vec<_>>();
+        let header = HeaderBuilder::default()
+            .nonce(1)
+            .txs_commit(merkle_root(
+                &transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+            ))
+            .build();
+        let nonce = 2;
+        let (key0, key1) = short_transaction_id_keys(0, &header, nonce);
+        let compact_block = |prefilled: &Transaction| CompactBlock {
+            header: header.clone(),
+            uncles: Vec::new(),
+            nonce,
+            short_ids: vec![short_transaction_id(key0, key1, &transactions[1].hash())],
+            prefilled_transactions: vec![IndexTransaction {
+                index: 0,
+                transaction: prefilled.clone(),
+            }],
+            proposal_transactions: Vec::new(),
+            version: 0,
+        };
+
+        let (block, missing_indexes, _) = reconstruct_from(
+            &compact_block(&transactions[0]),
+            Vec::new(),
+            vec![transactions[1].clone()],
+        );
+        assert!(missing_indexes.is_empty());
+        assert!(block.is_some());
+
+        // a substituted prefilled transaction gives a block mismatching the header
+        let substituted = gen_transaction(H256::zero());
+        let (block, missing_indexes, _) = reconstruct_from(
+            &compact_block(&substituted),
+            Vec::new(),
+            vec![transactions[1].clone()],
+        );
+        assert!(missing_indexes.is_empty());
+        assert!(block.is_none());
+    }
+
+    #[test]
+    fn test_pending_requests_budget() {
+        let relayer = gen_relayer_with_pool();
+        let max = relayer.config.max_pending_requests_per_peer;
+        {
+            let mut pending_proposals_request = relayer.state.pending_proposals_request.lock();
+            for i in 0..max {
+                let mut inner = [0u8; 10];
+                inner[0] = i as u8;
+                inner[1] = (i >> 8) as u8;
+                pending_proposals_request
+                    .entry(ProposalShortId::new(inner))
+                    .or_insert_with(Default::default)
+                    .insert(0);
+            }
+        }
+        assert_eq!(relayer.pending_requests(0), max);
+        assert_eq!(relayer.pending_requests(1), 0);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_proposal(fbb, 1, &[]);
+        fbb.finish(message, None);
+        let message = get_root::<RelayMessage>(fbb.finished_data());
+        let get_block_proposal = message.payload_as_get_block_proposal().unwrap();
+        let nc = MockNetworkContext::new(vec![0, 1]);
+
+        GetBlockProposalProcess::new(&get_block_proposal, &relayer, 0, &nc).execute();
+        assert_eq!(nc.sent_to(0), 0);
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), Some(&10));
+
+        GetBlockProposalProcess::new(&get_block_proposal, &relayer, 1, &nc).execute();
+        assert_eq!(nc.sent_to(1), 1);
+        assert_eq!(relayer.peers.misbehavior.read().get(&1), None);
+
+        // a single request can't take more than the budget left
+        let unknown_ids = (0..max + 2)
+            .map(|i| {
+                let mut inner = [0xffu8; 10];
+                inner[0] = i as u8;
+                inner[1] = (i >> 8) as u8;
+                ProposalShortId::new(inner)
+            })
+            .collect::<Vec<_>>();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_proposal(fbb, 1, &unknown_ids);
+        fbb.finish(message, None);
+        let message = get_root::<RelayMessage>(fbb.finished_data());
+        let get_block_proposal = message.payload_as_get_block_proposal().unwrap();
+        GetBlockProposalProcess::new(&get_block_proposal, &relayer, 1, &nc).execute();
+        assert_eq!(relayer.pending_requests(1), max);
+    }
+
+    #[test]
+    fn test_compact_block_nonce_reuse() {
+        let relayer = gen_relayer();
+        let compact_block = |header_nonce: u64, nonce: u64| CompactBlock {
+            header: HeaderBuilder::default().nonce(header_nonce).build(),
+            uncles: Vec::new(),
+            nonce,
+            short_ids: Vec::new(),
+            prefilled_transactions: Vec::new(),
+            proposal_transactions: Vec::new(),
+            version: 0,
+        };
+
+        assert!(relayer.check_compact_block_nonce(0, &compact_block(1, 7)));
+        // the same block announced again
+        assert!(relayer.check_compact_block_nonce(0, &compact_block(1, 7)));
+        // another peer picked the same nonce by chance
+        assert!(relayer.check_compact_block_nonce(1, &compact_block(2, 7)));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), None);
+
+        // another block reusing the nonce
+        assert!(!relayer.check_compact_block_nonce(0, &compact_block(2, 7)));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), Some(&10));
+        assert!(relayer.check_compact_block_nonce(0, &compact_block(2, 8)));
+    }
+
+    #[test]
+    fn test_block_inventory_size() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::default();
+        let inventory = |len: usize| {
+            let hashes = (0..len)
+                .map(|i| H256::from_slice(&[i as u8; 32]).unwrap())
+                .collect::<Vec<_>>();
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = RelayMessage::build_block_inventory(fbb, &hashes);
+            fbb.finish(message, None);
+            fbb.finished_data().to_vec()
+        };
+
+        let data = inventory(MAX_BLOCKS_TO_ANNOUNCE);
+        relayer.process(&nc, 0, get_root::<RelayMessage>(&data));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), None);
+
+        let data = inventory(MAX_BLOCKS_TO_ANNOUNCE + 1);
+        relayer.process(&nc, 0, get_root::<RelayMessage>(&data));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), Some(&10));
+    }
+
+    #[test]
+    fn test_transaction_inventory_size() {
+        let relayer = gen_relayer_with_pool();
+        let nc = MockNetworkContext::default();
+        let hashes = |len: usize| {
+            (0..len)
+                .map(|i| {
+                    let mut hash = [0u8; 32];
+                    hash[0] = i as u8;
+                    hash[1] = (i >> 8) as u8;
+                    H256::from_slice(&hash).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message =
+            RelayMessage::build_transaction_inventory(fbb, &hashes(MAX_TRANSACTION_INVENTORY_LEN));
+        fbb.finish(message, None);
+        relayer.process(&nc, 0, get_root::<RelayMessage>(fbb.finished_data()));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), None);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_transaction_inventory(
+            fbb,
+            &hashes(MAX_TRANSACTION_INVENTORY_LEN + 1),
+        );
+        fbb.finish(message, None);
+        relayer.process(&nc, 1, get_root::<RelayMessage>(fbb.finished_data()));
+        assert_eq!(relayer.peers.misbehavior.read().get(&1), Some(&10));
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_transactions(fbb, &hashes(MAX_GET_TRANSACTIONS + 1));
+        fbb.finish(message, None);
+        relayer.process(&nc, 2, get_root::<RelayMessage>(fbb.finished_data()));
+        assert_eq!(relayer.peers.misbehavior.read().get(&2), Some(&10));
+    }
+
+    #[test]
+    fn test_sketch_decode_failures() {
+        let relayer = gen_relayer_with_pool();
+        let nc = MockNetworkContext::default();
+        let peer = 0;
+
+        // far more hashes than the cells can give back
+        let hashes = (0..100u64)
+            .map(|i| {
+                let mut hash = [0u8; 32];
+                for (j, byte) in hash.iter_mut().enumerate() {
+                    *byte = (i * 31 + j as u64 * 7) as u8;
+                }
+                H256::from_slice(&hash).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let sketch = MempoolSketch::from_hashes(30, &hashes);
+        let counts = sketch
+            .cells()
+            .iter()
+            .map(|cell| cell.count)
+            .collect::<Vec<_>>();
+        let key_sums = sketch
+            .cells()
+            .iter()
+            .map(|cell| H256::from_slice(&cell.key_sum).unwrap())
+            .collect::<Vec<_>>();
+        let check_sums = sketch
+            .cells()
+            .iter()
+            .map(|cell| cell.check_sum)
+            .collect::<Vec<_>>();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_mempool_sketch(fbb, &counts, &key_sums, &check_sums);
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+
+        relayer.process(&nc, peer, get_root::<RelayMessage>(&data));
+        let last_fallback = relayer
+            .peers
+            .sketch_decode_failures
+            .read()
+            .get(&peer)
+            .and_then(|(_, last_fallback)| *last_fallback);
+        assert!(last_fallback.is_some());
+
+        // only the first failure in the interval is answered with our pool
+        assert!(!relayer.peers.sketch_decode_failed(peer));
+        for _ in 2..MAX_SKETCH_DECODE_FAILURES {
+            relayer.process(&nc, peer, get_root::<RelayMessage>(&data));
+        }
+        assert_eq!(
+            relayer.peers.sketch_decode_failures.read().get(&peer),
+            Some(&(MAX_SKETCH_DECODE_FAILURES, last_fallback))
+        );
+        assert_eq!(relayer.peers.misbehavior.read().get(&peer), None);
+
+        relayer.process(&nc, peer, get_root::<RelayMessage>(&data));
+        assert_eq!(relayer.peers.misbehavior.read().get(&peer), Some(&10));
+
+        // a sketch decoding again starts the count over
+        relayer.peers.sketch_decoded(peer);
+        relayer.process(&nc, peer, get_root::<RelayMessage>(&data));
+        assert_eq!(relayer.peers.misbehavior.read().get(&peer), Some(&10));
+    }
+
+    #[test]
+    fn test_compact_block_malformed_short_ids() {
+        let relayer = gen_relayer();
+        let nc = MockNetworkContext::default();
+        let fbb = &mut FlatBufferBuilder::new();
+        let short_ids = [
+            FbsBytes::build(fbb, &[0u8; 6]),
+            FbsBytes::build(fbb, &[0u8; 5]),
+        ];
+        let short_ids = fbb.create_vector(&short_ids);
+        let mut builder = CompactBlockBuilder::new(fbb);
+        builder.add_short_ids(short_ids);
+        let compact_block = builder.finish();
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::CompactBlock);
+        builder.add_payload(compact_block.as_union_value());
+        let message = builder.finish();
+        fbb.finish(message, None);
+
+        relayer.process(&nc, 0, get_root::<RelayMessage>(fbb.finished_data()));
+        assert_eq!(relayer.peers.misbehavior.read().get(&0), Some(&10));
+        assert!(relayer.state.pending_compact_blocks.read().is_empty());
+    }
 }
@@ -0,0 +1,62 @@
+use crate::log_context::LogContext;
+use crate::relayer::Relayer;
+use crate::MAX_TRANSACTION_INVENTORY_LEN;
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{enum_name_relay_payload, RelayMessage, RelayPayload, TransactionInventory};
+use ckb_shared::index::ChainIndex;
+use flatbuffers::FlatBufferBuilder;
+use log::debug;
+
+pub struct TransactionInventoryProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a TransactionInventory<'a>,
+    relayer: &'a Relayer<CI>,
+    peer: PeerIndex,
+    log_context: LogContext,
+    nc: &'a CKBProtocolContext,
+}
+
+impl<'a, CI> TransactionInventoryProcess<'a, CI>
+where
+    CI: ChainIndex + 'static,
+{
+    pub fn new(
+        message: &'a TransactionInventory,
+        relayer: &'a Relayer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        TransactionInventoryProcess {
+            message,
+            relayer,
+            peer,
+            nc,
+            log_context: LogContext::new(
+                peer,
+                enum_name_relay_payload(RelayPayload::TransactionInventory),
+            ),
+        }
+    }
+
+    // Unknown transactions are asked from the announcing peer, unless already asked
+    // from another one which still has time to answer
+    pub fn execute(self) {
+        let transaction_hashes = self.message.transaction_hashes().unwrap();
+        if transaction_hashes.len() > MAX_TRANSACTION_INVENTORY_LEN {
+            debug!(target: "relay", "{} rejected, {} transactions announced at once", self.log_context, transaction_hashes.len());
+            self.relayer.peers.misbehavior(self.peer, 10);
+            return;
+        }
+        let unknown = self
+            .relayer
+            .unknown_transactions(transaction_hashes.iter().map(Into::into).collect());
+        if unknown.is_empty() {
+            return;
+        }
+
+        debug!(target: "relay", "{} request {} announced transactions", self.log_context, unknown.len());
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_transactions(fbb, &unknown);
+        fbb.finish(message, None);
+        let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+    }
+}
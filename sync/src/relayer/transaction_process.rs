@@ -1,14 +1,17 @@
+use crate::log_context::LogContext;
 use crate::relayer::Relayer;
 use ckb_core::transaction::Transaction;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{RelayMessage, Transaction as FbsTransaction};
+use ckb_pool::txs_pool::PoolError;
+use ckb_protocol::{enum_name_relay_payload, RelayPayload, Transaction as FbsTransaction};
 use ckb_shared::index::ChainIndex;
-use flatbuffers::FlatBufferBuilder;
+use log::debug;
 
 pub struct TransactionProcess<'a, CI: ChainIndex + 'a> {
     message: &'a FbsTransaction<'a>,
     relayer: &'a Relayer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
     nc: &'a CKBProtocolContext,
 }
 
@@ -27,29 +30,32 @@ where
             nc,
             relayer,
             peer,
+            log_context: LogContext::new(peer, enum_name_relay_payload(RelayPayload::Transaction)),
         }
     }
 
     pub fn execute(self) {
         let tx: Transaction = (*self.message).into();
-        if self.relayer.tx_pool.add_transaction(tx.clone()).is_ok() {
-            let fbb = &mut FlatBufferBuilder::new();
-            let message = RelayMessage::build_transaction(fbb, &tx);
-            fbb.finish(message, None);
-
-            for peer_id in self.nc.connected_peers() {
-                if peer_id != self.peer
-                    && self
-                        .relayer
-                        .peers()
-                        .transaction_filters
-                        .read()
-                        .get(&peer_id)
-                        .map_or(true, |filter| filter.contains(&tx))
-                {
-                    let _ = self.nc.send(peer_id, fbb.finished_data().to_vec());
-                }
+        self.relayer
+            .state
+            .inflight_transactions
+            .lock()
+            .remove(&tx.hash());
+        if self.relayer.is_confirmed(&tx.hash()) {
+            debug!(target: "relay", "{} ignored, already in a recent block", self.log_context.with_hash(&tx.hash()));
+            return;
+        }
+        match self.relayer.tx_pool.add_transaction(tx.clone()) {
+            Ok(_) => {
+                self.relayer.peers.useful_message_received(self.peer);
+                self.relayer.relay_transaction(self.nc, self.peer, &tx);
+            }
+            // large transactions could exhaust the resources of every node relaying them
+            Err(PoolError::ExceededMaximumSize) => {
+                debug!(target: "relay", "{} rejected, {} bytes exceed the maximum size", self.log_context.with_hash(&tx.hash()), tx.bytes_len());
+                self.relayer.peers.misbehavior(self.peer, 10);
             }
+            Err(_) => {}
         }
     }
 }
@@ -1,9 +1,6 @@
 use crate::synchronizer::{BlockStatus, Synchronizer};
 use crate::types::HeaderView;
-use crate::{
-    BLOCK_DOWNLOAD_TIMEOUT, BLOCK_DOWNLOAD_WINDOW, MAX_BLOCKS_IN_TRANSIT_PER_PEER,
-    PER_FETCH_BLOCK_LIMIT,
-};
+use crate::{BLOCK_DOWNLOAD_TIMEOUT, BLOCK_DOWNLOAD_WINDOW, PER_FETCH_BLOCK_LIMIT};
 use ckb_core::header::Header;
 use ckb_network::PeerIndex;
 use ckb_shared::index::ChainIndex;
@@ -33,18 +30,40 @@ where
         }
     }
     pub fn initial_and_check_inflight(&self) -> bool {
-        let mut blocks_inflight = self.synchronizer.peers.blocks_inflight.write();
-        let inflight = blocks_inflight
-            .entry(self.peer)
-            .or_insert_with(Default::default);
-
-        if inflight.timestamp < unix_time_as_millis().saturating_sub(BLOCK_DOWNLOAD_TIMEOUT) {
-            debug!(target: "sync", "[block downloader] inflight block download timeout");
-            inflight.clear();
+        let (timed_out, inflight_len) = {
+            let mut blocks_inflight = self.synchronizer.peers.blocks_inflight.write();
+            let inflight = blocks_inflight
+                .entry(self.peer)
+                .or_insert_with(Default::default);
+
+            let mut timed_out = Vec::new();
+            if inflight.timestamp < unix_time_as_millis().saturating_sub(BLOCK_DOWNLOAD_TIMEOUT) {
+                debug!(target: "sync", "[block downloader] inflight block download timeout");
+                timed_out = inflight.blocks.iter().cloned().collect();
+                inflight.clear();
+                // the blocks asked from now on get a full timeout of their own
+                inflight.update_timestamp();
+            }
+            (timed_out, inflight.len())
+        };
+        if !timed_out.is_empty() {
+            self.synchronizer.peers.block_download_timeout(self.peer);
+            let max_retries = self.synchronizer.config.max_block_request_retries;
+            for hash in &timed_out {
+                self.synchronizer
+                    .peers
+                    .block_request_timeout(hash, max_retries);
+            }
         }
 
         // current peer block blocks_inflight reach limit
-        if MAX_BLOCKS_IN_TRANSIT_PER_PEER.saturating_sub(inflight.len()) == 0 {
+        if self
+            .synchronizer
+            .config
+            .max_blocks_in_transit_per_peer
+            .saturating_sub(inflight_len)
+            == 0
+        {
             debug!(target: "sync", "[block downloader] inflight count reach limit");
             true
         } else {
@@ -171,13 +190,27 @@ where
         let max_height = cmp::min(window_end + 1, best_known_header.number());
 
         let mut n_height = fixed_last_common_header.number();
-        let mut v_fetch = Vec::with_capacity(PER_FETCH_BLOCK_LIMIT);
+        let mut v_fetch = Vec::new();
 
+        // Blocks in flight from any peer are skipped, so that each peer is asked the
+        // lowest heights nobody is downloading yet and parents mostly arrive before
+        // their children instead of piling up as orphans
         {
             let mut guard = self.synchronizer.peers.blocks_inflight.write();
-            let inflight = guard.get_mut(&self.peer).expect("inflight already init");
+            // only what is left of the window of the peer is asked
+            let inflight_len = guard.get(&self.peer).expect("inflight already init").len();
+            let fetch_limit = cmp::min(
+                cmp::min(
+                    PER_FETCH_BLOCK_LIMIT,
+                    self.synchronizer.config.max_blocks_per_getdata,
+                ),
+                self.synchronizer
+                    .config
+                    .max_blocks_in_transit_per_peer
+                    .saturating_sub(inflight_len),
+            );
 
-            while n_height < max_height && v_fetch.len() < PER_FETCH_BLOCK_LIMIT {
+            while n_height < max_height && v_fetch.len() < fetch_limit {
                 n_height += 1;
                 let to_fetch = try_option!(self
                     .synchronizer
@@ -186,8 +219,18 @@ where
 
                 let block_status = self.synchronizer.get_block_status(&to_fetch_hash);
                 if block_status == BlockStatus::VALID_MASK
-                    && inflight.insert(to_fetch_hash.clone().clone())
+                    && !guard
+                        .values()
+                        .any(|inflight| inflight.contains(&to_fetch_hash))
+                    && !self
+                        .synchronizer
+                        .peers
+                        .is_block_request_suspended(&to_fetch_hash)
                 {
+                    guard
+                        .get_mut(&self.peer)
+                        .expect("inflight already init")
+                        .insert(to_fetch_hash.clone());
                     debug!(
                         target: "sync", "[Synchronizer] inflight insert {:?}------------{:?}",
                         to_fetch.number(),
@@ -7,48 +7,127 @@ use std::collections::VecDeque;
 
 pub type ParentHash = H256;
 
-#[derive(Default)]
+// Orphans hanging off the same missing ancestor make up a chain, chains are bounded in
+// depth and evicted least recently added first once the pool holds too many blocks
 pub struct OrphanBlockPool {
-    blocks: RwLock<FnvHashMap<ParentHash, FnvHashSet<Block>>>,
+    inner: RwLock<Inner>,
+    limit: usize,
+    max_chain_depth: usize,
 }
 
-impl OrphanBlockPool {
-    pub fn with_capacity(capacity: usize) -> Self {
-        OrphanBlockPool {
-            blocks: RwLock::new(FnvHashMap::with_capacity_and_hasher(
-                capacity,
-                Default::default(),
-            )),
-        }
-    }
+struct Inner {
+    blocks: FnvHashMap<ParentHash, FnvHashSet<Block>>,
+    // the missing ancestor of the chain of each orphan, and its depth in that chain
+    chains: FnvHashMap<H256, (ParentHash, usize)>,
+    // missing ancestors of the chains, least recently added first
+    roots: VecDeque<ParentHash>,
+}
 
-    /// Insert orphaned block, for which we have already requested its parent block
-    pub fn insert(&self, block: Block) {
-        self.blocks
-            .write()
-            .entry(block.header().parent_hash().clone())
-            .or_insert_with(FnvHashSet::default)
-            .insert(block);
+impl Inner {
+    fn is_root(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash) && !self.chains.contains_key(hash)
     }
 
-    pub fn remove_blocks_by_parent(&self, hash: &H256) -> VecDeque<Block> {
-        let mut guard = self.blocks.write();
+    fn remove_blocks_by_parent(&mut self, hash: &H256) -> VecDeque<Block> {
         let mut queue: VecDeque<H256> = VecDeque::new();
         queue.push_back(hash.clone());
 
         let mut removed: VecDeque<Block> = VecDeque::new();
         while let Some(parent_hash) = queue.pop_front() {
-            if let Entry::Occupied(entry) = guard.entry(parent_hash) {
+            if let Entry::Occupied(entry) = self.blocks.entry(parent_hash) {
                 let (_, orphaned) = entry.remove_entry();
+                for block in &orphaned {
+                    self.chains.remove(block.header().hash());
+                }
                 queue.extend(orphaned.iter().map(|b| b.header().hash().clone()));
                 removed.extend(orphaned.into_iter());
             }
         }
+        self.roots.retain(|root| root != hash);
         removed
     }
+}
+
+impl OrphanBlockPool {
+    pub fn new(limit: usize, max_chain_depth: usize) -> Self {
+        OrphanBlockPool {
+            inner: RwLock::new(Inner {
+                blocks: FnvHashMap::with_capacity_and_hasher(limit, Default::default()),
+                chains: FnvHashMap::with_capacity_and_hasher(limit, Default::default()),
+                roots: VecDeque::new(),
+            }),
+            limit,
+            max_chain_depth,
+        }
+    }
+
+    /// Insert orphaned block, for which we have already requested its parent block.
+    /// Returns false when the block is not kept, its chain being too deep or evicted
+    pub fn insert(&self, block: Block) -> bool {
+        let mut inner = self.inner.write();
+        let hash = block.header().hash().clone();
+        if inner.chains.contains_key(&hash) {
+            return true;
+        }
+        let parent_hash = block.header().parent_hash().clone();
+        let (root, depth) = match inner.chains.get(&parent_hash) {
+            Some((root, depth)) => (root.clone(), depth + 1),
+            None => (parent_hash.clone(), 1),
+        };
+        if depth > self.max_chain_depth {
+            return false;
+        }
+
+        // the block is the missing ancestor of a chain we hold, which now hangs off the
+        // chain of the block, unless that makes it too deep
+        if inner.is_root(&hash) {
+            let height = inner
+                .chains
+                .values()
+                .filter(|(chain_root, _)| chain_root == &hash)
+                .map(|(_, depth)| *depth)
+                .max()
+                .unwrap_or(0);
+            if depth + height > self.max_chain_depth {
+                inner.remove_blocks_by_parent(&hash);
+            } else {
+                for (chain_root, chain_depth) in inner.chains.values_mut() {
+                    if chain_root == &hash {
+                        *chain_root = root.clone();
+                        *chain_depth += depth;
+                    }
+                }
+                inner.roots.retain(|chain_root| chain_root != &hash);
+            }
+        }
+
+        if depth == 1 && !inner.is_root(&root) {
+            inner.roots.push_back(root.clone());
+        }
+        inner
+            .blocks
+            .entry(parent_hash)
+            .or_insert_with(FnvHashSet::default)
+            .insert(block);
+        inner.chains.insert(hash.clone(), (root, depth));
+
+        while inner.chains.len() > self.limit {
+            match inner.roots.pop_front() {
+                Some(oldest) => {
+                    inner.remove_blocks_by_parent(&oldest);
+                }
+                None => break,
+            }
+        }
+        inner.chains.contains_key(&hash)
+    }
+
+    pub fn remove_blocks_by_parent(&self, hash: &H256) -> VecDeque<Block> {
+        self.inner.write().remove_blocks_by_parent(hash)
+    }
 
     pub fn len(&self) -> usize {
-        self.blocks.read().len()
+        self.inner.read().chains.len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -83,7 +162,7 @@ mod tests {
         let block_number = 200;
         let mut blocks: Vec<Block> = Vec::new();
         let mut parent = consensus.genesis_block().header().clone();
-        let pool = OrphanBlockPool::with_capacity(200);
+        let pool = OrphanBlockPool::new(200, 200);
         for _ in 1..block_number {
             let new_block = gen_block(parent);
             blocks.push(new_block.clone());
@@ -96,4 +175,57 @@ mod tests {
         let block: HashSet<Block> = HashSet::from_iter(blocks.into_iter());
         assert_eq!(orphan, block)
     }
+
+    fn gen_chain(parent_header: Header, len: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = parent_header;
+        for _ in 0..len {
+            let new_block = gen_block(parent);
+            parent = new_block.header().clone();
+            blocks.push(new_block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_orphan_chain_depth() {
+        // the parent of the chain is never received
+        let missing = HeaderBuilder::default().number(100).build();
+        let blocks = gen_chain(missing.clone(), 20);
+        let pool = OrphanBlockPool::new(100, 10);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(pool.insert(block.clone()), i < 10);
+        }
+        assert_eq!(pool.len(), 10);
+
+        // received the other way around, the chain hangs off each new block
+        let pool = OrphanBlockPool::new(100, 10);
+        for block in blocks.iter().rev() {
+            assert!(pool.insert(block.clone()));
+            assert!(pool.len() <= 10);
+        }
+        let orphan = pool.remove_blocks_by_parent(&missing.hash());
+        let orphan: HashSet<Block> = HashSet::from_iter(orphan.into_iter());
+        let block: HashSet<Block> = HashSet::from_iter(blocks.into_iter().take(10));
+        assert_eq!(orphan, block);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_evict_oldest_orphan_chain() {
+        let oldest = HeaderBuilder::default().number(100).nonce(1).build();
+        let newest = HeaderBuilder::default().number(200).nonce(2).build();
+        let pool = OrphanBlockPool::new(10, 10);
+        for block in gen_chain(oldest.clone(), 6) {
+            assert!(pool.insert(block));
+        }
+        for block in gen_chain(newest.clone(), 6) {
+            assert!(pool.insert(block));
+        }
+
+        assert_eq!(pool.len(), 6);
+        assert!(pool.remove_blocks_by_parent(&oldest.hash()).is_empty());
+        assert_eq!(pool.remove_blocks_by_parent(&newest.hash()).len(), 6);
+        assert!(pool.is_empty());
+    }
 }
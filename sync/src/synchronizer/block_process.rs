@@ -1,14 +1,45 @@
-use crate::synchronizer::Synchronizer;
+use crate::log_context::LogContext;
+use crate::synchronizer::{BlockStatus, Synchronizer};
+use ckb_chain::error::ProcessBlockError;
 use ckb_core::block::Block;
-use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::Block as PBlock;
+use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
+use ckb_protocol::{enum_name_sync_payload, Block as PBlock, DisconnectCode, SyncPayload};
 use ckb_shared::index::ChainIndex;
 use log::debug;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockProcessResult {
+    /// The block was accepted by the chain
+    Stored,
+    /// The block is already stored, nothing to do
+    AlreadyStored,
+    /// The parent is unknown, the block is held in the orphan pool
+    Orphan,
+    /// We never accepted the header of this block, or it was marked as failed
+    UnexpectedStatus(BlockStatus),
+    /// The chain rejected the block
+    Invalid(ProcessBlockError),
+    /// The block was asked in full after a compact block, but it is not the announced one
+    MismatchedCompactBlock,
+}
+
+impl BlockProcessResult {
+    /// Whether the peer which sent the block should be blamed for the result
+    pub fn is_misbehavior(&self) -> bool {
+        match self {
+            BlockProcessResult::Invalid(ProcessBlockError::Verification(_))
+            | BlockProcessResult::MismatchedCompactBlock => true,
+            _ => false,
+        }
+    }
+}
+
 pub struct BlockProcess<'a, CI: ChainIndex + 'a> {
     message: &'a PBlock<'a>,
     synchronizer: &'a Synchronizer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
+    nc: &'a CKBProtocolContext,
 }
 
 impl<'a, CI> BlockProcess<'a, CI>
@@ -19,20 +50,52 @@ where
         message: &'a PBlock,
         synchronizer: &'a Synchronizer<CI>,
         peer: PeerIndex,
-        _nc: &'a CKBProtocolContext,
+        nc: &'a CKBProtocolContext,
     ) -> Self {
         BlockProcess {
             message,
             synchronizer,
             peer,
+            nc,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::Block)),
         }
     }
 
-    pub fn execute(self) {
+    pub fn execute(self) -> BlockProcessResult {
         let block: Block = (*self.message).into();
-        debug!(target: "sync", "BlockProcess received block {} {:?}", block.header().number(), block.header().hash());
+        let log_context = self.log_context.with_hash(&block.header().hash());
+        debug!(target: "sync", "{} received block {}", log_context, block.header().number());
+
+        if !self
+            .synchronizer
+            .peers
+            .check_compact_block_fallback(self.peer, &block)
+        {
+            debug!(target: "sync", "{} sent a block other than the announced compact block", log_context);
+            self.synchronizer.peers.misbehavior(self.peer, 100);
+            self.nc.report_peer(
+                self.peer,
+                Severity::Bad("block mismatches the announced compact block"),
+            );
+            return BlockProcessResult::MismatchedCompactBlock;
+        }
 
         self.synchronizer.peers.block_received(self.peer, &block);
-        self.synchronizer.process_new_block(self.peer, block);
+        let result = self.synchronizer.process_new_block(self.peer, block);
+        if result == BlockProcessResult::Stored && self.synchronizer.check_ibd_finished() {
+            self.synchronizer.on_ibd_finished(self.nc);
+        }
+        if result.is_misbehavior() {
+            debug!(target: "sync", "{} sent invalid block {:?}", log_context, result);
+            if self.synchronizer.peers.invalid_block_received(self.peer) {
+                self.synchronizer.disconnect_peer(
+                    self.nc,
+                    self.peer,
+                    DisconnectCode::Misbehavior,
+                    Severity::Bad("served invalid blocks"),
+                );
+            }
+        }
+        result
     }
 }
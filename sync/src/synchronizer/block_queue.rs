@@ -0,0 +1,45 @@
+use super::block_process::BlockProcessResult;
+use ckb_core::block::Block;
+use ckb_core::header::BlockNumber;
+use ckb_network::PeerIndex;
+use fnv::FnvHashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Received blocks waiting for the block process lock, handed out lowest first so a parent
+// is processed before the children which arrived with it instead of orphaning them.
+// Whoever holds the lock processes the blocks of every waiting peer, the results are
+// kept here until each of them takes its own
+#[derive(Default)]
+pub struct BlockQueue {
+    next_id: u64,
+    // lowest height first, ties in arrival order
+    heap: BinaryHeap<Reverse<(BlockNumber, u64)>>,
+    blocks: FnvHashMap<u64, (PeerIndex, Block)>,
+    results: FnvHashMap<u64, BlockProcessResult>,
+}
+
+impl BlockQueue {
+    pub fn push(&mut self, peer: PeerIndex, block: Block) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.heap.push(Reverse((block.header().number(), id)));
+        self.blocks.insert(id, (peer, block));
+        id
+    }
+
+    pub fn pop(&mut self) -> Option<(u64, PeerIndex, Block)> {
+        let Reverse((_, id)) = self.heap.pop()?;
+        self.blocks
+            .remove(&id)
+            .map(|(peer, block)| (id, peer, block))
+    }
+
+    pub fn set_result(&mut self, id: u64, result: BlockProcessResult) {
+        self.results.insert(id, result);
+    }
+
+    pub fn take_result(&mut self, id: u64) -> Option<BlockProcessResult> {
+        self.results.remove(&id)
+    }
+}
@@ -0,0 +1,43 @@
+use super::Synchronizer;
+use channel::Receiver;
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::SyncMessage;
+use ckb_shared::index::ChainIndex;
+use flatbuffers::get_root;
+use log::debug;
+use std::thread::{self, JoinHandle};
+
+// A GetBlocks request left to the block server, it owns everything needed to answer
+// the peer once the handler which received it has returned
+pub struct ServeRequest<CI: ChainIndex> {
+    pub synchronizer: Synchronizer<CI>,
+    pub nc: Box<CKBProtocolContext>,
+    pub peer: PeerIndex,
+    pub data: Vec<u8>,
+}
+
+// Reads the requested blocks from the store and sends them on its own thread, so serving
+// peers doesn't hold back handling the headers and blocks we receive. Stops once every
+// sender is dropped
+pub fn start<CI, S>(thread_name: Option<S>, receiver: Receiver<ServeRequest<CI>>) -> JoinHandle<()>
+where
+    CI: ChainIndex + 'static,
+    S: ToString,
+{
+    let mut thread_builder = thread::Builder::new();
+    // Mainly for test: give a empty thread_name
+    if let Some(name) = thread_name {
+        thread_builder = thread_builder.name(name.to_string());
+    }
+    thread_builder
+        .spawn(move || {
+            for request in receiver.iter() {
+                let message = get_root::<SyncMessage>(&request.data);
+                request
+                    .synchronizer
+                    .process(request.nc.as_ref(), request.peer, message);
+            }
+            debug!(target: "sync", "block server stopped");
+        })
+        .expect("Start block server failed")
+}
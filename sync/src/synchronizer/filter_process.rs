@@ -1,13 +1,17 @@
+use crate::log_context::LogContext;
 use crate::synchronizer::Synchronizer;
 use crate::types::TransactionFilter;
+use crate::{MAX_FILTER_NUM_HASHES, MAX_FILTER_SIZE};
 use ckb_network::PeerIndex;
-use ckb_protocol::{AddFilter, SetFilter};
+use ckb_protocol::{enum_name_sync_payload, AddFilter, SetFilter, SyncPayload};
 use ckb_shared::index::ChainIndex;
+use log::debug;
 
 pub struct SetFilterProcess<'a, CI: ChainIndex + 'a> {
     message: &'a SetFilter<'a>,
     synchronizer: &'a Synchronizer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
 }
 
 impl<'a, CI> SetFilterProcess<'a, CI>
@@ -23,19 +27,24 @@ where
             peer,
             message,
             synchronizer,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::SetFilter)),
         }
     }
 
     pub fn execute(self) {
-        // TODO add filter size and num_hashes max value checking
+        let filter = self.message.filter().unwrap();
+        let num_hashes = self.message.num_hashes() as usize;
+        if filter.len() > MAX_FILTER_SIZE || num_hashes > MAX_FILTER_NUM_HASHES {
+            debug!(target: "sync", "{} sent oversized filter, size={} num_hashes={}", self.log_context, filter.len(), num_hashes);
+            self.synchronizer.peers.misbehavior(self.peer, 100);
+            return;
+        }
+
         let mut filters = self.synchronizer.peers.transaction_filters.write();
-        filters.entry(self.peer).or_insert_with(|| {
-            TransactionFilter::new(
-                self.message.filter().unwrap(),
-                self.message.num_hashes() as usize,
-                self.message.hash_seed() as usize,
-            )
-        });
+        filters.insert(
+            self.peer,
+            TransactionFilter::new(filter, num_hashes, self.message.hash_seed() as usize),
+        );
     }
 }
 
@@ -43,6 +52,7 @@ pub struct AddFilterProcess<'a, CI: ChainIndex + 'a> {
     message: &'a AddFilter<'a>,
     synchronizer: &'a Synchronizer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
 }
 
 impl<'a, CI> AddFilterProcess<'a, CI>
@@ -58,14 +68,22 @@ where
             peer,
             message,
             synchronizer,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::AddFilter)),
         }
     }
 
     pub fn execute(self) {
+        let filter = self.message.filter().unwrap();
+        if filter.len() > MAX_FILTER_SIZE {
+            debug!(target: "sync", "{} sent oversized filter, size={}", self.log_context, filter.len());
+            self.synchronizer.peers.misbehavior(self.peer, 100);
+            return;
+        }
+
         let mut filters = self.synchronizer.peers.transaction_filters.write();
         filters
             .entry(self.peer)
-            .and_modify(|filter| filter.update(self.message.filter().unwrap()));
+            .and_modify(|transaction_filter| transaction_filter.update(filter));
     }
 }
 
@@ -1,15 +1,21 @@
+use crate::log_context::LogContext;
 use crate::synchronizer::Synchronizer;
+use crate::{MAX_BLOCKS_TO_ANNOUNCE, RELAY_PROTOCOL_ID};
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{GetBlocks, SyncMessage};
+use ckb_protocol::{
+    enum_name_sync_payload, GetBlocks, InventoryType, RelayMessage, SyncMessage, SyncPayload,
+};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use log::debug;
+use std::collections::HashSet;
 
 pub struct GetBlocksProcess<'a, CI: ChainIndex + 'a> {
     message: &'a GetBlocks<'a>,
     synchronizer: &'a Synchronizer<CI>,
     nc: &'a CKBProtocolContext,
     peer: PeerIndex,
+    log_context: LogContext,
 }
 
 impl<'a, CI> GetBlocksProcess<'a, CI>
@@ -27,34 +33,100 @@ where
             message,
             nc,
             synchronizer,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::GetBlocks)),
         }
     }
 
     pub fn execute(self) {
-        self.message.block_hashes().unwrap().iter().for_each(|fbs_h256| {
+        if self.message.inventory_type() == InventoryType::Header {
+            self.respond_headers();
+            return;
+        }
+        let compact = self.message.inventory_type() == InventoryType::CompactBlock;
+        for fbs_h256 in self.message.block_hashes().unwrap().iter() {
             let block_hash = fbs_h256.into();
-            debug!(target: "sync", "get_blocks {:?}", block_hash);
+            let log_context = self.log_context.with_hash(&block_hash);
+            debug!(target: "sync", "{} requested", log_context);
+            if !self.synchronizer.peers.within_fair_share(self.peer) {
+                debug!(target: "sync", "{} dropped, over the bandwidth share of the peer", log_context);
+                break;
+            }
             if let Some(block) = self.synchronizer.get_block(&block_hash) {
-                debug!(target: "sync", "respond_block {} {:?}", block.header().number(), block.header().hash());
-                if let Some(filter) = self.synchronizer.peers.transaction_filters.read().get(&self.peer) {
-                    let transactions_index = block.commit_transactions().iter().enumerate().filter(|(_index, tx)|
-                        filter.contains(tx)
-                    ).map(|ti| ti.0).collect::<Vec<_>>();
+                debug!(target: "sync", "{} respond block {}", log_context, block.header().number());
+                let fbb = &mut FlatBufferBuilder::new();
+                if compact {
+                    // Compact blocks are served over the relay protocol, the cellbase is
+                    // never in the peer's pool so it is always prefilled
+                    let prefilled_transactions_indexes =
+                        vec![0].into_iter().collect::<HashSet<_>>();
+                    let message = RelayMessage::build_compact_block(
+                        fbb,
+                        &block,
+                        &prefilled_transactions_indexes,
+                        self.synchronizer.peers.compact_block_version(self.peer),
+                    );
+                    fbb.finish(message, None);
+                } else if let Some(filter) = self
+                    .synchronizer
+                    .peers
+                    .transaction_filters
+                    .read()
+                    .get(&self.peer)
+                {
+                    let transactions_index = block
+                        .commit_transactions()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_index, tx)| filter.contains(tx))
+                        .map(|ti| ti.0)
+                        .collect::<Vec<_>>();
 
-                    let fbb = &mut FlatBufferBuilder::new();
-                    let message = SyncMessage::build_filtered_block(fbb, &block, &transactions_index);
+                    let message =
+                        SyncMessage::build_filtered_block(fbb, &block, &transactions_index);
                     fbb.finish(message, None);
-                    let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
                 } else {
-                    let fbb = &mut FlatBufferBuilder::new();
                     let message = SyncMessage::build_block(fbb, &block);
                     fbb.finish(message, None);
-                    let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+                }
+                let data = fbb.finished_data().to_vec();
+                self.synchronizer.peers.bytes_sent(self.peer, data.len());
+                if compact {
+                    let _ = self.nc.send_protocol(self.peer, RELAY_PROTOCOL_ID, data);
+                } else {
+                    let _ = self.nc.send(self.peer, data);
                 }
             } else {
                 // TODO response not found
                 // TODO add timeout check in synchronizer
             }
-        })
+        }
+    }
+
+    // Answer each requested hash we know with a Headers message holding just its header.
+    // The peer asks for the headers of the blocks we announced, a request for more than an
+    // announcement holds is scored and only its first hashes are answered
+    fn respond_headers(&self) {
+        let block_hashes = self.message.block_hashes().unwrap();
+        if block_hashes.len() > MAX_BLOCKS_TO_ANNOUNCE {
+            debug!(target: "sync", "{} too many headers requested {}", self.log_context, block_hashes.len());
+            self.synchronizer.peers.misbehavior(self.peer, 10);
+        }
+        for fbs_h256 in block_hashes.iter().take(MAX_BLOCKS_TO_ANNOUNCE) {
+            let block_hash = fbs_h256.into();
+            let log_context = self.log_context.with_hash(&block_hash);
+            if !self.synchronizer.peers.within_fair_share(self.peer) {
+                debug!(target: "sync", "{} dropped, over the bandwidth share of the peer", log_context);
+                break;
+            }
+            if let Some(header) = self.synchronizer.get_header(&block_hash) {
+                debug!(target: "sync", "{} respond header {}", log_context, header.number());
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = SyncMessage::build_headers(fbb, &[header]);
+                fbb.finish(message, None);
+                let data = fbb.finished_data().to_vec();
+                self.synchronizer.peers.bytes_sent(self.peer, data.len());
+                let _ = self.nc.send(self.peer, data);
+            }
+        }
     }
 }
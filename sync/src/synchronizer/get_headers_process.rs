@@ -1,17 +1,25 @@
+use crate::log_context::LogContext;
 use crate::synchronizer::Synchronizer;
-use crate::MAX_LOCATOR_SIZE;
-use ckb_core::header::Header;
+use crate::{
+    GET_HEADERS_VERSION, MAX_HEADERS_LEN, MAX_LOCATOR_SIZE, MAX_TOLERATED_LOCATOR_SIZE,
+    MIN_COMPRESSED_HEADERS_LEN,
+};
+use ckb_core::header::{BlockNumber, Header};
 use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
-use ckb_protocol::{GetHeaders, SyncMessage};
+use ckb_protocol::{
+    enum_name_sync_payload, DisconnectCode, GetHeaders, SyncMessage, SyncPayload, H256 as FbsH256,
+};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use log::{debug, info, warn};
 use numext_fixed_hash::H256;
+use std::cmp;
 
 pub struct GetHeadersProcess<'a, CI: ChainIndex + 'a> {
     message: &'a GetHeaders<'a>,
     synchronizer: &'a Synchronizer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
     nc: &'a CKBProtocolContext,
 }
 
@@ -30,51 +38,140 @@ where
             nc,
             synchronizer,
             peer,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::GetHeaders)),
         }
     }
 
     pub fn execute(self) {
         if self.synchronizer.is_initial_block_download() {
-            info!(target: "sync", "Ignoring getheaders from peer={} because node is in initial block download", self.peer);
+            info!(target: "sync", "{} ignored, node is in initial block download", self.log_context);
+            return;
+        }
+        let version = self.message.version();
+        if version > GET_HEADERS_VERSION {
+            debug!(target: "sync", "{} ignored, unsupported version {}", self.log_context, version);
+            return;
+        }
+        self.synchronizer
+            .peers
+            .set_getheaders_version(self.peer, version);
+        if !self.synchronizer.peers.getheaders_received(self.peer) {
+            return;
+        }
+        if !self.synchronizer.peers.within_fair_share(self.peer) {
+            debug!(target: "sync", "{} dropped, over the bandwidth share of the peer", self.log_context);
             return;
         }
         if let Some(locator) = self.message.block_locator_hashes() {
             let locator_size = locator.len();
-            if locator_size > MAX_LOCATOR_SIZE {
-                warn!(target: "sync", " getheaders locator size {} from peer={}", locator_size, self.peer);
-                self.nc
-                    .report_peer(self.peer, Severity::Bad("over maximum locator size"));
+            if locator_size > MAX_TOLERATED_LOCATOR_SIZE {
+                warn!(target: "sync", "{} locator size {}", self.log_context, locator_size);
+                self.synchronizer.peers.misbehavior(self.peer, 20);
+            } else if locator_size > MAX_LOCATOR_SIZE {
+                debug!(target: "sync", "{} truncate locator size {}", self.log_context, locator_size);
+            }
+            // the locator starts from the most recent hashes, a partial response is sent
+            // from the common block among the first ones
+            let truncated = locator_size > MAX_LOCATOR_SIZE;
+            let locator = &locator[..cmp::min(locator_size, MAX_LOCATOR_SIZE)];
+
+            if self.message.reverse() {
+                self.respond_ancestor_headers(locator);
                 return;
             }
 
             let hash_stop = H256::zero(); // TODO PENDING self.message.hash_stop().unwrap().into();
             let block_locator_hashes = locator.iter().map(Into::into).collect::<Vec<_>>();
 
-            if let Some(block_number) = self
+            let latest_common = self
                 .synchronizer
-                .locate_latest_common_block(&hash_stop, &block_locator_hashes[..])
-            {
-                debug!(target: "sync", "\n\nheaders latest_common={} tip={} begin\n\n", block_number, {self.synchronizer.tip_header().number()});
+                .locate_peer_common_block(self.peer, &hash_stop, &block_locator_hashes[..])
+                .or_else(|| {
+                    block_locator_hashes
+                        .first()
+                        .and_then(|hash| self.stale_tip_fork_point(hash))
+                });
+            if let Some(block_number) = latest_common {
+                debug!(target: "sync", "{} latest_common={} tip={}", self.log_context, block_number, {self.synchronizer.tip_header().number()});
 
-                self.synchronizer.peers.getheaders_received(self.peer);
                 let headers: Vec<Header> = self
                     .synchronizer
                     .get_locator_response(block_number, &hash_stop);
                 // response headers
 
-                debug!(target: "sync", "\nheaders len={}\n", headers.len());
+                debug!(target: "sync", "{} respond headers len={}", self.log_context, headers.len());
 
-                let fbb = &mut FlatBufferBuilder::new();
-                let message = SyncMessage::build_headers(fbb, &headers);
-                fbb.finish(message, None);
-                let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+                // the common block is our tip, tell the peer it has nothing to catch up
+                if headers.is_empty() {
+                    self.send_up_to_date();
+                } else {
+                    self.send_headers(&headers);
+                }
+            } else if block_locator_hashes.len() == 1 || truncated {
+                // An explicit starting point we don't know, e.g. a checkpoint on a chain
+                // we don't follow, the peer may retry with a full locator. A truncated
+                // locator may have lost the hashes we know
+                debug!(target: "sync", "{} unknown starting point", self.log_context.with_hash(&block_locator_hashes[0]));
+                self.send_headers(&[]);
             } else {
-                warn!(target: "sync", "\n\nunknown block headers from peer {} {:?}\n\n", self.peer, block_locator_hashes);
+                warn!(target: "sync", "{} without common headers {:?}", self.log_context, block_locator_hashes);
                 // Got 'headers' message without known blocks
                 // ban or close peers
-                self.nc
-                    .report_peer(self.peer, Severity::Bad("without common headers"));
+                self.synchronizer.disconnect_peer(
+                    self.nc,
+                    self.peer,
+                    DisconnectCode::Misbehavior,
+                    Severity::Bad("without common headers"),
+                );
             }
         }
     }
+
+    // The peer's tip is a block we know off our main chain, the response starting from
+    // where it forked holds our header at the same height
+    fn stale_tip_fork_point(&self, tip: &H256) -> Option<BlockNumber> {
+        if !self.synchronizer.config.notify_stale_tips {
+            return None;
+        }
+        let fork_point = self.synchronizer.fork_point(tip)?;
+        debug!(target: "sync", "{} stale tip forked at {}", self.log_context.with_hash(tip), fork_point);
+        Some(fork_point)
+    }
+
+    fn respond_ancestor_headers(&self, locator: &[FbsH256]) {
+        let count = match self.message.count() as usize {
+            0 => MAX_HEADERS_LEN,
+            count => cmp::min(count, MAX_HEADERS_LEN),
+        };
+        let headers = locator
+            .first()
+            .map(|hash| self.synchronizer.get_ancestor_headers(&hash.into(), count))
+            .unwrap_or_default();
+        debug!(target: "sync", "{} respond ancestor headers len={}", self.log_context, headers.len());
+        self.send_headers(&headers);
+    }
+
+    fn send_up_to_date(&self) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_up_to_date_headers(fbb);
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+        self.synchronizer.peers.bytes_sent(self.peer, data.len());
+        let _ = self.nc.send(self.peer, data);
+    }
+
+    // Headers are compressed for the peers telling they understand it
+    fn send_headers(&self, headers: &[Header]) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message =
+            if self.message.compressed_headers() && headers.len() >= MIN_COMPRESSED_HEADERS_LEN {
+                SyncMessage::build_compressed_headers(fbb, headers)
+            } else {
+                SyncMessage::build_headers(fbb, headers)
+            };
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+        self.synchronizer.peers.bytes_sent(self.peer, data.len());
+        let _ = self.nc.send(self.peer, data);
+    }
 }
@@ -0,0 +1,79 @@
+use crate::log_context::LogContext;
+use crate::synchronizer::Synchronizer;
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{enum_name_sync_payload, GetTransactionProof, SyncMessage, SyncPayload};
+use ckb_shared::index::ChainIndex;
+use flatbuffers::FlatBufferBuilder;
+use log::debug;
+use numext_fixed_hash::H256;
+
+pub struct GetTransactionProofProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a GetTransactionProof<'a>,
+    synchronizer: &'a Synchronizer<CI>,
+    nc: &'a CKBProtocolContext,
+    peer: PeerIndex,
+    log_context: LogContext,
+}
+
+impl<'a, CI> GetTransactionProofProcess<'a, CI>
+where
+    CI: ChainIndex + 'a,
+{
+    pub fn new(
+        message: &'a GetTransactionProof,
+        synchronizer: &'a Synchronizer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        GetTransactionProofProcess {
+            peer,
+            message,
+            nc,
+            synchronizer,
+            log_context: LogContext::new(
+                peer,
+                enum_name_sync_payload(SyncPayload::GetTransactionProof),
+            ),
+        }
+    }
+
+    // Only transactions committed in our main chain can be proved, the others are left
+    // unanswered like the blocks we don't have
+    pub fn execute(self) {
+        let transaction_hash: H256 = self.message.transaction_hash().unwrap().into();
+        let log_context = self.log_context.with_hash(&transaction_hash);
+        if !self.synchronizer.peers.within_fair_share(self.peer) {
+            debug!(target: "sync", "{} dropped, over the bandwidth share of the peer", log_context);
+            return;
+        }
+        let block = match self
+            .synchronizer
+            .shared
+            .store()
+            .get_transaction_address(&transaction_hash)
+            .and_then(|address| self.synchronizer.get_block(&address.block_hash))
+        {
+            Some(block) => block,
+            None => {
+                debug!(target: "sync", "{} no transaction to prove", log_context);
+                return;
+            }
+        };
+        let transaction_index = match block
+            .commit_transactions()
+            .iter()
+            .position(|tx| tx.hash() == transaction_hash)
+        {
+            Some(index) => index,
+            None => return,
+        };
+
+        debug!(target: "sync", "{} respond proof in block {}", log_context, block.header().number());
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_transaction_proof(fbb, &block, transaction_index);
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+        self.synchronizer.peers.bytes_sent(self.peer, data.len());
+        let _ = self.nc.send(self.peer, data);
+    }
+}
@@ -1,22 +1,26 @@
+use crate::log_context::LogContext;
 use crate::synchronizer::{BlockStatus, Synchronizer};
-use crate::MAX_HEADERS_LEN;
+use crate::{LOCATOR_DENSE_PREFIX, MAX_EMPTY_HEADERS_RETRIES, MAX_HEADERS_LEN, MAX_LOCATOR_SIZE};
 use ckb_core::header::Header;
-use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{FlatbuffersVectorIterator, Headers};
+use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
+use ckb_protocol::{enum_name_sync_payload, Headers, SyncPayload};
 use ckb_shared::block_median_time_context::BlockMedianTimeContext;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::ChainProvider;
 use ckb_verification::{Error as VerifyError, HeaderResolver, HeaderVerifier, Verifier};
+use faketime::unix_time_as_millis;
 use log;
 use log::{debug, log_enabled};
 use numext_fixed_hash::H256;
 use numext_fixed_uint::U256;
+use std::cmp;
 use std::sync::Arc;
 
 pub struct HeadersProcess<'a, CI: ChainIndex + 'a> {
     message: &'a Headers<'a>,
     synchronizer: &'a Synchronizer<CI>,
     peer: PeerIndex,
+    log_context: LogContext,
     nc: &'a CKBProtocolContext,
 }
 
@@ -161,6 +165,7 @@ where
             nc,
             synchronizer,
             peer,
+            log_context: LogContext::new(peer, enum_name_sync_payload(SyncPayload::Headers)),
         }
     }
 
@@ -189,11 +194,108 @@ where
         true
     }
 
+    // A peer whose best known header is ahead of our tip has nothing to send only if it
+    // failed to match our locator. Ask again with a locator twice as dense each time, it
+    // gets longer but its recent hashes pinpoint where we diverged, and score the peer if
+    // it keeps answering empty
+    fn empty_headers_from_peer_ahead(&self) {
+        let tip_total_difficulty = self
+            .synchronizer
+            .shared
+            .tip_header()
+            .read()
+            .total_difficulty()
+            .clone();
+        let is_ahead = self
+            .synchronizer
+            .peers
+            .best_known_header(self.peer)
+            .map_or(false, |best| {
+                best.total_difficulty() > &tip_total_difficulty
+            });
+        if !is_ahead {
+            return;
+        }
+
+        let retries = self
+            .synchronizer
+            .peers
+            .state
+            .write()
+            .get_mut(&self.peer)
+            .map(|state| {
+                state.empty_headers_retries += 1;
+                state.empty_headers_retries
+            });
+        match retries {
+            Some(retries) if retries <= MAX_EMPTY_HEADERS_RETRIES => {
+                let tip = self.synchronizer.tip_header();
+                // half of the locator is left for the doubling steps down to genesis
+                let dense = cmp::min(LOCATOR_DENSE_PREFIX << retries, MAX_LOCATOR_SIZE / 2);
+                let locator = self.synchronizer.get_dense_locator(&tip, dense);
+                debug!(target: "sync", "{} retry getheaders with {} hashes locator", self.log_context, locator.len());
+                self.synchronizer
+                    .send_getheaders_with_locator(self.nc, self.peer, &locator);
+            }
+            Some(_) => {
+                debug!(target: "sync", "{} ahead of us keeps sending empty headers", self.log_context);
+                self.synchronizer.peers.misbehavior(self.peer, 20);
+            }
+            None => {}
+        }
+    }
+
+    // Headers further than the lookahead from our tip would pile up in memory before
+    // their blocks are downloaded, drop them and ask the peer again once the blocks
+    // catch up. Returns whether some headers were deferred
+    fn defer_beyond_lookahead(&self, headers: &mut Vec<Header>) -> bool {
+        let limit = self.synchronizer.shared.tip_header().read().number()
+            + self.synchronizer.config.max_header_lookahead;
+        let within = headers
+            .iter()
+            .take_while(|header| header.number() <= limit)
+            .count();
+        if within == headers.len() {
+            return false;
+        }
+
+        headers.truncate(within);
+        if let Some(state) = self.synchronizer.peers.state.write().get_mut(&self.peer) {
+            state.headers_deferred = true;
+        }
+        true
+    }
+
     fn received_new_header(&self, headers: &[Header]) -> bool {
         let last = headers.last().expect("empty checked");
         self.synchronizer.get_block_status(&last.hash()) == BlockStatus::UNKNOWN
     }
 
+    // A header failing its proof of work advertises work that was never done, most
+    // likely to lure our block download onto a fake chain, so the peer is reported
+    fn punish(&self, result: &ValidationResult) {
+        if result.misbehavior > 0 {
+            self.synchronizer
+                .peers
+                .misbehavior(self.peer, result.misbehavior);
+        }
+        match result.error {
+            Some(ValidationError::Verify(VerifyError::Pow(_))) => {
+                self.nc.report_peer(
+                    self.peer,
+                    Severity::Bad("header with invalid proof of work"),
+                );
+            }
+            Some(ValidationError::ImplausibleTotalDifficulty) => {
+                self.nc.report_peer(
+                    self.peer,
+                    Severity::Bad("header with unattainable total difficulty"),
+                );
+            }
+            _ => {}
+        }
+    }
+
     pub fn accept_first(&self, first: &Header) -> ValidationResult {
         let parent = self.synchronizer.get_header(&first.parent_hash());
         let resolver = VerifierResolver::new(parent.as_ref(), &first, &self.synchronizer);
@@ -207,37 +309,62 @@ where
     }
 
     pub fn execute(self) {
-        debug!(target: "sync", "HeadersProcess begin");
+        debug!(target: "sync", "{} begin", self.log_context);
+
+        if let Some(state) = self.synchronizer.peers.state.write().get_mut(&self.peer) {
+            state.initial_headers_deadline = None;
+        }
 
         if self.is_oversize() {
             self.synchronizer.peers.misbehavior(self.peer, 20);
-            debug!(target: "sync", "HeadersProcess is_oversize");
+            debug!(target: "sync", "{} is_oversize", self.log_context);
             return;
         }
 
         if self.is_empty() {
-            debug!(target: "sync", "HeadersProcess is_empty");
+            debug!(target: "sync", "{} is_empty", self.log_context);
+            // The peer matched our locator with its tip, there is nothing to ask it for
+            // until it announces new headers
+            if self.message.up_to_date() {
+                if let Some(state) = self.synchronizer.peers.state.write().get_mut(&self.peer) {
+                    state.empty_headers_retries = 0;
+                }
+                self.synchronizer.peers.useful_message_received(self.peer);
+                return;
+            }
+            self.empty_headers_from_peer_ahead();
             return;
         }
 
-        let headers = FlatbuffersVectorIterator::new(self.message.headers().unwrap())
-            .map(Into::into)
-            .collect::<Vec<Header>>();
+        let mut headers = match self.message.decompress() {
+            Some(headers) => headers,
+            None => {
+                self.synchronizer.peers.misbehavior(self.peer, 20);
+                debug!(target: "sync", "{} malformed compressed headers", self.log_context);
+                return;
+            }
+        };
 
+        // a header repeated in the batch, only padding it, breaks the continuity too
         if !self.is_continuous(&headers) {
             self.synchronizer.peers.misbehavior(self.peer, 20);
-            debug!(target: "sync", "HeadersProcess is not continuous");
+            self.nc
+                .report_peer(self.peer, Severity::Bad("discontinuous headers"));
+            debug!(target: "sync", "{} is not continuous", self.log_context);
             return;
         }
 
+        if self.defer_beyond_lookahead(&mut headers) {
+            debug!(target: "sync", "{} defer headers beyond the lookahead", self.log_context);
+            if headers.is_empty() {
+                return;
+            }
+        }
+
         let result = self.accept_first(&headers[0]);
         if !result.is_valid() {
-            if result.misbehavior > 0 {
-                self.synchronizer
-                    .peers
-                    .misbehavior(self.peer, result.misbehavior);
-            }
-            debug!(target: "sync", "\n\nHeadersProcess accept_first is_valid {:?} headers = {:?}\n\n", result, headers[0]);
+            self.punish(&result);
+            debug!(target: "sync", "{} accept_first is_valid {:?} headers = {:?}", self.log_context, result, headers[0]);
             return;
         }
 
@@ -253,12 +380,8 @@ where
                 let result = acceptor.accept();
 
                 if !result.is_valid() {
-                    if result.misbehavior > 0 {
-                        self.synchronizer
-                            .peers
-                            .misbehavior(self.peer, result.misbehavior);
-                    }
-                    debug!(target: "sync", "HeadersProcess accept is invalid {:?}", result);
+                    self.punish(&result);
+                    debug!(target: "sync", "{} accept is invalid {:?}", self.log_context, result);
                     return;
                 }
             }
@@ -287,6 +410,11 @@ where
             );
         }
 
+        if let Some(state) = self.synchronizer.peers.state.write().get_mut(&self.peer) {
+            state.empty_headers_retries = 0;
+        }
+        self.synchronizer.peers.useful_message_received(self.peer);
+
         if self.received_new_header(&headers) {
             // update peer last_block_announcement
         }
@@ -334,6 +462,25 @@ where
         }
     }
 
+    fn log_context(&self) -> LogContext {
+        LogContext::new(self.peer, enum_name_sync_payload(SyncPayload::Headers))
+            .with_hash(&self.header.hash())
+    }
+
+    // The block was already downloaded and rejected, its header can only come from a
+    // peer following an invalid chain
+    pub fn known_invalid_check(&self, state: &mut ValidationResult) -> Result<(), ()> {
+        if self
+            .synchronizer
+            .is_known_invalid_block(&self.header.hash())
+        {
+            state.dos(Some(ValidationError::KnownInvalidBlock), 100);
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn duplicate_check(&self, state: &mut ValidationResult) -> Result<(), ()> {
         let status = self.synchronizer.get_block_status(&self.header.hash());
         if status != BlockStatus::UNKNOWN {
@@ -358,21 +505,31 @@ where
         Ok(())
     }
 
+    pub fn future_time_check(&self, state: &mut ValidationResult) -> Result<(), ()> {
+        let max = unix_time_as_millis() + self.synchronizer.config.max_future_block_time;
+        if self.header.timestamp() > max {
+            state.dos(Some(ValidationError::FutureTimestamp), 20);
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn non_contextual_check(&self, state: &mut ValidationResult) -> Result<(), ()> {
         self.verifier.verify(&self.resolver).map_err(|error| match error {
             VerifyError::Pow(e) => {
-                debug!(target: "sync", "HeadersProcess accept {:?} pow", self.header.number());
+                debug!(target: "sync", "{} accept {} pow", self.log_context(), self.header.number());
                 state.dos(Some(ValidationError::Verify(VerifyError::Pow(e))), 100);
             }
             VerifyError::Difficulty(e) => {
-                debug!(target: "sync", "HeadersProcess accept {:?} difficulty", self.header.number());
+                debug!(target: "sync", "{} accept {} difficulty", self.log_context(), self.header.number());
                 state.dos(
                     Some(ValidationError::Verify(VerifyError::Difficulty(e))),
                     50,
                 );
             }
             error => {
-                debug!(target: "sync", "HeadersProcess accept {:?} {:?}", self.header.number(), error);
+                debug!(target: "sync", "{} accept {} {:?}", self.log_context(), self.header.number(), error);
                 state.invalid(Some(ValidationError::Verify(error)));
             }
         })
@@ -387,29 +544,70 @@ where
         }
     }
 
+    // A header claiming more work than its height allows would win every best known
+    // header comparison and steer our block download, whatever chain it is on
+    pub fn total_difficulty_check(&self, state: &mut ValidationResult) -> Result<(), ()> {
+        let parent_view = match self.synchronizer.get_header_view(self.header.parent_hash()) {
+            Some(parent_view) => parent_view,
+            None => return Ok(()),
+        };
+        let attainable = self
+            .synchronizer
+            .max_total_difficulty(self.header.number())
+            .map_or(true, |max| {
+                // checked first so the sum below can't overflow
+                self.header.difficulty() <= &max
+                    && parent_view.total_difficulty() + self.header.difficulty() <= max
+            });
+        if attainable {
+            Ok(())
+        } else {
+            state.dos(Some(ValidationError::ImplausibleTotalDifficulty), 100);
+            Err(())
+        }
+    }
+
     pub fn accept(&self) -> ValidationResult {
         let mut result = ValidationResult::default();
+        if self.known_invalid_check(&mut result).is_err() {
+            debug!(target: "sync", "{} accept {} known invalid block", self.log_context(), self.header.number());
+            return result;
+        }
+
         if self.duplicate_check(&mut result).is_err() {
-            debug!(target: "sync", "HeadersProcess accept {:?} duplicate", self.header.number());
+            debug!(target: "sync", "{} accept {} duplicate", self.log_context(), self.header.number());
             return result;
         }
 
         if self.prev_block_check(&mut result).is_err() {
-            debug!(target: "sync", "HeadersProcess accept {:?} prev_block", self.header.number());
+            debug!(target: "sync", "{} accept {} prev_block", self.log_context(), self.header.number());
             self.synchronizer
                 .insert_block_status(self.header.hash().clone(), BlockStatus::FAILED_MASK);
             return result;
         }
 
+        // a header too far in the future may become valid later, so don't mark it failed
+        if self.future_time_check(&mut result).is_err() {
+            debug!(target: "sync", "{} accept {} future timestamp", self.log_context(), self.header.number());
+            return result;
+        }
+
         if self.non_contextual_check(&mut result).is_err() {
-            debug!(target: "sync", "HeadersProcess accept {:?} non_contextual", self.header.number());
+            debug!(target: "sync", "{} accept {} non_contextual", self.log_context(), self.header.number());
             self.synchronizer
                 .insert_block_status(self.header.hash().clone(), BlockStatus::FAILED_MASK);
             return result;
         }
 
         if self.version_check(&mut result).is_err() {
-            debug!(target: "sync", "HeadersProcess accept {:?} version", self.header.number());
+            debug!(target: "sync", "{} accept {} version", self.log_context(), self.header.number());
+            self.synchronizer
+                .insert_block_status(self.header.hash().clone(), BlockStatus::FAILED_MASK);
+            return result;
+        }
+
+        if self.total_difficulty_check(&mut result).is_err() {
+            debug!(target: "sync", "{} accept {} total_difficulty", self.log_context(), self.header.number());
             self.synchronizer
                 .insert_block_status(self.header.hash().clone(), BlockStatus::FAILED_MASK);
             return result;
@@ -439,8 +637,13 @@ impl Default for ValidationState {
 pub enum ValidationError {
     Verify(VerifyError),
     FailedMask,
+    FutureTimestamp,
     Version,
     InvalidParent,
+    // more work than any chain could have gathered by the height of the header
+    ImplausibleTotalDifficulty,
+    // the block of the header was rejected by the chain
+    KnownInvalidBlock,
 }
 
 #[derive(Debug, Clone, Default)]
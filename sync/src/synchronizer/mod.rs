@@ -1,50 +1,76 @@
 mod block_fetcher;
 mod block_pool;
 mod block_process;
+mod block_queue;
+mod block_server;
 mod filter_process;
 mod get_blocks_process;
 mod get_headers_process;
+mod get_transaction_proof_process;
 mod headers_process;
 
 use self::block_fetcher::BlockFetcher;
 use self::block_pool::OrphanBlockPool;
 use self::block_process::BlockProcess;
+pub use self::block_process::BlockProcessResult;
+use self::block_queue::BlockQueue;
+use self::block_server::ServeRequest;
 use self::filter_process::{AddFilterProcess, ClearFilterProcess, SetFilterProcess};
 use self::get_blocks_process::GetBlocksProcess;
 use self::get_headers_process::GetHeadersProcess;
-use self::headers_process::HeadersProcess;
+use self::get_transaction_proof_process::GetTransactionProofProcess;
+use self::headers_process::{HeaderAcceptor, HeadersProcess, VerifierResolver};
 use crate::config::Config;
-use crate::types::{HeaderView, Peers};
+use crate::log_context::LogContext;
+use crate::types::{CommonBlock, HeaderView, PeerState, Peers};
 use crate::{
     CHAIN_SYNC_TIMEOUT, EVICTION_HEADERS_RESPONSE_TIME, HEADERS_DOWNLOAD_TIMEOUT_BASE,
-    HEADERS_DOWNLOAD_TIMEOUT_PER_HEADER, MAX_HEADERS_LEN,
-    MAX_OUTBOUND_PEERS_TO_PROTECT_FROM_DISCONNECT, MAX_TIP_AGE, POW_SPACE,
+    HEADERS_DOWNLOAD_TIMEOUT_PER_HEADER, IDLE_INBOUND_PEER_TIMEOUT, LOCATOR_DENSE_PREFIX,
+    MAX_BLOCK_DOWNLOAD_TIMEOUTS, MAX_HEADERS_LEN, MAX_KNOWN_INVALID_BLOCKS,
+    MAX_OUTBOUND_PEERS_TO_PROTECT_FROM_DISCONNECT, MAX_QUEUED_GET_BLOCKS, MAX_SYNC_PEERS_PER_TICK,
+    MAX_TIP_AGE, MISBEHAVING_INBOUND_PEER_SCORE, PING_INTERVAL, PING_TIMEOUT, POW_SPACE,
+    REPROCESS_PROGRESS_INTERVAL, START_DOWNLOAD_TIMEOUT, USELESS_PEER_TIMEOUT,
 };
 use bitflags::bitflags;
+use channel::{Sender, TrySendError};
 use ckb_chain::chain::ChainController;
 use ckb_chain::error::ProcessBlockError;
 use ckb_chain_spec::consensus::Consensus;
 use ckb_core::block::Block;
 use ckb_core::header::{BlockNumber, Header};
-use ckb_network::{CKBProtocolContext, CKBProtocolHandler, PeerIndex, Severity, TimerToken};
-use ckb_protocol::{SyncMessage, SyncPayload};
+use ckb_network::{
+    CKBProtocolContext, CKBProtocolHandler, PeerIndex, Severity, TaskPriority, TimerToken,
+};
+use ckb_notify::{NotifyController, PeerEviction, ReprocessProgress};
+use ckb_protocol::{
+    enum_name_disconnect_code, enum_name_sync_payload, DisconnectCode, SyncMessage, SyncPayload,
+};
+use ckb_shared::error::SharedError;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
-use ckb_util::{try_option, RwLock, RwLockUpgradableReadGuard};
+use ckb_util::{try_option, Mutex, RwLock, RwLockUpgradableReadGuard};
+use ckb_verification::{
+    DuplicateVerifier, Error as VerifyError, HeaderVerifier, MerkleRootVerifier, Verifier,
+};
 use faketime::unix_time_as_millis;
 use flatbuffers::{get_root, FlatBufferBuilder};
+use fnv::FnvHashMap;
 use log::{debug, info, warn};
+use lru_cache::LruCache;
 use numext_fixed_hash::H256;
+use numext_fixed_uint::U256;
 use std::cmp;
-use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 use std::time::Duration;
 
 pub const SEND_GET_HEADERS_TOKEN: TimerToken = 0;
 pub const BLOCK_FETCH_TOKEN: TimerToken = 1;
 pub const TIMEOUT_EVICTION_TOKEN: TimerToken = 2;
+pub const PING_TOKEN: TimerToken = 3;
 
 bitflags! {
     pub struct BlockStatus: u32 {
@@ -69,6 +95,43 @@ bitflags! {
 pub type BlockStatusMap = Arc<RwLock<HashMap<H256, BlockStatus>>>;
 pub type BlockHeaderMap = Arc<RwLock<HashMap<H256, HeaderView>>>;
 
+// Why reprocessing the stored blocks stopped, it resumes from the carried height
+#[derive(Debug, PartialEq)]
+pub enum ReprocessError {
+    MissingBlock(BlockNumber),
+    Verification(BlockNumber, VerifyError),
+    Shared(BlockNumber, SharedError),
+}
+
+impl ReprocessError {
+    pub fn height(&self) -> BlockNumber {
+        match self {
+            ReprocessError::MissingBlock(number)
+            | ReprocessError::Verification(number, _)
+            | ReprocessError::Shared(number, _) => *number,
+        }
+    }
+}
+
+// The first inconsistency found revalidating the stored headers, at the carried height
+#[derive(Debug, PartialEq)]
+pub enum RevalidateError {
+    MissingHeader(BlockNumber),
+    // the header doesn't point to the stored header one height below
+    Linkage(BlockNumber),
+    Verification(BlockNumber, VerifyError),
+}
+
+impl RevalidateError {
+    pub fn height(&self) -> BlockNumber {
+        match self {
+            RevalidateError::MissingHeader(number)
+            | RevalidateError::Linkage(number)
+            | RevalidateError::Verification(number, _) => *number,
+        }
+    }
+}
+
 pub struct Synchronizer<CI: ChainIndex> {
     chain: ChainController,
     shared: Shared<CI>,
@@ -80,6 +143,24 @@ pub struct Synchronizer<CI: ChainIndex> {
     pub config: Arc<Config>,
     pub orphan_block_pool: Arc<OrphanBlockPool>,
     pub outbound_peers_with_protect: Arc<AtomicUsize>,
+    pub ibd_finished: Arc<AtomicBool>,
+    // Shared with the relayer, relay is held back until initial block download finishes
+    relay_enabled: Arc<AtomicBool>,
+    pub download_started: Arc<AtomicBool>,
+    pub download_wait_start: Arc<RwLock<Option<u64>>>,
+    no_block_source: Arc<AtomicBool>,
+    // Where the next tick starts going through the syncing peers to download blocks
+    block_download_rotation: Arc<AtomicUsize>,
+    // Held while a received block is processed, deciding whether it is an orphan and
+    // accepting it and its orphans must not interleave with another block
+    block_process_lock: Arc<Mutex<()>>,
+    block_queue: Arc<Mutex<BlockQueue>>,
+    // Blocks rejected by the chain, their status may still say the header was valid
+    known_invalid_blocks: Arc<Mutex<LruCache<H256, ()>>>,
+    notify: Option<NotifyController>,
+    // GetBlocks requests are queued to the block server when there is one, otherwise
+    // they are served by the handler which received them
+    block_server: Option<Sender<ServeRequest<CI>>>,
 }
 
 // https://github.com/rust-lang/rust/issues/40754
@@ -96,6 +177,17 @@ impl<CI: ChainIndex> ::std::clone::Clone for Synchronizer<CI> {
             config: Arc::clone(&self.config),
             orphan_block_pool: Arc::clone(&self.orphan_block_pool),
             outbound_peers_with_protect: Arc::clone(&self.outbound_peers_with_protect),
+            ibd_finished: Arc::clone(&self.ibd_finished),
+            relay_enabled: Arc::clone(&self.relay_enabled),
+            download_started: Arc::clone(&self.download_started),
+            download_wait_start: Arc::clone(&self.download_wait_start),
+            no_block_source: Arc::clone(&self.no_block_source),
+            block_download_rotation: Arc::clone(&self.block_download_rotation),
+            block_process_lock: Arc::clone(&self.block_process_lock),
+            block_queue: Arc::clone(&self.block_queue),
+            known_invalid_blocks: Arc::clone(&self.known_invalid_blocks),
+            notify: self.notify.clone(),
+            block_server: self.block_server.clone(),
         }
     }
 }
@@ -105,33 +197,102 @@ fn is_outbound(nc: &CKBProtocolContext, peer: PeerIndex) -> Option<bool> {
         .map(|session_info| session_info.peer.is_outbound())
 }
 
+// The tip and its total difficulty read under a single lock, so they can't come from
+// two different tips
+fn tip_header_view<CI: ChainIndex>(shared: &Shared<CI>) -> HeaderView {
+    let tip_header = shared.tip_header().read();
+    let block_ext = shared
+        .block_ext(&tip_header.hash())
+        .expect("tip block_ext must exist");
+    HeaderView::new(
+        tip_header.inner().clone(),
+        tip_header.total_difficulty().clone(),
+        block_ext.total_uncles_count,
+    )
+}
+
 impl<CI: ChainIndex> Synchronizer<CI> {
     pub fn new(chain: ChainController, shared: Shared<CI>, config: Config) -> Synchronizer<CI> {
-        let (total_difficulty, header, total_uncles_count) = {
-            let tip_header = shared.tip_header().read();
-            let block_ext = shared
-                .block_ext(&tip_header.hash())
-                .expect("tip block_ext must exist");
-            (
-                tip_header.total_difficulty().clone(),
-                tip_header.inner().clone(),
-                block_ext.total_uncles_count,
-            )
-        };
-        let best_known_header = HeaderView::new(header, total_difficulty, total_uncles_count);
-        let orphan_block_limit = config.orphan_block_limit;
+        let best_known_header = tip_header_view(&shared);
+        let orphan_block_pool =
+            OrphanBlockPool::new(config.orphan_block_limit, config.max_orphan_chain_depth);
+        let initial_block_download = unix_time_as_millis()
+            .saturating_sub(best_known_header.inner().timestamp())
+            > MAX_TIP_AGE;
 
         Synchronizer {
             config: Arc::new(config),
             chain,
             shared,
             peers: Arc::new(Peers::default()),
-            orphan_block_pool: Arc::new(OrphanBlockPool::with_capacity(orphan_block_limit)),
+            orphan_block_pool: Arc::new(orphan_block_pool),
             best_known_header: Arc::new(RwLock::new(best_known_header)),
             status_map: Arc::new(RwLock::new(HashMap::new())),
             header_map: Arc::new(RwLock::new(HashMap::new())),
             n_sync: Arc::new(AtomicUsize::new(0)),
             outbound_peers_with_protect: Arc::new(AtomicUsize::new(0)),
+            ibd_finished: Arc::new(AtomicBool::new(false)),
+            relay_enabled: Arc::new(AtomicBool::new(!initial_block_download)),
+            download_started: Arc::new(AtomicBool::new(false)),
+            download_wait_start: Arc::new(RwLock::new(None)),
+            no_block_source: Arc::new(AtomicBool::new(false)),
+            block_download_rotation: Arc::new(AtomicUsize::new(0)),
+            block_process_lock: Arc::new(Mutex::new(())),
+            block_queue: Arc::new(Mutex::new(BlockQueue::default())),
+            known_invalid_blocks: Arc::new(Mutex::new(LruCache::new(
+                MAX_KNOWN_INVALID_BLOCKS,
+                false,
+            ))),
+            notify: None,
+            block_server: None,
+        }
+    }
+
+    // Evicted peers and reprocess progress are published to the subscribers of this
+    // notify service
+    pub fn notify(mut self, notify: NotifyController) -> Self {
+        self.notify = Some(notify);
+        self
+    }
+
+    // Serve GetBlocks requests on a dedicated thread, reading blocks from the store
+    // mustn't delay the headers and blocks received meanwhile
+    pub fn block_server<S: ToString>(mut self, thread_name: Option<S>) -> Self
+    where
+        CI: 'static,
+    {
+        let (sender, receiver) = channel::bounded(MAX_QUEUED_GET_BLOCKS);
+        let _handle = block_server::start(thread_name, receiver);
+        self.block_server = Some(sender);
+        self
+    }
+
+    // Hand a GetBlocks request over to the block server
+    fn queue_get_blocks(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex, data: &[u8]) {
+        let block_server = match self.block_server {
+            Some(ref block_server) => block_server,
+            None => return,
+        };
+        let request = ServeRequest {
+            synchronizer: self.clone(),
+            nc,
+            peer,
+            data: data.to_vec(),
+        };
+        match block_server.try_send(request) {
+            Ok(()) => {}
+            // the peers ask for more than we serve, the peer asks again once its request
+            // times out
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    target: "sync",
+                    "{} dropped, too many requests waiting for the block server",
+                    LogContext::new(peer, enum_name_sync_payload(SyncPayload::GetBlocks))
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!(target: "sync", "block server stopped, drop GetBlocks of peer {}", peer);
+            }
         }
     }
 
@@ -150,7 +311,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                     .execute()
             }
             SyncPayload::Block => {
-                BlockProcess::new(&message.payload_as_block().unwrap(), self, peer, nc).execute()
+                BlockProcess::new(&message.payload_as_block().unwrap(), self, peer, nc).execute();
             }
             SyncPayload::SetFilter => {
                 SetFilterProcess::new(&message.payload_as_set_filter().unwrap(), self, peer)
@@ -162,7 +323,46 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             }
             SyncPayload::ClearFilter => ClearFilterProcess::new(self, peer).execute(),
             SyncPayload::FilteredBlock => {} // ignore, should not receive FilteredBlock in full node mode
-            SyncPayload::NONE => {}
+            SyncPayload::DisconnectReason => {
+                let code = message.payload_as_disconnect_reason().unwrap().code();
+                info!(target: "sync", "peer={} is disconnecting us, reason {:?}", peer, code);
+            }
+            SyncPayload::Ping => {
+                let nonce = message.payload_as_ping().unwrap().nonce();
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = SyncMessage::build_pong(fbb, nonce);
+                fbb.finish(message, None);
+                let _ = nc.send(peer, fbb.finished_data().to_vec());
+            }
+            SyncPayload::Pong => {
+                let nonce = message.payload_as_pong().unwrap().nonce();
+                if let Some(latency) = self.peers.pong_received(peer, nonce) {
+                    debug!(target: "sync", "peer={} ping latency {}ms", peer, latency);
+                }
+            }
+            SyncPayload::GetTransactionProof => GetTransactionProofProcess::new(
+                &message.payload_as_get_transaction_proof().unwrap(),
+                self,
+                peer,
+                nc,
+            )
+            .execute(),
+            SyncPayload::TransactionProof => {} // ignore, only light clients ask for proofs
+            SyncPayload::GetTip => {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = SyncMessage::build_tip(fbb, &self.tip_header());
+                fbb.finish(message, None);
+                let _ = nc.send(peer, fbb.finished_data().to_vec());
+            }
+            SyncPayload::Tip => {
+                if let Some(header) = message.payload_as_tip().unwrap().header() {
+                    self.tip_received(peer, &header.into());
+                }
+            }
+            SyncPayload::NONE => {
+                warn!(target: "sync", "peer={} sent a message without payload", peer);
+                self.peers.unknown_payload(peer);
+            }
         }
     }
 
@@ -186,19 +386,132 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         Arc::clone(&self.peers)
     }
 
+    // Handed to the relayer, see Relayer::relay_switch
+    pub fn relay_switch(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.relay_enabled)
+    }
+
+    // Peers connected to the sync protocol, for health reporting
+    pub fn connected_peer_count(&self) -> usize {
+        self.peers.state.read().len()
+    }
+
+    // The connected peers as (inbound, outbound), peers whose session is already gone are
+    // in neither
+    pub fn connected_peers_by_direction(&self, nc: &CKBProtocolContext) -> (usize, usize) {
+        let peers = self.peers.state.read().keys().cloned().collect::<Vec<_>>();
+        peers.into_iter().fold((0, 0), |(inbound, outbound), peer| {
+            match is_outbound(nc, peer) {
+                Some(true) => (inbound, outbound + 1),
+                Some(false) => (inbound + 1, outbound),
+                None => (inbound, outbound),
+            }
+        })
+    }
+
+    // Misbehavior score accumulated by the peer, None when it was never scored
+    pub fn peer_score(&self, peer: PeerIndex) -> Option<u32> {
+        self.peers.misbehavior.read().get(&peer).cloned()
+    }
+
+    // Forget the score of the peer, e.g. once an operator found it was scored for a
+    // false positive
+    pub fn reset_peer_score(&self, peer: PeerIndex) {
+        if let Some(score) = self.peers.misbehavior.write().remove(&peer) {
+            info!(target: "sync", "reset score {} of peer={}", score, peer);
+        }
+    }
+
     pub fn insert_block_status(&self, hash: H256, status: BlockStatus) {
         self.status_map.write().insert(hash, status);
     }
 
+    pub fn is_known_invalid_block(&self, hash: &H256) -> bool {
+        self.known_invalid_blocks.lock().contains_key(hash)
+    }
+
     pub fn best_known_header(&self) -> HeaderView {
         self.best_known_header.read().clone()
     }
 
+    // Snapshot of the best header each peer announced, ordered by peer, for logging or
+    // dumping when the sync looks stuck
+    pub fn export_peer_headers(&self) -> Vec<(PeerIndex, HeaderView)> {
+        let mut headers = self
+            .peers
+            .best_known_headers
+            .read()
+            .iter()
+            .map(|(peer, header_view)| (*peer, header_view.clone()))
+            .collect::<Vec<_>>();
+        headers.sort_by_key(|(peer, _)| *peer);
+        headers
+    }
+
+    // Tips of the known branches, ours included, whose total difficulty is within
+    // `competing_tips_margin` blocks of work of our tip, the most worked first
+    pub fn competing_tips(&self) -> Vec<HeaderView> {
+        let (tip_hash, min_total_difficulty) = {
+            let tip = self.shared.tip_header().read();
+            let margin = tip.inner().difficulty() * U256::from(self.config.competing_tips_margin);
+            let min_total_difficulty = if tip.total_difficulty() > &margin {
+                tip.total_difficulty() - &margin
+            } else {
+                U256::zero()
+            };
+            (tip.hash(), min_total_difficulty)
+        };
+
+        let (mut tips, tip_missing) = {
+            let header_map = self.header_map.read();
+            let parents = header_map
+                .values()
+                .map(|view| view.inner().parent_hash().clone())
+                .collect::<HashSet<_>>();
+            let tips = header_map
+                .values()
+                .filter(|view| !parents.contains(&view.hash()))
+                .cloned()
+                .collect::<Vec<_>>();
+            (
+                tips,
+                !header_map.contains_key(&tip_hash) && !parents.contains(&tip_hash),
+            )
+        };
+        // our tip is missing from the map when its block didn't come through sync,
+        // e.g. mined by ourselves
+        if tip_missing {
+            tips.extend(self.get_header_view(&tip_hash));
+        }
+        tips.retain(|view| view.total_difficulty() >= &min_total_difficulty);
+        tips.sort_by(|a, b| b.total_difficulty().cmp(a.total_difficulty()));
+        tips
+    }
+
     pub fn is_initial_block_download(&self) -> bool {
+        if self.ibd_finished.load(Ordering::Acquire) {
+            return false;
+        }
         unix_time_as_millis().saturating_sub(self.shared.tip_header().read().inner().timestamp())
             > MAX_TIP_AGE
     }
 
+    // Whether we know headers ahead of our tip but none of the peers we sync from serves
+    // the blocks, see check_block_sources
+    pub fn no_block_source(&self) -> bool {
+        self.no_block_source.load(Ordering::Acquire)
+    }
+
+    // Once we leave initial block download we never go back, returns true only for
+    // the call which observes the transition. Checked as blocks are stored, and on the
+    // get headers timer for a tip moved by the relayer
+    pub fn check_ibd_finished(&self) -> bool {
+        if self.is_initial_block_download() {
+            return false;
+        }
+        !self.ibd_finished.swap(true, Ordering::AcqRel)
+    }
+
     pub fn predict_headers_sync_time(&self, header: &Header) -> u64 {
         let now = unix_time_as_millis();
         now + HEADERS_DOWNLOAD_TIMEOUT_BASE
@@ -218,7 +531,17 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         self.shared.tip_header().read().inner().clone()
     }
 
+    pub fn tip_header_view(&self) -> HeaderView {
+        tip_header_view(&self.shared)
+    }
+
     pub fn get_locator(&self, start: &Header) -> Vec<H256> {
+        self.get_dense_locator(start, LOCATOR_DENSE_PREFIX)
+    }
+
+    // Same as get_locator with the first `dense` hashes consecutive, a denser locator
+    // pinpoints a divergence close to start
+    pub fn get_dense_locator(&self, start: &Header, dense: usize) -> Vec<H256> {
         let mut step = 1;
         let mut locator = Vec::with_capacity(32);
         let mut index = start.number();
@@ -229,7 +552,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 .expect("index calculated in get_locator");
             locator.push(header.hash().clone());
 
-            if locator.len() >= 10 {
+            if locator.len() >= dense {
                 step <<= 1;
             }
 
@@ -247,13 +570,58 @@ impl<CI: ChainIndex> Synchronizer<CI> {
 
     pub fn locate_latest_common_block(
         &self,
-        _hash_stop: &H256,
+        hash_stop: &H256,
+        locator: &[H256],
+    ) -> Option<BlockNumber> {
+        self.locate_common_block(hash_stop, locator, None)
+            .map(|(number, _)| number)
+    }
+
+    // The latest common block of the peer's locator, the branch it walks is only walked
+    // down to where the branch of the peer's previous locator joined our main chain. A
+    // common block no longer on our main chain after a reorg is forgotten
+    pub fn locate_peer_common_block(
+        &self,
+        peer: PeerIndex,
+        hash_stop: &H256,
         locator: &[H256],
     ) -> Option<BlockNumber> {
+        let cached = self
+            .peers
+            .state
+            .read()
+            .get(&peer)
+            .and_then(|state| state.getheaders_common_block.clone())
+            .filter(|cached| self.shared.block_hash(cached.number).as_ref() == Some(&cached.hash));
+        let (number, common_block) =
+            self.locate_common_block(hash_stop, locator, cached.as_ref())?;
+        if let Some(state) = self.peers.state.write().get_mut(&peer) {
+            state.getheaders_common_block = common_block.or(cached);
+        }
+        Some(number)
+    }
+
+    // Along with the latest common block, where the branch walked to find it joined our
+    // main chain, when there was one
+    fn locate_common_block(
+        &self,
+        _hash_stop: &H256,
+        locator: &[H256],
+        cached: Option<&CommonBlock>,
+    ) -> Option<(BlockNumber, Option<CommonBlock>)> {
         if locator.is_empty() {
             return None;
         }
 
+        // A single hash is an explicit starting point, e.g. a checkpoint,
+        // rather than a locator ending at genesis
+        if locator.len() == 1 {
+            return self
+                .shared
+                .block_number(&locator[0])
+                .map(|number| (number, None));
+        }
+
         if locator.last().expect("empty checked") != &self.shared.genesis_hash() {
             return None;
         }
@@ -267,28 +635,40 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             .expect("locator last checked");
 
         if index == 0 || latest_common == Some(0) {
-            return latest_common;
+            return latest_common.map(|number| (number, None));
         }
 
-        if let Some(header) = locator
-            .get(index - 1)
-            .and_then(|hash| self.shared.block_header(hash))
-        {
+        let branch = &locator[index - 1];
+        if let Some(header) = self.shared.block_header(branch) {
             let mut block_hash = header.parent_hash().clone();
             loop {
                 let block_header = match self.shared.block_header(&block_hash) {
-                    None => break latest_common,
+                    None => break latest_common.map(|number| (number, None)),
                     Some(block_header) => block_header,
                 };
 
                 if let Some(block_number) = self.shared.block_number(&block_hash) {
-                    return Some(block_number);
+                    let common_block = CommonBlock {
+                        branch: branch.clone(),
+                        number: block_number,
+                        hash: block_hash,
+                    };
+                    return Some((block_number, Some(common_block)));
+                }
+
+                // the rest of the branch was walked for the previous locator
+                if let Some(cached) = cached.filter(|cached| cached.branch == block_hash) {
+                    let common_block = CommonBlock {
+                        branch: branch.clone(),
+                        ..cached.clone()
+                    };
+                    return Some((cached.number, Some(common_block)));
                 }
 
                 block_hash = block_header.parent_hash().clone();
             }
         } else {
-            latest_common
+            latest_common.map(|number| (number, None))
         }
     }
 
@@ -357,6 +737,61 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             .collect()
     }
 
+    // Up to `count` headers walking back from `hash` through its ancestors, highest
+    // first, fork headers are followed as well as the main chain ones
+    pub fn get_ancestor_headers(&self, hash: &H256, count: usize) -> Vec<Header> {
+        let mut headers = Vec::new();
+        let mut next = self.shared.block_header(hash);
+        while let Some(header) = next {
+            if headers.len() >= count {
+                break;
+            }
+            next = if header.is_genesis() {
+                None
+            } else {
+                self.shared.block_header(header.parent_hash())
+            };
+            headers.push(header);
+        }
+        headers
+    }
+
+    // Height of the main chain block the known header forked from, None when the header
+    // is on the main chain or unknown
+    pub fn fork_point(&self, hash: &H256) -> Option<BlockNumber> {
+        if self.shared.block_number(hash).is_some() {
+            return None;
+        }
+        let mut header = self.shared.block_header(hash)?;
+        loop {
+            if let Some(number) = self.shared.block_number(header.parent_hash()) {
+                return Some(number);
+            }
+            header = self.shared.block_header(header.parent_hash())?;
+        }
+    }
+
+    // The most work a chain could have gathered by the given height, its difficulty starting
+    // at the genesis one and at most doubling every adjustment interval. None when the bound
+    // is too large to be of any use
+    pub fn max_total_difficulty(&self, number: BlockNumber) -> Option<U256> {
+        let consensus = self.shared.consensus();
+        let genesis_difficulty = consensus.min_difficulty();
+        let interval = consensus.difficulty_adjustment_interval();
+        let doublings = number / interval;
+        if doublings >= 192 {
+            return None;
+        }
+        // a full interval of blocks at each difficulty below the current one, then the
+        // blocks up to the height at the current one
+        let factor = (U256::from(interval + number % interval + 1) << doublings as u32)
+            - U256::from(interval);
+        if factor > &(U256::max_value() >> 1u32) / genesis_difficulty {
+            return None;
+        }
+        Some(genesis_difficulty * factor)
+    }
+
     #[allow(clippy::op_ref)]
     pub fn insert_header_view(&self, header: &Header, peer: PeerIndex) {
         if let Some(parent_view) = self.get_header_view(&header.parent_hash()) {
@@ -384,6 +819,51 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         }
     }
 
+    // The tip a peer answered our GetTip with becomes its best known header right away,
+    // before the headers sync with it gets going, a Tip we didn't ask for is ignored. Its
+    // total difficulty is only known when we know the header or its parent, a tip further
+    // ahead is left to the headers sync. A new tip is accepted like the headers the peer
+    // sends, and scored the same way when invalid
+    pub fn tip_received(&self, peer: PeerIndex, header: &Header) {
+        let requested = self
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .map_or(false, |state| {
+                let requested = state.tip_requested;
+                state.tip_requested = false;
+                requested
+            });
+        if !requested {
+            debug!(target: "sync", "peer={} unrequested tip {}", peer, header.number());
+            return;
+        }
+
+        if self.get_header_view(&header.hash()).is_none() {
+            let parent = match self.get_header(&header.parent_hash()) {
+                Some(parent) => parent,
+                None => {
+                    debug!(target: "sync", "peer={} tip {} is beyond the headers we know", peer, header.number());
+                    return;
+                }
+            };
+            let resolver = VerifierResolver::new(Some(&parent), header, self);
+            let verifier =
+                HeaderVerifier::new(resolver.clone(), Arc::clone(&self.consensus().pow_engine()));
+            let result = HeaderAcceptor::new(header, peer, self, resolver, verifier).accept();
+            if !result.is_valid() {
+                debug!(target: "sync", "peer={} tip {} rejected {:?}", peer, header.number(), result.error);
+                self.peers.misbehavior(peer, result.misbehavior);
+                return;
+            }
+        }
+        if let Some(header_view) = self.get_header_view(&header.hash()) {
+            debug!(target: "sync", "peer={} tip {}", peer, header_view.number());
+            self.peers.new_header_received(peer, &header_view);
+        }
+    }
+
     // If the peer reorganized, our previous last_common_header may not be an ancestor
     // of its current best_known_header. Go back enough to fix that.
     pub fn last_common_ancestor(
@@ -411,14 +891,74 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     }
 
     //TODO: process block which we don't request
-    #[allow(clippy::single_match)]
-    pub fn process_new_block(&self, peer: PeerIndex, block: Block) {
-        match self.get_block_status(&block.header().hash()) {
+    // Blocks are received concurrently but processed one at a time, otherwise a child
+    // could be stored as orphan right after its parent was accepted and never leave the
+    // orphan pool
+    pub fn process_new_block(&self, peer: PeerIndex, block: Block) -> BlockProcessResult {
+        if !self.config.process_blocks_by_height {
+            let _block_process_guard = self.block_process_lock.lock();
+            return self.process_block(peer, block);
+        }
+        self.process_new_blocks(peer, vec![block])
+            .pop()
+            .expect("a result for every block")
+    }
+
+    // The blocks are queued along with the ones other peers are waiting to process, and
+    // whoever takes the lock processes all of them by ascending height. Returns the results
+    // in the order of the given blocks
+    pub fn process_new_blocks(
+        &self,
+        peer: PeerIndex,
+        blocks: Vec<Block>,
+    ) -> Vec<BlockProcessResult> {
+        let ids = {
+            let mut block_queue = self.block_queue.lock();
+            blocks
+                .into_iter()
+                .map(|block| block_queue.push(peer, block))
+                .collect::<Vec<_>>()
+        };
+
+        let _block_process_guard = self.block_process_lock.lock();
+        while let Some((id, peer, block)) = self.pop_queued_block() {
+            let result = self.process_block(peer, block);
+            self.block_queue.lock().set_result(id, result);
+        }
+
+        let mut block_queue = self.block_queue.lock();
+        ids.into_iter()
+            .map(|id| {
+                block_queue
+                    .take_result(id)
+                    .expect("queued blocks are processed before the lock is released")
+            })
+            .collect()
+    }
+
+    // Releases the queue right away, peers keep queueing blocks while one is processed
+    fn pop_queued_block(&self) -> Option<(u64, PeerIndex, Block)> {
+        self.block_queue.lock().pop()
+    }
+
+    // Must be called holding the block process lock
+    fn process_block(&self, peer: PeerIndex, block: Block) -> BlockProcessResult {
+        let hash = block.header().hash();
+        if self.is_known_invalid_block(&hash) {
+            return BlockProcessResult::UnexpectedStatus(BlockStatus::FAILED_MASK);
+        }
+        match self.get_block_status(&hash) {
             BlockStatus::VALID_MASK => {
-                self.insert_new_block(peer, block);
+                let result = self.insert_new_block(peer, block);
+                if result.is_misbehavior() {
+                    self.known_invalid_blocks.lock().insert(hash, ());
+                }
+                result
             }
+            BlockStatus::BLOCK_HAVE_MASK => BlockProcessResult::AlreadyStored,
             status => {
                 debug!(target: "sync", "[Synchronizer] process_new_block unexpect status {:?}", status);
+                BlockProcessResult::UnexpectedStatus(status)
             }
         }
     }
@@ -430,49 +970,64 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         Ok(())
     }
 
-    //FIXME: guarantee concurrent block process
-    fn insert_new_block(&self, peer: PeerIndex, block: Block) {
+    fn insert_orphan_block(&self, block: Block) {
+        let number = block.header().number();
+        let hash = block.header().hash().clone();
+        if !self.orphan_block_pool.insert(block) {
+            debug!(
+                target: "sync", "[Synchronizer] orphan block {} {:?} dropped, its chain is too deep or evicted",
+                number,
+                hash
+            );
+        }
+    }
+
+    fn insert_new_block(&self, peer: PeerIndex, block: Block) -> BlockProcessResult {
         let block = Arc::new(block);
-        if self
+        let result = if self
             .shared
             .output_root(&block.header().parent_hash())
             .is_some()
         {
-            let accept_ret = self.accept_block(peer, &block);
-            if accept_ret.is_ok() {
-                let pre_orphan_block = self
-                    .orphan_block_pool
-                    .remove_blocks_by_parent(&block.header().hash());
-                for block in pre_orphan_block {
-                    let block = Arc::new(block);
-                    if self
-                        .shared
-                        .output_root(&block.header().parent_hash())
-                        .is_some()
-                    {
-                        let ret = self.accept_block(peer, &block);
-                        if ret.is_err() {
+            match self.accept_block(peer, &block) {
+                Ok(()) => {
+                    let pre_orphan_block = self
+                        .orphan_block_pool
+                        .remove_blocks_by_parent(&block.header().hash());
+                    for block in pre_orphan_block {
+                        let block = Arc::new(block);
+                        if self
+                            .shared
+                            .output_root(&block.header().parent_hash())
+                            .is_some()
+                        {
+                            let ret = self.accept_block(peer, &block);
+                            if ret.is_err() {
+                                debug!(
+                                    target: "sync", "[Synchronizer] accept_block {:?} error {:?}",
+                                    block,
+                                    ret.unwrap_err()
+                                );
+                            }
+                        } else {
                             debug!(
-                                target: "sync", "[Synchronizer] accept_block {:?} error {:?}",
-                                block,
-                                ret.unwrap_err()
+                                target: "sync", "[Synchronizer] insert_orphan_block {:?}------------{:?}",
+                                block.header().number(),
+                                block.header().hash()
                             );
+                            self.insert_orphan_block(Block::clone(&block));
                         }
-                    } else {
-                        debug!(
-                            target: "sync", "[Synchronizer] insert_orphan_block {:?}------------{:?}",
-                            block.header().number(),
-                            block.header().hash()
-                        );
-                        self.orphan_block_pool.insert(Block::clone(&block));
                     }
+                    BlockProcessResult::Stored
+                }
+                Err(err) => {
+                    debug!(
+                        target: "sync", "[Synchronizer] accept_block {:?} error {:?}",
+                        block,
+                        err
+                    );
+                    BlockProcessResult::Invalid(err)
                 }
-            } else {
-                debug!(
-                    target: "sync", "[Synchronizer] accept_block {:?} error {:?}",
-                    block,
-                    accept_ret.unwrap_err()
-                )
             }
         } else {
             debug!(
@@ -480,17 +1035,141 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 block.header().number(),
                 block.header().hash()
             );
-            self.orphan_block_pool.insert(Block::clone(&block));
-        }
+            self.insert_orphan_block(Block::clone(&block));
+            BlockProcessResult::Orphan
+        };
 
         debug!(target: "sync", "[Synchronizer] insert_new_block finish");
+        result
     }
 
     pub fn get_blocks_to_fetch(&self, peer: PeerIndex) -> Option<Vec<H256>> {
         BlockFetcher::new(self.clone(), peer).fetch()
     }
 
+    // Re-validate the stored blocks of the main chain from height `from` up to the tip
+    // and rewrite their index, without involving the network. Every block is written in
+    // a batch of its own, so an interrupted reprocess resumes from the height of the error
+    pub fn reprocess_from_height(&self, from: BlockNumber) -> Result<(), ReprocessError> {
+        let tip = self.shared.tip_header().read().inner().clone();
+        let target = tip.number();
+        if from > target {
+            return Ok(());
+        }
+
+        // the index of these heights is what gets rebuilt, walk the stored headers instead
+        let mut hashes = Vec::with_capacity((target - from + 1) as usize);
+        let mut header = tip;
+        while header.number() > from {
+            let parent_number = header.number() - 1;
+            hashes.push(header.hash());
+            header = self
+                .shared
+                .block_header(header.parent_hash())
+                .ok_or(ReprocessError::MissingBlock(parent_number))?;
+        }
+        hashes.push(header.hash());
+        hashes.reverse();
+
+        let store = self.shared.store();
+        for (number, hash) in (from..).zip(hashes) {
+            let block = self
+                .shared
+                .block(&hash)
+                .ok_or(ReprocessError::MissingBlock(number))?;
+            MerkleRootVerifier::new()
+                .verify(&block)
+                .and_then(|_| DuplicateVerifier::new().verify(&block))
+                .map_err(|err| ReprocessError::Verification(number, err))?;
+            store
+                .save_with_batch(|batch| {
+                    store.insert_block_hash(batch, number, &hash);
+                    store.insert_block_number(batch, &hash, number);
+                    store.insert_transaction_address(batch, &hash, block.commit_transactions());
+                    Ok(())
+                })
+                .map_err(|err| ReprocessError::Shared(number, err))?;
+
+            if number == target || (number - from + 1) % REPROCESS_PROGRESS_INTERVAL == 0 {
+                debug!(
+                    target: "sync",
+                    "reprocessed blocks {} to {} of {}",
+                    from, number, target
+                );
+                if let Some(ref notify) = self.notify {
+                    notify.notify_reprocess_progress(Arc::new(ReprocessProgress {
+                        from,
+                        current: number,
+                        target,
+                    }));
+                }
+            }
+        }
+        info!(target: "sync", "reprocessed blocks {} to {}", from, target);
+        Ok(())
+    }
+
+    // Re-run the checks of the header sync over the stored headers of the main chain from
+    // height `from` up to `to`: linkage to the header one height below, PoW and difficulty.
+    // Only reads the store, the blocks and their index are left as they are
+    pub fn revalidate_headers(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<(), RevalidateError> {
+        let stored_header = |number| {
+            self.shared
+                .block_hash(number)
+                .and_then(|hash| self.shared.block_header(&hash))
+                .ok_or(RevalidateError::MissingHeader(number))
+        };
+
+        // genesis has nothing to be checked against
+        let from = cmp::max(from, 1);
+        if from > to {
+            return Ok(());
+        }
+        let pow_engine = self.shared.consensus().pow_engine();
+        let mut parent = stored_header(from - 1)?;
+        for number in from..=to {
+            let header = stored_header(number)?;
+            if header.parent_hash() != &parent.hash() {
+                warn!(
+                    target: "sync",
+                    "revalidate stored header {} {:x}: parent {:x} is not stored at {}",
+                    number,
+                    header.hash(),
+                    header.parent_hash(),
+                    number - 1
+                );
+                return Err(RevalidateError::Linkage(number));
+            }
+            let resolver = VerifierResolver::new(Some(&parent), &header, self);
+            HeaderVerifier::new(resolver.clone(), Arc::clone(&pow_engine))
+                .verify(&resolver)
+                .map_err(|err| {
+                    warn!(
+                        target: "sync",
+                        "revalidate stored header {} {:x}: {:?}",
+                        number,
+                        header.hash(),
+                        err
+                    );
+                    RevalidateError::Verification(number, err)
+                })?;
+            parent = header;
+        }
+        info!(target: "sync", "revalidated stored headers {} to {}", from, to);
+        Ok(())
+    }
+
     fn on_connected(&self, nc: &CKBProtocolContext, peer: PeerIndex) {
+        if let Some(reason) = self.reject_identity(nc, peer) {
+            debug!(target: "sync", "reject peer={} {}", peer, reason);
+            nc.report_peer(peer, Severity::Useless(reason));
+            return;
+        }
+
         let tip = self.tip_header();
         let predicted_headers_sync_time = self.predict_headers_sync_time(&tip);
 
@@ -503,8 +1182,56 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 .fetch_add(1, Ordering::Release);
         }
 
+        // trusted peers are protected without taking one of the outbound slots
+        let protect = protect_outbound || self.is_trusted(nc, peer);
         self.peers
-            .on_connected(peer, predicted_headers_sync_time, protect_outbound);
+            .on_connected(peer, predicted_headers_sync_time, protect);
+
+        if let Some(session) = nc.session_info(peer) {
+            if self.peers.reconnected(
+                peer,
+                &session.peer.peer_id,
+                self.config.reconnect_grace_period,
+            ) {
+                debug!(target: "sync", "peer={} reconnected, restore its previous state", peer);
+            }
+        }
+
+        // learn where the peer is before the getheaders of the next tick
+        if let Some(state) = self.peers.state.write().get_mut(&peer) {
+            state.tip_requested = true;
+        }
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_tip(fbb);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+    }
+
+    pub fn is_trusted(&self, nc: &CKBProtocolContext, peer: PeerIndex) -> bool {
+        nc.session_info(peer).map_or(false, |session| {
+            let peer_id = session.peer.peer_id.to_base58();
+            self.config.trusted_peers.contains(&peer_id)
+        })
+    }
+
+    // A session dialed back to ourselves, or a second session to a node we are already
+    // syncing with, would only double the work, keep the first one
+    fn reject_identity(&self, nc: &CKBProtocolContext, peer: PeerIndex) -> Option<&'static str> {
+        let peer_id = try_option!(nc.session_info(peer)).peer.peer_id;
+        if nc.local_peer_id().as_ref() == Some(&peer_id) {
+            return Some("self connection");
+        }
+        let duplicated = self.peers.state.read().keys().any(|other| {
+            *other != peer
+                && nc
+                    .session_info(*other)
+                    .map_or(false, |session| session.peer.peer_id == peer_id)
+        });
+        if duplicated {
+            Some("duplicate peer identity")
+        } else {
+            None
+        }
     }
 
     pub fn send_getheaders_to_peer(
@@ -514,8 +1241,32 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         header: &Header,
     ) {
         let locator_hash = self.get_locator(header);
+        self.send_getheaders_with_locator(nc, peer, &locator_hash);
+    }
+
+    pub fn send_getheaders_with_locator(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        locator_hash: &[H256],
+    ) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let version = self.peers.getheaders_version(peer);
+        let message = SyncMessage::build_get_compressed_headers(fbb, version, locator_hash);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+    }
+
+    // Ask the peer to prove the transaction is committed in its main chain, the answer
+    // holds the header of the block and the merkle branch of the transaction
+    pub fn request_transaction_proof(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        transaction_hash: &H256,
+    ) {
         let fbb = &mut FlatBufferBuilder::new();
-        let message = SyncMessage::build_get_headers(fbb, &locator_hash);
+        let message = SyncMessage::build_get_transaction_proof(fbb, transaction_hash);
         fbb.finish(message, None);
         let _ = nc.send(peer, fbb.finished_data().to_vec());
     }
@@ -529,25 +1280,45 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     //     If their best known block is still behind when that new timeout is
     //     reached, disconnect.
     pub fn eviction(&self, nc: &CKBProtocolContext) {
+        let chain_tip = self.tip_header_view();
         let mut peer_state = self.peers.state.write();
         let best_known_headers = self.peers.best_known_headers.read();
         let is_initial_block_download = self.is_initial_block_download();
         let mut eviction = Vec::new();
         for (peer, state) in peer_state.iter_mut() {
+            if self.is_trusted(nc, *peer) {
+                continue;
+            }
             let now = unix_time_as_millis();
+            // the peer never answered the first getheaders, try another one instead
+            if let Some(deadline) = state.initial_headers_deadline {
+                if now > deadline && !state.disconnect {
+                    eviction.push((*peer, DisconnectCode::HeadersSyncTimeout));
+                    state.disconnect = true;
+                    continue;
+                }
+            }
             // headers_sync_timeout
             if let Some(timeout) = state.headers_sync_timeout {
                 if now > timeout && is_initial_block_download && !state.disconnect {
-                    eviction.push(*peer);
+                    eviction.push((*peer, DisconnectCode::HeadersSyncTimeout));
                     state.disconnect = true;
                     continue;
                 }
             }
+            // the peer never sent anything we could use, make room for a productive one
+            if now > state.last_useful_message + USELESS_PEER_TIMEOUT
+                && !state.chain_sync.protect
+                && !state.disconnect
+            {
+                eviction.push((*peer, DisconnectCode::Useless));
+                state.disconnect = true;
+                continue;
+            }
             if let Some(is_outbound) = is_outbound(nc, *peer) {
                 if !state.chain_sync.protect && is_outbound {
                     let best_known_header = best_known_headers.get(peer);
 
-                    let chain_tip = self.shared.tip_header().read();
                     if best_known_header.map(|h| h.total_difficulty())
                         >= Some(chain_tip.total_difficulty())
                     {
@@ -577,7 +1348,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                         // of our tip, when we first detected it was behind. Send a single getheaders
                         // message to give the peer a chance to update us.
                         if state.chain_sync.sent_getheaders {
-                            eviction.push(*peer);
+                            eviction.push((*peer, DisconnectCode::ChainSyncTimeout));
                             state.disconnect = true;
                         } else {
                             state.chain_sync.sent_getheaders = true;
@@ -592,46 +1363,218 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 }
             }
         }
-        for peer in eviction {
-            warn!(target: "sync", "timeout eviction peer={}", peer);
-            nc.report_peer(peer, Severity::Timeout);
+        eviction.extend(self.inbound_ratio_eviction(nc, &mut peer_state));
+        for (peer, code) in eviction {
+            let severity = match code {
+                DisconnectCode::Useless => Severity::Useless("no useful messages"),
+                DisconnectCode::Misbehavior => Severity::Bad("misbehaving inbound peer"),
+                _ => Severity::Timeout,
+            };
+            warn!(target: "sync", "{} eviction peer={}", enum_name_disconnect_code(code), peer);
+            self.disconnect_peer(nc, peer, code, severity);
         }
     }
 
-    fn start_sync_headers(&self, nc: &CKBProtocolContext) {
-        let peers: Vec<PeerIndex> = self
-            .peers
-            .state
-            .read()
+    // Inbound peers dominating the outbound ones could be eclipsing us, the misbehaving
+    // ones first, then the longest idle, are marked for disconnection so that outbound
+    // connections take their slots. Until we dialed a peer there is no ratio to keep,
+    // evicting inbound peers then only leaves us without any
+    fn inbound_ratio_eviction(
+        &self,
+        nc: &CKBProtocolContext,
+        peer_state: &mut FnvHashMap<PeerIndex, PeerState>,
+    ) -> Vec<(PeerIndex, DisconnectCode)> {
+        let (mut inbound, mut outbound) = (0, 0);
+        for peer in peer_state
             .iter()
-            .filter(|(_, state)| !state.sync_started)
-            .map(|(peer_id, _)| peer_id)
-            .cloned()
-            .collect();
-        if !peers.is_empty() {
-            debug!(target: "sync", "start sync peers= {:?}", &peers);
-        }
-        let tip = {
-            let local = { self.shared.tip_header().read().clone() };
-            let best_known = self.best_known_header();
-            if local.total_difficulty() > best_known.total_difficulty()
-                || (local.total_difficulty() == best_known.total_difficulty()
-                    && local.hash() < best_known.hash())
-            {
-                local.into_inner()
-            } else {
-                best_known.into_inner()
+            .filter(|(_, state)| !state.disconnect)
+            .map(|(peer, _)| *peer)
+        {
+            match is_outbound(nc, peer) {
+                Some(true) => outbound += 1,
+                Some(false) => inbound += 1,
+                None => (),
             }
-        };
-        for peer in peers {
-            // Only sync with 1 peer if we're in IBD
-            if self.is_initial_block_download() && !self.n_sync.load(Ordering::Acquire) == 0 {
+        }
+        let max_inbound = self.config.max_inbound_per_outbound * outbound;
+        if outbound == 0 || inbound <= max_inbound {
+            return Vec::new();
+        }
+
+        let now = unix_time_as_millis();
+        let misbehavior = self.peers.misbehavior.read();
+        let mut candidates = peer_state
+            .iter()
+            .filter(|(peer, state)| {
+                !state.disconnect
+                    && !state.chain_sync.protect
+                    && is_outbound(nc, **peer) == Some(false)
+                    && !self.is_trusted(nc, **peer)
+            })
+            .filter_map(|(peer, state)| {
+                let score = misbehavior.get(peer).cloned().unwrap_or(0);
+                if score >= MISBEHAVING_INBOUND_PEER_SCORE {
+                    Some((Reverse(score), state.last_useful_message, *peer))
+                } else if now > state.last_useful_message + IDLE_INBOUND_PEER_TIMEOUT {
+                    // idle peers go after the misbehaving ones
+                    Some((Reverse(0), state.last_useful_message, *peer))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        debug!(target: "sync", "{} inbound peers for {} outbound, evict up to {}", inbound, outbound, inbound - max_inbound);
+        let evicted = candidates
+            .into_iter()
+            .take(inbound - max_inbound)
+            .map(|(Reverse(score), _, peer)| {
+                if score > 0 {
+                    (peer, DisconnectCode::Misbehavior)
+                } else {
+                    (peer, DisconnectCode::Useless)
+                }
+            })
+            .collect::<Vec<_>>();
+        for (peer, _) in &evicted {
+            if let Some(state) = peer_state.get_mut(peer) {
+                state.disconnect = true;
+            }
+        }
+        evicted
+    }
+
+    // Ping every peer once in PING_INTERVAL, a peer leaving its ping unanswered for
+    // PING_TIMEOUT is silently gone and gets evicted
+    pub fn send_pings(&self, nc: &CKBProtocolContext) {
+        let now = unix_time_as_millis();
+        let mut pings = Vec::new();
+        let mut timeouts = Vec::new();
+        for (peer, state) in self.peers.state.write().iter_mut() {
+            if state.disconnect {
+                continue;
+            }
+            match state.pending_ping {
+                Some(sent) => {
+                    if now > sent + PING_TIMEOUT {
+                        // trusted peers are never evicted, they are pinged again instead
+                        if self.is_trusted(nc, *peer) {
+                            debug!(target: "sync", "ping timeout trusted peer={}", peer);
+                            state.pending_ping = None;
+                        } else {
+                            state.disconnect = true;
+                            timeouts.push(*peer);
+                        }
+                    }
+                }
+                None => {
+                    if state
+                        .last_ping_sent
+                        .map_or(true, |last| now >= last + PING_INTERVAL)
+                    {
+                        state.pending_ping = Some(now);
+                        state.last_ping_sent = Some(now);
+                        pings.push(*peer);
+                    }
+                }
+            }
+        }
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_ping(fbb, now);
+        fbb.finish(message, None);
+        for peer in pings {
+            let _ = nc.send(peer, fbb.finished_data().to_vec());
+        }
+        for peer in timeouts {
+            warn!(target: "sync", "ping timeout peer={}", peer);
+            self.disconnect_peer(nc, peer, DisconnectCode::PingTimeout, Severity::Timeout);
+        }
+    }
+
+    // Tell the peer why we are going to drop it before reporting it, the session is
+    // torn down once reported
+    pub fn disconnect_peer(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        code: DisconnectCode,
+        severity: Severity,
+    ) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_disconnect_reason(fbb, code);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+        if let Some(ref notify) = self.notify {
+            notify.notify_peer_evicted(Arc::new(PeerEviction {
+                peer,
+                peer_id: nc
+                    .session_info(peer)
+                    .map(|session| session.peer.peer_id.to_base58()),
+                reason: enum_name_disconnect_code(code).to_string(),
+                timestamp: unix_time_as_millis(),
+            }));
+        }
+        nc.report_peer(peer, severity);
+    }
+
+    // Enable full relay and announce our tip to every peer, the header sync is no longer
+    // limited to a single peer from now on, see start_sync_headers
+    fn on_ibd_finished(&self, nc: &CKBProtocolContext) {
+        let tip = self.tip_header();
+        info!(target: "sync", "initial block download finished at {} {:?}", tip.number(), tip.hash());
+        self.relay_enabled.store(true, Ordering::Release);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_headers(fbb, &[tip]);
+        fbb.finish(message, None);
+        for peer in nc.connected_peers() {
+            let _ = nc.send(peer, fbb.finished_data().to_vec());
+        }
+    }
+
+    fn start_sync_headers(&self, nc: &CKBProtocolContext) {
+        let mut peers: Vec<PeerIndex> = self
+            .peers
+            .state
+            .read()
+            .iter()
+            .filter(|(_, state)| !state.sync_started)
+            .map(|(peer_id, _)| peer_id)
+            .cloned()
+            .collect();
+        // Prefer outbound peers as the primary sync peer, order by index to stay deterministic
+        peers.sort_by_key(|peer| (!is_outbound(nc, *peer).unwrap_or(false), *peer));
+        if !peers.is_empty() {
+            debug!(target: "sync", "start sync peers= {:?}", &peers);
+        }
+        let tip = {
+            let local = { self.shared.tip_header().read().clone() };
+            let best_known = self.best_known_header();
+            if local.total_difficulty() > best_known.total_difficulty()
+                || (local.total_difficulty() == best_known.total_difficulty()
+                    && local.hash() < best_known.hash())
+            {
+                local.into_inner()
+            } else {
+                best_known.into_inner()
+            }
+        };
+        let is_initial_block_download = self.is_initial_block_download();
+        // Remaining peers are picked up by the following ticks, so that connecting to
+        // many peers at once doesn't fire all the getheaders together
+        for peer in peers.into_iter().take(MAX_SYNC_PEERS_PER_TICK) {
+            // Only sync with 1 peer if we're in IBD
+            if is_initial_block_download && self.n_sync.load(Ordering::Acquire) != 0 {
                 return;
             }
             {
                 let mut state = self.peers.state.write();
                 if let Some(mut peer_state) = state.get_mut(&peer) {
                     peer_state.sync_started = true;
+                    peer_state.initial_headers_deadline =
+                        Some(unix_time_as_millis() + self.config.initial_headers_response_timeout);
                 }
             }
             self.n_sync.fetch_add(1, Ordering::Release);
@@ -640,22 +1583,200 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         }
     }
 
+    // Ask the peers whose headers were deferred to go on from our best known header, once
+    // the blocks caught up within the lookahead
+    fn resume_deferred_headers(&self, nc: &CKBProtocolContext) {
+        let best_known = self.best_known_header();
+        let tip_number = self.shared.tip_header().read().number();
+        if best_known.number() >= tip_number + self.config.max_header_lookahead {
+            return;
+        }
+
+        let peers = self
+            .peers
+            .state
+            .write()
+            .iter_mut()
+            .filter(|(_, state)| state.headers_deferred)
+            .map(|(peer, state)| {
+                state.headers_deferred = false;
+                *peer
+            })
+            .collect::<Vec<_>>();
+        let start = best_known.into_inner();
+        for peer in peers {
+            debug!(target: "sync", "resume deferred headers sync with peer={}", peer);
+            self.send_getheaders_to_peer(nc, peer, &start);
+        }
+    }
+
+    // Downloading from the first peer that shows up risks following a malicious chain, wait
+    // until enough peers announced their best header, or until we waited long enough
+    fn can_start_download(&self) -> bool {
+        if self.download_started.load(Ordering::Acquire) {
+            return true;
+        }
+        let known = self.peers.best_known_headers.read().len();
+        if known == 0 {
+            return false;
+        }
+        let now = unix_time_as_millis();
+        let wait_start = *self.download_wait_start.write().get_or_insert(now);
+        if known >= self.config.min_peers_to_start_download
+            || now >= wait_start + START_DOWNLOAD_TIMEOUT
+        {
+            self.download_started.store(true, Ordering::Release);
+            true
+        } else {
+            debug!(target: "sync", "wait for more peers to start download, known={}", known);
+            false
+        }
+    }
+
     fn find_blocks_to_fetch(&self, nc: &CKBProtocolContext) {
-        let peers: Vec<PeerIndex> = self
+        if !self.can_start_download() {
+            return;
+        }
+
+        // peers answering pings faster are asked first
+        let mut peers: Vec<(PeerIndex, Option<u64>)> = self
             .peers
             .state
             .read()
             .iter()
             .filter(|(_, state)| state.sync_started)
-            .map(|(peer_id, _)| peer_id)
-            .cloned()
+            .map(|(peer_id, state)| (*peer_id, state.ping_latency))
             .collect();
+        peers.sort_by_key(|(peer, latency)| (latency.unwrap_or_else(u64::max_value), *peer));
+
+        // Only max_block_download_peers of them are asked, the next tick goes on with the
+        // peer after the last one asked, so every syncing peer gets its turn
+        let start = if peers.is_empty() {
+            0
+        } else {
+            self.block_download_rotation.load(Ordering::Acquire) % peers.len()
+        };
+        peers.rotate_left(start);
+        if self.config.evict_slow_download_peers {
+            self.evict_slowest_download_peer(&mut peers);
+        }
 
         debug!(target: "sync", "poll find_blocks_to_fetch select peers");
-        for peer in peers {
+        let mut asked = 0;
+        let mut downloading = 0;
+        for (peer, _) in peers {
+            if downloading >= self.config.max_block_download_peers {
+                break;
+            }
+            asked += 1;
             if let Some(v_fetch) = self.get_blocks_to_fetch(peer) {
                 self.send_getblocks(&v_fetch, nc, peer);
+                if !v_fetch.is_empty() {
+                    downloading += 1;
+                }
+            }
+        }
+        self.block_download_rotation
+            .store(start + asked, Ordering::Release);
+        self.check_block_sources(nc);
+    }
+
+    // The first max_block_download_peers of the peers are the ones due this tick, the
+    // slowest of them by measured throughput swaps places with the fastest peer left
+    // out when that one delivered more blocks lately. What the slow peer still has in
+    // flight is released so the faster one can ask for it right away
+    fn evict_slowest_download_peer(&self, peers: &mut Vec<(PeerIndex, Option<u64>)>) {
+        let slots = self.config.max_block_download_peers;
+        if peers.len() <= slots {
+            return;
+        }
+        let throughputs: Vec<u64> = peers
+            .iter()
+            .map(|(peer, _)| self.peers.block_throughput(*peer))
+            .collect();
+        let slowest = (0..slots).min_by_key(|index| throughputs[*index]);
+        let fastest = (slots..peers.len()).max_by_key(|index| throughputs[*index]);
+        if let (Some(slowest), Some(fastest)) = (slowest, fastest) {
+            if throughputs[fastest] > throughputs[slowest] {
+                debug!(
+                    target: "sync",
+                    "evict download peer={} throughput={} for peer={} throughput={}",
+                    peers[slowest].0,
+                    throughputs[slowest],
+                    peers[fastest].0,
+                    throughputs[fastest]
+                );
+                if let Some(inflight) = self
+                    .peers
+                    .blocks_inflight
+                    .write()
+                    .get_mut(&peers[slowest].0)
+                {
+                    inflight.clear();
+                }
+                peers.swap(slowest, fastest);
+            }
+        }
+    }
+
+    // Headers may keep advancing while every peer we sync from lets its block requests
+    // time out, e.g. pruned nodes, which stalls the sync. Report it rather than asking
+    // them again and again silently, and evict one of them per tick to make room for
+    // peers serving blocks
+    fn check_block_sources(&self, nc: &CKBProtocolContext) {
+        let tip = self.shared.tip_header().read().clone();
+        let best_known_header = self.best_known_header();
+        let sources: Vec<(PeerIndex, u32, bool)> = self
+            .peers
+            .state
+            .read()
+            .iter()
+            .filter(|(_, state)| state.sync_started && !state.disconnect)
+            .map(|(peer, state)| {
+                (
+                    *peer,
+                    state.block_download_timeouts,
+                    state.chain_sync.protect,
+                )
+            })
+            .collect();
+        let no_block_source = best_known_header.total_difficulty() > tip.total_difficulty()
+            && !sources.is_empty()
+            && sources
+                .iter()
+                .all(|(_, timeouts, _)| *timeouts >= MAX_BLOCK_DOWNLOAD_TIMEOUTS);
+
+        let reported = self.no_block_source.swap(no_block_source, Ordering::AcqRel);
+        if !no_block_source {
+            if reported {
+                info!(target: "sync", "found a peer serving blocks again");
+            }
+            return;
+        }
+        if !reported {
+            warn!(
+                target: "sync",
+                "no peer serves blocks, best known header {} while our tip is {}",
+                best_known_header.number(),
+                tip.number()
+            );
+        }
+
+        let evict = sources
+            .into_iter()
+            .filter(|(peer, _, protect)| !protect && !self.is_trusted(nc, *peer))
+            .max_by_key(|(_, timeouts, _)| *timeouts)
+            .map(|(peer, _, _)| peer);
+        if let Some(peer) = evict {
+            if let Some(state) = self.peers.state.write().get_mut(&peer) {
+                state.disconnect = true;
             }
+            self.disconnect_peer(
+                nc,
+                peer,
+                DisconnectCode::BlockDownloadTimeout,
+                Severity::Useless("serves no blocks"),
+            );
         }
     }
 
@@ -677,12 +1798,22 @@ where
         let _ = nc.register_timer(SEND_GET_HEADERS_TOKEN, Duration::from_millis(1000));
         let _ = nc.register_timer(BLOCK_FETCH_TOKEN, Duration::from_millis(1000));
         let _ = nc.register_timer(TIMEOUT_EVICTION_TOKEN, Duration::from_millis(1000));
+        let _ = nc.register_timer(PING_TOKEN, Duration::from_millis(1000));
     }
 
     fn received(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex, data: &[u8]) {
         // TODO use flatbuffers verifier
         let msg = get_root::<SyncMessage>(&data);
-        debug!(target: "sync", "msg {:?}", msg.payload_type());
+        debug!(
+            target: "sync",
+            "{} received",
+            LogContext::new(peer, enum_name_sync_payload(msg.payload_type()))
+        );
+        self.peers.bytes_received(peer, data.len());
+        if msg.payload_type() == SyncPayload::GetBlocks && self.block_server.is_some() {
+            self.queue_get_blocks(nc, peer, data);
+            return;
+        }
         self.process(nc.as_ref(), peer, msg);
     }
 
@@ -693,6 +1824,15 @@ where
 
     fn disconnected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "sync", "peer={} SyncProtocol.disconnected", peer);
+        let sync_started = self
+            .peers
+            .state
+            .read()
+            .get(&peer)
+            .map_or(false, |state| state.sync_started);
+        if sync_started {
+            self.n_sync.fetch_sub(1, Ordering::Release);
+        }
         self.peers.disconnected(peer);
     }
 
@@ -700,7 +1840,11 @@ where
         if !self.peers.state.read().is_empty() {
             match token as usize {
                 SEND_GET_HEADERS_TOKEN => {
+                    if self.check_ibd_finished() {
+                        self.on_ibd_finished(nc.as_ref());
+                    }
                     self.start_sync_headers(nc.as_ref());
+                    self.resume_deferred_headers(nc.as_ref());
                 }
                 BLOCK_FETCH_TOKEN => {
                     self.find_blocks_to_fetch(nc.as_ref());
@@ -708,39 +1852,67 @@ where
                 TIMEOUT_EVICTION_TOKEN => {
                     self.eviction(nc.as_ref());
                 }
+                PING_TOKEN => {
+                    self.send_pings(nc.as_ref());
+                }
                 _ => unreachable!(),
             }
         } else {
             debug!(target: "sync", "no peers connected");
         }
     }
+
+    // Blocks and headers are what the chain progresses with, they wait for the peer to
+    // have a free slot instead of being dropped with the rest of its messages
+    fn task_priority(&self, data: &[u8]) -> TaskPriority {
+        match get_root::<SyncMessage>(data).payload_type() {
+            SyncPayload::Block | SyncPayload::Headers => TaskPriority::Critical,
+            _ => TaskPriority::High,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use self::block_process::BlockProcess;
-    use self::headers_process::HeadersProcess;
+    use self::headers_process::{HeaderAcceptor, HeadersProcess, ValidationError};
     use super::*;
+    use crate::tests::AcceptAll;
+    use crate::types::TransactionFilter;
+    use crate::{
+        Relayer, BLOCK_DOWNLOAD_TIMEOUT, BLOCK_REQUEST_RETRY_DELAY, GET_HEADERS_MIN_INTERVAL,
+        GET_HEADERS_VERSION, MAX_BANDWIDTH_ABOVE_AVERAGE, MAX_BLOCKS_IN_TRANSIT_PER_PEER,
+        MAX_BLOCKS_TO_ANNOUNCE, MAX_EMPTY_HEADERS_RETRIES, MAX_INVALID_BLOCKS, MAX_LOCATOR_SIZE,
+        MAX_THROTTLED_GET_HEADERS, MAX_TOLERATED_LOCATOR_SIZE, MAX_UNKNOWN_PAYLOADS,
+    };
     use ckb_chain::chain::ChainBuilder;
     use ckb_chain_spec::consensus::Consensus;
     use ckb_core::block::BlockBuilder;
     use ckb_core::header::{Header, HeaderBuilder};
-    use ckb_core::transaction::{CellInput, CellOutput, Transaction, TransactionBuilder};
+    use ckb_core::transaction::{
+        CellInput, CellOutput, IndexTransaction, Transaction, TransactionBuilder,
+    };
     use ckb_db::memorydb::MemoryKeyValueDB;
     use ckb_network::{
-        random_peer_id, CKBProtocolContext, Endpoint, Error as NetworkError, PeerIndex, PeerInfo,
-        ProtocolId, SessionInfo, Severity, TimerToken, ToMultiaddr,
+        random_peer_id, CKBProtocolContext, Endpoint, Error as NetworkError, ErrorKind, PeerId,
+        PeerIndex, PeerInfo, ProtocolId, SessionInfo, Severity, TimerToken, ToMultiaddr,
     };
     use ckb_notify::{NotifyController, NotifyService};
-    use ckb_protocol::{Block as FbsBlock, Headers as FbsHeaders};
+    use ckb_pool::txs_pool::TransactionPoolController;
+    use ckb_pow::{CuckooParams, Pow};
+    use ckb_protocol::{
+        Block as FbsBlock, FlatbuffersVectorIterator, Headers as FbsHeaders, RelayMessage,
+        SyncMessageBuilder,
+    };
     use ckb_shared::index::ChainIndex;
     use ckb_shared::shared::SharedBuilder;
-    use ckb_shared::store::ChainKVStore;
+    use ckb_shared::store::{ChainKVStore, ChainStore};
     use ckb_util::Mutex;
     #[cfg(not(disable_faketime))]
     use faketime;
     use flatbuffers::FlatBufferBuilder;
     use fnv::{FnvHashMap, FnvHashSet};
+    use merkle_root::verify_merkle_proof;
     use numext_fixed_uint::U256;
     use std::ops::Deref;
     use std::time::Duration;
@@ -1004,6 +2176,284 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_headers_process_future_timestamp() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        let tip = shared.tip_header().read().inner().clone();
+        let header = HeaderBuilder::default()
+            .parent_hash(tip.hash().clone())
+            .number(tip.number() + 1)
+            .difficulty(shared.calculate_difficulty(&tip).unwrap())
+            .timestamp(unix_time_as_millis() + synchronizer.config.max_future_block_time + 1000)
+            .build();
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[header.clone()]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+
+        let peer = 1;
+        HeadersProcess::new(&fbs_headers, &synchronizer, peer, &mock_network_context(0)).execute();
+
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), Some(&20));
+        assert!(synchronizer.peers.best_known_header(peer).is_none());
+        assert_eq!(
+            synchronizer.get_block_status(&header.hash()),
+            BlockStatus::UNKNOWN
+        );
+    }
+
+    #[test]
+    fn test_process_new_block_result() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let block1 = gen_block(tip.clone(), difficulty.clone(), 1);
+        let block2 = gen_block(block1.header().clone(), difficulty.clone(), 2);
+        // a block without cellbase is rejected by the chain
+        let invalid = BlockBuilder::default().with_header_builder(
+            HeaderBuilder::default()
+                .parent_hash(tip.hash().clone())
+                .number(tip.number() + 1)
+                .timestamp(tip.timestamp() + 1)
+                .difficulty(difficulty)
+                .nonce(3),
+        );
+
+        assert_eq!(
+            synchronizer.process_new_block(peer, block1.clone()),
+            BlockProcessResult::UnexpectedStatus(BlockStatus::UNKNOWN)
+        );
+
+        for block in &[&block1, &block2, &invalid] {
+            synchronizer
+                .insert_block_status(block.header().hash().clone(), BlockStatus::VALID_MASK);
+        }
+
+        assert_eq!(
+            synchronizer.process_new_block(peer, block2.clone()),
+            BlockProcessResult::Orphan
+        );
+        assert_eq!(
+            synchronizer.process_new_block(peer, block1.clone()),
+            BlockProcessResult::Stored
+        );
+        assert_eq!(shared.tip_header().read().hash(), block2.header().hash());
+        assert_eq!(
+            synchronizer.process_new_block(peer, block1),
+            BlockProcessResult::AlreadyStored
+        );
+
+        let result = synchronizer.process_new_block(peer, invalid);
+        assert!(result.is_misbehavior(), "unexpected result {:?}", result);
+    }
+
+    #[test]
+    fn test_get_blocks_process_filtered_block() {
+        let consensus = Consensus::default().set_verification(false);
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus), None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let transactions = (0..4u64)
+            .map(|i| {
+                TransactionBuilder::default()
+                    .output(CellOutput::new(i, vec![], H256::zero(), None))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let block = BlockBuilder::default()
+            .commit_transaction(create_cellbase(tip.number() + 1))
+            .commit_transactions(transactions.clone())
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .number(tip.number() + 1)
+                    .timestamp(tip.timestamp() + 1)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap()),
+            );
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .expect("process block ok");
+
+        let mut filter = TransactionFilter::new(&[0; 512], 3, 1);
+        filter.insert(&transactions[2].hash());
+        synchronizer
+            .peers
+            .transaction_filters
+            .write()
+            .insert(peer, filter);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_blocks(fbb, &[block.header().hash().clone()]);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        GetBlocksProcess::new(
+            &message.payload_as_get_blocks().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let filtered_block = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_filtered_block()
+            .unwrap();
+        let leaves = FlatbuffersVectorIterator::new(filtered_block.transactions().unwrap())
+            .map(|transaction| {
+                let transaction: IndexTransaction = transaction.into();
+                (transaction.index, transaction.transaction.hash())
+            })
+            .collect::<Vec<_>>();
+        // the cellbase comes first, so transactions[2] sits at index 3
+        assert!(leaves.contains(&(3, transactions[2].hash())));
+
+        let proof = filtered_block
+            .hashes()
+            .unwrap()
+            .iter()
+            .map(Into::into)
+            .collect::<Vec<H256>>();
+        assert!(verify_merkle_proof(
+            block.header().txs_commit(),
+            block.commit_transactions().len(),
+            &leaves,
+            &proof
+        ));
+    }
+
+    #[test]
+    fn test_get_blocks_process_compact_block() {
+        let consensus = Consensus::default().set_verification(false);
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus), None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let block = BlockBuilder::default()
+            .commit_transaction(create_cellbase(tip.number() + 1))
+            .commit_transaction(
+                TransactionBuilder::default()
+                    .output(CellOutput::new(1, vec![], H256::zero(), None))
+                    .build(),
+            )
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .number(tip.number() + 1)
+                    .timestamp(tip.timestamp() + 1)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap()),
+            );
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .expect("process block ok");
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_compact_blocks(fbb, &[block.header().hash().clone()]);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        GetBlocksProcess::new(
+            &message.payload_as_get_blocks().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let compact_block = get_root::<RelayMessage>(&sent[0].1)
+            .payload_as_compact_block()
+            .expect("compact block");
+        let header: Header = compact_block.header().unwrap().into();
+        assert_eq!(header.hash(), block.header().hash());
+        // only the cellbase is prefilled, the other transaction is sent as a short id
+        let prefilled =
+            FlatbuffersVectorIterator::new(compact_block.prefilled_transactions().unwrap())
+                .map(|transaction| {
+                    let transaction: IndexTransaction = transaction.into();
+                    (transaction.index, transaction.transaction.hash())
+                })
+                .collect::<Vec<_>>();
+        assert_eq!(prefilled, vec![(0, block.commit_transactions()[0].hash())]);
+        assert_eq!(compact_block.short_ids().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_get_blocks_process_header() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        for i in 1..=3 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let header = shared.block_header(&shared.block_hash(2).unwrap()).unwrap();
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_block_headers(fbb, &[header.hash(), H256::zero()]);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        GetBlocksProcess::new(
+            &message.payload_as_get_blocks().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        // the unknown hash is ignored
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let headers = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_headers()
+            .expect("headers");
+        let headers = FlatbuffersVectorIterator::new(headers.headers().unwrap())
+            .map(Header::from)
+            .collect::<Vec<_>>();
+        assert_eq!(headers, vec![header]);
+    }
+
+    #[test]
+    fn test_get_blocks_process_header_limit() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        let num = MAX_BLOCKS_TO_ANNOUNCE as u64 + 1;
+        for i in 1..=num {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let hashes = (1..=num)
+            .map(|number| shared.block_hash(number).unwrap())
+            .collect::<Vec<_>>();
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_block_headers(fbb, &hashes);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        GetBlocksProcess::new(
+            &message.payload_as_get_blocks().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        assert_eq!(nc.sent.lock().len(), MAX_BLOCKS_TO_ANNOUNCE);
+        assert_eq!(synchronizer.peer_score(peer), Some(10));
+    }
+
     #[test]
     fn test_get_locator_response() {
         let consensus = Consensus::default();
@@ -1040,6 +2490,10 @@ mod tests {
     struct DummyNetworkContext {
         pub sessions: FnvHashMap<PeerIndex, SessionInfo>,
         pub disconnected: Arc<Mutex<FnvHashSet<PeerIndex>>>,
+        // peers reported with Severity::Bad
+        pub reported_bad: Arc<Mutex<FnvHashSet<PeerIndex>>>,
+        pub sent: Arc<Mutex<Vec<(PeerIndex, Vec<u8>)>>>,
+        pub local_peer_id: PeerId,
     }
 
     fn mock_session_info() -> SessionInfo {
@@ -1065,21 +2519,29 @@ mod tests {
 
     impl CKBProtocolContext for DummyNetworkContext {
         /// Send a packet over the network to another peer.
-        fn send(&self, _peer: PeerIndex, _data: Vec<u8>) -> Result<(), NetworkError> {
+        fn send(&self, peer: PeerIndex, data: Vec<u8>) -> Result<(), NetworkError> {
+            // the session is gone once the peer has been reported
+            if self.disconnected.lock().contains(&peer) {
+                return Err(ErrorKind::PeerNotFound.into());
+            }
+            self.sent.lock().push((peer, data));
             Ok(())
         }
 
         /// Send a packet over the network to another peer using specified protocol.
         fn send_protocol(
             &self,
-            _peer: PeerIndex,
+            peer: PeerIndex,
             _protocol: ProtocolId,
-            _data: Vec<u8>,
+            data: Vec<u8>,
         ) -> Result<(), NetworkError> {
-            Ok(())
+            self.send(peer, data)
         }
         /// Report peer. Depending on the report, peer may be disconnected and possibly banned.
-        fn report_peer(&self, peer: PeerIndex, _reason: Severity) {
+        fn report_peer(&self, peer: PeerIndex, reason: Severity) {
+            if let Severity::Bad(_) = reason {
+                self.reported_bad.lock().insert(peer);
+            }
             self.disconnected.lock().insert(peer);
         }
 
@@ -1105,7 +2567,13 @@ mod tests {
         }
 
         fn connected_peers(&self) -> Vec<PeerIndex> {
-            unimplemented!();
+            let mut peers = self.sessions.keys().cloned().collect::<Vec<_>>();
+            peers.sort();
+            peers
+        }
+
+        fn local_peer_id(&self) -> Option<PeerId> {
+            Some(self.local_peer_id.clone())
         }
     }
 
@@ -1117,6 +2585,9 @@ mod tests {
         DummyNetworkContext {
             sessions,
             disconnected: Arc::new(Mutex::new(FnvHashSet::default())),
+            reported_bad: Arc::new(Mutex::new(FnvHashSet::default())),
+            sent: Arc::new(Mutex::new(Vec::new())),
+            local_peer_id: random_peer_id().unwrap(),
         }
     }
 
@@ -1228,14 +2699,411 @@ mod tests {
 
     #[cfg(not(disable_faketime))]
     #[test]
-    fn test_header_sync_timeout() {
-        use std::iter::FromIterator;
+    fn test_get_headers_throttle() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(
+            fbb,
+            GET_HEADERS_VERSION,
+            &[shared.tip_header().read().hash().clone()],
+        );
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let get_headers = message.payload_as_get_headers().unwrap();
+        let nc = mock_network_context(1);
+
+        // only the first request is served, the rest are dropped without scoring yet
+        for _ in 0..=MAX_THROTTLED_GET_HEADERS {
+            GetHeadersProcess::new(&get_headers, &synchronizer, peer, &nc).execute();
+        }
+        assert_eq!(nc.sent.lock().len(), 1);
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), None);
+
+        GetHeadersProcess::new(&get_headers, &synchronizer, peer, &nc).execute();
+        assert_eq!(nc.sent.lock().len(), 1);
+        assert_eq!(synchronizer.peer_score(peer), Some(10));
+
+        faketime::write_millis(&faketime_file, GET_HEADERS_MIN_INTERVAL).expect("write millis");
+        GetHeadersProcess::new(&get_headers, &synchronizer, peer, &nc).execute();
+        assert_eq!(nc.sent.lock().len(), 2);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_blocks_fair_share() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let num = 10;
+        for i in 1..=num {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let (greedy, other) = (0, 1);
+        synchronizer.peers.on_connected(greedy, 0, false);
+        synchronizer.peers.on_connected(other, 0, false);
+        // the greedy peer has already been served a lot in this window
+        synchronizer
+            .peers
+            .bytes_sent(greedy, 3 * MAX_BANDWIDTH_ABOVE_AVERAGE as usize);
+
+        let hashes = (1..=num)
+            .map(|number| shared.block_hash(number).unwrap())
+            .collect::<Vec<_>>();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_blocks(fbb, &hashes);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let get_blocks = message.payload_as_get_blocks().unwrap();
+        let nc = mock_network_context(2);
+
+        for _ in 0..3 {
+            for peer in &[greedy, other] {
+                GetBlocksProcess::new(&get_blocks, &synchronizer, *peer, &nc).execute();
+            }
+        }
+
+        // the greedy peer is served alone at first, then dropped until the other one catches up
+        let sent = nc.sent.lock();
+        let served = |peer| sent.iter().filter(|(p, _)| *p == peer).count();
+        assert_eq!(served(greedy), num as usize);
+        assert_eq!(served(other), 3 * num as usize);
+
+        let (other_sent, _) = synchronizer.peers.bandwidth(other).unwrap();
+        assert_eq!(
+            other_sent,
+            sent.iter()
+                .filter(|(p, _)| *p == other)
+                .map(|(_, data)| data.len() as u64)
+                .sum::<u64>()
+        );
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_headers_single_locator() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let num = 20;
+        for i in 1..=num {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        let nc = mock_network_context(1);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(
+            fbb,
+            GET_HEADERS_VERSION,
+            &[shared.block_hash(10).unwrap()],
+        );
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        GetHeadersProcess::new(
+            &message.payload_as_get_headers().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        assert!(nc.disconnected.lock().is_empty());
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let headers = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_headers()
+            .unwrap();
+        let numbers = FlatbuffersVectorIterator::new(headers.headers().unwrap())
+            .map(|header| Header::from(header).number())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, (11..=num).collect::<Vec<_>>());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_headers_version() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        let nc = mock_network_context(1);
+        let locator = vec![shared.tip_header().read().hash().clone()];
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION + 1, &locator);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        GetHeadersProcess::new(
+            &message.payload_as_get_headers().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+        assert!(nc.sent.lock().is_empty());
+        assert!(nc.disconnected.lock().is_empty());
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), None);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION, &locator);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        GetHeadersProcess::new(
+            &message.payload_as_get_headers().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+        assert_eq!(nc.sent.lock().len(), 1);
+        assert_eq!(
+            synchronizer.peers.state.read()[&peer].getheaders_version,
+            Some(GET_HEADERS_VERSION)
+        );
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_start_sync_headers_staggered() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer_num = MAX_SYNC_PEERS_PER_TICK * 2 + 1;
+        let nc = mock_network_context(peer_num);
+        for peer in 0..peer_num {
+            synchronizer.peers.on_connected(peer, 0, false);
+        }
+
+        // headers sync starts with a limited number of peers per tick
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(nc.sent.lock().len(), MAX_SYNC_PEERS_PER_TICK);
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(nc.sent.lock().len(), MAX_SYNC_PEERS_PER_TICK * 2);
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(nc.sent.lock().len(), peer_num);
+        assert_eq!(synchronizer.n_sync.load(Ordering::Acquire), peer_num);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_start_sync_headers_single_peer_in_ibd() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer_num = MAX_SYNC_PEERS_PER_TICK * 2;
+        let nc = mock_network_context(peer_num);
+        for peer in 0..peer_num {
+            synchronizer.peers.on_connected(peer, 0, false);
+        }
+
+        faketime::write_millis(&faketime_file, MAX_TIP_AGE * 2).expect("write millis");
+        assert!(synchronizer.is_initial_block_download());
+        synchronizer.start_sync_headers(&nc);
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(
+            nc.sent
+                .lock()
+                .iter()
+                .map(|(peer, _)| *peer)
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+
+        // another peer is picked once the sync peer is gone
+        synchronizer.disconnected(Box::new(nc.clone()), 0);
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(
+            nc.sent
+                .lock()
+                .iter()
+                .map(|(peer, _)| *peer)
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_ibd_finished_once() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        let now = MAX_TIP_AGE * 2;
+        faketime::write_millis(&faketime_file, now).expect("write millis");
+        assert!(synchronizer.is_initial_block_download());
+        assert!(!synchronizer.check_ibd_finished());
+
+        let tip = shared.tip_header().read().inner().clone();
+        let number = tip.number() + 1;
+        let cellbase = create_cellbase(number);
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase.clone())
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .timestamp(now)
+                    .number(number)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap())
+                    .cellbase_id(cellbase.hash().clone()),
+            );
+        chain_controller
+            .process_block(Arc::new(block))
+            .expect("process block ok");
+
+        assert!(synchronizer.check_ibd_finished());
+        assert!(!synchronizer.check_ibd_finished());
+
+        // stays out of IBD even if the tip gets stale again
+        faketime::write_millis(&faketime_file, now + MAX_TIP_AGE * 2).expect("write millis");
+        assert!(!synchronizer.is_initial_block_download());
+        assert!(!synchronizer.check_ibd_finished());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_block_finishing_ibd_enables_relay() {
         let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
         faketime::enable(&faketime_file);
 
         let (chain_controller, shared, _notify) = start_chain(None, None);
+        let now = MAX_TIP_AGE * 2;
+        faketime::write_millis(&faketime_file, now).expect("write millis");
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let relay_switch = synchronizer.relay_switch();
+        assert!(!relay_switch.load(Ordering::Acquire));
+
+        let tip = shared.tip_header().read().inner().clone();
+        let number = tip.number() + 1;
+        let cellbase = create_cellbase(number);
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase.clone())
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .timestamp(now)
+                    .number(number)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap())
+                    .cellbase_id(cellbase.hash().clone()),
+            );
+        synchronizer.insert_block_status(block.header().hash().clone(), BlockStatus::VALID_MASK);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_block = FbsBlock::build(fbb, &block);
+        fbb.finish(fbs_block, None);
+        let fbs_block = get_root::<FbsBlock>(fbb.finished_data());
+        let nc = mock_network_context(2);
+        assert_eq!(
+            BlockProcess::new(&fbs_block, &synchronizer, 0, &nc).execute(),
+            BlockProcessResult::Stored
+        );
+
+        // the block ends IBD, relay is on and the new tip is announced to every peer
+        assert!(relay_switch.load(Ordering::Acquire));
+        let sent = nc.sent.lock();
+        assert_eq!(
+            sent.iter().map(|(peer, _)| *peer).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        for (_, data) in sent.iter() {
+            let headers = get_root::<SyncMessage>(data)
+                .payload_as_headers()
+                .expect("headers");
+            assert_eq!(headers.headers().unwrap().len(), 1);
+        }
+        assert!(!synchronizer.check_ibd_finished());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_relay_paused_across_ibd() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
 
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let now = MAX_TIP_AGE * 2;
+        faketime::write_millis(&faketime_file, now).expect("write millis");
         let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let (tx_pool_controller, _tx_pool_receivers) = TransactionPoolController::build();
+        let relayer = Relayer::new(
+            chain_controller.clone(),
+            shared.clone(),
+            tx_pool_controller,
+            synchronizer.peers(),
+            Arc::clone(&synchronizer.config),
+        )
+        .relay_switch(synchronizer.relay_switch());
+
+        // the operator can't turn relay on ahead of the end of IBD
+        relayer.set_relay_enabled(true);
+        assert!(!relayer.is_relay_enabled());
+        relayer.set_relay_enabled(false);
+
+        let tip = shared.tip_header().read().inner().clone();
+        let number = tip.number() + 1;
+        let cellbase = create_cellbase(number);
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase.clone())
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .timestamp(now)
+                    .number(number)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap())
+                    .cellbase_id(cellbase.hash().clone()),
+            );
+        synchronizer.insert_block_status(block.header().hash().clone(), BlockStatus::VALID_MASK);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_block = FbsBlock::build(fbb, &block);
+        fbb.finish(fbs_block, None);
+        let fbs_block = get_root::<FbsBlock>(fbb.finished_data());
+        let nc = mock_network_context(2);
+        assert_eq!(
+            BlockProcess::new(&fbs_block, &synchronizer, 0, &nc).execute(),
+            BlockProcessResult::Stored
+        );
+
+        // IBD is over, relay stays paused until the operator resumes it
+        assert!(synchronizer.relay_switch().load(Ordering::Acquire));
+        assert!(!relayer.is_relay_enabled());
+        relayer.set_relay_enabled(true);
+        assert!(relayer.is_relay_enabled());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_header_sync_timeout() {
+        use std::iter::FromIterator;
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, notify) = start_chain(None, None);
+
+        let synchronizer =
+            gen_synchronizer(chain_controller.clone(), shared.clone()).notify(notify.clone());
+        let evicted_receiver = notify.subscribe_peer_evicted("test_header_sync_timeout");
 
         let network_context = mock_network_context(5);
         faketime::write_millis(&faketime_file, MAX_TIP_AGE * 2).expect("write millis");
@@ -1250,7 +3118,63 @@ mod tests {
         assert_eq!(
             disconnected.deref(),
             &FnvHashSet::from_iter(vec![0, 1].into_iter())
-        )
+        );
+
+        // sends to a reported peer are dropped, so the reason went out first
+        let sent = network_context.sent.lock();
+        for peer in &[0, 1] {
+            let codes = sent
+                .iter()
+                .filter(|(p, _)| p == peer)
+                .filter_map(|(_, data)| {
+                    get_root::<SyncMessage>(data)
+                        .payload_as_disconnect_reason()
+                        .map(|reason| reason.code())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(codes, vec![DisconnectCode::HeadersSyncTimeout]);
+        }
+
+        let mut evictions = (0..2)
+            .map(|_| {
+                evicted_receiver
+                    .recv_timeout(Duration::from_secs(1))
+                    .expect("peer evicted event")
+            })
+            .collect::<Vec<_>>();
+        evictions.sort_by_key(|eviction| eviction.peer);
+        for (peer, eviction) in evictions.iter().enumerate() {
+            assert_eq!(eviction.peer, peer);
+            assert_eq!(
+                eviction.peer_id,
+                Some(network_context.sessions[&peer].peer.peer_id.to_base58())
+            );
+            assert_eq!(eviction.reason, "HeadersSyncTimeout");
+            assert_eq!(eviction.timestamp, MAX_TIP_AGE * 2);
+        }
+    }
+
+    #[test]
+    fn test_new_header_received_monotonic() {
+        let peers = Peers::default();
+        let peer = 0;
+        let header_view = |total_difficulty: u64, nonce: u64| {
+            HeaderView::new(
+                HeaderBuilder::default().nonce(nonce).build(),
+                U256::from(total_difficulty),
+                0,
+            )
+        };
+
+        peers.new_header_received(peer, &header_view(3, 0));
+        peers.new_header_received(peer, &header_view(2, 1));
+        assert_eq!(peers.best_known_header(peer), Some(header_view(3, 0)));
+
+        peers.new_header_received(peer, &header_view(3, 2));
+        assert_eq!(peers.best_known_header(peer), Some(header_view(3, 0)));
+
+        peers.new_header_received(peer, &header_view(4, 3));
+        assert_eq!(peers.best_known_header(peer), Some(header_view(4, 3)));
     }
 
     #[cfg(not(disable_faketime))]
@@ -1305,7 +3229,7 @@ mod tests {
             // that for the first time, OR this peer was able to catch up to some earlier point
             // where we checked against our tip.
             // Either way, set a new timeout based on current tip.
-            let tip = { shared.tip_header().read().clone() };
+            let tip = synchronizer.tip_header_view();
             assert_eq!(
                 peer_state.get(&3).unwrap().chain_sync.work_header,
                 Some(tip.clone())
@@ -1355,4 +3279,2218 @@ mod tests {
             )
         }
     }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_reject_self_and_duplicate_connection() {
+        use std::iter::FromIterator;
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        let mut nc = mock_network_context(2);
+        let mut own_session = mock_session_info();
+        own_session.peer.peer_id = nc.local_peer_id.clone();
+        nc.sessions.insert(2, own_session);
+        let duplicate_session = nc.sessions[&0].clone();
+        nc.sessions.insert(3, duplicate_session);
+
+        for peer in 0..4 {
+            synchronizer.on_connected(&nc, peer);
+        }
+        assert_eq!(
+            nc.disconnected.lock().deref(),
+            &FnvHashSet::from_iter(vec![2, 3].into_iter())
+        );
+        assert!(!synchronizer.peers.state.read().contains_key(&2));
+        assert!(!synchronizer.peers.state.read().contains_key(&3));
+
+        assert!(!synchronizer.is_initial_block_download());
+        synchronizer.start_sync_headers(&nc);
+        assert_eq!(synchronizer.n_sync.load(Ordering::Acquire), 2);
+    }
+
+    #[test]
+    fn test_get_blocks_batch_size() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=100 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+        assert_eq!(headers.len(), 100);
+
+        let batch_size = 4;
+        let mut config = Config::default();
+        config.max_blocks_per_getdata = batch_size;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let peer = 0;
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0)).execute();
+
+        synchronizer1.peers.on_connected(peer, 0, false);
+        synchronizer1
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .expect("peer connected")
+            .sync_started = true;
+
+        // the peer is far ahead, the blocks are asked a batch per tick until the
+        // peer has as many in flight as it is allowed
+        let nc = mock_network_context(1);
+        let ticks = MAX_BLOCKS_IN_TRANSIT_PER_PEER / batch_size + 1;
+        for _ in 0..ticks {
+            synchronizer1.find_blocks_to_fetch(&nc);
+        }
+
+        let batches = nc
+            .sent
+            .lock()
+            .iter()
+            .map(|(_, data)| {
+                get_root::<SyncMessage>(data)
+                    .payload_as_get_blocks()
+                    .expect("getblocks")
+                    .block_hashes()
+                    .expect("block hashes")
+                    .len()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batches,
+            vec![batch_size; MAX_BLOCKS_IN_TRANSIT_PER_PEER / batch_size]
+        );
+        assert_eq!(
+            synchronizer1.peers.blocks_inflight.read()[&peer].len(),
+            MAX_BLOCKS_IN_TRANSIT_PER_PEER
+        );
+    }
+
+    #[test]
+    fn test_empty_headers_from_peer_ahead() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        synchronizer
+            .peers
+            .new_header_received(peer, &mock_header_view(10));
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let nc = mock_network_context(1);
+
+        // empty responses are retried with a denser locator, from our tip, the genesis
+        for retry in 1..=MAX_EMPTY_HEADERS_RETRIES {
+            HeadersProcess::new(&fbs_headers, &synchronizer, peer, &nc).execute();
+            let sent = nc.sent.lock();
+            assert_eq!(sent.len(), retry as usize);
+            let locator = get_root::<SyncMessage>(&sent.last().unwrap().1)
+                .payload_as_get_headers()
+                .unwrap()
+                .block_locator_hashes()
+                .unwrap()
+                .iter()
+                .map(Into::into)
+                .collect::<Vec<H256>>();
+            assert_eq!(locator, vec![shared.genesis_hash()]);
+        }
+        assert!(synchronizer.peers.misbehavior.read().get(&peer).is_none());
+
+        // the peer is scored once it keeps answering empty
+        HeadersProcess::new(&fbs_headers, &synchronizer, peer, &nc).execute();
+        assert_eq!(nc.sent.lock().len(), MAX_EMPTY_HEADERS_RETRIES as usize);
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), Some(&20));
+    }
+
+    #[test]
+    fn test_min_peers_to_start_download() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=10 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+
+        let mut config = Config::default();
+        config.min_peers_to_start_download = 2;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let nc = mock_network_context(2);
+        let announce = |peer: PeerIndex| {
+            HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+                .execute();
+            synchronizer1.peers.on_connected(peer, 0, false);
+            synchronizer1
+                .peers
+                .state
+                .write()
+                .get_mut(&peer)
+                .expect("peer connected")
+                .sync_started = true;
+        };
+
+        announce(0);
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(nc.sent.lock().is_empty());
+
+        announce(1);
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(!nc.sent.lock().is_empty());
+    }
+
+    #[test]
+    fn test_block_mismatches_compact_block() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let announced = gen_block(tip.clone(), difficulty.clone(), 1);
+        let swapped = gen_block(tip.clone(), difficulty.clone(), 2);
+        synchronizer
+            .peers
+            .compact_block_fallback(peer, announced.header());
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_block = FbsBlock::build(fbb, &swapped);
+        fbb.finish(fbs_block, None);
+        let fbs_block = get_root::<FbsBlock>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        assert_eq!(
+            BlockProcess::new(&fbs_block, &synchronizer, peer, &nc).execute(),
+            BlockProcessResult::MismatchedCompactBlock
+        );
+        assert!(nc.disconnected.lock().contains(&peer));
+        assert_eq!(shared.tip_header().read().inner(), &tip);
+        assert!(synchronizer
+            .peers
+            .compact_block_fallbacks
+            .read()
+            .get(&peer)
+            .is_none());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_compact_block_fallback_forgotten() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peers = synchronizer.peers();
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let announced = gen_block(tip.clone(), difficulty.clone(), 1);
+        let swapped = gen_block(tip.clone(), difficulty.clone(), 2);
+
+        // the compact block was completed after all
+        peers.compact_block_fallback(0, announced.header());
+        peers.compact_block_completed(&announced.header().hash());
+        assert!(peers.check_compact_block_fallback(0, &swapped));
+
+        // the request timed out
+        peers.compact_block_fallback(0, announced.header());
+        faketime::write_millis(&faketime_file, BLOCK_DOWNLOAD_TIMEOUT + 1).expect("write millis");
+        assert!(peers.check_compact_block_fallback(0, &swapped));
+        assert!(peers.compact_block_fallbacks.read().is_empty());
+
+        peers.compact_block_fallback(0, announced.header());
+        assert!(!peers.check_compact_block_fallback(0, &swapped));
+    }
+
+    #[test]
+    fn test_unknown_payload() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let nc = mock_network_context(1);
+        let peer = 0;
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessageBuilder::new(fbb).finish();
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+
+        for _ in 0..MAX_UNKNOWN_PAYLOADS {
+            synchronizer.process(&nc, peer, get_root::<SyncMessage>(&data));
+        }
+        assert_eq!(
+            synchronizer.peers.unknown_payloads.read().get(&peer),
+            Some(&MAX_UNKNOWN_PAYLOADS)
+        );
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), None);
+
+        synchronizer.process(&nc, peer, get_root::<SyncMessage>(&data));
+        assert_eq!(synchronizer.peer_score(peer), Some(10));
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_ancestor_headers() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=10 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_ancestor_headers(
+            fbb,
+            GET_HEADERS_VERSION,
+            &shared.block_hash(6).unwrap(),
+            4,
+        );
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        GetHeadersProcess::new(
+            &message.payload_as_get_headers().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let headers = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_headers()
+            .unwrap();
+        let numbers = FlatbuffersVectorIterator::new(headers.headers().unwrap())
+            .map(|header| Header::from(header).number())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![6, 5, 4, 3]);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_reconnect_grace_period() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let grace_period = synchronizer.config.reconnect_grace_period;
+        let mut nc = mock_network_context(1);
+        let header_view = mock_header_view(5);
+
+        synchronizer.on_connected(&nc, 0);
+        synchronizer.peers.new_header_received(0, &header_view);
+        synchronizer.peers.disconnected(0);
+        assert!(synchronizer.peers.best_known_headers.read().is_empty());
+
+        // back as another session within the grace period
+        let session = nc.sessions.remove(&0).unwrap();
+        nc.sessions.insert(1, session.clone());
+        faketime::write_millis(&faketime_file, grace_period - 1).expect("write millis");
+        synchronizer.on_connected(&nc, 1);
+        assert_eq!(
+            synchronizer.peers.best_known_headers.read().get(&1),
+            Some(&header_view)
+        );
+
+        // too late
+        synchronizer.peers.disconnected(1);
+        nc.sessions.remove(&1);
+        nc.sessions.insert(2, session);
+        faketime::write_millis(&faketime_file, 2 * grace_period).expect("write millis");
+        synchronizer.on_connected(&nc, 2);
+        assert!(synchronizer
+            .peers
+            .best_known_headers
+            .read()
+            .get(&2)
+            .is_none());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_ping_timeout() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let nc = mock_network_context(2);
+        synchronizer.on_connected(&nc, 0);
+        synchronizer.on_connected(&nc, 1);
+
+        synchronizer.send_pings(&nc);
+        // the GetTip sent on connecting is not a ping
+        let nonces = nc
+            .sent
+            .lock()
+            .iter()
+            .filter_map(|(peer, data)| {
+                get_root::<SyncMessage>(data)
+                    .payload_as_ping()
+                    .map(|ping| (*peer, ping.nonce()))
+            })
+            .collect::<FnvHashMap<_, _>>();
+        assert_eq!(nonces.len(), 2);
+
+        // only peer 1 answers
+        faketime::write_millis(&faketime_file, 10).expect("write millis");
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_pong(fbb, nonces[&1]);
+        fbb.finish(message, None);
+        synchronizer.process(&nc, 1, get_root::<SyncMessage>(fbb.finished_data()));
+        assert_eq!(
+            synchronizer
+                .peers
+                .state
+                .read()
+                .get(&1)
+                .unwrap()
+                .ping_latency,
+            Some(10)
+        );
+
+        faketime::write_millis(&faketime_file, PING_TIMEOUT + 1).expect("write millis");
+        synchronizer.send_pings(&nc);
+        assert!(nc.disconnected.lock().contains(&0));
+        assert!(!nc.disconnected.lock().contains(&1));
+    }
+
+    #[test]
+    fn test_export_peer_headers() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        assert!(synchronizer.export_peer_headers().is_empty());
+
+        synchronizer
+            .peers
+            .new_header_received(1, &mock_header_view(3));
+        synchronizer
+            .peers
+            .new_header_received(0, &mock_header_view(1));
+        synchronizer
+            .peers
+            .new_header_received(1, &mock_header_view(5));
+        synchronizer
+            .peers
+            .new_header_received(0, &mock_header_view(2));
+
+        assert_eq!(
+            synchronizer.export_peer_headers(),
+            vec![(0, mock_header_view(2)), (1, mock_header_view(5))]
+        );
+    }
+
+    #[test]
+    fn test_empty_headers_refined_locator() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        // the peer forked from our chain after block 47
+        for i in 1..=60 {
+            insert_block(&chain_controller1, &shared1, i, i);
+        }
+        for i in 1..=70 {
+            let nonce = if i <= 47 { i } else { i + 1000 };
+            insert_block(&chain_controller2, &shared2, nonce, i);
+        }
+        let synchronizer1 = gen_synchronizer(chain_controller1.clone(), shared1.clone());
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let peer = 0;
+        synchronizer1.peers.on_connected(peer, 0, false);
+        synchronizer1
+            .peers
+            .new_header_received(peer, &mock_header_view(u64::max_value()));
+
+        // the coarse locator only reaches a common block below the fork point
+        let coarse = synchronizer1.get_locator(&synchronizer1.tip_header());
+        assert_eq!(
+            synchronizer2.locate_latest_common_block(&H256::zero(), &coarse),
+            Some(45)
+        );
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let nc = mock_network_context(1);
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &nc).execute();
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let refined = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_get_headers()
+            .unwrap()
+            .block_locator_hashes()
+            .unwrap()
+            .iter()
+            .map(Into::into)
+            .collect::<Vec<H256>>();
+        assert!(refined.len() > coarse.len());
+        assert_eq!(
+            synchronizer2.locate_latest_common_block(&H256::zero(), &refined),
+            Some(47)
+        );
+    }
+
+    #[test]
+    fn test_max_header_lookahead() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=30 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+        assert_eq!(headers.len(), 30);
+
+        let mut config = Config::default();
+        config.max_header_lookahead = 10;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+        let peer = 0;
+        synchronizer1.peers.on_connected(peer, 0, false);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let nc = mock_network_context(1);
+
+        // the headers beyond the lookahead are deferred
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &nc).execute();
+        assert_eq!(synchronizer1.best_known_header().number(), 10);
+        assert!(synchronizer1.peers.state.read()[&peer].headers_deferred);
+
+        // the gap is still at the cap, nothing to resume
+        synchronizer1.resume_deferred_headers(&nc);
+        assert!(nc.sent.lock().is_empty());
+
+        // the blocks catch up
+        for i in 1..=5 {
+            let block = shared2.block(&shared2.block_hash(i).unwrap()).unwrap();
+            chain_controller1
+                .process_block(Arc::new(block))
+                .expect("process block ok");
+        }
+        synchronizer1.resume_deferred_headers(&nc);
+        assert!(!synchronizer1.peers.state.read()[&peer].headers_deferred);
+        {
+            let sent = nc.sent.lock();
+            assert_eq!(sent.len(), 1);
+            assert!(get_root::<SyncMessage>(&sent[0].1)
+                .payload_as_get_headers()
+                .is_some());
+        }
+
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &nc).execute();
+        assert_eq!(synchronizer1.best_known_header().number(), 15);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_trusted_peer_not_evicted() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let network_context = mock_network_context(2);
+        let mut config = Config::default();
+        config.trusted_peers = vec![network_context.sessions[&0].peer.peer_id.to_base58()];
+        let synchronizer = Synchronizer::new(chain_controller.clone(), shared.clone(), config);
+
+        faketime::write_millis(&faketime_file, MAX_TIP_AGE * 2).expect("write millis");
+        assert!(synchronizer.is_initial_block_download());
+        // both exceed the headers sync timeout
+        synchronizer.peers.on_connected(0, 0, false);
+        synchronizer.peers.on_connected(1, 0, false);
+        synchronizer.eviction(&network_context);
+
+        let disconnected = network_context.disconnected.lock();
+        assert!(!disconnected.contains(&0));
+        assert!(disconnected.contains(&1));
+        assert!(!synchronizer.peers.state.read()[&0].disconnect);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_trusted_peer_ping_timeout() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let nc = mock_network_context(2);
+        let mut config = Config::default();
+        config.trusted_peers = vec![nc.sessions[&0].peer.peer_id.to_base58()];
+        let synchronizer = Synchronizer::new(chain_controller.clone(), shared.clone(), config);
+        synchronizer.on_connected(&nc, 0);
+        synchronizer.on_connected(&nc, 1);
+
+        // neither answers
+        synchronizer.send_pings(&nc);
+        faketime::write_millis(&faketime_file, PING_TIMEOUT + 1).expect("write millis");
+        synchronizer.send_pings(&nc);
+        assert!(!nc.disconnected.lock().contains(&0));
+        assert!(nc.disconnected.lock().contains(&1));
+        let state = synchronizer.peers.state.read();
+        assert!(!state[&0].disconnect);
+        assert_eq!(state[&0].pending_ping, None);
+    }
+
+    #[test]
+    fn test_headers_process_invalid_pow() {
+        let consensus = Consensus::default().set_pow(Pow::Cuckoo(CuckooParams::default()));
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus), None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        let tip = shared.tip_header().read().inner().clone();
+        // a proof no cycle can be read from
+        let header = HeaderBuilder::default()
+            .parent_hash(tip.hash().clone())
+            .number(tip.number() + 1)
+            .difficulty(shared.calculate_difficulty(&tip).unwrap())
+            .timestamp(tip.timestamp() + 1)
+            .proof(vec![0xff; 42 * 4])
+            .build();
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[header.clone()]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+
+        let peer = 1;
+        let nc = mock_network_context(2);
+        HeadersProcess::new(&fbs_headers, &synchronizer, peer, &nc).execute();
+
+        assert!(synchronizer.peers.best_known_header(peer).is_none());
+        assert_eq!(
+            synchronizer.get_block_status(&header.hash()),
+            BlockStatus::FAILED_MASK
+        );
+        assert_eq!(synchronizer.peers.misbehavior.read().get(&peer), Some(&100));
+        assert!(nc.reported_bad.lock().contains(&peer));
+    }
+
+    #[test]
+    fn test_blocks_to_fetch_interleaved_by_height() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=100 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+        let synchronizer1 = gen_synchronizer(chain_controller1.clone(), shared1.clone());
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        for peer in 0..2 {
+            HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+                .execute();
+            synchronizer1.peers.on_connected(peer, 0, false);
+            let mut state = synchronizer1.peers.state.write();
+            let state = state.get_mut(&peer).expect("peer connected");
+            state.sync_started = true;
+            // peer 1 is asked first
+            state.ping_latency = Some(10 - peer as u64);
+        }
+
+        let nc = mock_network_context(2);
+        synchronizer1.find_blocks_to_fetch(&nc);
+
+        let batch_size = synchronizer1.config.max_blocks_per_getdata as u64;
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 2);
+        for (i, (peer, data)) in sent.iter().enumerate() {
+            assert_eq!(*peer, 1 - i);
+            let numbers = get_root::<SyncMessage>(data)
+                .payload_as_get_blocks()
+                .expect("getblocks")
+                .block_hashes()
+                .expect("block hashes")
+                .iter()
+                .map(|hash| shared2.block_header(&hash.into()).unwrap().number())
+                .collect::<Vec<_>>();
+            let start = i as u64 * batch_size + 1;
+            assert_eq!(numbers, (start..start + batch_size).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_competing_tips() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=4 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let mut config = Config::default();
+        config.competing_tips_margin = 2;
+        let synchronizer = Synchronizer::new(chain_controller.clone(), shared.clone(), config);
+
+        let fork = |number: BlockNumber, nonce: u64| {
+            let parent = shared
+                .block_header(&shared.block_hash(number - 1).unwrap())
+                .unwrap();
+            let difficulty = shared.calculate_difficulty(&parent).unwrap();
+            let header = gen_block(parent, difficulty, nonce).header().clone();
+            synchronizer.insert_header_view(&header, 0);
+            header.hash()
+        };
+        // as much work as our tip, and three blocks of work behind it
+        let close = fork(4, 100);
+        let far = fork(1, 200);
+
+        let tip_hash = shared.tip_header().read().hash();
+        let tips = synchronizer
+            .competing_tips()
+            .iter()
+            .map(HeaderView::hash)
+            .collect::<HashSet<_>>();
+        assert_eq!(tips, vec![tip_hash, close].into_iter().collect());
+        assert!(!tips.contains(&far));
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_initial_headers_response_timeout() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let network_context = mock_network_context(2);
+        // far headers sync timeouts, only the first getheaders can time out
+        synchronizer.peers.on_connected(0, u64::max_value(), false);
+        synchronizer.peers.on_connected(1, u64::max_value(), false);
+        assert!(!synchronizer.is_initial_block_download());
+        synchronizer.start_sync_headers(&network_context);
+        assert_eq!(network_context.sent.lock().len(), 2);
+
+        // only peer 1 answers
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        HeadersProcess::new(&fbs_headers, &synchronizer, 1, &network_context).execute();
+
+        faketime::write_millis(
+            &faketime_file,
+            synchronizer.config.initial_headers_response_timeout,
+        )
+        .expect("write millis");
+        synchronizer.eviction(&network_context);
+        assert!(network_context.disconnected.lock().is_empty());
+
+        faketime::write_millis(
+            &faketime_file,
+            synchronizer.config.initial_headers_response_timeout + 1,
+        )
+        .expect("write millis");
+        synchronizer.eviction(&network_context);
+        let disconnected = network_context.disconnected.lock();
+        assert!(disconnected.contains(&0));
+        assert!(!disconnected.contains(&1));
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_compressed_headers() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=40 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let nc = mock_network_context(2);
+        let locator = [shared.genesis_hash()];
+
+        // peer 0 understands compressed headers, peer 1 doesn't
+        for (peer, compressed) in [true, false].iter().enumerate() {
+            synchronizer.peers.on_connected(peer, 0, false);
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = if *compressed {
+                SyncMessage::build_get_compressed_headers(fbb, GET_HEADERS_VERSION, &locator)
+            } else {
+                SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION, &locator)
+            };
+            fbb.finish(message, None);
+            let message = get_root::<SyncMessage>(fbb.finished_data());
+            GetHeadersProcess::new(
+                &message.payload_as_get_headers().unwrap(),
+                &synchronizer,
+                peer,
+                &nc,
+            )
+            .execute();
+        }
+
+        let sent = nc.sent.lock();
+        assert_eq!(sent.len(), 2);
+        let (compressed, uncompressed) = (&sent[0].1, &sent[1].1);
+        assert!(compressed.len() < uncompressed.len());
+        let headers = get_root::<SyncMessage>(uncompressed)
+            .payload_as_headers()
+            .unwrap();
+        let expected = FlatbuffersVectorIterator::new(headers.headers().unwrap())
+            .map(Header::from)
+            .collect::<Vec<_>>();
+        assert_eq!(expected.len(), 40);
+        let headers = get_root::<SyncMessage>(compressed)
+            .payload_as_headers()
+            .unwrap();
+        assert_eq!(headers.decompress(), Some(expected));
+    }
+
+    #[test]
+    fn test_invalid_blocks_escalate_misbehavior() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        let nc = mock_network_context(1);
+
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let mut scores = Vec::new();
+        for nonce in 0..u64::from(MAX_INVALID_BLOCKS) {
+            assert!(nc.disconnected.lock().is_empty());
+            // a block without cellbase is rejected by the chain
+            let invalid = BlockBuilder::default().with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .number(tip.number() + 1)
+                    .timestamp(tip.timestamp() + 1)
+                    .difficulty(difficulty.clone())
+                    .nonce(nonce),
+            );
+            synchronizer.insert_block_status(invalid.header().hash(), BlockStatus::VALID_MASK);
+
+            let fbb = &mut FlatBufferBuilder::new();
+            let fbs_block = FbsBlock::build(fbb, &invalid);
+            fbb.finish(fbs_block, None);
+            let fbs_block = get_root::<FbsBlock>(fbb.finished_data());
+            let result = BlockProcess::new(&fbs_block, &synchronizer, peer, &nc).execute();
+            assert!(result.is_misbehavior(), "unexpected result {:?}", result);
+            scores.push(synchronizer.peers.misbehavior.read()[&peer]);
+        }
+
+        assert_eq!(scores, vec![100, 300, 600]);
+        assert!(nc.disconnected.lock().contains(&peer));
+        assert!(nc.reported_bad.lock().contains(&peer));
+    }
+
+    #[test]
+    fn test_reprocess_from_height() {
+        let (chain_controller, shared, notify) = start_chain(None, None);
+        for i in 1..=10 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer =
+            gen_synchronizer(chain_controller.clone(), shared.clone()).notify(notify.clone());
+        let progress_receiver = notify.subscribe_reprocess_progress("test_reprocess_from_height");
+
+        let index = || {
+            (0..=10)
+                .map(|number| {
+                    let hash = shared.block_hash(number);
+                    let block = hash.as_ref().and_then(|hash| shared.block(hash));
+                    let addresses = block.map(|block| {
+                        block
+                            .commit_transactions()
+                            .iter()
+                            .map(|tx| shared.store().get_transaction_address(&tx.hash()))
+                            .collect::<Vec<_>>()
+                    });
+                    let number = hash.as_ref().and_then(|hash| shared.block_number(hash));
+                    (hash, number, addresses)
+                })
+                .collect::<Vec<_>>()
+        };
+        let original = index();
+
+        // drop the index of the upper half, as a format change would have to
+        let hashes = (5..=10)
+            .map(|number| shared.block_hash(number).unwrap())
+            .collect::<Vec<_>>();
+        shared
+            .store()
+            .save_with_batch(|batch| {
+                for (number, hash) in (5..).zip(hashes.iter()) {
+                    let block = shared.block(hash).unwrap();
+                    shared.store().delete_block_hash(batch, number);
+                    shared.store().delete_block_number(batch, hash);
+                    shared
+                        .store()
+                        .delete_transaction_address(batch, block.commit_transactions());
+                }
+                Ok(())
+            })
+            .expect("delete index");
+        assert!(shared.block_hash(5).is_none());
+        assert_ne!(index(), original);
+
+        assert_eq!(synchronizer.reprocess_from_height(5), Ok(()));
+        assert_eq!(index(), original);
+        assert_eq!(
+            progress_receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(Arc::new(ReprocessProgress {
+                from: 5,
+                current: 10,
+                target: 10,
+            }))
+        );
+
+        // resuming over already reprocessed heights leaves the index as it is
+        assert_eq!(synchronizer.reprocess_from_height(8), Ok(()));
+        assert_eq!(index(), original);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_no_block_source() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=20 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+        let synchronizer1 = gen_synchronizer(chain_controller1.clone(), shared1.clone());
+
+        // both peers serve headers but never the blocks
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        for peer in 0..2 {
+            HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+                .execute();
+            synchronizer1.peers.on_connected(peer, 0, false);
+            synchronizer1
+                .peers
+                .state
+                .write()
+                .get_mut(&peer)
+                .expect("peer connected")
+                .sync_started = true;
+        }
+
+        let nc = mock_network_context(2);
+        for tick in 0..MAX_BLOCK_DOWNLOAD_TIMEOUTS {
+            synchronizer1.find_blocks_to_fetch(&nc);
+            assert!(!synchronizer1.no_block_source());
+            faketime::write_millis(
+                &faketime_file,
+                u64::from(tick + 1) * (BLOCK_DOWNLOAD_TIMEOUT + 1),
+            )
+            .expect("write millis");
+        }
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(synchronizer1.no_block_source());
+        assert_eq!(shared1.tip_header().read().number(), 0);
+
+        // one of them is evicted to make room for a peer serving blocks
+        let disconnected = nc.disconnected.lock().iter().cloned().collect::<Vec<_>>();
+        assert_eq!(disconnected.len(), 1);
+        let codes = nc
+            .sent
+            .lock()
+            .iter()
+            .filter(|(peer, _)| *peer == disconnected[0])
+            .filter_map(|(_, data)| {
+                get_root::<SyncMessage>(data)
+                    .payload_as_disconnect_reason()
+                    .map(|reason| reason.code())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![DisconnectCode::BlockDownloadTimeout]);
+
+        // a block served by the other peer ends the condition
+        let remaining = 1 - disconnected[0];
+        let block = shared2.block(&shared2.block_hash(1).unwrap()).unwrap();
+        synchronizer1.peers.block_received(remaining, &block);
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(!synchronizer1.no_block_source());
+    }
+
+    #[test]
+    fn test_reset_peer_score() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared);
+        let peer = 1;
+        assert_eq!(synchronizer.peer_score(peer), None);
+
+        synchronizer.peers.misbehavior(peer, 100);
+        synchronizer.peers.misbehavior(peer, 100);
+        assert_eq!(synchronizer.peer_score(peer), Some(200));
+
+        synchronizer.reset_peer_score(peer);
+        assert_eq!(synchronizer.peer_score(peer), None);
+
+        // the peer is scored from scratch afterwards
+        synchronizer.peers.misbehavior(peer, 10);
+        assert_eq!(synchronizer.peer_score(peer), Some(10));
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_headers_up_to_date() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=20 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        let nc = mock_network_context(1);
+        let respond = |locator: &[H256]| {
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION, locator);
+            fbb.finish(message, None);
+            let message = get_root::<SyncMessage>(fbb.finished_data());
+            GetHeadersProcess::new(
+                &message.payload_as_get_headers().unwrap(),
+                &synchronizer,
+                peer,
+                &nc,
+            )
+            .execute();
+            let sent = nc.sent.lock();
+            let headers = get_root::<SyncMessage>(&sent.last().unwrap().1)
+                .payload_as_headers()
+                .unwrap();
+            let len = headers.headers().unwrap().len();
+            (len, headers.up_to_date())
+        };
+
+        // the locator reaches our tip
+        let locator = synchronizer.get_locator(&synchronizer.tip_header());
+        assert_eq!(respond(&locator), (0, true));
+
+        // the locator doesn't connect to our chain
+        faketime::write_millis(&faketime_file, GET_HEADERS_MIN_INTERVAL).expect("write millis");
+        assert_eq!(
+            respond(&[H256::from_trimmed_hex_str("1").unwrap()]),
+            (0, false)
+        );
+        assert!(nc.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_up_to_date_headers_not_retried() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared);
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        synchronizer
+            .peers
+            .new_header_received(peer, &mock_header_view(u64::max_value()));
+        let nc = mock_network_context(1);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_up_to_date_headers(fbb);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        HeadersProcess::new(
+            &message.payload_as_headers().unwrap(),
+            &synchronizer,
+            peer,
+            &nc,
+        )
+        .execute();
+        assert!(nc.sent.lock().is_empty());
+
+        // plain empty headers from a peer ahead of us are retried with a denser locator
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &[]);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        HeadersProcess::new(&fbs_headers, &synchronizer, peer, &nc).execute();
+        assert_eq!(nc.sent.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_process_new_block() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let mut blocks: Vec<Block> = Vec::new();
+        for nonce in 1..=40 {
+            let parent = blocks
+                .last()
+                .map_or_else(|| tip.clone(), |block| block.header().clone());
+            blocks.push(gen_block(parent, difficulty.clone(), nonce));
+        }
+        for block in &blocks {
+            synchronizer
+                .insert_block_status(block.header().hash().clone(), BlockStatus::VALID_MASK);
+        }
+
+        // every thread processes its share of the blocks from the highest down, so most
+        // of them arrive before their parent
+        let threads = 4;
+        let handles = (0..threads)
+            .map(|thread| {
+                let synchronizer = synchronizer.clone();
+                let blocks = blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| index % threads == thread)
+                    .map(|(_, block)| block.clone())
+                    .rev()
+                    .collect::<Vec<_>>();
+                ::std::thread::spawn(move || {
+                    for block in blocks {
+                        synchronizer.process_new_block(thread, block);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().expect("process blocks");
+        }
+
+        assert_eq!(
+            shared.tip_header().read().hash(),
+            blocks.last().unwrap().header().hash()
+        );
+        assert!(synchronizer.orphan_block_pool.is_empty());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_useless_peer_eviction() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let nc = mock_network_context(2);
+        // peer 0 never sends anything useful, peer 1 serves a block
+        for peer in 0..2 {
+            synchronizer
+                .peers
+                .on_connected(peer, u64::max_value(), false);
+        }
+        let tip = shared.tip_header().read().inner().clone();
+        let block = gen_block(tip.clone(), shared.calculate_difficulty(&tip).unwrap(), 1);
+
+        faketime::write_millis(&faketime_file, USELESS_PEER_TIMEOUT / 2).expect("write millis");
+        synchronizer.peers.block_received(1, &block);
+        synchronizer.eviction(&nc);
+        assert!(nc.disconnected.lock().is_empty());
+
+        faketime::write_millis(&faketime_file, USELESS_PEER_TIMEOUT + 1).expect("write millis");
+        synchronizer.eviction(&nc);
+        assert_eq!(
+            nc.disconnected.lock().iter().cloned().collect::<Vec<_>>(),
+            vec![0]
+        );
+        let codes = nc
+            .sent
+            .lock()
+            .iter()
+            .filter_map(|(_, data)| {
+                get_root::<SyncMessage>(data)
+                    .payload_as_disconnect_reason()
+                    .map(|reason| reason.code())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![DisconnectCode::Useless]);
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_block_request_retries() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=20 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+        let mut config = Config::default();
+        config.max_block_request_retries = 2;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        // the peer serves headers but never the blocks
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let peer = 0;
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0)).execute();
+        synchronizer1.peers.on_connected(peer, 0, false);
+        synchronizer1
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .expect("peer connected")
+            .sync_started = true;
+
+        let nc = mock_network_context(1);
+        let first = headers[0].hash();
+        let is_requested = || synchronizer1.peers.blocks_inflight.read()[&peer].contains(&first);
+        for tick in 0..2 {
+            faketime::write_millis(&faketime_file, tick * (BLOCK_DOWNLOAD_TIMEOUT + 1))
+                .expect("write millis");
+            synchronizer1.find_blocks_to_fetch(&nc);
+            assert!(is_requested());
+            assert!(synchronizer1.peers.suspended_block_requests().is_empty());
+        }
+
+        // the second timeout gives up on the blocks for a while
+        faketime::write_millis(&faketime_file, 2 * (BLOCK_DOWNLOAD_TIMEOUT + 1))
+            .expect("write millis");
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(!is_requested());
+        assert!(synchronizer1
+            .peers
+            .suspended_block_requests()
+            .contains(&first));
+
+        faketime::write_millis(
+            &faketime_file,
+            2 * (BLOCK_DOWNLOAD_TIMEOUT + 1) + BLOCK_REQUEST_RETRY_DELAY,
+        )
+        .expect("write millis");
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert!(is_requested());
+    }
+
+    #[test]
+    fn test_headers_process_difficulty_out_of_bounds() {
+        let mut consensus = Consensus::default();
+        consensus.pow_time_span = 4 * consensus.pow_spacing;
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus), None);
+        for number in 1..4 {
+            insert_block(&chain_controller, &shared, number, number);
+        }
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let tip = shared.tip_header().read().inner().clone();
+        // the next header is the first of a new difficulty window
+        assert_eq!(
+            (tip.number() + 1) % shared.consensus().difficulty_adjustment_interval(),
+            0
+        );
+
+        let peer = 1;
+        let send_header = |header: &Header| {
+            let fbb = &mut FlatBufferBuilder::new();
+            let fbs_headers = FbsHeaders::build(fbb, &[header.clone()]);
+            fbb.finish(fbs_headers, None);
+            let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+            HeadersProcess::new(&fbs_headers, &synchronizer, peer, &mock_network_context(0))
+                .execute();
+        };
+
+        // the retarget never more than doubles the difficulty of the window
+        let jump = gen_block(tip.clone(), tip.difficulty() * 4u32, 1)
+            .header()
+            .clone();
+        send_header(&jump);
+        assert_eq!(
+            synchronizer.get_block_status(&jump.hash()),
+            BlockStatus::FAILED_MASK
+        );
+        assert_eq!(synchronizer.peer_score(peer), Some(50));
+        assert!(synchronizer.get_header(&jump.hash()).is_none());
+
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let header = gen_block(tip, difficulty, 2).header().clone();
+        send_header(&header);
+        assert_eq!(
+            synchronizer.get_block_status(&header.hash()),
+            BlockStatus::VALID_MASK
+        );
+        assert_eq!(synchronizer.peer_score(peer), Some(50));
+    }
+
+    #[test]
+    fn test_transaction_proof() {
+        let consensus = Consensus::default().set_verification(false);
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus), None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let transactions = (0..5u64)
+            .map(|i| {
+                TransactionBuilder::default()
+                    .output(CellOutput::new(i, vec![], H256::zero(), None))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let block = BlockBuilder::default()
+            .commit_transaction(create_cellbase(tip.number() + 1))
+            .commit_transactions(transactions.clone())
+            .with_header_builder(
+                HeaderBuilder::default()
+                    .parent_hash(tip.hash().clone())
+                    .number(tip.number() + 1)
+                    .timestamp(tip.timestamp() + 1)
+                    .difficulty(shared.calculate_difficulty(&tip).unwrap()),
+            );
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .expect("process block ok");
+
+        let requester = mock_network_context(1);
+        let transaction_hash = transactions[3].hash();
+        synchronizer.request_transaction_proof(&requester, peer, &transaction_hash);
+        let request = requester.sent.lock()[0].1.clone();
+
+        let responder = mock_network_context(1);
+        let message = get_root::<SyncMessage>(&request);
+        GetTransactionProofProcess::new(
+            &message.payload_as_get_transaction_proof().unwrap(),
+            &synchronizer,
+            peer,
+            &responder,
+        )
+        .execute();
+
+        let sent = responder.sent.lock();
+        assert_eq!(sent.len(), 1);
+        let proof = get_root::<SyncMessage>(&sent[0].1)
+            .payload_as_transaction_proof()
+            .unwrap();
+        let header: Header = proof.header().unwrap().into();
+        assert_eq!(header.hash(), block.header().hash());
+        let proved: H256 = proof.transaction_hash().unwrap().into();
+        assert_eq!(proved, transaction_hash);
+        // the cellbase comes first, so transactions[3] sits at index 4
+        assert_eq!(proof.transaction_index(), 4);
+
+        let hashes = proof
+            .hashes()
+            .unwrap()
+            .iter()
+            .map(Into::into)
+            .collect::<Vec<H256>>();
+        assert!(verify_merkle_proof(
+            header.txs_commit(),
+            proof.transactions_count() as usize,
+            &[(proof.transaction_index() as usize, transaction_hash.clone())],
+            &hashes
+        ));
+        assert!(!verify_merkle_proof(
+            header.txs_commit(),
+            proof.transactions_count() as usize,
+            &[(proof.transaction_index() as usize, transactions[2].hash())],
+            &hashes
+        ));
+    }
+
+    #[test]
+    fn test_revalidate_headers() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=10 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        assert_eq!(synchronizer.revalidate_headers(0, 10), Ok(()));
+        assert_eq!(
+            synchronizer.revalidate_headers(0, 11),
+            Err(RevalidateError::MissingHeader(11))
+        );
+
+        // tamper with the difficulty of the header stored at height 5
+        let block = shared.block(&shared.block_hash(5).unwrap()).unwrap();
+        let tampered_header = HeaderBuilder::default()
+            .header(block.header().clone())
+            .difficulty(block.header().difficulty() + U256::from(1u64))
+            .build();
+        let tampered_hash = tampered_header.hash();
+        let tampered_block = BlockBuilder::default()
+            .block(block)
+            .header(tampered_header)
+            .build();
+        shared
+            .store()
+            .save_with_batch(|batch| {
+                shared.store().insert_block(batch, &tampered_block);
+                shared.store().insert_block_hash(batch, 5, &tampered_hash);
+                shared.store().insert_block_number(batch, &tampered_hash, 5);
+                Ok(())
+            })
+            .expect("tamper header");
+
+        match synchronizer.revalidate_headers(1, 10) {
+            Err(RevalidateError::Verification(5, VerifyError::Difficulty(_))) => {}
+            result => panic!("unexpected revalidation result {:?}", result),
+        }
+        // the untouched header above doesn't link to the tampered one
+        assert_eq!(
+            synchronizer.revalidate_headers(6, 10),
+            Err(RevalidateError::Linkage(6))
+        );
+        assert_eq!(synchronizer.revalidate_headers(1, 4), Ok(()));
+        // only the store was read, the tip is where it was
+        assert_eq!(synchronizer.tip_header().number(), 10);
+    }
+
+    #[test]
+    fn test_headers_process_duplicates() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let network_context = mock_network_context(2);
+        let peer = 1;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let first = gen_block(tip, difficulty.clone(), 1).header().clone();
+        let second = gen_block(first.clone(), difficulty, 2).header().clone();
+        let headers = vec![first.clone(), second.clone(), second.clone()];
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        HeadersProcess::new(&fbs_headers, &synchronizer, peer, &network_context).execute();
+
+        // the whole batch is rejected
+        assert!(synchronizer.get_header(&first.hash()).is_none());
+        assert!(synchronizer.get_header(&second.hash()).is_none());
+        assert_eq!(synchronizer.peer_score(peer), Some(20));
+        assert!(network_context.reported_bad.lock().contains(&peer));
+    }
+
+    #[test]
+    fn test_blocks_in_transit_window() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=100 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+
+        let window = 6;
+        let mut config = Config::default();
+        config.max_blocks_per_getdata = 4;
+        config.max_blocks_in_transit_per_peer = window;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let peer = 0;
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0)).execute();
+
+        synchronizer1.peers.on_connected(peer, 0, false);
+        synchronizer1
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .expect("peer connected")
+            .sync_started = true;
+
+        let nc = mock_network_context(1);
+        let requested = || {
+            nc.sent
+                .lock()
+                .iter()
+                .map(|(_, data)| {
+                    get_root::<SyncMessage>(data)
+                        .payload_as_get_blocks()
+                        .expect("getblocks")
+                        .block_hashes()
+                        .expect("block hashes")
+                        .len()
+                })
+                .collect::<Vec<_>>()
+        };
+        let inflight = || synchronizer1.peers.blocks_inflight.read()[&peer].len();
+
+        // the second batch only fills what is left of the window
+        for _ in 0..4 {
+            synchronizer1.find_blocks_to_fetch(&nc);
+            assert!(inflight() <= window);
+        }
+        assert_eq!(requested(), vec![4, 2]);
+        assert_eq!(inflight(), window);
+
+        // an answered request makes room for a single new one
+        let received = headers[0].hash();
+        assert!(synchronizer1
+            .peers
+            .blocks_inflight
+            .write()
+            .get_mut(&peer)
+            .expect("inflight")
+            .remove(&received));
+        synchronizer1.find_blocks_to_fetch(&nc);
+        assert_eq!(requested(), vec![4, 2, 1]);
+        assert_eq!(inflight(), window);
+    }
+
+    #[test]
+    fn test_tip_header_view() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        // the tip moves while the views are taken, each one must match its own block
+        let (inserting, inserted) = ::std::sync::mpsc::channel::<()>();
+        let handle = {
+            let shared = shared.clone();
+            ::std::thread::spawn(move || {
+                let _inserting = inserting;
+                for i in 1..=20 {
+                    insert_block(&chain_controller, &shared, i, i);
+                }
+            })
+        };
+        loop {
+            let view = synchronizer.tip_header_view();
+            let block_ext = shared.block_ext(&view.hash()).unwrap();
+            assert_eq!(view.total_difficulty(), &block_ext.total_difficulty);
+            assert_eq!(view.total_uncles_count(), block_ext.total_uncles_count);
+            if inserted.try_recv() != Err(::std::sync::mpsc::TryRecvError::Empty) {
+                break;
+            }
+        }
+        handle.join().unwrap();
+
+        let tip = shared.tip_header().read().clone();
+        let view = synchronizer.tip_header_view();
+        assert_eq!(view.number(), 20);
+        assert_eq!(view.inner(), tip.inner());
+        assert_eq!(view.total_difficulty(), tip.total_difficulty());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_get_headers_oversized_locator() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let num = 20;
+        for i in 1..=num {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let nc = mock_network_context(2);
+        let unknown = |len: usize| {
+            (0..len)
+                .map(|i| H256::from_trimmed_hex_str(&format!("{:x}", i + 1)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let get_headers = |peer: PeerIndex, locator: &[H256]| {
+            synchronizer.peers.on_connected(peer, 0, false);
+            let fbb = &mut FlatBufferBuilder::new();
+            let message = SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION, locator);
+            fbb.finish(message, None);
+            let message = get_root::<SyncMessage>(fbb.finished_data());
+            GetHeadersProcess::new(
+                &message.payload_as_get_headers().unwrap(),
+                &synchronizer,
+                peer,
+                &nc,
+            )
+            .execute();
+            let sent = nc.sent.lock();
+            let headers = get_root::<SyncMessage>(&sent.last().unwrap().1)
+                .payload_as_headers()
+                .unwrap();
+            FlatbuffersVectorIterator::new(headers.headers().unwrap())
+                .map(|header| Header::from(header).number())
+                .collect::<Vec<_>>()
+        };
+
+        // a slightly oversized locator is answered from its most recent hashes
+        let mut locator = vec![shared.block_hash(10).unwrap()];
+        locator.extend(unknown(MAX_LOCATOR_SIZE));
+        assert_eq!(get_headers(0, &locator), (11..=num).collect::<Vec<_>>());
+        assert_eq!(synchronizer.peer_score(0), None);
+
+        // the known hash is beyond the hashes looked up, the peer is scored
+        let mut locator = unknown(MAX_TOLERATED_LOCATOR_SIZE);
+        locator.push(shared.block_hash(10).unwrap());
+        assert!(get_headers(1, &locator).is_empty());
+        assert_eq!(synchronizer.peer_score(1), Some(20));
+        assert!(nc.reported_bad.lock().is_empty());
+        assert!(nc.disconnected.lock().is_empty());
+    }
+
+    #[cfg(not(disable_faketime))]
+    #[test]
+    fn test_inbound_ratio_eviction() {
+        let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+        faketime::enable(&faketime_file);
+
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let mut config = Config::default();
+        config.max_inbound_per_outbound = 2;
+        let synchronizer = Synchronizer::new(chain_controller, shared.clone(), config);
+        // peer 0 is the only outbound one
+        let mut nc = mock_network_context(6);
+        for peer in 1..6 {
+            nc.sessions.get_mut(&peer).unwrap().peer.endpoint_role = Endpoint::Listener;
+        }
+        let tip = shared.tip_header().read().inner().clone();
+        let block = gen_block(tip.clone(), shared.calculate_difficulty(&tip).unwrap(), 1);
+
+        for peer in 0..3 {
+            synchronizer
+                .peers
+                .on_connected(peer, u64::max_value(), false);
+        }
+        synchronizer.peers.misbehavior(1, 50);
+        synchronizer.peers.misbehavior(2, 10);
+        synchronizer.eviction(&nc);
+        assert!(nc.disconnected.lock().is_empty());
+
+        // inbound peers now dominate, the misbehaving ones and then the longest idle go
+        for peer in 3..6 {
+            synchronizer
+                .peers
+                .on_connected(peer, u64::max_value(), false);
+        }
+        faketime::write_millis(&faketime_file, 1).expect("write millis");
+        synchronizer.peers.block_received(3, &block);
+        faketime::write_millis(&faketime_file, IDLE_INBOUND_PEER_TIMEOUT + 2)
+            .expect("write millis");
+        synchronizer.peers.block_received(5, &block);
+        synchronizer.eviction(&nc);
+
+        let mut disconnected = nc.disconnected.lock().iter().cloned().collect::<Vec<_>>();
+        disconnected.sort();
+        assert_eq!(disconnected, vec![1, 2, 4]);
+        // peer 2 wasn't scored enough to count as misbehaving, it goes for being idle
+        let codes = nc
+            .sent
+            .lock()
+            .iter()
+            .filter_map(|(peer, data)| {
+                get_root::<SyncMessage>(data)
+                    .payload_as_disconnect_reason()
+                    .map(|reason| (*peer, reason.code()))
+            })
+            .collect::<FnvHashMap<_, _>>();
+        assert_eq!(codes[&1], DisconnectCode::Misbehavior);
+        assert_eq!(codes[&2], DisconnectCode::Useless);
+        assert_eq!(codes[&4], DisconnectCode::Useless);
+
+        // without any outbound peer there is no ratio to keep
+        let mut nc = mock_network_context(6);
+        for peer in 0..6 {
+            nc.sessions.get_mut(&peer).unwrap().peer.endpoint_role = Endpoint::Listener;
+        }
+        synchronizer.peers.misbehavior(3, 50);
+        synchronizer.eviction(&nc);
+        assert!(nc.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_process_new_blocks_by_height() {
+        let (chain_controller, shared, notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let peer = 0;
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        let mut blocks: Vec<Block> = Vec::new();
+        for nonce in 1..=10 {
+            let parent = blocks
+                .last()
+                .map_or_else(|| tip.clone(), |block| block.header().clone());
+            blocks.push(gen_block(parent, difficulty.clone(), nonce));
+        }
+        for block in &blocks {
+            synchronizer
+                .insert_block_status(block.header().hash().clone(), BlockStatus::VALID_MASK);
+        }
+        let new_tip_receiver = notify.subscribe_new_tip("test_process_new_blocks_by_height");
+
+        // children delivered ahead of their parents
+        let mut delivered = blocks.clone();
+        delivered.reverse();
+        delivered.swap(0, 4);
+        let results = synchronizer.process_new_blocks(peer, delivered);
+
+        assert!(results
+            .iter()
+            .all(|result| *result == BlockProcessResult::Stored));
+        let committed = blocks
+            .iter()
+            .map(|_| new_tip_receiver.recv().expect("new tip").header().number())
+            .collect::<Vec<_>>();
+        let expected = blocks
+            .iter()
+            .map(|block| block.header().number())
+            .collect::<Vec<_>>();
+        assert_eq!(committed, expected);
+        assert!(synchronizer.orphan_block_pool.is_empty());
+    }
+
+    #[test]
+    fn test_implausible_total_difficulty() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let peer = 1;
+        let tip = shared.tip_header().read().inner().clone();
+        let max = synchronizer.max_total_difficulty(tip.number() + 1).unwrap();
+        assert_eq!(&max, &(shared.consensus().min_difficulty() * 2u32));
+
+        let accept = |difficulty: U256, nonce: u64| {
+            let header = HeaderBuilder::default()
+                .parent_hash(tip.hash().clone())
+                .number(tip.number() + 1)
+                .timestamp(tip.timestamp() + 1)
+                .difficulty(difficulty)
+                .nonce(nonce)
+                .build();
+            let resolver = VerifierResolver::new(Some(&tip), &header, &synchronizer);
+            let result =
+                HeaderAcceptor::new(&header, peer, &synchronizer, resolver, AcceptAll::new())
+                    .accept();
+            (header.hash(), result)
+        };
+
+        // no chain has gathered that much work one block after genesis
+        let (hash, result) = accept(shared.consensus().min_difficulty() * 1_000_000u32, 1);
+        match result.error {
+            Some(ValidationError::ImplausibleTotalDifficulty) => {}
+            error => panic!("unexpected validation error {:?}", error),
+        }
+        assert_eq!(result.misbehavior, 100);
+        assert_eq!(
+            synchronizer.get_block_status(&hash),
+            BlockStatus::FAILED_MASK
+        );
+        assert!(synchronizer.peers.best_known_header(peer).is_none());
+        assert_eq!(synchronizer.best_known_header.read().hash(), tip.hash());
+
+        let (hash, result) = accept(shared.consensus().min_difficulty().clone(), 2);
+        assert!(result.is_valid());
+        assert_eq!(
+            synchronizer.peers.best_known_header(peer).unwrap().hash(),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_headers_of_known_invalid_block_rejected() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let peer = 1;
+
+        let tip = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&tip).unwrap();
+        // a block without cellbase is rejected by the chain
+        let invalid = BlockBuilder::default().with_header_builder(
+            HeaderBuilder::default()
+                .parent_hash(tip.hash().clone())
+                .number(tip.number() + 1)
+                .timestamp(tip.timestamp() + 1)
+                .difficulty(difficulty)
+                .nonce(1),
+        );
+        let hash = invalid.header().hash();
+        synchronizer.insert_block_status(hash.clone(), BlockStatus::VALID_MASK);
+        assert!(!synchronizer.is_known_invalid_block(&hash));
+
+        let result = synchronizer.process_new_block(0, invalid.clone());
+        assert!(result.is_misbehavior(), "unexpected result {:?}", result);
+        assert!(synchronizer.is_known_invalid_block(&hash));
+
+        let header = invalid.header().clone();
+        let resolver = VerifierResolver::new(Some(&tip), &header, &synchronizer);
+        let result =
+            HeaderAcceptor::new(&header, peer, &synchronizer, resolver, AcceptAll::new()).accept();
+        match result.error {
+            Some(ValidationError::KnownInvalidBlock) => {}
+            error => panic!("unexpected validation error {:?}", error),
+        }
+        assert_eq!(result.misbehavior, 100);
+        assert!(synchronizer.peers.best_known_header(peer).is_none());
+
+        // served again, the block isn't handed to the chain a second time
+        assert_eq!(
+            synchronizer.process_new_block(peer, invalid),
+            BlockProcessResult::UnexpectedStatus(BlockStatus::FAILED_MASK)
+        );
+    }
+
+    #[test]
+    fn test_max_block_download_peers() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=40 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+
+        let mut config = Config::default();
+        config.max_block_download_peers = 2;
+        config.max_blocks_per_getdata = 2;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let peers = 6;
+        for peer in 0..peers {
+            HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+                .execute();
+            synchronizer1.peers.on_connected(peer, 0, false);
+            synchronizer1
+                .peers
+                .state
+                .write()
+                .get_mut(&peer)
+                .expect("peer connected")
+                .sync_started = true;
+        }
+
+        // the peers asked for blocks in a round
+        let downloading_round = || {
+            let nc = mock_network_context(peers);
+            synchronizer1.find_blocks_to_fetch(&nc);
+            let sent = nc.sent.lock();
+            sent.iter()
+                .filter(|(_, data)| {
+                    get_root::<SyncMessage>(data)
+                        .payload_as_get_blocks()
+                        .and_then(|get_blocks| get_blocks.block_hashes())
+                        .map_or(false, |hashes| !hashes.is_empty())
+                })
+                .map(|(peer, _)| *peer)
+                .collect::<FnvHashSet<_>>()
+        };
+
+        let mut asked = FnvHashSet::default();
+        for _ in 0..3 {
+            let round = downloading_round();
+            assert_eq!(round.len(), 2, "unexpected round {:?}", round);
+            // the others take their turn before a peer is asked again
+            assert!(round.is_disjoint(&asked));
+            asked.extend(round);
+        }
+        assert_eq!(asked.len(), peers);
+    }
+
+    #[test]
+    fn test_connected_peers_by_direction() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared);
+        let mut nc = mock_network_context(6);
+        for peer in 0..2 {
+            nc.sessions.get_mut(&peer).unwrap().peer.endpoint_role = Endpoint::Listener;
+        }
+        assert_eq!(synchronizer.connected_peer_count(), 0);
+        assert_eq!(synchronizer.connected_peers_by_direction(&nc), (0, 0));
+
+        // peer 5 has a session but isn't connected to the sync protocol
+        for peer in 0..5 {
+            synchronizer.on_connected(&nc, peer);
+        }
+        assert_eq!(synchronizer.connected_peer_count(), 5);
+        assert_eq!(synchronizer.connected_peers_by_direction(&nc), (2, 3));
+
+        synchronizer.peers.disconnected(0);
+        assert_eq!(synchronizer.connected_peer_count(), 4);
+        assert_eq!(synchronizer.connected_peers_by_direction(&nc), (1, 3));
+
+        // the session of peer 4 is gone, it counts in neither direction
+        nc.sessions.remove(&4);
+        assert_eq!(synchronizer.connected_peer_count(), 4);
+        assert_eq!(synchronizer.connected_peers_by_direction(&nc), (1, 2));
+    }
+
+    #[test]
+    fn test_slowest_download_peer_evicted() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=40 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(0, &H256::zero());
+
+        let mut config = Config::default();
+        config.max_block_download_peers = 2;
+        config.max_blocks_per_getdata = 2;
+        config.evict_slow_download_peers = true;
+        let synchronizer1 = Synchronizer::new(chain_controller1.clone(), shared1.clone(), config);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let fbs_headers = FbsHeaders::build(fbb, &headers);
+        fbb.finish(fbs_headers, None);
+        let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
+        let peers = 3;
+        for peer in 0..peers {
+            HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+                .execute();
+            synchronizer1.peers.on_connected(peer, 0, false);
+            synchronizer1
+                .peers
+                .state
+                .write()
+                .get_mut(&peer)
+                .expect("peer connected")
+                .sync_started = true;
+        }
+
+        let downloading_round = || {
+            let nc = mock_network_context(peers);
+            synchronizer1.find_blocks_to_fetch(&nc);
+            let sent = nc.sent.lock();
+            sent.iter()
+                .filter(|(_, data)| {
+                    get_root::<SyncMessage>(data)
+                        .payload_as_get_blocks()
+                        .and_then(|get_blocks| get_blocks.block_hashes())
+                        .map_or(false, |hashes| !hashes.is_empty())
+                })
+                .map(|(peer, _)| *peer)
+                .collect::<FnvHashSet<_>>()
+        };
+        let deliver = |peer: PeerIndex, count: usize| {
+            let hashes: Vec<H256> = synchronizer1.peers.blocks_inflight.read()[&peer]
+                .blocks
+                .iter()
+                .take(count)
+                .cloned()
+                .collect();
+            for hash in hashes {
+                let block = shared2.block(&hash).expect("block stored");
+                synchronizer1.peers.block_received(peer, &block);
+            }
+        };
+
+        // nothing measured yet, the peers take their turns
+        assert_eq!(
+            downloading_round(),
+            vec![0, 1].into_iter().collect::<FnvHashSet<_>>()
+        );
+        assert_eq!(
+            downloading_round(),
+            vec![2, 0].into_iter().collect::<FnvHashSet<_>>()
+        );
+        deliver(2, 2);
+        deliver(0, 1);
+        assert_eq!(synchronizer1.peers.block_throughput(2), 2);
+        assert_eq!(synchronizer1.peers.block_throughput(0), 1);
+        assert_eq!(synchronizer1.peers.block_throughput(1), 0);
+
+        // peers 1 and 2 are due, peer 0 delivered more than peer 1 and takes its slot
+        assert_eq!(
+            downloading_round(),
+            vec![0, 2].into_iter().collect::<FnvHashSet<_>>()
+        );
+        assert!(synchronizer1.peers.blocks_inflight.read()[&1].is_empty());
+    }
+
+    #[test]
+    fn test_tip_seeds_best_known_header() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        for i in 1..=3 {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        for i in 1..=2 {
+            insert_block(&chain_controller1, &shared1, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller1.clone(), shared1.clone());
+        let nc = mock_network_context(1);
+
+        synchronizer.on_connected(&nc, 0);
+        assert!(nc.sent.lock().iter().any(|(peer, data)| {
+            *peer == 0 && get_root::<SyncMessage>(data).payload_as_get_tip().is_some()
+        }));
+        assert!(synchronizer.peers.best_known_header(0).is_none());
+
+        // the peer is a block ahead, known by its parent
+        let tip = shared2.tip_header().read().inner().clone();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_tip(fbb, &tip);
+        fbb.finish(message, None);
+        synchronizer.process(&nc, 0, get_root::<SyncMessage>(fbb.finished_data()));
+
+        let best_known_header = synchronizer
+            .peers
+            .best_known_header(0)
+            .expect("seeded by the tip");
+        assert_eq!(best_known_header.inner(), &tip);
+        assert_eq!(
+            best_known_header.total_difficulty(),
+            shared2.tip_header().read().total_difficulty()
+        );
+
+        // a tip we didn't ask for is ignored
+        insert_block(&chain_controller2, &shared2, 4, 4);
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_tip(fbb, &shared2.tip_header().read().inner());
+        fbb.finish(message, None);
+        synchronizer.process(&nc, 0, get_root::<SyncMessage>(fbb.finished_data()));
+        assert_eq!(
+            synchronizer.peers.best_known_header(0).unwrap().inner(),
+            &tip
+        );
+    }
+
+    #[test]
+    fn test_task_priority() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_block(fbb, &shared.block(&shared.genesis_hash()).unwrap());
+        fbb.finish(message, None);
+        assert_eq!(
+            synchronizer.task_priority(fbb.finished_data()),
+            TaskPriority::Critical
+        );
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_ping(fbb, 1);
+        fbb.finish(message, None);
+        assert_eq!(
+            synchronizer.task_priority(fbb.finished_data()),
+            TaskPriority::High
+        );
+    }
+
+    #[test]
+    fn test_block_server_does_not_delay_headers() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _) = start_chain(Some(consensus.clone()), None);
+        let served = 20;
+        for i in 1..=served {
+            insert_block(&chain_controller1, &shared1, i, i);
+        }
+        for i in 1..=2 * served {
+            insert_block(&chain_controller2, &shared2, i, i);
+        }
+        let synchronizer1 = gen_synchronizer(chain_controller1.clone(), shared1.clone())
+            .block_server(Some("BlockServer"));
+        let synchronizer2 = gen_synchronizer(chain_controller2.clone(), shared2.clone());
+        let headers = synchronizer2.get_locator_response(served, &H256::zero());
+
+        let (requester, announcer) = (0, 1);
+        let hashes = (1..=served)
+            .map(|number| shared1.block_hash(number).unwrap())
+            .collect::<Vec<_>>();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_blocks(fbb, &hashes);
+        fbb.finish(message, None);
+        let get_blocks = fbb.finished_data().to_vec();
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_headers(fbb, &headers);
+        fbb.finish(message, None);
+        let fbs_headers = fbb.finished_data().to_vec();
+
+        let serving_nc = mock_network_context(2);
+        {
+            // the block server is stuck sending the blocks
+            let sent = serving_nc.sent.lock();
+            synchronizer1.received(Box::new(serving_nc.clone()), requester, &get_blocks);
+            synchronizer1.received(Box::new(mock_network_context(2)), announcer, &fbs_headers);
+            assert_eq!(synchronizer1.best_known_header.read().number(), 2 * served);
+            assert!(sent.is_empty());
+        }
+
+        let start = ::std::time::Instant::now();
+        while serving_nc.sent.lock().len() < served as usize {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "blocks not served"
+            );
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(serving_nc
+            .sent
+            .lock()
+            .iter()
+            .all(|(peer, data)| *peer == requester
+                && get_root::<SyncMessage>(data).payload_type() == SyncPayload::Block));
+    }
+
+    #[test]
+    fn test_get_headers_from_stale_tip() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=5 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        // the peer's tip, outrun by our main chain
+        let parent = shared.block_header(&shared.block_hash(3).unwrap()).unwrap();
+        let difficulty = shared.calculate_difficulty(&parent).unwrap();
+        let stale = gen_block(parent, difficulty, 100);
+        chain_controller
+            .process_block(Arc::new(stale.clone()))
+            .expect("process block ok");
+        let stale_hash = stale.header().hash();
+        assert_eq!(shared.block_number(&stale_hash), None);
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(fbb, GET_HEADERS_VERSION, &[stale_hash]);
+        fbb.finish(message, None);
+        let message = get_root::<SyncMessage>(fbb.finished_data());
+        let get_headers = message.payload_as_get_headers().unwrap();
+        let peer = 0;
+        let respond = |notify_stale_tips| {
+            let mut config = Config::default();
+            config.notify_stale_tips = notify_stale_tips;
+            let synchronizer = Synchronizer::new(chain_controller.clone(), shared.clone(), config);
+            synchronizer.peers.on_connected(peer, 0, false);
+            let nc = mock_network_context(1);
+            GetHeadersProcess::new(&get_headers, &synchronizer, peer, &nc).execute();
+            assert!(nc.disconnected.lock().is_empty());
+            let sent = nc.sent.lock();
+            assert_eq!(sent.len(), 1);
+            let headers = get_root::<SyncMessage>(&sent[0].1)
+                .payload_as_headers()
+                .unwrap();
+            FlatbuffersVectorIterator::new(headers.headers().unwrap())
+                .map(Header::from)
+                .collect::<Vec<_>>()
+        };
+
+        // an unknown starting point without the flag
+        assert!(respond(false).is_empty());
+
+        let headers = respond(true);
+        assert_eq!(
+            headers.iter().map(Header::number).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        // the competing header at the height of the peer's tip
+        assert_eq!(headers[0].number(), stale.header().number());
+        assert_eq!(headers[0].hash(), shared.block_hash(4).unwrap());
+        assert_ne!(headers[0].hash(), stale.header().hash());
+    }
+
+    #[test]
+    fn test_locate_peer_common_block_cached() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..=20 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        // the peer's branch, forked off at 5 and outrun by our main chain
+        let mut parent = shared.block_header(&shared.block_hash(5).unwrap()).unwrap();
+        let mut branch = Vec::new();
+        for nonce in 100..110 {
+            let difficulty = shared.calculate_difficulty(&parent).unwrap();
+            let block = gen_block(parent, difficulty, nonce);
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+            parent = block.header().clone();
+            branch.push(parent.hash());
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peer = 0;
+        synchronizer.peers.on_connected(peer, 0, false);
+        let locator = |tip: &H256| {
+            vec![
+                tip.clone(),
+                shared.block_hash(3).unwrap(),
+                shared.genesis_hash(),
+            ]
+        };
+        let common_block = || {
+            synchronizer.peers.state.read()[&peer]
+                .getheaders_common_block
+                .clone()
+        };
+
+        assert_eq!(
+            synchronizer.locate_peer_common_block(peer, &H256::zero(), &locator(&branch[4])),
+            Some(5)
+        );
+        assert_eq!(
+            common_block(),
+            Some(CommonBlock {
+                branch: branch[4].clone(),
+                number: 5,
+                hash: shared.block_hash(5).unwrap(),
+            })
+        );
+
+        // the branch below the previous tip is not walked again, the common block
+        // remembered for it is taken as is
+        let remembered = CommonBlock {
+            branch: branch[4].clone(),
+            number: 4,
+            hash: shared.block_hash(4).unwrap(),
+        };
+        synchronizer
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .unwrap()
+            .getheaders_common_block = Some(remembered);
+        assert_eq!(
+            synchronizer.locate_peer_common_block(peer, &H256::zero(), &locator(&branch[9])),
+            Some(4)
+        );
+        assert_eq!(common_block().unwrap().branch, branch[9]);
+
+        // a common block off our main chain, e.g. after a reorg, is forgotten
+        synchronizer
+            .peers
+            .state
+            .write()
+            .get_mut(&peer)
+            .unwrap()
+            .getheaders_common_block = Some(CommonBlock {
+            branch: branch[4].clone(),
+            number: 7,
+            hash: branch[1].clone(),
+        });
+        assert_eq!(
+            synchronizer.locate_peer_common_block(peer, &H256::zero(), &locator(&branch[9])),
+            Some(5)
+        );
+        assert_eq!(common_block().unwrap().hash, shared.block_hash(5).unwrap());
+    }
 }
@@ -1,8 +1,20 @@
+use ckb_core::block::{Block, BlockBuilder};
+use ckb_core::header::HeaderBuilder;
+use ckb_core::transaction::{
+    CellInput, CellOutput, ProposalShortId, Transaction, TransactionBuilder,
+};
 use ckb_network::{
-    CKBProtocolContext, CKBProtocolHandler, Error as NetworkError, PeerIndex, ProtocolId,
+    CKBProtocolContext, CKBProtocolHandler, Error as NetworkError, PeerId, PeerIndex, ProtocolId,
     SessionInfo, Severity, TimerToken,
 };
+use ckb_protocol::RelayMessage;
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_verification::{Error as VerifyError, Verifier};
+use flatbuffers::get_root;
+use numext_fixed_hash::H256;
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::thread;
@@ -139,12 +151,46 @@ impl TestNode {
     }
 }
 
+#[derive(Clone)]
 struct TestNetworkContext {
     protocol: ProtocolId,
     msg_senders: HashMap<(ProtocolId, PeerIndex), Sender<Vec<u8>>>,
     timer_senders: HashMap<(ProtocolId, TimerToken), Sender<()>>,
 }
 
+impl TestNetworkContext {
+    // Connected to peers 0 to `peers` on each of `protocols` and talking the first one, the
+    // receivers get what is sent to each peer on each protocol
+    fn connected(
+        protocols: &[ProtocolId],
+        peers: PeerIndex,
+    ) -> (Self, HashMap<(ProtocolId, PeerIndex), Receiver<Vec<u8>>>) {
+        let mut msg_senders = HashMap::new();
+        let mut msg_receivers = HashMap::new();
+        for protocol in protocols {
+            for peer in 0..peers {
+                let (sender, receiver) = channel();
+                msg_senders.insert((*protocol, peer), sender);
+                msg_receivers.insert((*protocol, peer), receiver);
+            }
+        }
+        let nc = TestNetworkContext {
+            protocol: protocols[0],
+            msg_senders,
+            timer_senders: HashMap::new(),
+        };
+        (nc, msg_receivers)
+    }
+
+    // The same peers talking another protocol
+    fn with_protocol(&self, protocol: ProtocolId) -> Self {
+        TestNetworkContext {
+            protocol,
+            ..self.clone()
+        }
+    }
+}
+
 impl CKBProtocolContext for TestNetworkContext {
     fn send(&self, peer: PeerIndex, data: Vec<u8>) -> Result<(), NetworkError> {
         if let Some(sender) = self.msg_senders.get(&(self.protocol, peer)) {
@@ -155,10 +201,13 @@ impl CKBProtocolContext for TestNetworkContext {
     /// Send a packet over the network to another peer using specified protocol.
     fn send_protocol(
         &self,
-        _peer: PeerIndex,
-        _protocol: ProtocolId,
-        _data: Vec<u8>,
+        peer: PeerIndex,
+        protocol: ProtocolId,
+        data: Vec<u8>,
     ) -> Result<(), NetworkError> {
+        if let Some(sender) = self.msg_senders.get(&(protocol, peer)) {
+            let _ = sender.send(data);
+        }
         Ok(())
     }
 
@@ -192,6 +241,78 @@ impl CKBProtocolContext for TestNetworkContext {
     }
 
     fn connected_peers(&self) -> Vec<PeerIndex> {
-        self.msg_senders.keys().map(|k| k.1).collect::<Vec<_>>()
+        self.msg_senders
+            .keys()
+            .filter(|k| k.0 == self.protocol)
+            .map(|k| k.1)
+            .collect::<Vec<_>>()
+    }
+
+    fn local_peer_id(&self) -> Option<PeerId> {
+        None
+    }
+}
+
+// A child of `parent` with a cellbase and the given transactions and proposals
+fn build_block<CI: ChainIndex>(
+    shared: &Shared<CI>,
+    parent: &Block,
+    txs: Vec<Transaction>,
+    proposals: Vec<ProposalShortId>,
+) -> Block {
+    let number = parent.header().number() + 1;
+    let timestamp = parent.header().timestamp() + 1;
+    let difficulty = shared.calculate_difficulty(&parent.header()).unwrap();
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .output(CellOutput::default())
+        .build();
+
+    let header_builder = HeaderBuilder::default()
+        .parent_hash(parent.header().hash().clone())
+        .number(number)
+        .timestamp(timestamp)
+        .difficulty(difficulty)
+        .cellbase_id(cellbase.hash().clone());
+
+    BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transactions(txs)
+        .proposal_transactions(proposals)
+        .with_header_builder(header_builder)
+}
+
+// The hashes of each transaction inventory received so far, other messages are skipped
+fn announced_transactions(receiver: &Receiver<Vec<u8>>) -> Vec<Vec<H256>> {
+    receiver
+        .try_iter()
+        .filter_map(|data| {
+            get_root::<RelayMessage>(&data)
+                .payload_as_transaction_inventory()
+                .map(|inventory| {
+                    inventory
+                        .transaction_hashes()
+                        .unwrap()
+                        .iter()
+                        .map(Into::into)
+                        .collect()
+                })
+        })
+        .collect()
+}
+
+// Lets through whatever it verifies, to test the checks around a verifier
+pub struct AcceptAll<T>(PhantomData<T>);
+
+impl<T> AcceptAll<T> {
+    pub fn new() -> Self {
+        AcceptAll(PhantomData)
+    }
+}
+
+impl<T> Verifier for AcceptAll<T> {
+    type Target = T;
+    fn verify(&self, _target: &T) -> Result<(), VerifyError> {
+        Ok(())
     }
 }
@@ -1,16 +1,21 @@
-use crate::relayer::TX_PROPOSAL_TOKEN;
-use crate::tests::TestNode;
-use crate::{Relayer, RELAY_PROTOCOL_ID};
+use crate::relayer::{BLOCK_TRANSACTIONS_TIMEOUT_TOKEN, TX_ANNOUNCEMENT_TOKEN, TX_PROPOSAL_TOKEN};
+use crate::synchronizer::BlockProcessResult;
+use crate::tests::{announced_transactions, build_block, TestNetworkContext, TestNode};
+use crate::{Config, Relayer, Synchronizer, RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID};
 use ckb_chain::chain::{ChainBuilder, ChainController};
 use ckb_chain_spec::consensus::Consensus;
-use ckb_core::block::BlockBuilder;
+use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::header::HeaderBuilder;
 use ckb_core::script::Script;
-use ckb_core::transaction::{CellInput, CellOutput, OutPoint, TransactionBuilder};
+use ckb_core::transaction::{CellInput, CellOutput, OutPoint, Transaction, TransactionBuilder};
 use ckb_db::memorydb::MemoryKeyValueDB;
+use ckb_network::{CKBProtocolHandler, PeerIndex};
 use ckb_notify::NotifyService;
-use ckb_pool::txs_pool::{PoolConfig, TransactionPoolController, TransactionPoolService};
-use ckb_protocol::RelayMessage;
+use ckb_pool::txs_pool::types::InsertionResult;
+use ckb_pool::txs_pool::{
+    PoolConfig, PoolError, TransactionPoolController, TransactionPoolService,
+};
+use ckb_protocol::{AnnouncementMode, RelayMessage, SyncMessage};
 use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
 use ckb_shared::store::ChainKVStore;
 use faketime::{self, unix_time_as_millis};
@@ -94,7 +99,7 @@ fn relay_compact_block_with_one_tx() {
                     .expect("process block should be OK");
 
                 let fbb = &mut FlatBufferBuilder::new();
-                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
                 fbb.finish(message, None);
                 node1.broadcast(RELAY_PROTOCOL_ID, fbb.finished_data().to_vec());
             }
@@ -130,7 +135,7 @@ fn relay_compact_block_with_one_tx() {
                     .expect("process block should be OK");
 
                 let fbb = &mut FlatBufferBuilder::new();
-                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
                 fbb.finish(message, None);
                 node1.broadcast(RELAY_PROTOCOL_ID, fbb.finished_data().to_vec());
             }
@@ -233,7 +238,7 @@ fn relay_compact_block_with_missing_indexs() {
                     .expect("process block should be OK");
 
                 let fbb = &mut FlatBufferBuilder::new();
-                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
                 fbb.finish(message, None);
                 node1.broadcast(RELAY_PROTOCOL_ID, fbb.finished_data().to_vec());
             }
@@ -269,7 +274,7 @@ fn relay_compact_block_with_missing_indexs() {
                     .expect("process block should be OK");
 
                 let fbb = &mut FlatBufferBuilder::new();
-                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+                let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
                 fbb.finish(message, None);
                 node1.broadcast(RELAY_PROTOCOL_ID, fbb.finished_data().to_vec());
             }
@@ -295,6 +300,677 @@ fn relay_compact_block_with_missing_indexs() {
     assert_eq!(shared2.tip_header().read().number(), 5);
 }
 
+#[test]
+fn broadcast_local_transaction() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let tx = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(last_cellbase.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+
+    relayer
+        .broadcast_transaction(&nc, &tx)
+        .expect("broadcast transaction");
+    relayer.flush_transaction_announcements(&nc);
+
+    assert!(tx_pool_controller.contains_key(tx.proposal_short_id()));
+    for peer in 0..3 {
+        assert_eq!(
+            announced_transactions(&msg_receivers[&(RELAY_PROTOCOL_ID, peer)]),
+            vec![vec![tx.hash()]]
+        );
+    }
+}
+
+#[test]
+fn announce_block_with_inventory() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, chain_controller, _tx_pool_controller) = setup_relayer(&thread_name, 3);
+
+    let (relay_nc, msg_receivers) =
+        TestNetworkContext::connected(&[RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID], 3);
+    let sync_nc = relay_nc.with_protocol(SYNC_PROTOCOL_ID);
+
+    // peer 1 asks for inventory announcements, peer 2 keeps the compact block default
+    {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_announcement_preference(fbb, AnnouncementMode::Inventory);
+        fbb.finish(message, None);
+        relayer.received(Box::new(relay_nc.clone()), 1, fbb.finished_data());
+    }
+
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let block = build_block(&shared, &last_block, vec![], vec![]);
+    let hash = block.header().hash().clone();
+    relayer.accept_block(&relay_nc, 0, &Arc::new(block));
+
+    assert!(msg_receivers[&(RELAY_PROTOCOL_ID, 0)].try_recv().is_err());
+    let data = msg_receivers[&(RELAY_PROTOCOL_ID, 1)]
+        .try_recv()
+        .expect("inventory announced");
+    let announced = get_root::<RelayMessage>(&data)
+        .payload_as_block_inventory()
+        .expect("block inventory")
+        .block_hashes()
+        .unwrap()
+        .iter()
+        .map(Into::into)
+        .collect::<Vec<H256>>();
+    assert_eq!(announced, vec![hash.clone()]);
+    let data = msg_receivers[&(RELAY_PROTOCOL_ID, 2)]
+        .try_recv()
+        .expect("compact block announced");
+    assert!(get_root::<RelayMessage>(&data)
+        .payload_as_compact_block()
+        .is_some());
+
+    // peer 1 asks the announced block back
+    let synchronizer = Synchronizer::new(chain_controller, shared, Config::default());
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_get_blocks(fbb, &[hash.clone()]);
+    fbb.finish(message, None);
+    synchronizer.received(Box::new(sync_nc), 1, fbb.finished_data());
+
+    let data = msg_receivers[&(SYNC_PROTOCOL_ID, 1)]
+        .try_recv()
+        .expect("block responded");
+    let responded: Block = get_root::<SyncMessage>(&data)
+        .payload_as_block()
+        .expect("block")
+        .into();
+    assert_eq!(responded.header().hash(), hash);
+}
+
+#[test]
+fn accept_block_blames_invalid_block() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, _tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let (nc, _msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+
+    // a block without cellbase is rejected by the chain
+    let invalid = BlockBuilder::default().with_header_builder(
+        HeaderBuilder::default()
+            .parent_hash(last_block.header().hash().clone())
+            .number(last_block.header().number() + 1)
+            .timestamp(last_block.header().timestamp() + 1)
+            .difficulty(shared.calculate_difficulty(&last_block.header()).unwrap()),
+    );
+    let result = relayer.accept_block(&nc, 0, &Arc::new(invalid));
+    assert!(result.is_misbehavior(), "unexpected result {:?}", result);
+    assert_eq!(relayer.peers().misbehavior.read().get(&0), Some(&100));
+
+    let block = Arc::new(build_block(&shared, &last_block, vec![], vec![]));
+    assert_eq!(
+        relayer.accept_block(&nc, 1, &block),
+        BlockProcessResult::Stored
+    );
+    assert_eq!(
+        relayer.accept_block(&nc, 2, &block),
+        BlockProcessResult::AlreadyStored
+    );
+    assert_eq!(relayer.peers().misbehavior.read().get(&2), None);
+}
+
+#[test]
+fn relay_orphan_promoted_by_block() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let parent = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(last_cellbase.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(
+            50,
+            Vec::new(),
+            create_valid_script().type_hash(),
+            None,
+        ))
+        .build();
+    let orphan = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(parent.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+
+    // propose both, then wait for the pool to keep the orphan
+    let proposal_block = build_block(
+        &shared,
+        &last_block,
+        vec![],
+        vec![parent.proposal_short_id(), orphan.proposal_short_id()],
+    );
+    chain_controller
+        .process_block(Arc::new(proposal_block.clone()))
+        .expect("process block should be OK");
+    loop {
+        match tx_pool_controller.add_transaction(orphan.clone()) {
+            Ok(InsertionResult::Orphan) | Err(PoolError::AlreadyInPool) => break,
+            _ => thread::sleep(time::Duration::from_millis(10)),
+        }
+    }
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+
+    // the block commits the parent, accepted through the chain like the synchronizer
+    // does, the orphan is promoted and relayed once the new tip reaches the relayer
+    let block = build_block(&shared, &proposal_block, vec![parent], vec![]);
+    chain_controller
+        .process_block(Arc::new(block))
+        .expect("process block should be OK");
+    while !relayer.is_recently_relayed(&orphan.hash()) {
+        relayer.relay_promoted_orphans(&nc);
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    relayer.flush_transaction_announcements(&nc);
+
+    assert!(tx_pool_controller
+        .get_potential_transactions()
+        .contains(&orphan));
+    for receiver in msg_receivers.values() {
+        let announced = announced_transactions(receiver);
+        assert_eq!(announced, vec![vec![orphan.hash()]]);
+    }
+}
+
+#[test]
+fn relay_transaction_package() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let parent = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(last_cellbase.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(
+            50,
+            Vec::new(),
+            create_valid_script().type_hash(),
+            None,
+        ))
+        .build();
+    let child = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(parent.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+    tx_pool_controller
+        .add_transaction(parent.clone())
+        .expect("add parent");
+    tx_pool_controller
+        .add_transaction(child.clone())
+        .expect("add child");
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+
+    // the child is received from peer 0, the parent is announced ahead of it to the others
+    relayer.relay_transaction(&nc, 0, &child);
+    relayer.flush_transaction_announcements(&nc);
+
+    assert!(relayer.is_recently_relayed(&parent.hash()));
+    for ((_, peer), receiver) in &msg_receivers {
+        let announced = announced_transactions(receiver);
+        if *peer == 0 {
+            assert!(announced.is_empty());
+        } else {
+            assert_eq!(announced, vec![vec![parent.hash(), child.hash()]]);
+        }
+    }
+}
+
+#[test]
+fn relay_transactions_returned_by_reorg() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let spend = |index: u32| {
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(last_cellbase.hash().clone(), index),
+                create_valid_script(),
+            ))
+            .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+            .build()
+    };
+    // only the first block of the old branch commits `returned`
+    let returned = spend(0);
+    let recommitted = spend(1);
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+
+    let proposal_block = build_block(
+        &shared,
+        &last_block,
+        vec![],
+        vec![
+            returned.proposal_short_id(),
+            recommitted.proposal_short_id(),
+        ],
+    );
+    chain_controller
+        .process_block(Arc::new(proposal_block.clone()))
+        .expect("process block should be OK");
+    let old_block = build_block(
+        &shared,
+        &proposal_block,
+        vec![returned.clone(), recommitted.clone()],
+        vec![],
+    );
+    relayer.accept_block(&nc, 0, &Arc::new(old_block));
+    assert!(relayer.is_confirmed(&returned.hash()));
+    assert!(relayer.is_confirmed(&recommitted.hash()));
+
+    // a longer branch commits `recommitted` only
+    let new_block = build_block(&shared, &proposal_block, vec![recommitted.clone()], vec![]);
+    let new_tip = build_block(&shared, &new_block, vec![], vec![]);
+    chain_controller
+        .process_block(Arc::new(new_block))
+        .expect("process block should be OK");
+    chain_controller
+        .process_block(Arc::new(new_tip.clone()))
+        .expect("process block should be OK");
+    assert_eq!(shared.tip_header().read().hash(), new_tip.header().hash());
+
+    // the switch fork notification reaches the relayer asynchronously
+    while relayer.is_confirmed(&returned.hash()) {
+        relayer.reconcile_forks(&nc);
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    assert!(relayer.is_confirmed(&recommitted.hash()));
+    while tx_pool_controller
+        .get_transaction(returned.proposal_short_id())
+        .is_none()
+    {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    relayer.flush_transaction_announcements(&nc);
+    for receiver in msg_receivers.values() {
+        let announced = announced_transactions(receiver);
+        assert_eq!(announced, vec![vec![returned.hash()]]);
+    }
+}
+
+#[test]
+fn fetch_missing_transactions_from_another_announcer() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, chain_controller, _tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    // never in our pool, both are missing from the compact block
+    let txs = (0..2u32)
+        .map(|index| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(last_cellbase.hash().clone(), index),
+                    create_valid_script(),
+                ))
+                .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let proposal_block = build_block(
+        &shared,
+        &last_block,
+        vec![],
+        txs.iter().map(|tx| tx.proposal_short_id()).collect(),
+    );
+    chain_controller
+        .process_block(Arc::new(proposal_block.clone()))
+        .expect("process block should be OK");
+    let block = build_block(&shared, &proposal_block, txs.clone(), vec![]);
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+    let requested_indexes = |peer: PeerIndex| {
+        msg_receivers[&(RELAY_PROTOCOL_ID, peer)]
+            .try_iter()
+            .filter_map(|data| {
+                get_root::<RelayMessage>(&data)
+                    .payload_as_get_block_transactions()
+                    .map(|message| message.indexes().unwrap().safe_slice().to_vec())
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // peers 0 and 1 announce the block, the missing transactions are asked from peer 0
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new(), 0);
+    fbb.finish(message, None);
+    relayer.received(Box::new(nc.clone()), 0, fbb.finished_data());
+    relayer.received(Box::new(nc.clone()), 1, fbb.finished_data());
+    assert_eq!(requested_indexes(0), vec![vec![1, 2]]);
+    assert!(requested_indexes(1).is_empty());
+
+    // peer 0 doesn't answer in time
+    relayer.timer_triggered(Box::new(nc.clone()), BLOCK_TRANSACTIONS_TIMEOUT_TOKEN);
+    assert!(requested_indexes(1).is_empty());
+    faketime::write_millis(&faketime_file, relayer.config.block_transactions_timeout)
+        .expect("write millis");
+    relayer.timer_triggered(Box::new(nc.clone()), BLOCK_TRANSACTIONS_TIMEOUT_TOKEN);
+    assert_eq!(requested_indexes(1), vec![vec![1, 2]]);
+    assert!(requested_indexes(0).is_empty());
+    assert!(requested_indexes(2).is_empty());
+
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_block_transactions(fbb, &block.header().hash(), &txs);
+    fbb.finish(message, None);
+    relayer.received(Box::new(nc.clone()), 1, fbb.finished_data());
+    assert_eq!(shared.tip_header().read().hash(), block.header().hash());
+}
+
+#[test]
+fn submit_mined_block_echoed_back() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, _tx_pool_controller) = setup_relayer(&thread_name, 3);
+
+    let (nc, msg_receivers) =
+        TestNetworkContext::connected(&[RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID], 3);
+
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let block = build_block(&shared, &last_block, vec![], vec![]);
+    let hash = block.header().hash().clone();
+    relayer
+        .submit_mined_block(&nc, &Arc::new(block))
+        .expect("submit mined block");
+
+    assert_eq!(shared.tip_header().read().hash(), hash);
+    let mut relayed = Vec::new();
+    for peer in 0..3 {
+        let data = msg_receivers[&(RELAY_PROTOCOL_ID, peer)]
+            .try_recv()
+            .expect("compact block relayed");
+        assert!(get_root::<RelayMessage>(&data)
+            .payload_as_compact_block()
+            .is_some());
+        relayed.push(data);
+    }
+
+    // every peer echoes the compact block back
+    for (peer, data) in relayed.iter().enumerate() {
+        relayer.received(Box::new(nc.clone()), peer, data);
+        assert_eq!(relayer.pending_requests(peer), 0);
+    }
+
+    for receiver in msg_receivers.values() {
+        assert!(receiver.try_recv().is_err());
+    }
+}
+
+#[test]
+fn ignore_transaction_in_recent_block() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let tx = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(last_cellbase.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+
+    let proposal_block = build_block(&shared, &last_block, vec![], vec![tx.proposal_short_id()]);
+    chain_controller
+        .process_block(Arc::new(proposal_block.clone()))
+        .expect("process block should be OK");
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+
+    let block = build_block(&shared, &proposal_block, vec![tx.clone()], vec![]);
+    relayer.accept_block(&nc, 0, &Arc::new(block.clone()));
+    assert_eq!(shared.tip_header().read().hash(), block.header().hash());
+    for receiver in msg_receivers.values() {
+        let _ = receiver.try_iter().count();
+    }
+
+    // a peer lagging behind sends the committed transaction
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction(fbb, &tx);
+    fbb.finish(message, None);
+    relayer.received(Box::new(nc.clone()), 0, fbb.finished_data());
+
+    assert!(!tx_pool_controller.contains_key(tx.proposal_short_id()));
+    for receiver in msg_receivers.values() {
+        assert!(receiver.try_recv().is_err());
+    }
+}
+
+#[test]
+fn ingest_transaction_while_relay_disabled() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let transaction = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(last_cellbase.hash().clone(), 0),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+    relayer.set_relay_enabled(false);
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction(fbb, &transaction);
+    fbb.finish(message, None);
+    relayer.received(Box::new(nc.clone()), 0, fbb.finished_data());
+    relayer.flush_transaction_announcements(&nc);
+
+    // the transaction reaches our pool, but no peer hears of it
+    assert!(tx_pool_controller.contains_key(transaction.proposal_short_id()));
+    for receiver in msg_receivers.values() {
+        assert!(receiver.try_recv().is_err());
+    }
+}
+
+#[test]
+fn reject_oversized_transaction() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    let (relayer, shared, _chain_controller, tx_pool_controller) = setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let build_transaction = |index, data| {
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(last_cellbase.hash().clone(), index),
+                create_valid_script(),
+            ))
+            .output(CellOutput::new(50, data, H256::zero(), None))
+            .build()
+    };
+    let oversized = build_transaction(0, vec![0; PoolConfig::default().max_tx_size]);
+    let transaction = build_transaction(1, Vec::new());
+
+    let (nc, msg_receivers) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 3);
+    for tx in &[&oversized, &transaction] {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_transaction(fbb, tx);
+        fbb.finish(message, None);
+        relayer.received(Box::new(nc.clone()), 0, fbb.finished_data());
+    }
+    relayer.flush_transaction_announcements(&nc);
+
+    assert!(!tx_pool_controller.contains_key(oversized.proposal_short_id()));
+    assert!(tx_pool_controller.contains_key(transaction.proposal_short_id()));
+    assert_eq!(relayer.peers().misbehavior.read().get(&0), Some(&10));
+    // only the other transaction is announced
+    for peer in 1..3 {
+        assert_eq!(
+            announced_transactions(&msg_receivers[&(RELAY_PROTOCOL_ID, peer)]),
+            vec![vec![transaction.hash()]]
+        );
+    }
+}
+
+#[test]
+fn reconcile_mempools_with_sketch() {
+    let faketime_file = faketime::millis_tempfile(0).expect("create faketime file");
+    faketime::enable(&faketime_file);
+    let thread_name = format!("FAKETIME={}", faketime_file.display());
+
+    // both chains are the same, so are the cells the transactions spend
+    let (relayer1, shared, _chain_controller1, tx_pool_controller1) =
+        setup_relayer(&thread_name, 3);
+    let (relayer2, _shared2, _chain_controller2, tx_pool_controller2) =
+        setup_relayer(&thread_name, 3);
+    let last_block = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let last_cellbase = last_block.commit_transactions().first().unwrap();
+    let txs = (0..10u32)
+        .map(|index| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(last_cellbase.hash().clone(), index),
+                    create_valid_script(),
+                ))
+                .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let (common, only1) = txs[..7].split_at(5);
+    let only2 = &txs[7..];
+    for tx in common.iter().chain(only1) {
+        tx_pool_controller1
+            .add_transaction(tx.clone())
+            .expect("add transaction");
+    }
+    for tx in common.iter().chain(only2) {
+        tx_pool_controller2
+            .add_transaction(tx.clone())
+            .expect("add transaction");
+    }
+
+    // each relayer sees the other one as peer 0
+    let (nc1, receivers1) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 1);
+    let (nc2, receivers2) = TestNetworkContext::connected(&[RELAY_PROTOCOL_ID], 1);
+    let receiver1 = &receivers1[&(RELAY_PROTOCOL_ID, 0)];
+    let receiver2 = &receivers2[&(RELAY_PROTOCOL_ID, 0)];
+    let hashes = |txs: &[Transaction]| txs.iter().map(Transaction::hash).collect::<HashSet<_>>();
+
+    relayer1.send_mempool_sketches(&nc1);
+    let sketch = receiver1.try_recv().expect("sketch sent");
+    assert!(get_root::<RelayMessage>(&sketch)
+        .payload_as_mempool_sketch()
+        .is_some());
+    relayer2.received(Box::new(nc2.clone()), 0, &sketch);
+
+    // the transactions only one of the pools holds are asked for and announced, nothing else
+    let messages = receiver2.try_iter().collect::<Vec<_>>();
+    assert_eq!(messages.len(), 2);
+    let requested = messages
+        .iter()
+        .filter_map(|data| {
+            get_root::<RelayMessage>(data)
+                .payload_as_get_transactions()
+                .map(|message| {
+                    message
+                        .transaction_hashes()
+                        .unwrap()
+                        .iter()
+                        .map(Into::into)
+                        .collect::<HashSet<H256>>()
+                })
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(requested, vec![hashes(only1)]);
+    let announced = messages
+        .iter()
+        .filter_map(|data| {
+            get_root::<RelayMessage>(data)
+                .payload_as_transaction_inventory()
+                .map(|message| {
+                    message
+                        .transaction_hashes()
+                        .unwrap()
+                        .iter()
+                        .map(Into::into)
+                        .collect::<HashSet<H256>>()
+                })
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(announced, vec![hashes(only2)]);
+
+    // only the missing transactions are sent back
+    let get_transactions = messages
+        .iter()
+        .find(|data| {
+            get_root::<RelayMessage>(data)
+                .payload_as_get_transactions()
+                .is_some()
+        })
+        .unwrap();
+    relayer1.received(Box::new(nc1.clone()), 0, get_transactions);
+    let transactions = receiver1
+        .try_iter()
+        .map(|data| {
+            let message = get_root::<RelayMessage>(&data);
+            let tx: Transaction = message
+                .payload_as_transaction()
+                .expect("transaction")
+                .into();
+            relayer2.received(Box::new(nc2.clone()), 0, &data);
+            tx
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(hashes(&transactions[..]), hashes(only1));
+    for tx in only1 {
+        assert!(tx_pool_controller2.contains_key(tx.proposal_short_id()));
+    }
+}
+
 fn setup_node(
     thread_name: &str,
     height: u64,
@@ -302,6 +978,27 @@ fn setup_node(
     TestNode,
     Shared<ChainKVStore<MemoryKeyValueDB>>,
     ChainController,
+) {
+    let (relayer, shared, chain_controller, _tx_pool_controller) =
+        setup_relayer(thread_name, height);
+
+    let mut node = TestNode::default();
+    node.add_protocol(
+        RELAY_PROTOCOL_ID,
+        Arc::new(relayer),
+        vec![TX_PROPOSAL_TOKEN, TX_ANNOUNCEMENT_TOKEN],
+    );
+    (node, shared, chain_controller)
+}
+
+fn setup_relayer(
+    thread_name: &str,
+    height: u64,
+) -> (
+    Relayer<ChainKVStore<MemoryKeyValueDB>>,
+    Shared<ChainKVStore<MemoryKeyValueDB>>,
+    ChainController,
+    TransactionPoolController,
 ) {
     let mut block = BlockBuilder::default().with_header_builder(
         HeaderBuilder::default()
@@ -358,17 +1055,13 @@ fn setup_node(
     let relayer = Relayer::new(
         chain_controller.clone(),
         shared.clone(),
-        tx_pool_controller,
+        tx_pool_controller.clone(),
         Arc::new(Default::default()),
-    );
+        Arc::new(Config::default()),
+    )
+    .notify(notify);
 
-    let mut node = TestNode::default();
-    node.add_protocol(
-        RELAY_PROTOCOL_ID,
-        Arc::new(relayer),
-        vec![TX_PROPOSAL_TOKEN],
-    );
-    (node, shared, chain_controller)
+    (relayer, shared, chain_controller, tx_pool_controller)
 }
 
 // This helper is copied from pool test
@@ -1,17 +1,24 @@
+use crate::{
+    BANDWIDTH_WINDOW, BLOCK_DOWNLOAD_TIMEOUT, BLOCK_REQUEST_RETRY_DELAY, COMPACT_BLOCK_VERSION,
+    GET_HEADERS_MIN_INTERVAL, GET_HEADERS_VERSION, MAX_BANDWIDTH_ABOVE_AVERAGE, MAX_INVALID_BLOCKS,
+    MAX_SKETCH_DECODE_FAILURES, MAX_THROTTLED_GET_HEADERS, MAX_UNKNOWN_PAYLOADS,
+    SKETCH_FALLBACK_INTERVAL,
+};
 use bloom_filters::{
     BloomFilter, ClassicBloomFilter, DefaultBuildHashKernels, UpdatableBloomFilter,
 };
 use ckb_core::block::Block;
 use ckb_core::header::{BlockNumber, Header};
 use ckb_core::transaction::Transaction;
-use ckb_network::PeerIndex;
-use ckb_shared::shared::TipHeader;
-use ckb_util::RwLock;
+use ckb_network::{PeerId, PeerIndex};
+use ckb_protocol::AnnouncementMode;
+use ckb_util::{try_option, RwLock};
 use faketime::unix_time_as_millis;
 use fnv::{FnvHashMap, FnvHashSet};
-use log::debug;
+use log::{debug, warn};
 use numext_fixed_hash::H256;
 use numext_fixed_uint::U256;
+use std::cmp;
 use std::hash::{BuildHasher, Hasher};
 
 // State used to enforce CHAIN_SYNC_TIMEOUT
@@ -31,7 +38,7 @@ use std::hash::{BuildHasher, Hasher};
 #[derive(Clone, Debug, PartialEq)]
 pub struct ChainSyncState {
     pub timeout: u64,
-    pub work_header: Option<TipHeader>,
+    pub work_header: Option<HeaderView>,
     pub sent_getheaders: bool,
     pub protect: bool,
 }
@@ -47,6 +54,15 @@ impl Default for ChainSyncState {
     }
 }
 
+// Where the branch a peer's locator started from joined our main chain, at `number`
+// with `hash`. The branch of its next locator is walked down to `branch` only
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommonBlock {
+    pub branch: H256,
+    pub number: BlockNumber,
+    pub hash: H256,
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct PeerState {
     pub sync_started: bool,
@@ -54,6 +70,29 @@ pub struct PeerState {
     pub headers_sync_timeout: Option<u64>,
     pub disconnect: bool,
     pub chain_sync: ChainSyncState,
+    pub last_getheaders_response: Option<u64>, //ms
+    pub throttled_getheaders: u32,
+    pub getheaders_version: Option<u32>,
+    // Empty headers responses from a peer ahead of us retried in a row
+    pub empty_headers_retries: u32,
+    // Nonce of the ping waiting for its pong, it is the time the ping was sent
+    pub pending_ping: Option<u64>,
+    pub last_ping_sent: Option<u64>, //ms
+    pub ping_latency: Option<u64>,   //ms
+    // Headers from this peer were deferred as too far ahead of our tip, we ask for
+    // them again once the blocks catch up
+    pub headers_deferred: bool,
+    // Deadline of the answer to the first getheaders we sent, cleared by any headers
+    pub initial_headers_deadline: Option<u64>, //ms
+    // Block requests to this peer timed out in a row, reset by any block it serves
+    pub block_download_timeouts: u32,
+    // Last time the peer sent headers, a block or a transaction we accepted, starting
+    // from its connection
+    pub last_useful_message: u64, // ms
+    // Common block found for the last getheaders of the peer which walked a branch
+    pub getheaders_common_block: Option<CommonBlock>,
+    // A GetTip is waiting for its answer, only that Tip is taken as the peer's tip
+    pub tip_requested: bool,
 }
 
 #[derive(Default)]
@@ -64,6 +103,93 @@ pub struct Peers {
     pub best_known_headers: RwLock<FnvHashMap<PeerIndex, HeaderView>>,
     pub last_common_headers: RwLock<FnvHashMap<PeerIndex, Header>>,
     pub transaction_filters: RwLock<FnvHashMap<PeerIndex, TransactionFilter>>,
+    pub bandwidth: RwLock<FnvHashMap<PeerIndex, Bandwidth>>,
+    // Header of the compact block we failed to reconstruct and asked this peer in full,
+    // and when it was asked
+    pub compact_block_fallbacks: RwLock<FnvHashMap<PeerIndex, (Header, u64)>>,
+    // How each peer wants new blocks announced, a compact block when not told otherwise
+    pub announcement_modes: RwLock<FnvHashMap<PeerIndex, AnnouncementMode>>,
+    // Compact block version negotiated with each peer, 0 when it never told us its own
+    pub compact_block_versions: RwLock<FnvHashMap<PeerIndex, u32>>,
+    // Messages received from each peer that carried no recognized payload
+    pub unknown_payloads: RwLock<FnvHashMap<PeerIndex, u32>>,
+    // Blocks from each peer which failed validation
+    pub invalid_blocks: RwLock<FnvHashMap<PeerIndex, u32>>,
+    // Sketches from each peer which failed to decode in a row, and when we last answered
+    // one with our pool
+    pub sketch_decode_failures: RwLock<FnvHashMap<PeerIndex, (u32, Option<u64>)>>,
+    // Identity of each connected peer, to recognize it when it reconnects
+    pub identities: RwLock<FnvHashMap<PeerIndex, PeerId>>,
+    pub recently_disconnected: RwLock<FnvHashMap<PeerId, DisconnectedPeer>>,
+    // Timed out requests of each block across peers
+    pub block_request_retries: RwLock<FnvHashMap<H256, BlockRequestRetries>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockRequestRetries {
+    pub timeouts: u32,
+    // When we stopped asking for the block after too many timeouts, ms
+    pub suspended_at: Option<u64>,
+}
+
+// What we knew about a peer when it disconnected
+#[derive(Clone, Debug)]
+pub struct DisconnectedPeer {
+    pub best_known_header: Option<HeaderView>,
+    pub announcement_mode: Option<AnnouncementMode>,
+    pub compact_block_version: Option<u32>,
+    pub getheaders_version: Option<u32>,
+    pub timestamp: u64, // ms
+}
+
+// Counts bytes in fixed windows aligned to BANDWIDTH_WINDOW, the sliding total
+// weights the previous window by how much of it still overlaps
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct WindowCounter {
+    window_start: u64,
+    previous: u64,
+    current: u64,
+}
+
+impl WindowCounter {
+    fn add(&mut self, now: u64, bytes: u64) {
+        let windows = now.saturating_sub(self.window_start) / BANDWIDTH_WINDOW;
+        if windows > 0 {
+            self.previous = if windows == 1 { self.current } else { 0 };
+            self.current = 0;
+            self.window_start += windows * BANDWIDTH_WINDOW;
+        }
+        self.current += bytes;
+    }
+
+    fn total(&self, now: u64) -> u64 {
+        let mut counter = *self;
+        counter.add(now, 0);
+        let remaining = BANDWIDTH_WINDOW - now.saturating_sub(counter.window_start);
+        counter.previous * remaining / BANDWIDTH_WINDOW + counter.current
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bandwidth {
+    sent: WindowCounter,
+    received: WindowCounter,
+    // blocks asked from the peer which it delivered, the download throughput
+    blocks: WindowCounter,
+}
+
+impl Bandwidth {
+    pub fn sent(&self, now: u64) -> u64 {
+        self.sent.total(now)
+    }
+
+    pub fn received(&self, now: u64) -> u64 {
+        self.received.total(now)
+    }
+
+    pub fn blocks(&self, now: u64) -> u64 {
+        self.blocks.total(now)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -98,6 +224,10 @@ impl BlocksInflight {
         self.blocks.remove(hash)
     }
 
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains(hash)
+    }
+
     pub fn update_timestamp(&mut self) {
         self.timestamp = unix_time_as_millis();
     }
@@ -136,6 +266,19 @@ impl Peers {
                     headers_sync_timeout: Some(predicted_headers_sync_time),
                     disconnect: false,
                     chain_sync,
+                    last_getheaders_response: None,
+                    throttled_getheaders: 0,
+                    getheaders_version: None,
+                    empty_headers_retries: 0,
+                    pending_ping: None,
+                    last_ping_sent: None,
+                    ping_latency: None,
+                    headers_deferred: false,
+                    initial_headers_deadline: None,
+                    block_download_timeouts: 0,
+                    last_useful_message: unix_time_as_millis(),
+                    getheaders_common_block: None,
+                    tip_requested: false,
                 }
             });
     }
@@ -144,40 +287,401 @@ impl Peers {
         self.best_known_headers.read().get(&peer).cloned()
     }
 
+    // The best known header of a peer only moves forward, a header announced with
+    // less or equal total difficulty never replaces it
     pub fn new_header_received(&self, peer: PeerIndex, header_view: &HeaderView) {
         self.best_known_headers
             .write()
             .entry(peer)
             .and_modify(|hv| {
-                if header_view.total_difficulty() > hv.total_difficulty()
-                    || (header_view.total_difficulty() == hv.total_difficulty()
-                        && header_view.hash() < hv.hash())
-                {
+                if header_view.total_difficulty() > hv.total_difficulty() {
                     *hv = header_view.clone();
                 }
             })
             .or_insert_with(|| header_view.clone());
     }
 
-    pub fn getheaders_received(&self, _peer: PeerIndex) {
-        // TODO:
+    // The getheaders version negotiated with peer, our latest one until the peer talks
+    pub fn getheaders_version(&self, peer: PeerIndex) -> u32 {
+        self.state
+            .read()
+            .get(&peer)
+            .and_then(|state| state.getheaders_version)
+            .unwrap_or(GET_HEADERS_VERSION)
+    }
+
+    pub fn set_getheaders_version(&self, peer: PeerIndex, version: u32) {
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.getheaders_version = Some(cmp::min(version, GET_HEADERS_VERSION));
+        }
+    }
+
+    // Returns whether the getheaders request should be served
+    pub fn getheaders_received(&self, peer: PeerIndex) -> bool {
+        let now = unix_time_as_millis();
+        let throttled = {
+            let mut state = self.state.write();
+            match state.get_mut(&peer) {
+                Some(state) => {
+                    if state
+                        .last_getheaders_response
+                        .map_or(false, |last| now < last + GET_HEADERS_MIN_INTERVAL)
+                    {
+                        state.throttled_getheaders += 1;
+                        Some(state.throttled_getheaders)
+                    } else {
+                        state.last_getheaders_response = Some(now);
+                        state.throttled_getheaders = 0;
+                        None
+                    }
+                }
+                None => None,
+            }
+        };
+
+        match throttled {
+            Some(count) => {
+                debug!(target: "sync", "throttle getheaders from peer={} count={}", peer, count);
+                if count > MAX_THROTTLED_GET_HEADERS {
+                    self.misbehavior(peer, 10);
+                }
+                false
+            }
+            None => true,
+        }
+    }
+
+    // Counts a message without a recognized payload, a peer sending too many of them
+    // is likely speaking another protocol version or just garbage
+    pub fn unknown_payload(&self, peer: PeerIndex) {
+        let count = {
+            let mut unknown_payloads = self.unknown_payloads.write();
+            let count = unknown_payloads.entry(peer).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if count > MAX_UNKNOWN_PAYLOADS {
+            self.misbehavior(peer, 10);
+        }
+    }
+
+    // Scores a peer for serving a block which failed validation, more for each one,
+    // returns whether the peer served too many of them and should be disconnected
+    pub fn invalid_block_received(&self, peer: PeerIndex) -> bool {
+        let count = {
+            let mut invalid_blocks = self.invalid_blocks.write();
+            let count = invalid_blocks.entry(peer).or_insert(0);
+            *count += 1;
+            *count
+        };
+        self.misbehavior(peer, 100 * count);
+        count >= MAX_INVALID_BLOCKS
+    }
+
+    // Counts a sketch of the peer we failed to decode, scoring it once too many failed in
+    // a row. Returns whether it may be answered with our pool, at most once per
+    // SKETCH_FALLBACK_INTERVAL
+    pub fn sketch_decode_failed(&self, peer: PeerIndex) -> bool {
+        let now = unix_time_as_millis();
+        let (count, fallback) = {
+            let mut failures = self.sketch_decode_failures.write();
+            let (count, last_fallback) = failures.entry(peer).or_insert((0, None));
+            *count += 1;
+            let fallback =
+                last_fallback.map_or(true, |last| now >= last + SKETCH_FALLBACK_INTERVAL);
+            if fallback {
+                *last_fallback = Some(now);
+            }
+            (*count, fallback)
+        };
+        if count > MAX_SKETCH_DECODE_FAILURES {
+            self.misbehavior(peer, 10);
+        }
+        fallback
+    }
+
+    pub fn sketch_decoded(&self, peer: PeerIndex) {
+        if let Some((count, _)) = self.sketch_decode_failures.write().get_mut(&peer) {
+            *count = 0;
+        }
+    }
+
+    // Returns the round trip of the pending ping when the pong answers it
+    pub fn pong_received(&self, peer: PeerIndex, nonce: u64) -> Option<u64> {
+        let now = unix_time_as_millis();
+        let mut peer_state = self.state.write();
+        let state = try_option!(peer_state.get_mut(&peer));
+        if state.pending_ping != Some(nonce) {
+            return None;
+        }
+        let latency = now.saturating_sub(nonce);
+        state.pending_ping = None;
+        state.ping_latency = Some(latency);
+        Some(latency)
+    }
+
+    pub fn bytes_sent(&self, peer: PeerIndex, bytes: usize) {
+        let now = unix_time_as_millis();
+        self.bandwidth
+            .write()
+            .entry(peer)
+            .or_default()
+            .sent
+            .add(now, bytes as u64);
+    }
+
+    pub fn bytes_received(&self, peer: PeerIndex, bytes: usize) {
+        let now = unix_time_as_millis();
+        self.bandwidth
+            .write()
+            .entry(peer)
+            .or_default()
+            .received
+            .add(now, bytes as u64);
+    }
+
+    // Bytes (sent, received) to the peer within the last BANDWIDTH_WINDOW
+    pub fn bandwidth(&self, peer: PeerIndex) -> Option<(u64, u64)> {
+        let now = unix_time_as_millis();
+        self.bandwidth
+            .read()
+            .get(&peer)
+            .map(|bandwidth| (bandwidth.sent(now), bandwidth.received(now)))
+    }
+
+    // Blocks asked from the peer it delivered within the last BANDWIDTH_WINDOW
+    pub fn block_throughput(&self, peer: PeerIndex) -> u64 {
+        let now = unix_time_as_millis();
+        self.bandwidth
+            .read()
+            .get(&peer)
+            .map_or(0, |bandwidth| bandwidth.blocks(now))
+    }
+
+    // Returns whether the peer may be served now, the requests of a peer which has been
+    // sent far more than the other active peers are dropped until they catch up, it asks
+    // again once its requests time out
+    pub fn within_fair_share(&self, peer: PeerIndex) -> bool {
+        let now = unix_time_as_millis();
+        let bandwidth = self.bandwidth.read();
+        let sent = bandwidth
+            .get(&peer)
+            .map_or(0, |bandwidth| bandwidth.sent(now));
+        let (total, active) = bandwidth
+            .values()
+            .map(|bandwidth| bandwidth.sent(now))
+            .filter(|sent| *sent > 0)
+            .fold((0, 0), |(total, active), sent| (total + sent, active + 1));
+        active == 0 || sent <= total / active + MAX_BANDWIDTH_ABOVE_AVERAGE
     }
 
     pub fn disconnected(&self, peer: PeerIndex) {
+        if let Some(peer_id) = self.identities.write().remove(&peer) {
+            let disconnected = DisconnectedPeer {
+                best_known_header: self.best_known_headers.read().get(&peer).cloned(),
+                announcement_mode: self.announcement_modes.read().get(&peer).cloned(),
+                compact_block_version: self.compact_block_versions.read().get(&peer).cloned(),
+                getheaders_version: self
+                    .state
+                    .read()
+                    .get(&peer)
+                    .and_then(|state| state.getheaders_version),
+                timestamp: unix_time_as_millis(),
+            };
+            self.recently_disconnected
+                .write()
+                .insert(peer_id, disconnected);
+        }
         self.state.write().remove(&peer);
         self.best_known_headers.write().remove(&peer);
         // self.misbehavior.write().remove(peer);
         self.blocks_inflight.write().remove(&peer);
         self.last_common_headers.write().remove(&peer);
+        self.bandwidth.write().remove(&peer);
+        self.compact_block_fallbacks.write().remove(&peer);
+        self.announcement_modes.write().remove(&peer);
+        self.compact_block_versions.write().remove(&peer);
+        self.unknown_payloads.write().remove(&peer);
+        self.invalid_blocks.write().remove(&peer);
+        self.sketch_decode_failures.write().remove(&peer);
+    }
+
+    // Records the identity of a connected peer and gives it back what we knew about it
+    // if it was disconnected less than grace_period ago, returns whether it did
+    pub fn reconnected(&self, peer: PeerIndex, peer_id: &PeerId, grace_period: u64) -> bool {
+        let now = unix_time_as_millis();
+        let previous = {
+            let mut recently_disconnected = self.recently_disconnected.write();
+            recently_disconnected
+                .retain(|_, disconnected| now < disconnected.timestamp + grace_period);
+            recently_disconnected.remove(peer_id)
+        };
+        self.identities.write().insert(peer, peer_id.clone());
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return false,
+        };
+        if let Some(header_view) = previous.best_known_header {
+            self.best_known_headers.write().insert(peer, header_view);
+        }
+        if let Some(mode) = previous.announcement_mode {
+            self.announcement_modes.write().insert(peer, mode);
+        }
+        if let Some(version) = previous.compact_block_version {
+            self.compact_block_versions.write().insert(peer, version);
+        }
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.getheaders_version = previous.getheaders_version;
+        }
+        true
     }
 
     pub fn block_received(&self, peer: PeerIndex, block: &Block) {
-        let mut blocks_inflight = self.blocks_inflight.write();
-        debug!(target: "sync", "block_received from peer {} {} {:?}", peer, block.header().number(), block.header().hash());
-        blocks_inflight.entry(peer).and_modify(|inflight| {
-            inflight.remove(&block.header().hash());
-            inflight.update_timestamp();
+        let requested = {
+            let mut blocks_inflight = self.blocks_inflight.write();
+            debug!(target: "sync", "block_received from peer {} {} {:?}", peer, block.header().number(), block.header().hash());
+            blocks_inflight.get_mut(&peer).map_or(false, |inflight| {
+                inflight.update_timestamp();
+                inflight.remove(&block.header().hash())
+            })
+        };
+        if requested {
+            self.bandwidth
+                .write()
+                .entry(peer)
+                .or_default()
+                .blocks
+                .add(unix_time_as_millis(), 1);
+        }
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.block_download_timeouts = 0;
+            state.last_useful_message = unix_time_as_millis();
+        }
+        self.block_request_retries
+            .write()
+            .remove(&block.header().hash());
+    }
+
+    // Counts a timed out request of the block, once it timed out `max_retries` times
+    // across peers the block is not asked for until BLOCK_REQUEST_RETRY_DELAY elapsed
+    pub fn block_request_timeout(&self, hash: &H256, max_retries: u32) {
+        let mut block_request_retries = self.block_request_retries.write();
+        let retries = block_request_retries.entry(hash.clone()).or_default();
+        retries.timeouts += 1;
+        if retries.timeouts >= max_retries && retries.suspended_at.is_none() {
+            warn!(target: "sync", "block {:x} timed out {} times, stop requesting it", hash, retries.timeouts);
+            retries.suspended_at = Some(unix_time_as_millis());
+        }
+    }
+
+    // Whether the block timed out too many times to be requested now, the suspension of
+    // the block is lifted once the delay elapsed
+    pub fn is_block_request_suspended(&self, hash: &H256) -> bool {
+        let now = unix_time_as_millis();
+        let mut block_request_retries = self.block_request_retries.write();
+        let suspended_at = match block_request_retries.get(hash) {
+            Some(BlockRequestRetries {
+                suspended_at: Some(suspended_at),
+                ..
+            }) => *suspended_at,
+            _ => return false,
+        };
+        if now < suspended_at + BLOCK_REQUEST_RETRY_DELAY {
+            true
+        } else {
+            block_request_retries.remove(hash);
+            false
+        }
+    }
+
+    // Blocks we currently stopped requesting
+    pub fn suspended_block_requests(&self) -> Vec<H256> {
+        self.block_request_retries
+            .read()
+            .iter()
+            .filter(|(_, retries)| retries.suspended_at.is_some())
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    pub fn useful_message_received(&self, peer: PeerIndex) {
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.last_useful_message = unix_time_as_millis();
+        }
+    }
+
+    // None of the blocks asked to the peer arrived in BLOCK_DOWNLOAD_TIMEOUT
+    pub fn block_download_timeout(&self, peer: PeerIndex) {
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.block_download_timeouts += 1;
+        }
+    }
+
+    pub fn compact_block_fallback(&self, peer: PeerIndex, header: &Header) {
+        self.compact_block_fallbacks
+            .write()
+            .insert(peer, (header.clone(), unix_time_as_millis()));
+    }
+
+    // The block was completed some other way, no peer owes it to us anymore
+    pub fn compact_block_completed(&self, hash: &H256) {
+        self.compact_block_fallbacks
+            .write()
+            .retain(|_, (header, _)| &header.hash() != hash);
+    }
+
+    // A block at the height of the compact block we asked in full, which the sync didn't
+    // request either, must have the hash the peer announced. Returns false when the peer
+    // swapped it for another block. The request is forgotten once it times out like the
+    // blocks the sync asks for
+    pub fn check_compact_block_fallback(&self, peer: PeerIndex, block: &Block) -> bool {
+        let header = block.header();
+        let hash = header.hash();
+        let requested = self
+            .blocks_inflight
+            .read()
+            .get(&peer)
+            .map_or(false, |inflight| inflight.contains(&hash));
+        let mut fallbacks = self.compact_block_fallbacks.write();
+        let expired = fallbacks.get(&peer).map_or(false, |(_, requested_at)| {
+            unix_time_as_millis().saturating_sub(*requested_at) > BLOCK_DOWNLOAD_TIMEOUT
         });
+        if expired {
+            fallbacks.remove(&peer);
+        }
+        let is_fallback = fallbacks
+            .get(&peer)
+            .map_or(false, |(expected, _)| expected.number() == header.number());
+        if requested || !is_fallback {
+            return true;
+        }
+        fallbacks
+            .remove(&peer)
+            .map_or(true, |(expected, _)| expected.hash() == hash)
+    }
+
+    pub fn announcement_mode(&self, peer: PeerIndex) -> AnnouncementMode {
+        self.announcement_modes
+            .read()
+            .get(&peer)
+            .cloned()
+            .unwrap_or(AnnouncementMode::CompactBlock)
+    }
+
+    pub fn compact_block_version(&self, peer: PeerIndex) -> u32 {
+        self.compact_block_versions
+            .read()
+            .get(&peer)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    // The latest version both the peer and us understand
+    pub fn set_compact_block_version(&self, peer: PeerIndex, version: u32) {
+        self.compact_block_versions
+            .write()
+            .insert(peer, cmp::min(version, COMPACT_BLOCK_VERSION));
     }
 
     pub fn set_last_common_header(&self, peer: PeerIndex, header: &Header) {
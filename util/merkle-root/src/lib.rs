@@ -1,5 +1,6 @@
 use hash::Sha3;
 use numext_fixed_hash::H256;
+use std::collections::{BTreeMap, BTreeSet};
 
 fn lowest_children_len(amount: usize) -> usize {
     let mut n: usize = 1;
@@ -47,6 +48,124 @@ pub fn merkle_root(input: &[H256]) -> H256 {
     nodes[0].clone()
 }
 
+/// Collects the sibling hashes required to rebuild the merkle root from the leaves at
+/// `indices`, in the order `verify_merkle_proof` consumes them.
+pub fn merkle_proof(input: &[H256], indices: &[usize]) -> Option<Vec<H256>> {
+    let inlen = input.len();
+    if inlen == 0 || indices.iter().any(|index| *index >= inlen) {
+        return None;
+    }
+
+    let lwlen = lowest_children_len(inlen);
+    let known = indices.iter().cloned().collect::<BTreeSet<_>>();
+    let mut proof = Vec::new();
+    let mut positions = BTreeSet::new();
+    let mut nodes = Vec::with_capacity(inlen - lwlen / 2);
+
+    let mut i: usize = 0;
+    while i < lwlen {
+        if known.contains(&i) || known.contains(&(i + 1)) {
+            if !known.contains(&i) {
+                proof.push(input[i].clone());
+            }
+            if !known.contains(&(i + 1)) {
+                proof.push(input[i + 1].clone());
+            }
+            positions.insert(nodes.len());
+        }
+        nodes.push(merge(&input[i], &input[i + 1]));
+        i += 2;
+    }
+
+    for (index, h) in input.iter().enumerate().skip(i) {
+        if known.contains(&index) {
+            positions.insert(nodes.len());
+        }
+        nodes.push(h.clone());
+    }
+
+    while nodes.len() > 1 {
+        let mut parents = BTreeSet::new();
+        for position in &positions {
+            let sibling = position ^ 1;
+            if !positions.contains(&sibling) {
+                proof.push(nodes[sibling].clone());
+            }
+            parents.insert(position / 2);
+        }
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| merge(&pair[0], &pair[1]))
+            .collect();
+        positions = parents;
+    }
+
+    Some(proof)
+}
+
+/// Checks that `leaves`, given as (index, hash) pairs of a tree built from `len` hashes,
+/// together with `proof` rebuild `root`.
+pub fn verify_merkle_proof(
+    root: &H256,
+    len: usize,
+    leaves: &[(usize, H256)],
+    proof: &[H256],
+) -> bool {
+    if len == 0 || leaves.is_empty() || leaves.iter().any(|(index, _)| *index >= len) {
+        return false;
+    }
+
+    let lwlen = lowest_children_len(len);
+    let known = leaves.iter().cloned().collect::<BTreeMap<_, _>>();
+    let mut proof = proof.iter();
+    let mut nodes = BTreeMap::new();
+
+    let mut i: usize = 0;
+    while i < lwlen {
+        if known.contains_key(&i) || known.contains_key(&(i + 1)) {
+            let left = match known.get(&i).or_else(|| proof.next()) {
+                Some(left) => left,
+                None => return false,
+            };
+            let right = match known.get(&(i + 1)).or_else(|| proof.next()) {
+                Some(right) => right,
+                None => return false,
+            };
+            nodes.insert(i / 2, merge(left, right));
+        }
+        i += 2;
+    }
+
+    for (index, h) in known.range(lwlen..) {
+        nodes.insert(index - lwlen / 2, h.clone());
+    }
+
+    let mut width = len - lwlen / 2;
+    while width > 1 {
+        let mut parents = BTreeMap::new();
+        for (position, h) in &nodes {
+            let parent = if position % 2 == 0 {
+                match nodes.get(&(position + 1)).or_else(|| proof.next()) {
+                    Some(right) => merge(h, right),
+                    None => return false,
+                }
+            } else if nodes.contains_key(&(position - 1)) {
+                continue;
+            } else {
+                match proof.next() {
+                    Some(left) => merge(left, h),
+                    None => return false,
+                }
+            };
+            parents.insert(position / 2, parent);
+        }
+        nodes = parents;
+        width >>= 1;
+    }
+
+    proof.next().is_none() && nodes.get(&0) == Some(root)
+}
+
 fn merge(left: &H256, right: &H256) -> H256 {
     let mut hash = [0u8; 32];
     let mut sha3 = Sha3::new_sha3_256();
@@ -58,7 +177,8 @@ fn merge(left: &H256, right: &H256) -> H256 {
 
 #[cfg(test)]
 mod tests {
-    use super::merkle_root;
+    use super::{merkle_proof, merkle_root, verify_merkle_proof};
+    use hash::sha3_256;
     use numext_fixed_hash::H256;
     use std::str::FromStr;
 
@@ -82,4 +202,57 @@ mod tests {
         );
     }
 
+    fn leaves(len: usize) -> Vec<H256> {
+        (0..len).map(|i| sha3_256(&[i as u8]).into()).collect()
+    }
+
+    #[test]
+    fn merkle_proof_single_leaf() {
+        for len in 1..20 {
+            let input = leaves(len);
+            let root = merkle_root(&input);
+            for index in 0..len {
+                let proof = merkle_proof(&input, &[index]).unwrap();
+                assert!(verify_merkle_proof(
+                    &root,
+                    len,
+                    &[(index, input[index].clone())],
+                    &proof
+                ));
+                assert!(!verify_merkle_proof(
+                    &root,
+                    len,
+                    &[(index, H256::zero())],
+                    &proof
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn merkle_proof_multiple_leaves() {
+        for len in 2..20 {
+            let input = leaves(len);
+            let root = merkle_root(&input);
+            for step in 1..len {
+                let indices = (0..len).step_by(step).collect::<Vec<_>>();
+                let proof = merkle_proof(&input, &indices).unwrap();
+                let known = indices
+                    .iter()
+                    .map(|index| (*index, input[*index].clone()))
+                    .collect::<Vec<_>>();
+                assert!(verify_merkle_proof(&root, len, &known, &proof));
+                if !proof.is_empty() {
+                    assert!(!verify_merkle_proof(&root, len, &known, &proof[1..]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn merkle_proof_out_of_range() {
+        let input = leaves(3);
+        assert_eq!(merkle_proof(&input, &[3]), None);
+        assert_eq!(merkle_proof(&[], &[0]), None);
+    }
 }
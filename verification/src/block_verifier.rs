@@ -138,7 +138,7 @@ impl EmptyVerifier {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct DuplicateVerifier {}
 
 impl DuplicateVerifier {
@@ -168,7 +168,7 @@ impl DuplicateVerifier {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct MerkleRootVerifier {}
 
 impl MerkleRootVerifier {